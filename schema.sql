@@ -1,15 +1,31 @@
 CREATE TABLE IF NOT EXISTS trips (
     id TEXT PRIMARY KEY,
     destination TEXT NOT NULL,
-    days INTEGER NOT NULL
+    days INTEGER NOT NULL,
+    deleted_at INTEGER,
+    status TEXT NOT NULL DEFAULT 'planning' CHECK (status IN ('planning', 'booked', 'completed', 'cancelled')),
+    title TEXT,
+    tenant_id TEXT,
+    summary TEXT,
+    slug TEXT,
+    packing_list TEXT,
+    tags TEXT,
+    interests TEXT,
+    units TEXT,
+    currency TEXT
 );
 
+CREATE UNIQUE INDEX IF NOT EXISTS trips_slug ON trips(slug);
+
 CREATE TABLE IF NOT EXISTS plans (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     trip_id TEXT NOT NULL,
     plan BLOB NOT NULL,
     input_text BLOB NOT NULL,
-    updated_at TEXT NOT NULL,
+    updated_at INTEGER NOT NULL,
+    refused INTEGER NOT NULL DEFAULT 0,
+    tenant_id TEXT,
+    schedule TEXT,
     FOREIGN KEY (trip_id) REFERENCES trips(id) ON DELETE CASCADE
 );
 
@@ -18,6 +34,68 @@ CREATE TABLE IF NOT EXISTS messages(
     trip_id TEXT NOT NULL,
     message TEXT NOT NULL,
     messager_role TEXT NOT NULL,
-    created_at TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    metadata TEXT,
+    refused INTEGER NOT NULL DEFAULT 0,
+    pinned INTEGER NOT NULL DEFAULT 0,
+    tenant_id TEXT,
     FOREIGN KEY (trip_id) REFERENCES trips(id) ON DELETE CASCADE
-);
\ No newline at end of file
+);
+
+CREATE TABLE IF NOT EXISTS jobs(
+    id TEXT PRIMARY KEY,
+    type TEXT NOT NULL DEFAULT 'regenerate',
+    status TEXT NOT NULL CHECK (status IN ('running', 'done')),
+    total INTEGER NOT NULL,
+    completed INTEGER NOT NULL DEFAULT 0,
+    failed INTEGER NOT NULL DEFAULT 0,
+    results TEXT NOT NULL DEFAULT '[]',
+    created_at TEXT NOT NULL,
+    tenant_id TEXT
+);
+
+CREATE TABLE IF NOT EXISTS sessions(
+    trip_id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (trip_id, key)
+);
+
+CREATE TABLE IF NOT EXISTS ai_calls(
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    operation TEXT NOT NULL,
+    model TEXT NOT NULL,
+    latency_ms INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS ai_calls_created_at ON ai_calls(created_at);
+
+-- Migrations for databases created before the columns above existed:
+-- ALTER TABLE trips ADD COLUMN deleted_at INTEGER;
+-- ALTER TABLE messages ADD COLUMN metadata TEXT;
+-- ALTER TABLE plans ADD COLUMN refused INTEGER NOT NULL DEFAULT 0;
+-- ALTER TABLE messages ADD COLUMN refused INTEGER NOT NULL DEFAULT 0;
+-- ALTER TABLE trips ADD COLUMN status TEXT NOT NULL DEFAULT 'planning' CHECK (status IN ('planning', 'booked', 'completed', 'cancelled'));
+-- ALTER TABLE messages ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+-- ALTER TABLE trips ADD COLUMN title TEXT;
+-- ALTER TABLE trips ADD COLUMN tenant_id TEXT;
+-- ALTER TABLE plans ADD COLUMN tenant_id TEXT;
+-- ALTER TABLE messages ADD COLUMN tenant_id TEXT;
+-- ALTER TABLE trips ADD COLUMN summary TEXT;
+-- ALTER TABLE trips ADD COLUMN slug TEXT;
+-- CREATE UNIQUE INDEX IF NOT EXISTS trips_slug ON trips(slug);
+-- ALTER TABLE jobs ADD COLUMN type TEXT NOT NULL DEFAULT 'regenerate';
+-- ALTER TABLE jobs ADD COLUMN completed INTEGER NOT NULL DEFAULT 0;
+-- ALTER TABLE jobs ADD COLUMN failed INTEGER NOT NULL DEFAULT 0;
+-- ALTER TABLE plans ADD COLUMN schedule TEXT;
+-- ALTER TABLE trips ADD COLUMN packing_list TEXT;
+-- ALTER TABLE trips ADD COLUMN tags TEXT;
+-- ALTER TABLE trips ADD COLUMN interests TEXT;
+-- ALTER TABLE trips ADD COLUMN units TEXT;
+-- ALTER TABLE trips ADD COLUMN currency TEXT;
+--
+-- plans.updated_at, messages.created_at and trips.deleted_at are INTEGER epoch milliseconds.
+-- SQLite cannot change a column's type in place, so older databases keep the TEXT columns:
+-- new rows store integers in them all the same, and rows written before hold date strings,
+-- which are still read (see time::stored_millis), and get_messages orders both kinds by
+-- their parsed time. deleted_at is only ever tested for NULL.
@@ -0,0 +1,160 @@
+//! Global admission control for trip creation.
+//!
+//! `POST /input` makes an AI call and a durable object `/init` per trip, so a spike of new
+//! trips can exhaust the AI quota and crowd the durable object infrastructure. With
+//! `ADMISSION_MAX_CONCURRENCY` set, [`guarded`] takes a slot from the single
+//! [`AdmissionCounter`] durable object (binding `ADMISSION_DO`) before the operation runs and
+//! gives it back once it finishes, whether it succeeded or failed. Requests arriving while
+//! every slot is taken are shed with `503` and a `Retry-After` header.
+//!
+//! A slot that is never given back (the worker was evicted mid-request, or the release
+//! itself failed) expires after [`SLOT_LEASE_MS`], so a lost release cannot shrink the cap
+//! for good.
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+
+use uuid::Uuid;
+use worker::*;
+
+use crate::config::Config;
+use crate::{do_fetch, json_error, DO_FETCH_RETRIES, DO_FETCH_TIMEOUT_MS};
+
+/// How long a slot is held before it is considered lost and freed, in milliseconds. Well
+/// above the time a plan generation takes.
+const SLOT_LEASE_MS: u64 = 120_000;
+
+/// The name of the one [`AdmissionCounter`] every worker shares.
+const COUNTER_NAME: &str = "global";
+
+/// Runs `operation` under the `ADMISSION_MAX_CONCURRENCY` cap.
+///
+/// With the cap at `0` (the default) the operation simply runs. Otherwise a slot is taken
+/// first and released after the operation returns, on its error paths as well.
+///
+/// # Returns
+/// The operation's response, or `503 Service Unavailable` with a
+/// `Retry-After: ADMISSION_RETRY_AFTER_SECS` header when no slot is free. When the counter
+/// cannot be reached the operation runs anyway if `ADMISSION_FAIL_OPEN` is on (the
+/// default), and is shed like a full cap if it is off.
+pub async fn guarded<F>(env: &Env, config: &Config, operation: F) -> Result<Response>
+where
+    F: Future<Output = Result<Response>>,
+{
+    if config.admission_max_concurrency == 0 {
+        return operation.await;
+    }
+    let ticket = match acquire(env, config.admission_max_concurrency).await {
+        Ok(Some(ticket)) => Some(ticket),
+        Ok(None) => return shed(config, "too many trips are being planned right now, try again shortly"),
+        Err(e) if config.admission_fail_open => {
+            console_error!("Admission counter unreachable, admitting the request: {e}");
+            None
+        }
+        Err(e) => {
+            console_error!("Admission counter unreachable, shedding the request: {e}");
+            return shed(config, "trip planning is temporarily unavailable, try again shortly");
+        }
+    };
+    let result = operation.await;
+    if let Some(ticket) = ticket {
+        if let Err(e) = release(env, &ticket).await {
+            console_warn!("Failed to release admission slot {ticket}, it expires in {SLOT_LEASE_MS}ms: {e}");
+        }
+    }
+    result
+}
+
+/// The `503` sent for a shed request.
+fn shed(config: &Config, message: &str) -> Result<Response> {
+    let mut resp = json_error(message, 503)?;
+    resp.headers_mut().set("Retry-After", &config.admission_retry_after_secs.to_string())?;
+    Ok(resp)
+}
+
+/// Asks the counter for a slot under a cap of `max`.
+///
+/// # Returns
+/// The slot's ticket, or `None` when every slot is taken.
+async fn acquire(env: &Env, max: u32) -> Result<Option<String>> {
+    let mut resp = send(env, &format!("/acquire?max={max}")).await?;
+    match resp.status_code() {
+        200 => Ok(Some(resp.text().await?)),
+        503 => Ok(None),
+        status => Err(Error::RustError(format!("admission counter answered {status}"))),
+    }
+}
+
+/// Gives the slot of `ticket` back to the counter.
+async fn release(env: &Env, ticket: &str) -> Result<()> {
+    let resp = send(env, &format!("/release?ticket={ticket}")).await?;
+    match resp.status_code() {
+        200 => Ok(()),
+        status => Err(Error::RustError(format!("admission counter answered {status}"))),
+    }
+}
+
+/// Sends a `POST` to the shared counter.
+async fn send(env: &Env, path: &str) -> Result<Response> {
+    let stub = env.durable_object("ADMISSION_DO")?.get_by_name(COUNTER_NAME)?;
+    let mut init = RequestInit::new();
+    init.method = Method::Post;
+    let req = Request::new_with_init(&format!("https://admission{path}"), &init)?;
+    do_fetch(&stub, req, DO_FETCH_RETRIES, DO_FETCH_TIMEOUT_MS).await
+}
+
+/// The durable object counting the operations admitted by [`guarded`] across all workers.
+///
+/// # Attributes:
+/// - `slots`: The tickets currently holding a slot, with the time (epoch milliseconds) each
+///   was handed out. Kept in memory only: if the object is evicted the count starts over,
+///   which at worst admits one extra round of requests.
+#[durable_object]
+pub struct AdmissionCounter {
+    slots: RefCell<HashMap<String, u64>>,
+}
+
+impl DurableObject for AdmissionCounter {
+    fn new(_state: State, _env: Env) -> Self {
+        Self { slots: RefCell::new(HashMap::new()) }
+    }
+
+    /// Handles the counter's routes:
+    ///
+    /// - **POST /acquire?max=N**: Frees slots older than [`SLOT_LEASE_MS`], then hands out a
+    ///   new slot if fewer than `N` are taken, answering `200` with its ticket as text.
+    ///   Otherwise answers `503` with `Retry-After: 1` (so `do_fetch` does not retry it).
+    /// - **POST /release?ticket=T**: Frees the slot of ticket `T` and answers `"released"`,
+    ///   also when it had already expired.
+    /// - Anything else answers `404`.
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let param = |name: &str| url.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.to_string());
+        match (req.method(), url.path()) {
+            (Method::Post, "/acquire") => {
+                let Some(max) = param("max").and_then(|m| m.parse::<usize>().ok()) else {
+                    return Response::error("max must be a number", 400);
+                };
+                let now = Date::now().as_millis();
+                let mut slots = self.slots.borrow_mut();
+                slots.retain(|_, acquired| now.saturating_sub(*acquired) < SLOT_LEASE_MS);
+                if slots.len() >= max {
+                    let mut resp = Response::error("admission limit reached", 503)?;
+                    resp.headers_mut().set("Retry-After", "1")?;
+                    return Ok(resp);
+                }
+                let ticket = Uuid::new_v4().to_string();
+                slots.insert(ticket.clone(), now);
+                Response::ok(ticket)
+            }
+            (Method::Post, "/release") => {
+                let Some(ticket) = param("ticket") else {
+                    return Response::error("missing ticket", 400);
+                };
+                self.slots.borrow_mut().remove(&ticket);
+                Response::ok("released")
+            }
+            _ => Response::error("not found", 404),
+        }
+    }
+}
@@ -27,7 +27,13 @@
 use serde_json::json;
 use worker::wasm_bindgen::__rt::IntoJsResult;
 use worker::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use futures_util::stream::LocalBoxStream;
+use crate::config::Config;
+use crate::role::MessageRole;
+use futures_util::{StreamExt, TryStreamExt};
+use crate::time::CalendarDate;
+use crate::units::UnitPreferences;
 
 /// Represents the response structure from a Cloudflare AI service.
 ///
@@ -41,7 +47,7 @@ use serde::Deserialize;
 ///
 /// # Derives
 /// * `Deserialize` - Automatically implements deserialization behavior for transforming
-/// JSON data into an instance of `CfAiResponse`.
+///   JSON data into an instance of `CfAiResponse`.
 ///
 /// # Example
 /// ```rust
@@ -88,13 +94,303 @@ struct CfAiResponse {
 struct CfAiResult {
     response: String,
 }
+/// The longest destination, in characters, that is inserted into a plan prompt.
+///
+/// This is deliberately separate from any validation applied to the stored destination so
+/// that prompts stay bounded even if the input limits change.
+const PROMPT_DESTINATION_MAX_CHARS: usize = 80;
+
+/// Shortens a destination for use in a prompt.
+///
+/// Destinations longer than [`PROMPT_DESTINATION_MAX_CHARS`] are cut at a character
+/// boundary and suffixed with `…`; a log line records that truncation happened.
+///
+/// # Arguments
+///
+/// * `destination` - The destination as submitted by the user.
+///
+/// # Returns
+///
+/// The destination unchanged, or its truncated form.
+fn prompt_destination(destination: &str) -> String {
+    if destination.chars().count() <= PROMPT_DESTINATION_MAX_CHARS {
+        return destination.to_string();
+    }
+    console_log!("Destination truncated to {PROMPT_DESTINATION_MAX_CHARS} characters for the plan prompt");
+    let truncated: String = destination.chars().take(PROMPT_DESTINATION_MAX_CHARS).collect();
+    format!("{}…", truncated.trim_end())
+}
+
+/// Normalizes a destination into the key used to look up its facts in KV.
+///
+/// The key is lowercased with surrounding whitespace removed and inner whitespace runs
+/// collapsed to a single space, so `"  New   York "` becomes `"new york"`.
+fn facts_key(destination: &str) -> String {
+    destination.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Loads known facts about a destination from the `DESTINATION_FACTS` KV namespace.
+///
+/// Facts are free text (e.g. "Currency is EUR. Tipping is not expected.") stored under the
+/// key produced by [`facts_key`].
+///
+/// # Returns
+///
+/// `Some(facts)` when an entry exists. A missing binding, a missing key, an empty value or
+/// a KV error all yield `None` so plan generation carries on without enrichment.
+async fn destination_facts(env: &Env, destination: &str) -> Option<String> {
+    let kv = env.kv("DESTINATION_FACTS").ok()?;
+    match kv.get(&facts_key(destination)).text().await {
+        Ok(facts) => facts.filter(|f| !f.trim().is_empty()),
+        Err(e) => {
+            console_warn!("Failed to load destination facts: {e:?}");
+            None
+        }
+    }
+}
+
+/// Wraps a rendered prompt with the deployment-wide prefix and suffix.
+///
+/// The `PROMPT_PREFIX` and `PROMPT_SUFFIX` environment variables let a deployment add
+/// standard instructions (branding, guardrails) to every prompt without touching the
+/// templates in this module. Both default to empty, in which case the prompt is
+/// returned unchanged.
+///
+/// # Arguments
+///
+/// * `env` - The environment used to look up `PROMPT_PREFIX` and `PROMPT_SUFFIX`.
+/// * `prompt` - The prompt rendered from one of the templates in this module.
+///
+/// # Returns
+///
+/// The prompt with the non-empty prefix and suffix joined on with newlines.
+fn wrap_prompt(env: &Env, prompt: String) -> String {
+    let prefix = env.var("PROMPT_PREFIX").map(|v| v.to_string()).unwrap_or_default();
+    let suffix = env.var("PROMPT_SUFFIX").map(|v| v.to_string()).unwrap_or_default();
+    [prefix.trim(), prompt.as_str(), suffix.trim()]
+        .iter()
+        .filter(|part| !part.is_empty())
+        .copied()
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The model used when neither `AI_MODEL` nor `AI_MODEL_TIERS` is set.
+const DEFAULT_MODEL: &str = "@cf/meta/llama-3.1-8b-instruct-fast";
+
+/// Models accepted in `AI_MODEL_TIERS` when `AI_MODEL_ALLOWLIST` is not set.
+const DEFAULT_ALLOWED_MODELS: [&str; 8] = [
+    "@cf/meta/llama-3.2-1b-instruct",
+    "@cf/meta/llama-3.2-3b-instruct",
+    "@cf/meta/llama-3.1-8b-instruct",
+    "@cf/meta/llama-3.1-8b-instruct-fast",
+    "@cf/meta/llama-3.1-70b-instruct",
+    "@cf/meta/llama-3.3-70b-instruct-fp8-fast",
+    "@cf/mistral/mistral-7b-instruct-v0.1",
+    "@cf/google/gemma-7b-it",
+];
+
+/// The highest score [`Complexity::score`] can give.
+const MAX_COMPLEXITY: usize = 5;
+
+/// The model for requests that do not go through [`choose_model`]: `AI_MODEL`, or
+/// [`DEFAULT_MODEL`] when it is not set.
+fn default_model(env: &Env) -> String {
+    env.var("AI_MODEL").map(|v| v.to_string()).unwrap_or(DEFAULT_MODEL.to_string())
+}
+
+/// Splits a comma-separated list of models, dropping blank entries.
+fn model_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|m| !m.is_empty()).map(str::to_string).collect()
+}
+
+/// Reads the models [`choose_model`] picks from, cheapest first.
+///
+/// `AI_MODEL_TIERS` lists them as a comma-separated string, e.g.
+/// `@cf/meta/llama-3.2-3b-instruct,@cf/meta/llama-3.3-70b-instruct-fp8-fast`. When it is not
+/// set the only tier is [`default_model`], so every request uses `AI_MODEL` as before.
+///
+/// # Errors
+/// A message naming the first tier that is not on the allowlist: `AI_MODEL_ALLOWLIST`
+/// (comma-separated) when set, [`DEFAULT_ALLOWED_MODELS`] otherwise.
+pub fn model_tiers(env: &Env) -> std::result::Result<Vec<String>, String> {
+    let tiers = env.var("AI_MODEL_TIERS").map(|v| model_list(&v.to_string())).unwrap_or_default();
+    if tiers.is_empty() {
+        return Ok(vec![default_model(env)]);
+    }
+    let allowed = match env.var("AI_MODEL_ALLOWLIST") {
+        Ok(v) => model_list(&v.to_string()),
+        Err(_) => DEFAULT_ALLOWED_MODELS.iter().map(|m| m.to_string()).collect(),
+    };
+    match tiers.iter().find(|m| !allowed.contains(m)) {
+        Some(model) => Err(format!("model {model:?} is not on the allowlist")),
+        None => Ok(tiers),
+    }
+}
+
+/// Returns `true` when `model` is one of the [`model_tiers`], so a request may ask for it.
+pub fn is_tier_model(env: &Env, model: &str) -> bool {
+    model_tiers(env).is_ok_and(|tiers| tiers.iter().any(|m| m == model))
+}
+
+/// What makes a request harder for the model, used by [`choose_model`].
+///
+/// # Fields
+/// * `days` - The number of days to plan, or `0` for chat.
+/// * `interests` - How many interests the traveller listed.
+/// * `history` - How many earlier chat messages are sent as context.
+/// * `specific_destination` - Whether the destination names more than one place or a
+///   specific area, e.g. `"Kyoto, Nara and Osaka"` or `"Le Marais, Paris"`.
+pub struct Complexity {
+    pub days: u32,
+    pub interests: usize,
+    pub history: usize,
+    pub specific_destination: bool,
+}
+
+impl Complexity {
+    /// The complexity of writing a plan.
+    pub fn plan(destination: &str, days: u32, interests: usize) -> Self {
+        let specific_destination = destination.contains(',') || destination.split_whitespace().count() > 3;
+        Self { days, interests, history: 0, specific_destination }
+    }
+
+    /// The complexity of answering a chat question with `history` messages of context.
+    pub fn chat(history: usize) -> Self {
+        Self { days: 0, interests: 0, history, specific_destination: false }
+    }
+
+    /// Scores the request from `0` to [`MAX_COMPLEXITY`], with the reasons that added to it.
+    fn score(&self) -> (usize, Vec<&'static str>) {
+        let reasons: Vec<&'static str> = [
+            (self.days > 7, "more than 7 days"),
+            (self.days > 14, "more than 14 days"),
+            (self.interests >= 3, "3 or more interests"),
+            (self.history >= 10, "10 or more history messages"),
+            (self.specific_destination, "specific destination"),
+        ]
+        .into_iter()
+        .filter_map(|(applies, reason)| applies.then_some(reason))
+        .collect();
+        (reasons.len(), reasons)
+    }
+}
+
+/// Picks the model for a request from the [`model_tiers`] and logs the choice.
+///
+/// The request's [`Complexity`] score is spread over the tiers, so a score of `0` uses the
+/// cheapest tier and [`MAX_COMPLEXITY`] the strongest. A `requested` model that is one of
+/// the tiers wins over the score. With invalid tiers (reported by `Config::from_env`),
+/// [`default_model`] is used.
+fn choose_model(env: &Env, complexity: &Complexity, requested: Option<&str>) -> String {
+    let tiers = model_tiers(env).unwrap_or_else(|_| vec![default_model(env)]);
+    if let Some(model) = requested.filter(|m| tiers.iter().any(|t| t == m)) {
+        console_log!("Model {model} chosen: requested");
+        return model.to_string();
+    }
+    let (score, reasons) = complexity.score();
+    let tier = (score * (tiers.len() - 1) + MAX_COMPLEXITY / 2) / MAX_COMPLEXITY;
+    let model = tiers[tier.min(tiers.len() - 1)].clone();
+    let reasons = if reasons.is_empty() { "simple request".to_string() } else { reasons.join(", ") };
+    console_log!("Model {model} chosen: complexity {score}/{MAX_COMPLEXITY} ({reasons})");
+    model
+}
+
+/// Phrases that mark a model response as a refusal when `REFUSAL_PATTERNS` is not set.
+const DEFAULT_REFUSAL_PATTERNS: [&str; 8] = [
+    "i can't help",
+    "i cannot help",
+    "i can't assist",
+    "i cannot assist",
+    "i'm unable to",
+    "i am unable to",
+    "i'm sorry, but i can",
+    "as an ai language model",
+];
+
+/// Returns `true` when a model response looks like a refusal rather than a real answer.
+///
+/// Refusals ("I can't help with that") are still stored, but flagged so the frontend can
+/// offer a retry instead of presenting them as a plan or reply.
+///
+/// # Arguments
+///
+/// * `env` - The environment; `REFUSAL_PATTERNS` replaces the built-in phrases with a
+///   `|`-separated list, e.g. `I can't help|not able to plan`.
+/// * `text` - The model's response.
+///
+/// # Returns
+///
+/// Whether any pattern occurs in `text`. Matching is case-insensitive and treats curly
+/// apostrophes (`’`) as straight ones.
+pub fn is_refusal(env: &Env, text: &str) -> bool {
+    let text = text.to_lowercase().replace('’', "'");
+    let matches = |pattern: &str| {
+        let pattern = pattern.trim().to_lowercase().replace('’', "'");
+        !pattern.is_empty() && text.contains(&pattern)
+    };
+    match env.var("REFUSAL_PATTERNS") {
+        Ok(patterns) => patterns.to_string().split('|').any(matches),
+        Err(_) => DEFAULT_REFUSAL_PATTERNS.iter().any(|pattern| matches(pattern)),
+    }
+}
+
+/// Checks that the settings needed to call the AI service are present.
+///
+/// No request is made to the AI service, so this is safe to call from health probes.
+///
+/// # Returns
+///
+/// `Ok(())` when `mock` (`MOCK_AI`) is on or both `CF_ACCOUNT_ID` and the `CF_API_TOKEN`
+/// secret are set, otherwise an error naming the missing setting.
+pub fn check_configured(env: &Env, mock: bool) -> Result<()> {
+    if mock {
+        return Ok(());
+    }
+    env.var("CF_ACCOUNT_ID").map_err(|_| Error::RustError("CF_ACCOUNT_ID is not set".into()))?;
+    env.secret("CF_API_TOKEN").map_err(|_| Error::RustError("CF_API_TOKEN is not set".into()))?;
+    Ok(())
+}
+
+/// Sends the AI service a trivial prompt, so the path to it is warm for the next real request.
+///
+/// Used by the warmup (see `WARMUP_ENABLED`). The answer is discarded. With `MOCK_AI` on,
+/// nothing is sent.
+///
+/// # Errors
+/// If the AI service is not configured or the call fails.
+pub async fn ping(env: &Env, config: &Config) -> Result<()> {
+    if config.mock_ai {
+        return Ok(());
+    }
+    run_prompt(env, config, "Reply with the single word OK.".to_string()).await?;
+    Ok(())
+}
+
+/// Builds the canned plan returned by [`create_plan`] and [`extend_plan`] when `MOCK_AI` is enabled.
+///
+/// The output depends only on the inputs and uses the same `Day N` layout the real model
+/// produces, so the rest of the pipeline (storage, parsing, rendering) behaves identically.
+fn mock_plan(destination: &str, days: std::ops::RangeInclusive<u32>) -> String {
+    days
+        .map(|i| format!(
+            "Day {i}:\nMorning: Breakfast in {destination} - A relaxed start to day {i}.\n\
+             Afternoon: Sightseeing in {destination} - Visit a local landmark.\n\
+             Evening: Dinner in {destination} - Try a regional speciality."
+        ))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 /// Asynchronously generates a multi-day travel itinerary for a specified destination.
 ///
 /// # Arguments
 ///
 /// * `env` - A reference to the environment object (`Env`) that contains configuration values such as Cloudflare Account ID, AI model, and API tokens.
-/// * `destination` - A reference to a `String` representing the destination for the travel plan.
+/// * `config` - The deployment settings; `MOCK_AI` skips the model and `PROMPT_MAX_CHARS` caps the prompt.
+/// * `destination` - A string slice representing the destination for the travel plan.
 /// * `days` - A `u32` representing the number of days for which the trip should be planned.
+/// * `units` - The units and currency distances, temperatures and costs are given in (see `units`).
 ///
 /// # Returns
 ///
@@ -120,6 +416,12 @@ struct CfAiResult {
 /// - `CF_ACCOUNT_ID` (Required): The Cloudflare Account ID used to identify the user.
 /// - `CF_API_TOKEN` (Required): A secure API token for authentication.
 /// - `AI_MODEL` (Optional, defaults to "@cf/meta/llama-3.1-8b-instruct-fast"): The AI model to run for generating the travel itinerary.
+/// - `DESTINATION_FACTS` (Optional KV binding): Facts about popular destinations, keyed by the
+///   normalized destination (see [`destination_facts`]), added to every day's prompt when present.
+/// - `MOCK_AI` (Optional, default `false`): Return a deterministic canned plan instead of calling the AI service.
+/// - `PROMPT_PREFIX` / `PROMPT_SUFFIX` (Optional, default empty): Text wrapped around every day's prompt (see [`wrap_prompt`]).
+/// - `DEFAULT_PLAN_FORMAT` (Optional, default `structured`): Whether days are written as
+///   structured entries or `prose` (see [`PlanFormat`]).
 ///
 /// # Errors
 ///
@@ -140,7 +442,7 @@ struct CfAiResult {
 ///     let destination = "Paris".to_string();
 ///     let days = 3;
 ///
-///     match create_plan(&env, &destination, days).await {
+///     match create_plan(&env, config, &destination, days, &UnitPreferences::default()).await {
 ///         Ok((itinerary, summary)) => {
 ///             println!("Generated Itinerary:\n{}", itinerary);
 ///             println!("Summary:\n{}", summary);
@@ -154,27 +456,416 @@ struct CfAiResult {
 ///
 /// # Notes
 ///
-/// - The AI prompt enforces that the response includes only an itinerary in a structured format with no additional content.
+/// - The AI prompt enforces that the response includes only an itinerary, in the deployment's [`PlanFormat`], with no additional content.
+/// - The destination is shortened to [`PROMPT_DESTINATION_MAX_CHARS`] characters before it is put in the prompt.
 /// - Each API call is logged per day (e.g., "Day X of Y done").
-pub async fn create_plan(env: &Env, destination: &String, days: u32) -> Result<(String, String)> {
+/// - A `Day N:` heading is prepended to any day the model returned without one, so the plan
+///   can always be split with `plan::parse_days`.
+pub async fn create_plan(env: &Env, config: &Config, destination: &str, days: u32, units: &UnitPreferences) -> Result<(String, String)> {
+    create_plan_with_options(env, config, destination, days, &PlanOptions { units: units.clone(), ..Default::default() }).await
+}
+
+/// How the days of a generated plan are written.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PlanFormat {
+    /// Time-of-day entries, each naming a place with a one or two sentence description.
+    #[default]
+    Structured,
+    /// A short narrative paragraph per day.
+    Prose,
+}
+
+impl PlanFormat {
+    /// Parses `"structured"` or `"prose"`, ignoring ASCII case and surrounding whitespace.
+    pub fn parse(value: &str) -> Option<PlanFormat> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "structured" => Some(PlanFormat::Structured),
+            "prose" => Some(PlanFormat::Prose),
+            _ => None,
+        }
+    }
+
+    /// The deployment's house style from `DEFAULT_PLAN_FORMAT`.
+    ///
+    /// Unset or unrecognized values fall back to [`PlanFormat::Structured`], with a warning
+    /// for the latter.
+    pub fn deployment_default(env: &Env) -> PlanFormat {
+        let Ok(value) = env.var("DEFAULT_PLAN_FORMAT") else {
+            return PlanFormat::default();
+        };
+        PlanFormat::parse(&value.to_string()).unwrap_or_else(|| {
+            console_warn!("Ignoring DEFAULT_PLAN_FORMAT {:?}: expected prose or structured", value.to_string());
+            PlanFormat::default()
+        })
+    }
+
+    /// The closing instruction of every day's prompt.
+    fn instructions(self) -> &'static str {
+        match self {
+            PlanFormat::Structured => "Do not add anything except for the plan. All you need is the time of day, name of the place, and a short one to two sentence description of the place",
+            PlanFormat::Prose => "Do not add anything except for the plan. Write the day as one short paragraph of flowing prose that names each place visited, in the order they are visited",
+        }
+    }
+}
+
+/// Optional preferences that shape a generated plan.
+///
+/// # Fields
+/// * `style` - The kind of trip, e.g. `"relaxed"` or `"food-focused"`.
+/// * `budget` - The spending level, e.g. `"budget"` or `"luxury"`.
+/// * `format` - How each day is written; `None` uses [`PlanFormat::deployment_default`].
+/// * `interests` - Things the traveller enjoys, e.g. `["food", "art"]`.
+/// * `model` - A model from `AI_MODEL_TIERS` to use instead of the one [`choose_model`]
+///   would pick; check it with [`is_tier_model`] first.
+/// * `start_date` - The date of Day 1, when the trip has real dates. The prompt then names
+///   each day's date and weekday, so the plan can allow for weekly closures.
+/// * `units` - The units and currency the plan is written in (see `units`); metric by default.
+#[derive(Default)]
+pub struct PlanOptions {
+    pub style: Option<String>,
+    pub budget: Option<String>,
+    pub format: Option<PlanFormat>,
+    pub interests: Vec<String>,
+    pub model: Option<String>,
+    pub start_date: Option<CalendarDate>,
+    pub units: UnitPreferences,
+}
+
+impl PlanOptions {
+    /// Renders the preferences as sentences for the prompt. The unit instruction is always
+    /// included, so the text is never empty.
+    ///
+    /// With a `start_date`, this lists the date and weekday of each of the `days` days, e.g.
+    /// ` Day 1 is Friday 2025-07-11, Day 2 is Saturday 2025-07-12. Keep in mind ...`.
+    fn prompt_text(&self, days: u32) -> String {
+        let mut text = String::new();
+        if let Some(style) = &self.style {
+            text.push_str(&format!(" The traveller wants a {style} trip."));
+        }
+        if let Some(budget) = &self.budget {
+            text.push_str(&format!(" Their budget is: {budget}."));
+        }
+        if !self.interests.is_empty() {
+            text.push_str(&format!(" They are interested in: {}.", self.interests.join(", ")));
+        }
+        if let Some(start) = self.start_date {
+            let dates: Vec<String> = (0..days)
+                .map(|i| {
+                    let date = start.add_days(i);
+                    format!("Day {} is {} {date}", i + 1, date.weekday())
+                })
+                .collect();
+            text.push_str(&format!(
+                " {}. Keep in mind that many museums and shops close on some weekdays.",
+                dates.join(", ")
+            ));
+        }
+        text.push(' ');
+        text.push_str(&self.units.instruction());
+        text
+    }
+}
+
+/// Like [`create_plan`], with a travel style, budget and interests added to every day's prompt.
+///
+/// # Returns
+///
+/// The same as [`create_plan`]; the summary mentions the preferences.
+pub async fn create_plan_with_options(env: &Env, config: &Config, destination: &str, days: u32, options: &PlanOptions) -> Result<(String, String)> {
+    let preferences = options.prompt_text(days);
+    if config.mock_ai {
+        let destination = prompt_destination(destination);
+        return Ok((mock_plan(&destination, 1..=days), format!("You are a trip planner. Plan a fun and engaging trip to {destination} for {days} days.{preferences}")));
+    }
+    let format = options.format.unwrap_or_else(|| PlanFormat::deployment_default(env));
+    let complexity = Complexity::plan(destination, days, options.interests.len());
+    let model = choose_model(env, &complexity, options.model.as_deref());
+    let started = Date::now().as_millis();
+    let plan = generate_days(env, config, destination, 1..=days, vec![], &preferences, format, &model).await?;
+    crate::analytics::record_ai_call(env, config, "plan", &model, started).await;
+    let destination = prompt_destination(destination);
+    Ok((plan.join("\n"), format!("You are a trip planner. Plan a fun and engaging trip to {destination} for {days} days.{preferences}")))
+}
+
+/// Asks the AI service to turn a plan into a time-slotted schedule.
+///
+/// # Arguments
+///
+/// * `env` - The environment, with the same requirements as [`create_plan`].
+/// * `config` - The deployment settings; `MOCK_AI` skips the model and `PROMPT_MAX_CHARS` caps the prompt.
+/// * `plan` - The plan text to convert.
+/// * `days` - The number of days the plan covers.
+///
+/// # Returns
+///
+/// The model's response, which should be a JSON array of `{ "day", "time", "activity" }`
+/// objects; check it with `plan::parse_schedule`. With `MOCK_AI` on, a fixed three-slot
+/// schedule per day.
+pub async fn create_schedule(env: &Env, config: &Config, plan: &str, days: u32) -> Result<String> {
+    if config.mock_ai {
+        let entries: Vec<_> = (1..=days)
+            .flat_map(|day| [("09:00", "Breakfast"), ("14:00", "Sightseeing"), ("19:00", "Dinner")]
+                .map(|(time, activity)| json!({ "day": day, "time": time, "activity": activity })))
+            .collect();
+        return Ok(serde_json::to_string(&entries)?);
+    }
+    let prompt = wrap_prompt(env, format!(
+        "Here is a {days}-day travel plan:\n{plan}\n\
+         Rewrite it as a schedule. Answer only with a JSON array of objects with the keys \
+         \"day\" (the day number), \"time\" (the start time as HH:MM, 24-hour clock) and \
+         \"activity\" (a short description). List each day's entries in time order. \
+         Do not add anything else."
+    ));
+    run_prompt(env, config, prompt).await
+}
+
+/// Asks the AI service to compare two destinations for a trip of `days` days.
+///
+/// # Returns
+///
+/// The model's response, which should be a JSON object of the shape of
+/// `compare::Comparison`. With `MOCK_AI` on, a fixed comparison of the two destinations.
+pub async fn compare_destinations(env: &Env, config: &Config, first: &str, second: &str, days: u32) -> Result<String> {
+    let (first, second) = (prompt_destination(first), prompt_destination(second));
+    if config.mock_ai {
+        let option = |d: &str| json!({ "destination": d, "pros": [format!("Plenty to see in {d}")], "cons": ["Busy in high season"], "best_for": "first-time visitors" });
+        return Ok(json!({
+            "options": [option(&first), option(&second)],
+            "summary": format!("{first} and {second} both suit a {days}-day trip."),
+            "recommendation": format!("Pick {first} for a first visit."),
+        }).to_string());
+    }
+    let prompt = wrap_prompt(env, format!(
+        "You are a travel advisor. A traveller is deciding between {first} and {second} for a {days}-day trip. \
+         Compare the two for a trip of that length. Answer only with a JSON object with the keys \
+         \"options\" (an array with one object per destination, in the order given, each with \
+         \"destination\", \"pros\" (an array of short strings), \"cons\" (an array of short strings) \
+         and \"best_for\" (the kind of traveller it suits)), \"summary\" (two or three sentences) \
+         and \"recommendation\" (which one you suggest and why, in one sentence). Do not add anything else."
+    ));
+    run_prompt(env, config, prompt).await
+}
+
+/// Asks the AI service to join the plans of several trips into one multi-leg itinerary.
+///
+/// # Arguments
+///
+/// * `env` - The environment, with the same requirements as [`create_plan`].
+/// * `config` - The deployment settings; `MOCK_AI` skips the model and `PROMPT_MAX_CHARS` caps the prompt.
+/// * `legs` - `(destination, days, plan)` for each trip, in travel order.
+///
+/// # Returns
+///
+/// The combined itinerary, numbered `Day 1` to the total of the legs' days so it can be
+/// split with `plan::parse_days`. With `MOCK_AI` on, the mock plan of each leg, numbered on.
+pub async fn combine_plans(env: &Env, config: &Config, legs: &[(String, u32, String)]) -> Result<String> {
+    let total: u32 = legs.iter().map(|(_, days, _)| days).sum();
+    if config.mock_ai {
+        let mut first = 1;
+        let plans: Vec<String> = legs
+            .iter()
+            .map(|(destination, days, _)| {
+                let plan = mock_plan(&prompt_destination(destination), first..=first + days - 1);
+                first += days;
+                plan
+            })
+            .collect();
+        return Ok(plans.join("\n"));
+    }
+    let route = legs
+        .iter()
+        .map(|(destination, days, _)| format!("{} ({days} days)", prompt_destination(destination)))
+        .collect::<Vec<_>>()
+        .join(", then ");
+    let plans = legs
+        .iter()
+        .enumerate()
+        .map(|(i, (destination, _, plan))| format!("Leg {} - {}:\n{plan}", i + 1, prompt_destination(destination)))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let prompt = wrap_prompt(env, format!(
+        "You are a travel planner. A traveller is making one {total}-day journey through {route}. \
+         Here are the separate plans for each leg:\n\n{plans}\n\n\
+         Combine them into a single itinerary numbered Day 1 to Day {total}, keeping each leg's \
+         highlights, adding the travel between legs to the first or last day of a leg, and removing \
+         anything repeated across legs. Start every day with a \"Day N:\" heading. \
+         Do not add anything except for the plan."
+    ));
+    run_prompt(env, config, prompt).await
+}
+
+/// Asks the AI service for a packing list for a trip.
+///
+/// # Arguments
+///
+/// * `env` - The environment, with the same requirements as [`create_plan`].
+/// * `config` - The deployment settings; `MOCK_AI` skips the model and `PROMPT_MAX_CHARS` caps the prompt.
+/// * `destination` - The trip's destination.
+/// * `days` - The length of the trip.
+/// * `plan` - The trip's plan, so the list covers its activities.
+/// * `season` - When the trip takes place (e.g. `"winter"` or `"July"`), if the traveller said.
+///
+/// # Returns
+///
+/// The model's response, which should be a JSON array of `{ "category", "items" }`
+/// objects; check it with `packing::parse`. With `MOCK_AI` on, a fixed two-category list.
+pub async fn create_packing_list(env: &Env, config: &Config, destination: &str, days: u32, plan: &str, season: Option<&str>) -> Result<String> {
+    let destination = prompt_destination(destination);
+    if config.mock_ai {
+        return Ok(json!([
+            { "category": "Clothing", "items": [format!("Outfits for {days} days"), "Comfortable walking shoes"] },
+            { "category": "Documents", "items": ["Passport", format!("Bookings for {destination}")] },
+        ]).to_string());
+    }
+    let season = season.map(|s| format!("The trip takes place in {}.\n", prompt_destination(s))).unwrap_or_default();
+    let prompt = wrap_prompt(env, format!(
+        "Here is a {days}-day travel plan for {destination}:\n{plan}\n{season}\
+         Write a packing list for this trip, taking the destination's climate and the planned \
+         activities into account. Answer only with a JSON array of objects with the keys \
+         \"category\" (e.g. \"Clothing\") and \"items\" (an array of short strings). \
+         Do not add anything else."
+    ));
+    run_prompt(env, config, prompt).await
+}
+
+/// Asks the AI service whether each day of a plan is realistic.
+///
+/// # Arguments
+///
+/// * `env` - The environment, with the same requirements as [`create_plan`].
+/// * `config` - The deployment settings; `MOCK_AI` skips the model and `PROMPT_MAX_CHARS` caps the prompt.
+/// * `destination` - The trip's destination.
+/// * `plan` - The plan's days, sent to the model as JSON so it can refer to them by number.
+///
+/// # Returns
+///
+/// The model's response, which should be a JSON object with `warnings` (objects with `day`,
+/// `kind` and `message`) and a `summary`; check it with `feasibility::parse`. With `MOCK_AI`
+/// on, a report without warnings.
+pub async fn check_feasibility(env: &Env, config: &Config, destination: &str, plan: &[crate::plan::PlanDay]) -> Result<String> {
+    let destination = prompt_destination(destination);
+    if config.mock_ai {
+        return Ok(json!({ "warnings": [], "summary": format!("Every day in {destination} looks realistic.") }).to_string());
+    }
+    let plan = serde_json::to_string(plan)?;
+    let prompt = wrap_prompt(env, format!(
+        "Here is a travel plan for {destination} as a JSON array of days:\n{plan}\n\
+         Check whether each day is realistic for a traveller. Flag a day as \"too_packed\" when it \
+         has more than can comfortably be done in a day, \"too_sparse\" when little is planned, \
+         and \"scattered\" when its places are far apart and much of the day would be spent \
+         travelling between them. Answer only with a JSON object with the keys \"warnings\" (an \
+         array of objects with \"day\" (the day number), \"kind\" (one of the flags above) and \
+         \"message\" (one short sentence)) and \"summary\" (one or two sentences on the plan as \
+         a whole). Use an empty array when every day is fine. Do not add anything else."
+    ));
+    run_prompt(env, config, prompt).await
+}
+
+/// Asks the AI service for other ways to spend one day of a plan.
+///
+/// # Arguments
+///
+/// * `env` - The environment, with the same requirements as [`create_plan`].
+/// * `config` - The deployment settings; `MOCK_AI` skips the model and `PROMPT_MAX_CHARS` caps the prompt.
+/// * `destination` - The trip's destination.
+/// * `plan` - The plan's days, sent to the model as JSON so it knows what the other days hold.
+/// * `day` - The day to suggest alternatives for.
+/// * `count` - How many alternatives to ask for.
+///
+/// # Returns
+///
+/// The model's response, which should be a JSON array of objects with `title` and
+/// `activities`; check it with `alternatives::parse`. With `MOCK_AI` on, `count` canned
+/// alternatives.
+pub async fn day_alternatives(env: &Env, config: &Config, destination: &str, plan: &[crate::plan::PlanDay], day: u32, count: usize) -> Result<String> {
+    let destination = prompt_destination(destination);
+    if config.mock_ai {
+        let alternatives: Vec<_> = (1..=count)
+            .map(|i| json!({ "title": format!("Alternative {i} for day {day}"), "activities": [format!("Explore {destination}, option {i}")] }))
+            .collect();
+        return Ok(json!(alternatives).to_string());
+    }
+    let plan = serde_json::to_string(plan)?;
+    let prompt = wrap_prompt(env, format!(
+        "Here is a travel plan for {destination} as a JSON array of days:\n{plan}\n\
+         Suggest {count} different ways to spend day {day} instead. Each should be a realistic \
+         day on its own, different from the current day {day} and from each other, and should \
+         not repeat what the other days already cover. Answer only with a JSON array of objects \
+         with the keys \"title\" (a short name for the day) and \"activities\" (an array of \
+         short strings in the order they happen, e.g. \"Morning: ...\"). Do not add anything else."
+    ));
+    run_prompt(env, config, prompt).await
+}
+
+/// Asks the AI service to extend an existing plan with more days.
+///
+/// # Arguments
+///
+/// * `env` - The environment, with the same requirements as [`create_plan`].
+/// * `config` - The deployment settings; `MOCK_AI` skips the model and `PROMPT_MAX_CHARS` caps the prompt.
+/// * `destination` - The trip's destination.
+/// * `current_plan` - The plan generated so far, given to the model as context.
+/// * `current_days` - The number of days `current_plan` covers.
+/// * `additional_days` - How many days to add.
+/// * `units` - The trip's units and currency, so the new days match the existing ones.
+///
+/// # Returns
+///
+/// Only the newly written days (`Day {current_days + 1}` onwards), each opening with a
+/// `Day N:` heading, so the caller can append them to `current_plan`.
+///
+/// # Errors
+///
+/// The same as [`create_plan`].
+pub async fn extend_plan(env: &Env, config: &Config, destination: &str, current_plan: &str, current_days: u32, additional_days: u32, units: &UnitPreferences) -> Result<String> {
+    let days = current_days + additional_days;
+    if config.mock_ai {
+        return Ok(mock_plan(&prompt_destination(destination), current_days + 1..=days));
+    }
+    let model = choose_model(env, &Complexity::plan(destination, days, 0), None);
+    let plan = generate_days(env, config, destination, current_days + 1..=days, vec![current_plan.to_string()], &format!(" {}", units.instruction()), PlanFormat::deployment_default(env), &model).await?;
+    Ok(plan[1..].join("\n"))
+}
+
+/// Generates the days of a plan that are missing from `plan`, one AI call per day.
+///
+/// # Arguments
+///
+/// * `env` - The environment providing `CF_ACCOUNT_ID`, `CF_API_TOKEN` and `AI_MODEL`.
+/// * `config` - The deployment settings; `MOCK_AI` skips the model and `PROMPT_MAX_CHARS` caps the prompt.
+/// * `destination` - The trip's destination, shortened with [`prompt_destination`] for the prompt.
+/// * `days` - The days to write, from the first one to the last day of the trip.
+/// * `plan` - The plan for the days before the first one; it is shown to the model as
+///   context and new days are appended to it.
+/// * `preferences` - Extra sentences about the traveller (see [`PlanOptions`]), or `""`.
+/// * `format` - How each day is written.
+/// * `model` - The model to run, picked with [`choose_model`].
+///
+/// # Returns
+///
+/// `plan` with `days` appended, one entry per day, each sanitized when
+/// `SANITIZE_AI_HTML` is on (see [`crate::sanitize`]).
+#[allow(clippy::too_many_arguments)]
+async fn generate_days(env: &Env, config: &Config, destination: &str, days: std::ops::RangeInclusive<u32>, mut plan: Vec<String>, preferences: &str, format: PlanFormat, model: &str) -> Result<Vec<String>> {
+    let (first_day, days) = (*days.start(), *days.end());
     let account_id = env.var("CF_ACCOUNT_ID")?.to_string();
-    let model = env
-        .var("AI_MODEL")
-        .map(|v| v.to_string())
-        .unwrap_or("@cf/meta/llama-3.1-8b-instruct-fast".to_string());
 
     let url = format!("https://api.cloudflare.com/client/v4/accounts/{account_id}/ai/run/{model}");
     let token = env.secret("CF_API_TOKEN")?.to_string();
-    let mut plan: Vec<String> = vec![];
+    let facts = destination_facts(env, destination)
+        .await
+        .map(|facts| format!(" Known facts about the destination: {}", facts.trim()))
+        .unwrap_or_default();
+    let destination = prompt_destination(destination);
 
-    for i in 1..days+1 {
+    for i in first_day..days+1 {
         let body = json!({
-        "prompt": format!(
-            "You are a travel planner. Continue planning a {days}-day trip to {destination}. \
+        "prompt": wrap_prompt(env, format!(
+            "You are a travel planner. Continue planning a {days}-day trip to {destination}.{preferences}{facts} \
              Here are the plans for the previous day of your trip:{}
              Now write the itinerary for Day {i}.
-             Do not add anything except for the plan. All you need is the time of day, name of the place, and a short one to two sentence description of the place",plan.join("\n")
-        ),
+             {}",plan.join("\n"), format.instructions()
+        )),
     }).to_string();
         console_log!("Day {i} of {days} done");
         let mut init = RequestInit::new();
@@ -186,27 +877,54 @@ pub async fn create_plan(env: &Env, destination: &String, days: u32) -> Result<(
         req.headers_mut()?.set("Content-Type", "application/json")?;
         req.headers_mut()?.set("Accept", "application/json")?;
 
-        let mut resp = Fetch::Request(req).send().await?;
-        if resp.status_code() != 200 {
-            return Err(format!("Failed to create plan with error {}", resp.status_code()).into());
-        }
+        let mut resp = send(req, "Failed to create plan").await?;
 
         let parsed: CfAiResponse = resp.json().await?;
-        plan.push(parsed.result.response);
+        let response = crate::sanitize::ai_output(config, parsed.result.response);
+        // Make sure every day opens with a heading so the plan can be split back into days.
+        if crate::plan::parse_days(&response).is_empty() {
+            plan.push(format!("Day {i}:\n{response}"));
+        } else {
+            plan.push(response);
+        }
     }
 
-    Ok((plan.join("\n"), format!("You are a trip planner. Plan a fun and engaging trip to {destination} for {days} days.")))
+    Ok(plan)
 }
+
+/// Collapses runs of identical consecutive messages from the same role.
+///
+/// Retries and edits can leave the same message stored several times in a row, which
+/// confuses the model. Only the first message of each run is kept; messages are compared
+/// by text (ignoring surrounding whitespace) and role, not by timestamp.
+///
+/// This only changes what is sent to the model. The stored history is left untouched so it
+/// remains a faithful record of the conversation.
+///
+/// # Arguments
+///
+/// * `history` - `(message, messager_role, created_at)` tuples in chronological order.
+///
+/// # Returns
+///
+/// The history without consecutive duplicates.
+fn collapse_duplicates(mut history: Vec<(String, MessageRole, i64)>) -> Vec<(String, MessageRole, i64)> {
+    history.dedup_by(|next, prev| next.1 == prev.1 && next.0.trim() == prev.0.trim());
+    history
+}
+
 /// Asynchronously handles a chat request for a trip planning AI service.
 ///
 /// # Arguments
 ///
 /// * `env` - A reference to the environment (`Env`) that provides configuration values and secrets such as 
 ///   account ID, model name, and API token.
+/// * `config` - The deployment settings; `MOCK_AI` skips the model and `PROMPT_MAX_CHARS` caps the prompt.
 /// * `plan` - A reference to a string containing the pre-planned trip information.
 /// * `body` - A vector of tuples where each tuple consists of three `String` values representing additional
 ///   context that may assist the AI in responding to the question.
 /// * `question` - A reference to a string containing a user's question about the trip plan.
+/// * `options` - The model, reply language and units to use (see [`ChatOptions`]).
 ///
 /// # Returns
 ///
@@ -217,12 +935,17 @@ pub async fn create_plan(env: &Env, destination: &String, days: u32) -> Result<(
 /// # Details
 ///
 /// This function:
+/// 0. If `MOCK_AI` is enabled, returns a deterministic canned reply built from the question
+///    and the amount of context, without calling the AI service.
+///    When `DEDUP_CHAT_HISTORY` is enabled, consecutive duplicate messages are first removed
+///    from `body` with [`collapse_duplicates`].
 /// 1. Retrieves the Cloudflare account ID (`CF_ACCOUNT_ID`) and AI model name (`AI_MODEL`) from the environment.
 ///    If the `AI_MODEL` is not provided, it defaults to `@cf/meta/llama-3.1-8b-instruct-fast`.
 /// 2. Constructs the appropriate URL for the Cloudflare AI API based on the account ID and model.
 /// 3. Obtains the API token (`CF_API_TOKEN`) securely from the environment.
 /// 4. Prepares the API request payload in JSON format, which includes:
-///    - A prompt describing the function of the AI as a trip planner.
+///    - A prompt describing the function of the AI as a trip planner, wrapped with the
+///      optional `PROMPT_PREFIX`/`PROMPT_SUFFIX` (see [`wrap_prompt`]).
 ///    - The user's question.
 ///    - Context supplied via the `body` parameter.
 /// 5. Builds the HTTP request with the required headers (e.g., Authorization, Content-Type).
@@ -234,8 +957,11 @@ pub async fn create_plan(env: &Env, destination: &String, days: u32) -> Result<(
 /// The function returns an error in the following cases:
 /// * If required environment variables (`CF_ACCOUNT_ID` or `CF_API_TOKEN`) cannot be retrieved.
 /// * If constructing the HTTP request or serializing the body fails.
-/// * If the API response status code is not `200 OK`.
+/// * If the API response status code is not `200 OK`. Failures to send and `429`/`5xx`
+///   answers are retried first (see [`send`]) and end in an [`AI_UNAVAILABLE`] error.
 /// * If parsing the response body into the `CfAiResponse` type fails.
+/// * If the plan and question alone exceed the prompt budget ([`PROMPT_TOO_LARGE`]); older
+///   history is trimmed silently (apart from a log line) when only the history is too long.
 ///
 /// # Example
 ///
@@ -252,45 +978,485 @@ pub async fn create_plan(env: &Env, destination: &String, days: u32) -> Result<(
 ///     ];
 ///     let question = "What are the transportation options for Day 2?";
 ///
-///     match chat(&env, plan, body, &question).await {
+///     match chat(&env, config, plan, body, &question, &ChatOptions::default()).await {
 ///         Ok(response) => println!("AI Response: {}", response),
 ///         Err(e) => eprintln!("Error: {}", e),
 ///     }
 /// }
 /// ```
-pub async fn chat(env: &Env, plan: &str, body: Vec<(String, String, String)>, question: &String) -> Result<String> {
+pub async fn chat(env: &Env, config: &Config, plan: &str, body: Vec<(String, MessageRole, i64)>, question: &String, options: &ChatOptions<'_>) -> Result<String> {
+    if config.mock_ai {
+        return Ok(format!("Mock reply to \"{question}\" with {} message(s) of context.", chat_history(config, body).len()));
+    }
+    let model = choose_model(env, &Complexity::chat(body.len()), options.model);
+    let started = Date::now().as_millis();
+    let reply = run_chat(env, config, plan, chat_history(config, body), question, &options.instructions(), &model).await?;
+    crate::analytics::record_ai_call(env, config, "chat", &model, started).await;
+    Ok(reply)
+}
+
+/// The pieces of a streamed chat reply, in the order the model writes them.
+pub type TextStream = LocalBoxStream<'static, Result<String>>;
+
+/// Like [`chat`], but streams the reply as it is generated instead of waiting for all of it.
+///
+/// The model is called with `"stream": true` and its server-sent events are decoded with
+/// [`crate::sse::Decoder`]; each `{"response": "..."}` event becomes one item of the stream,
+/// which ends at `[DONE]`. With `MOCK_AI` the mock reply of [`chat`] is streamed word by
+/// word. `SANITIZE_AI_HTML` is not applied to the pieces, since a tag can be split across
+/// them; callers sanitize the assembled reply.
+///
+/// # Errors
+///
+/// The same as [`chat`] for the request itself. A failure while reading the stream is
+/// returned as an item of the stream.
+pub async fn chat_stream(env: &Env, config: &Config, plan: &str, body: Vec<(String, MessageRole, i64)>, question: &String, options: &ChatOptions<'_>) -> Result<TextStream> {
+    if config.mock_ai {
+        let reply = format!("Mock reply to \"{question}\" with {} message(s) of context.", chat_history(config, body).len());
+        let words: Vec<Result<String>> = reply.split_inclusive(' ').map(|word| Ok(word.to_string())).collect();
+        return Ok(futures_util::stream::iter(words).boxed_local());
+    }
+    let model = choose_model(env, &Complexity::chat(body.len()), options.model);
+    let req = chat_request(env, config, plan, chat_history(config, body), question, &options.instructions(), &model, true)?;
+    let mut resp = send(req, "Failed to stream chat reply").await?;
+    let mut decoder = crate::sse::Decoder::default();
+    let pieces = resp
+        .stream()?
+        .map_ok(move |chunk| futures_util::stream::iter(decoder.push(&chunk).into_iter().map(Ok)))
+        .try_flatten()
+        .try_take_while(|data| futures_util::future::ready(Ok(data.trim() != "[DONE]")))
+        .try_filter_map(|data| futures_util::future::ready(Ok(
+            serde_json::from_str::<CfAiResult>(&data).ok().map(|chunk| chunk.response).filter(|piece| !piece.is_empty())
+        )));
+    Ok(pieces.boxed_local())
+}
+
+/// Per-request settings for a chat reply.
+///
+/// # Fields
+/// * `model` - A model from `AI_MODEL_TIERS` to use instead of the one [`choose_model`] picks.
+/// * `language` - The language to reply in (e.g. `"Spanish"`), when the question's language
+///   was detected (see `language::detect`). `None` leaves the choice to the model.
+/// * `units` - The trip's units and currency (see `units`), so replies match its plan.
+#[derive(Default)]
+pub struct ChatOptions<'a> {
+    pub model: Option<&'a str>,
+    pub language: Option<&'a str>,
+    pub units: UnitPreferences,
+}
+
+impl ChatOptions<'_> {
+    /// The instructions added to the prompt after the question: the reply language, if any,
+    /// followed by the unit instruction.
+    fn instructions(&self) -> String {
+        let units = self.units.instruction();
+        match self.language {
+            Some(language) => format!("The question is written in {language}; write your answer in {language}. {units}"),
+            None => units,
+        }
+    }
+}
+
+/// A chat reply together with the parts of the plan the model says it relied on.
+///
+/// # Fields
+/// * `reply` - The answer shown to the user.
+/// * `references` - Plan sections cited by the model, e.g. `"Day 2 - Afternoon"`. Empty when
+///   the model did not cite anything or did not produce structured output.
+/// * `refused` - Whether the reply looks like a refusal (see [`is_refusal`]). Never read
+///   from the model's output; callers set it after checking the reply.
+#[derive(Serialize, Deserialize)]
+pub struct ChatReply {
+    pub reply: String,
+    #[serde(default)]
+    pub references: Vec<String>,
+    #[serde(skip_deserializing)]
+    pub refused: bool,
+}
+
+impl ChatReply {
+    /// Wraps a plain reply that carries no references.
+    pub fn plain(reply: String) -> Self {
+        Self { reply, references: vec![], refused: false }
+    }
+}
+
+/// Instruction appended to the chat prompt by [`chat_verbose`].
+const CITE_INSTRUCTIONS: &str = "Answer only with a JSON object of the form \
+    {\"reply\": \"<your answer>\", \"references\": [\"<plan section you used, e.g. Day 2 - Afternoon>\"]} \
+    and nothing else.";
+
+/// Like [`chat`], but asks the model to cite the plan sections its answer is based on.
+///
+/// The model is prompted to answer with a JSON object matching [`ChatReply`]. Models do not
+/// always comply, so any answer that does not parse (after stripping a surrounding code
+/// fence) is returned as a plain reply with no references rather than as an error.
+///
+/// # Arguments
+///
+/// The same as [`chat`].
+///
+/// # Errors
+///
+/// The same as [`chat`].
+pub async fn chat_verbose(env: &Env, config: &Config, plan: &str, body: Vec<(String, MessageRole, i64)>, question: &String, options: &ChatOptions<'_>) -> Result<ChatReply> {
+    if config.mock_ai {
+        let reply = format!("Mock reply to \"{question}\" with {} message(s) of context.", chat_history(config, body).len());
+        return Ok(ChatReply { reply, references: vec!["Day 1".to_string()], refused: false });
+    }
+    let model = choose_model(env, &Complexity::chat(body.len()), options.model);
+    let instructions = format!("{} {CITE_INSTRUCTIONS}", options.instructions());
+    let started = Date::now().as_millis();
+    let response = run_chat(env, config, plan, chat_history(config, body), question, &instructions, &model).await?;
+    crate::analytics::record_ai_call(env, config, "chat", &model, started).await;
+    let json = response
+        .trim()
+        .trim_start_matches("```json")
+        .trim_start_matches("```")
+        .trim_end_matches("```")
+        .trim();
+    match serde_json::from_str::<ChatReply>(json) {
+        Ok(reply) if !reply.reply.trim().is_empty() => Ok(reply),
+        _ => {
+            console_log!("Chat reply was not structured; returning it without references");
+            Ok(ChatReply::plain(response))
+        }
+    }
+}
+
+/// Applies `DEDUP_CHAT_HISTORY` (see [`collapse_duplicates`]) to the history sent to the model.
+fn chat_history(config: &Config, body: Vec<(String, MessageRole, i64)>) -> Vec<(String, MessageRole, i64)> {
+    if config.dedup_chat_history {
+        collapse_duplicates(body)
+    } else {
+        body
+    }
+}
+
+/// The default prompt budget, in characters, used when `PROMPT_MAX_CHARS` is not set.
+///
+/// Roughly 8k tokens at ~4 characters per token, comfortably inside the default model's
+/// context window with room left for the reply.
+pub const DEFAULT_PROMPT_MAX_CHARS: usize = 32_000;
+
+/// Prefix of the error returned when a prompt cannot fit the budget even without history.
+/// Handlers check for it with [`is_prompt_too_large`] and answer `413`.
+pub const PROMPT_TOO_LARGE: &str = "prompt too large";
+
+/// Returns `true` if `error` came from a prompt that exceeded the budget (see [`PROMPT_TOO_LARGE`]).
+pub fn is_prompt_too_large(error: &Error) -> bool {
+    matches!(error, Error::RustError(message) if message.starts_with(PROMPT_TOO_LARGE))
+}
+
+/// How many times an AI request is sent before its error is returned: the first try and
+/// up to three retries.
+const AI_ATTEMPTS: u32 = 4;
+
+/// The wait before the first retry of an AI request, doubled for each later one (200ms,
+/// 400ms, 800ms).
+const AI_RETRY_BASE_MS: u64 = 200;
+
+/// The start of the message of an AI error that may go away on its own: the request could
+/// not be sent, or the service answered `429` or a `5xx`. See [`is_transient`].
+pub const AI_UNAVAILABLE: &str = "AI service temporarily unavailable";
+
+/// Returns `true` if `error` is an AI error worth retrying (see [`AI_UNAVAILABLE`]).
+pub fn is_transient(error: &Error) -> bool {
+    matches!(error, Error::RustError(message) if message.starts_with(AI_UNAVAILABLE))
+}
+
+/// Runs `f` up to `attempts` times, until it succeeds or fails with an error that is not
+/// transient (see [`is_transient`]).
+///
+/// Tries are spaced with exponential backoff starting at [`AI_RETRY_BASE_MS`], and each
+/// retry is logged with the error that caused it.
+///
+/// # Errors
+/// The error of the last try, unchanged.
+async fn with_retry<T, F, Fut>(attempts: u32, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Err(e) if attempt < attempts && is_transient(&e) => {
+                let delay = AI_RETRY_BASE_MS * 2u64.pow(attempt - 1);
+                console_warn!("{e}; retrying in {delay}ms (attempt {attempt} of {attempts})");
+                Delay::from(std::time::Duration::from_millis(delay)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sends a request to the AI service, retrying transient failures with [`with_retry`].
+///
+/// # Returns
+/// The `200 OK` response.
+///
+/// # Errors
+/// - `"{context} with error {status}"` for any other status below `500` except `429`, e.g.
+///   a malformed prompt, without retrying.
+/// - An [`AI_UNAVAILABLE`] error when every attempt failed to send or answered `429` or a
+///   `5xx`, naming the last failure.
+async fn send(req: Request, context: &str) -> Result<Response> {
+    with_retry(AI_ATTEMPTS, || async {
+        let resp = Fetch::Request(req.clone()?)
+            .send()
+            .await
+            .map_err(|e| Error::RustError(format!("{AI_UNAVAILABLE}: {context}: {e}")))?;
+        match resp.status_code() {
+            200 => Ok(resp),
+            status if status == 429 || status >= 500 => Err(Error::RustError(format!("{AI_UNAVAILABLE}: {context} with error {status}"))),
+            status => Err(format!("{context} with error {status}").into()),
+        }
+    })
+    .await
+}
+
+/// Trims the oldest chat history so the prompt and history fit the prompt budget.
+///
+/// Sizes are estimated in characters (the prompt text plus each history entry as JSON),
+/// which is cheap and close enough to tokens to stop requests the model would reject with
+/// a cryptic error. The budget comes from `PROMPT_MAX_CHARS` (default
+/// [`DEFAULT_PROMPT_MAX_CHARS`]). Trimming is logged so the budget can be tuned.
+///
+/// # Returns
+///
+/// The newest history entries that fit, in their original order.
+///
+/// # Errors
+///
+/// A [`PROMPT_TOO_LARGE`] error when the prompt alone (plan, question and instructions)
+/// exceeds the budget.
+fn fit_history(config: &Config, prompt: &str, mut history: Vec<(String, MessageRole, i64)>) -> Result<Vec<(String, MessageRole, i64)>> {
+    let budget = config.prompt_max_chars;
+    let prompt_chars = prompt.chars().count();
+    if prompt_chars > budget {
+        return Err(Error::RustError(format!(
+            "{PROMPT_TOO_LARGE}: the plan and question take {prompt_chars} characters, over the {budget} character budget; shorten the question or the plan"
+        )));
+    }
+    let entry_chars = |(message, role, created_at): &(String, MessageRole, i64)| {
+        // Quotes, brackets and commas of the JSON array.
+        message.chars().count() + role.as_str().chars().count() + created_at.to_string().len() + 10
+    };
+    let mut total = prompt_chars + history.iter().map(entry_chars).sum::<usize>();
+    let mut dropped = 0;
+    while total > budget && !history.is_empty() {
+        total -= entry_chars(&history.remove(0));
+        dropped += 1;
+    }
+    if dropped > 0 {
+        console_log!("Trimmed {dropped} oldest chat message(s) to fit PROMPT_MAX_CHARS={budget} ({total} characters sent)");
+    }
+    Ok(history)
+}
+
+/// Sends a chat prompt to the AI service and returns the raw response text, only sanitized
+/// when `SANITIZE_AI_HTML` is on (see [`crate::sanitize`]).
+///
+/// `instructions` is added to the prompt right after the question; pass `""` for none.
+/// `model` is the model to run, picked with [`choose_model`].
+async fn run_chat(env: &Env, config: &Config, plan: &str, body: Vec<(String, MessageRole, i64)>, question: &String, instructions: &str, model: &str) -> Result<String> {
+    let req = chat_request(env, config, plan, body, question, instructions, model, false)?;
+    let mut resp = send(req, "Failed to create chat reply").await?;
+
+    let parsed: CfAiResponse = resp.json().await?;
+    Ok(crate::sanitize::ai_output(config, parsed.result.response))
+}
+
+/// Builds the Workers AI request for a chat prompt, as described on [`run_chat`]. With
+/// `stream` the model is asked to answer with server-sent events (see [`chat_stream`]).
+#[allow(clippy::too_many_arguments)]
+fn chat_request(env: &Env, config: &Config, plan: &str, body: Vec<(String, MessageRole, i64)>, question: &String, instructions: &str, model: &str, stream: bool) -> Result<Request> {
     let account_id = env.var("CF_ACCOUNT_ID")?.to_string();
-    let model = env
-        .var("AI_MODEL")
-        .map(|v| v.to_string())
-        .unwrap_or("@cf/meta/llama-3.1-8b-instruct-fast".to_string());
 
     let url = format!("https://api.cloudflare.com/client/v4/accounts/{account_id}/ai/run/{model}");
     let token = env.secret("CF_API_TOKEN")?.to_string();
+    let instructions = if instructions.is_empty() { String::new() } else { format!("{instructions} ") };
 
-    let body = json!({
-        "prompt": format!(
-            "You are a trip planner. You have already planned a fun and engaging trip and this is your plan: {plan}. \
-             You are asked this question about the trip: {question}. \
-             You will be given the following context:"
-        ),
-        "context": body
-    }).to_string();
+    let prompt = wrap_prompt(env, format!(
+        "You are a trip planner. You have already planned a fun and engaging trip and this is your plan: {plan}. \
+         You are asked this question about the trip: {question}. \
+         {instructions}You will be given the following context:"
+    ));
+    let context = fit_history(config, &prompt, body)?;
+    let mut body = json!({
+        "prompt": prompt,
+        "context": context
+    });
+    if stream {
+        body["stream"] = json!(true);
+    }
 
     let mut init = RequestInit::new();
     init.with_method(Method::Post);
-    init.with_body(Some(body.clone().into_js_result()?));
+    init.with_body(Some(body.to_string().into_js_result()?));
 
     let mut req = Request::new_with_init(&url, &init)?;
     req.headers_mut()?.set("Authorization", &format!("Bearer {token}"))?;
     req.headers_mut()?.set("Content-Type", "application/json")?;
-    req.headers_mut()?.set("Accept", "application/json")?;
+    req.headers_mut()?.set("Accept", if stream { "text/event-stream" } else { "application/json" })?;
+    Ok(req)
+}
+
+/// The longest title, in characters, kept from [`generate_title`].
+const TITLE_MAX_CHARS: usize = 80;
+
+/// Returns the title used when the AI cannot provide one, e.g. `"5 days in Paris"`.
+pub fn fallback_title(destination: &str, days: u32) -> String {
+    format!("{days} days in {}", prompt_destination(destination))
+}
 
-    let mut resp = Fetch::Request(req).send().await?;
-    if resp.status_code() != 200 {
-        return Err(format!("Failed to create plan with error {}", resp.status_code()).into());
+/// Generates a short, catchy title for a plan, such as "5 Days of Art and Food in Paris".
+///
+/// This is a single short AI call made when a trip is created or its plan regenerated.
+///
+/// # Arguments
+///
+/// * `env` - The environment, with the same requirements as [`create_plan`].
+/// * `config` - The deployment settings; `MOCK_AI` skips the model and `PROMPT_MAX_CHARS` caps the prompt.
+/// * `destination` - The trip's destination.
+/// * `days` - The length of the trip.
+/// * `plan` - The plan the title should summarise.
+///
+/// # Returns
+///
+/// The first line of the model's answer with surrounding quotes removed, capped at
+/// [`TITLE_MAX_CHARS`] characters. Never fails: when `MOCK_AI` is enabled, the call fails
+/// or the answer is empty, [`fallback_title`] is returned instead.
+pub async fn generate_title(env: &Env, config: &Config, destination: &str, days: u32, plan: &str) -> String {
+    if config.mock_ai {
+        return fallback_title(destination, days);
     }
+    let prompt = wrap_prompt(env, format!(
+        "Write a short, catchy title of at most eight words for this {days}-day trip to {}. \
+         Reply with the title only, without quotes.\n\n{plan}",
+        prompt_destination(destination)
+    ));
+    let title = match run_prompt(env, config, prompt).await {
+        Ok(title) => title,
+        Err(e) => {
+            console_warn!("Title generation failed, using the default title: {e}");
+            return fallback_title(destination, days);
+        }
+    };
+    let title: String = title
+        .lines()
+        .map(|line| line.trim().trim_matches(|c| c == '"' || c == '\'' || c == '*').trim())
+        .find(|line| !line.is_empty())
+        .unwrap_or_default()
+        .chars()
+        .take(TITLE_MAX_CHARS)
+        .collect();
+    if title.is_empty() {
+        fallback_title(destination, days)
+    } else {
+        title
+    }
+}
 
+/// Sends a single prompt to the AI service and returns the response text, sanitized when
+/// `SANITIZE_AI_HTML` is on (see [`crate::sanitize`]).
+async fn run_prompt(env: &Env, config: &Config, prompt: String) -> Result<String> {
+    let account_id = env.var("CF_ACCOUNT_ID")?.to_string();
+    let model = default_model(env);
+
+    let url = format!("https://api.cloudflare.com/client/v4/accounts/{account_id}/ai/run/{model}");
+    let token = env.secret("CF_API_TOKEN")?.to_string();
+    let body = json!({ "prompt": prompt }).to_string();
+
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post);
+    init.with_body(Some(body.into_js_result()?));
+
+    let mut req = Request::new_with_init(&url, &init)?;
+    req.headers_mut()?.set("Authorization", &format!("Bearer {token}"))?;
+    req.headers_mut()?.set("Content-Type", "application/json")?;
+    req.headers_mut()?.set("Accept", "application/json")?;
+
+    let mut resp = send(req, "AI request failed").await?;
     let parsed: CfAiResponse = resp.json().await?;
-    Ok(parsed.result.response)
-}
\ No newline at end of file
+    Ok(crate::sanitize::ai_output(config, parsed.result.response))
+}
+
+/// The longest conversation summary, in characters, kept by [`summarize_history`] and
+/// [`update_summary`].
+const SUMMARY_MAX_CHARS: usize = 2_000;
+
+/// Cleans up a summary returned by the model and caps it at [`SUMMARY_MAX_CHARS`].
+fn clean_summary(summary: &str) -> String {
+    summary.trim().chars().take(SUMMARY_MAX_CHARS).collect::<String>().trim_end().to_string()
+}
+
+/// Summarizes a whole chat history from scratch.
+///
+/// # Arguments
+///
+/// * `env` - The environment, with the same requirements as [`create_plan`].
+/// * `config` - The deployment settings; `MOCK_AI` skips the model and `PROMPT_MAX_CHARS` caps the prompt.
+/// * `history` - `(message, messager_role, created_at)` tuples in chronological order.
+///
+/// # Returns
+///
+/// A short summary of the conversation, or `""` for an empty history. The history is
+/// trimmed to the prompt budget first (see [`fit_history`]), so very long chats are
+/// summarized from their most recent part.
+///
+/// # Errors
+///
+/// The same as [`create_plan`].
+pub async fn summarize_history(env: &Env, config: &Config, history: Vec<(String, MessageRole, i64)>) -> Result<String> {
+    if history.is_empty() {
+        return Ok(String::new());
+    }
+    if config.mock_ai {
+        return Ok(format!("Mock summary of {} message(s).", history.len()));
+    }
+    let instructions = "Summarize this conversation between a traveller and their trip planner in at most \
+        five sentences. Keep the decisions made and the open questions. Reply with the summary only.";
+    let history = fit_history(config, instructions, history)?;
+    let transcript = history
+        .iter()
+        .map(|(message, role, _)| format!("{role}: {message}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let summary = run_prompt(env, config, wrap_prompt(env, format!("{instructions}\n\n{transcript}"))).await?;
+    Ok(clean_summary(&summary))
+}
+
+/// Folds one chat exchange into an existing conversation summary.
+///
+/// This is a single short AI call, so keeping a summary current after every turn is much
+/// cheaper than re-summarizing the whole history.
+///
+/// # Arguments
+///
+/// * `env` - The environment, with the same requirements as [`create_plan`].
+/// * `config` - The deployment settings; `MOCK_AI` skips the model and `PROMPT_MAX_CHARS` caps the prompt.
+/// * `summary` - The summary of the conversation so far.
+/// * `question` - The user's latest message.
+/// * `reply` - The AI's answer to it.
+///
+/// # Returns
+///
+/// The updated summary.
+///
+/// # Errors
+///
+/// The same as [`create_plan`].
+pub async fn update_summary(env: &Env, config: &Config, summary: &str, question: &str, reply: &str) -> Result<String> {
+    if config.mock_ai {
+        return Ok(clean_summary(&format!("{summary} The traveller asked: {question}")));
+    }
+    let prompt = wrap_prompt(env, format!(
+        "Here is the summary of a conversation between a traveller and their trip planner:\n{summary}\n\n\
+         Update it with the latest exchange below, in at most five sentences. Keep the decisions made \
+         and the open questions. Reply with the summary only.\n\nUser: {question}\nAI: {reply}"
+    ));
+    Ok(clean_summary(&run_prompt(env, config, prompt).await?))
+}
@@ -0,0 +1,65 @@
+//! AI-suggested alternatives for one day of a trip's plan.
+//!
+//! `POST /trip/{trip_id}/plan/day/{n}/alternatives` sends the structured plan (see
+//! [`plan::parse_days`](crate::plan::parse_days)) to the AI and asks for a few other ways
+//! to spend day `n`, with the rest of the plan as context so the suggestions do not repeat
+//! other days. The stored plan is left unchanged. As with feasibility checks, [`parse`]
+//! reads the model's JSON defensively.
+use serde::{Deserialize, Serialize};
+
+/// How many alternatives are asked for when the request does not say.
+pub const DEFAULT_ALTERNATIVES: usize = 3;
+
+/// The most alternatives one request may ask for.
+pub const MAX_ALTERNATIVES: usize = 5;
+
+/// One alternative way to spend a day.
+///
+/// # Fields
+/// * `title` - A short name for the alternative, e.g. `"Markets and street food"`.
+/// * `activities` - The activities of the day in order, e.g. `"Morning: Boqueria market"`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Alternative {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub activities: Vec<String>,
+}
+
+/// Parses the model's answer into at most `count` alternatives.
+///
+/// Text around the outermost `[` ... `]` is ignored. Blank activities are dropped, as are
+/// alternatives left without any; a missing title becomes `"Alternative N"`.
+///
+/// # Returns
+/// `None` when the answer holds no JSON array of the expected shape, or no usable
+/// alternative.
+pub fn parse(text: &str, count: usize) -> Option<Vec<Alternative>> {
+    let start = text.find('[')?;
+    let end = text.rfind(']')?;
+    if end < start {
+        return None;
+    }
+    let answer: Vec<Alternative> = serde_json::from_str(&text[start..=end]).ok()?;
+    let alternatives: Vec<Alternative> = answer
+        .into_iter()
+        .filter_map(|alternative| {
+            let activities: Vec<String> = alternative
+                .activities
+                .iter()
+                .map(|a| a.trim().to_string())
+                .filter(|a| !a.is_empty())
+                .collect();
+            (!activities.is_empty()).then(|| Alternative { title: alternative.title.trim().to_string(), activities })
+        })
+        .take(count)
+        .enumerate()
+        .map(|(i, mut alternative)| {
+            if alternative.title.is_empty() {
+                alternative.title = format!("Alternative {}", i + 1);
+            }
+            alternative
+        })
+        .collect();
+    (!alternatives.is_empty()).then_some(alternatives)
+}
@@ -0,0 +1,133 @@
+//! Anonymized product analytics written to Workers Analytics Engine.
+//!
+//! Events are only emitted when the `ANALYTICS_ENABLED` variable is on (`Config::analytics_enabled`) and an
+//! Analytics Engine dataset is bound as `ANALYTICS`. Values are bucketed before they
+//! are written so no user-identifying data is recorded:
+//! - the destination is reduced to its broadest component (`"Paris, France"` becomes `"france"`),
+//! - the day count is reduced to a range (`"1-3"`, `"4-7"`, `"8-14"`, `"15+"`),
+//! - AI latency is recorded in whole milliseconds.
+//!
+//! Writes are scheduled with `Context::wait_until` so they never delay a response.
+//!
+//! Under the same flag the latency of each `ai::create_plan` and `ai::chat` call is also
+//! stored in D1 (`ai_calls`) with the model that answered, by [`record_ai_call`].
+//! `GET /admin/stats` reports percentiles of it per operation and model (see
+//! [`latency_stats`]), so the effect of a model change on response times shows up there.
+use serde::Serialize;
+use worker::*;
+
+use crate::config::Config;
+
+/// Reduces a destination to a coarse category: the last comma separated component,
+/// lowercased and trimmed.
+fn destination_category(destination: &str) -> String {
+    destination
+        .rsplit(',')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
+
+/// Buckets a trip length into a small fixed set of ranges.
+fn days_bucket(days: u32) -> &'static str {
+    match days {
+        0..=3 => "1-3",
+        4..=7 => "4-7",
+        8..=14 => "8-14",
+        _ => "15+",
+    }
+}
+
+/// Records an analytics event in the background.
+///
+/// # Arguments
+/// * `env` - The environment providing the `ANALYTICS` dataset.
+/// * `ctx` - The request context used to schedule the write with `wait_until`.
+/// * `config` - The deployment settings; nothing is recorded unless `analytics_enabled`.
+/// * `event` - The event name, e.g. `"trip_created"` or `"chat"`.
+/// * `destination` - The trip's destination, reduced with [`destination_category`].
+/// * `days` - The trip's length, reduced with [`days_bucket`].
+/// * `ai_latency_ms` - How long the AI call for this event took.
+///
+/// # Notes
+/// - Does nothing when analytics are disabled.
+/// - Failures (such as a missing binding) are logged and otherwise ignored.
+pub fn record(env: &Env, ctx: &Context, config: &Config, event: &'static str, destination: &str, days: u32, ai_latency_ms: u64) {
+    if !config.analytics_enabled {
+        return;
+    }
+    let env = env.clone();
+    let category = destination_category(destination);
+    ctx.wait_until(async move {
+        let written = env.analytics_engine("ANALYTICS").and_then(|dataset| {
+            AnalyticsEngineDataPointBuilder::new()
+                .indexes([event])
+                .add_blob(event)
+                .add_blob(category.as_str())
+                .add_blob(days_bucket(days))
+                .add_double(ai_latency_ms as f64)
+                .write_to(&dataset)
+        });
+        if let Err(e) = written {
+            console_error!("analytics write failed: {e}");
+        }
+    });
+}
+
+/// Stores the latency of an AI call that started at `started` (epoch milliseconds).
+///
+/// Does nothing when analytics are disabled. A failed write is logged and otherwise
+/// ignored, so it never fails the call being measured.
+pub async fn record_ai_call(env: &Env, config: &Config, operation: &str, model: &str, started: u64) {
+    if !config.analytics_enabled {
+        return;
+    }
+    let latency_ms = Date::now().as_millis().saturating_sub(started);
+    if let Err(e) = crate::db::record_ai_call(operation, model, latency_ms, config, env.clone()).await {
+        console_error!("AI latency write failed: {e}");
+    }
+}
+
+/// Latency percentiles of the AI calls of one operation and model.
+///
+/// # Fields
+/// * `operation` - What the calls did, e.g. `plan` or `chat`.
+/// * `model` - The model that answered them.
+/// * `calls` - How many calls were measured.
+/// * `p50_ms` / `p95_ms` - The median and 95th percentile latency in milliseconds.
+#[derive(Serialize)]
+pub struct LatencyStats {
+    pub operation: String,
+    pub model: String,
+    pub calls: usize,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+}
+
+/// Returns the `p`th percentile (nearest rank) of latencies sorted from fastest to slowest.
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// Groups `(operation, model, latency_ms)` rows, as returned by `db::ai_call_latencies`
+/// (grouped, and sorted by latency within each group), into one [`LatencyStats`] per group.
+pub fn latency_stats(rows: Vec<(String, String, u64)>) -> Vec<LatencyStats> {
+    let mut stats = Vec::new();
+    let mut rows = rows.into_iter().peekable();
+    while let Some((operation, model, latency)) = rows.next() {
+        let mut latencies = vec![latency];
+        while let Some((_, _, latency)) = rows.next_if(|(o, m, _)| *o == operation && *m == model) {
+            latencies.push(latency);
+        }
+        stats.push(LatencyStats {
+            calls: latencies.len(),
+            p50_ms: percentile(&latencies, 50),
+            p95_ms: percentile(&latencies, 95),
+            operation,
+            model,
+        });
+    }
+    stats
+}
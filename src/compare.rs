@@ -0,0 +1,107 @@
+//! Side-by-side comparison of two destinations, for travellers who have not decided yet.
+//!
+//! `POST /compare` asks the AI to weigh two destinations for a trip of a given length and
+//! returns its answer as a [`Comparison`]. Nothing is stored in D1; comparisons are cached
+//! in the optional `COMPARISON_CACHE` KV namespace for [`CACHE_TTL_SECS`], so popular pairs
+//! are answered without another AI call. The cache key ignores the order of the two
+//! destinations, their case and extra whitespace.
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::config::Config;
+
+/// How long a comparison stays cached, in seconds (7 days).
+const CACHE_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// One destination's side of a [`Comparison`].
+///
+/// # Fields
+/// * `destination` - The destination as named in the request.
+/// * `pros` - Reasons to pick it for this trip.
+/// * `cons` - Reasons against it.
+/// * `best_for` - The kind of traveller it suits, e.g. `"food lovers"`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ComparedDestination {
+    pub destination: String,
+    #[serde(default)]
+    pub pros: Vec<String>,
+    #[serde(default)]
+    pub cons: Vec<String>,
+    #[serde(default)]
+    pub best_for: String,
+}
+
+/// The AI's comparison of two destinations.
+///
+/// # Fields
+/// * `options` - One entry per destination, in the order they were compared.
+/// * `summary` - A short overview of how the two differ.
+/// * `recommendation` - Which destination the model suggests, and why.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Comparison {
+    pub options: Vec<ComparedDestination>,
+    pub summary: String,
+    pub recommendation: String,
+}
+
+/// Builds the `COMPARISON_CACHE` key for a pair of destinations and a trip length.
+fn cache_key(first: &str, second: &str, days: u32) -> String {
+    let normalize = |d: &str| d.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    let mut pair = [normalize(first), normalize(second)];
+    pair.sort();
+    format!("compare:{}|{}|{days}", pair[0], pair[1])
+}
+
+/// Parses the model's answer into a [`Comparison`].
+///
+/// Text around the outermost `{` ... `}` is ignored. The answer must hold an entry for
+/// each destination.
+///
+/// # Errors
+/// A description of the problem when there is no JSON object, it does not have the
+/// expected shape, or it does not cover both destinations.
+fn parse(text: &str) -> std::result::Result<Comparison, String> {
+    let (Some(start), Some(end)) = (text.find('{'), text.rfind('}')) else {
+        return Err("no JSON object found".to_string());
+    };
+    if end < start {
+        return Err("no JSON object found".to_string());
+    }
+    let comparison: Comparison = serde_json::from_str(&text[start..=end]).map_err(|e| format!("invalid comparison JSON: {e}"))?;
+    if comparison.options.len() != 2 {
+        return Err(format!("expected 2 destinations, got {}", comparison.options.len()));
+    }
+    Ok(comparison)
+}
+
+/// Compares two destinations for a trip of `days` days, from the cache when possible.
+///
+/// # Returns
+/// The comparison and whether it came from `COMPARISON_CACHE`.
+///
+/// # Errors
+/// If the AI call fails, or its answer cannot be parsed (see [`parse`]). Cache failures are
+/// only logged.
+pub async fn compare(env: &Env, config: &Config, first: &str, second: &str, days: u32) -> Result<(Comparison, bool)> {
+    let key = cache_key(first, second, days);
+    let kv = env.kv("COMPARISON_CACHE").ok();
+    if let Some(kv) = &kv {
+        match kv.get(&key).json::<Comparison>().await {
+            Ok(Some(cached)) => return Ok((cached, true)),
+            Ok(None) => {}
+            Err(e) => console_warn!("Failed to read comparison cache: {e:?}"),
+        }
+    }
+    let text = crate::ai::compare_destinations(env, config, first, second, days).await?;
+    let comparison = parse(&text).map_err(|e| Error::RustError(format!("the AI did not return a usable comparison: {e}")))?;
+    if let Some(kv) = &kv {
+        let stored = match kv.put(&key, serde_json::to_string(&comparison)?) {
+            Ok(put) => put.expiration_ttl(CACHE_TTL_SECS).execute().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = stored {
+            console_warn!("Failed to write comparison cache: {e:?}");
+        }
+    }
+    Ok((comparison, false))
+}
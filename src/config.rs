@@ -0,0 +1,335 @@
+//! Typed deployment settings, read and validated once per request.
+//!
+//! [`Config::from_env`] reads the variables the request handlers act on into typed fields,
+//! applying the defaults listed in the README, and rejects malformed values instead of
+//! silently falling back: `PLAN_VERSIONS_KEPT=ten` or `CHAT_ENABLED=maybe` is reported by
+//! name, so a typo in `wrangler.toml` does not quietly change behavior.
+//!
+//! A missing AI setup (`CF_ACCOUNT_ID` or the `CF_API_TOKEN` secret) is not an error here,
+//! since routes that never call the AI keep working without it. It is recorded in
+//! [`Config::ai_missing`] instead, so AI routes can answer `503` before doing any work.
+//!
+//! Settings that only matter inside one module (encryption, geocoding, write retries, ...)
+//! are read here too, and handed to those modules through the `&Config` their callers pass
+//! down. The AI model settings are still read in `ai`, but validated here as well.
+use worker::Env;
+
+use crate::features::{self, Feature};
+use crate::quiet_hours::{self, QuietHours};
+use crate::session::{self, SessionBackend};
+
+/// How many plan versions are kept per trip when `PLAN_VERSIONS_KEPT` is not set.
+pub const DEFAULT_PLAN_VERSIONS_KEPT: u32 = 10;
+
+/// The cap on concurrent requests per trip session when `TRIP_MAX_CONCURRENCY` is not set.
+pub const DEFAULT_TRIP_MAX_CONCURRENCY: u32 = 8;
+
+/// How many requests may wait for a slot per trip session when `TRIP_MAX_QUEUE_DEPTH` is
+/// not set.
+pub const DEFAULT_TRIP_MAX_QUEUE_DEPTH: u32 = 0;
+
+/// The most interests `POST /input` accepts when `MAX_INTERESTS` is not set.
+pub const DEFAULT_MAX_INTERESTS: u32 = 10;
+
+/// How long, in seconds, clients shed by admission control are told to wait when
+/// `ADMISSION_RETRY_AFTER_SECS` is not set.
+pub const DEFAULT_ADMISSION_RETRY_AFTER_SECS: u32 = 5;
+
+/// The largest `POST /trip/{id}/messages/import` body, in bytes, when `IMPORT_MAX_BYTES` is
+/// not set (10 MiB).
+pub const DEFAULT_IMPORT_MAX_BYTES: usize = 10 * 1024 * 1024;
+
+/// The largest line of an import body, in bytes, when `IMPORT_MAX_LINE_BYTES` is not set
+/// (64 KiB).
+pub const DEFAULT_IMPORT_MAX_LINE_BYTES: usize = 64 * 1024;
+
+/// The most messages in one import when `IMPORT_MAX_MESSAGES` is not set.
+pub const DEFAULT_IMPORT_MAX_MESSAGES: usize = 1000;
+
+/// The length, in characters, of the plan in a `GET /trip/{trip_id}?preview=true` response
+/// when `PLAN_PREVIEW_CHARS` is not set.
+pub const DEFAULT_PLAN_PREVIEW_CHARS: usize = 2_000;
+
+/// The settings the request handlers act on. See the README for what each variable does.
+///
+/// # Fields
+/// * `require_https` - `REQUIRE_HTTPS`, default `false`.
+/// * `strict_form` - `STRICT_FORM`, default `false`.
+/// * `chat_enabled` - `CHAT_ENABLED`, default `true`.
+/// * `chat_summary` - `CHAT_SUMMARY`, default `false`.
+/// * `chat_created_json` - `CHAT_CREATED_JSON`, default `true`.
+/// * `dedup_trips` - `DEDUP_TRIPS`, default `false`.
+/// * `clamp_days` - `CLAMP_DAYS`, default `false`.
+/// * `warmup_enabled` - `WARMUP_ENABLED`, default `false`.
+/// * `plan_weekdays` - `PLAN_WEEKDAYS`, default `true`.
+/// * `auto_detect_lang` - `AUTO_DETECT_LANG`, default `false`.
+/// * `plan_context_once` - `true` when `PLAN_CONTEXT` is `once`, `false` for `always` (the default).
+/// * `plan_versions_kept` - `PLAN_VERSIONS_KEPT`, default [`DEFAULT_PLAN_VERSIONS_KEPT`].
+/// * `trip_max_concurrency` - `TRIP_MAX_CONCURRENCY`, default [`DEFAULT_TRIP_MAX_CONCURRENCY`].
+/// * `trip_max_queue_depth` - `TRIP_MAX_QUEUE_DEPTH`, default [`DEFAULT_TRIP_MAX_QUEUE_DEPTH`].
+/// * `max_interests` - `MAX_INTERESTS`, default [`DEFAULT_MAX_INTERESTS`].
+/// * `admission_max_concurrency` - `ADMISSION_MAX_CONCURRENCY`, default `0` (no cap; see `admission`).
+/// * `admission_retry_after_secs` - `ADMISSION_RETRY_AFTER_SECS`, default [`DEFAULT_ADMISSION_RETRY_AFTER_SECS`].
+/// * `admission_fail_open` - `ADMISSION_FAIL_OPEN`, default `true`.
+/// * `import_max_bytes` - `IMPORT_MAX_BYTES`, default [`DEFAULT_IMPORT_MAX_BYTES`].
+/// * `import_max_line_bytes` - `IMPORT_MAX_LINE_BYTES`, default [`DEFAULT_IMPORT_MAX_LINE_BYTES`].
+/// * `import_max_messages` - `IMPORT_MAX_MESSAGES`, default [`DEFAULT_IMPORT_MAX_MESSAGES`].
+/// * `plan_preview_chars` - `PLAN_PREVIEW_CHARS`, default [`DEFAULT_PLAN_PREVIEW_CHARS`].
+/// * `mock_ai` - `MOCK_AI`, default `false`.
+/// * `dedup_chat_history` - `DEDUP_CHAT_HISTORY`, default `false`.
+/// * `debug_bodies` - `DEBUG_BODIES`, default `false`.
+/// * `debug_bodies_until` - `DEBUG_BODIES_UNTIL` in epoch milliseconds, default `None` (see `debug`).
+/// * `encrypt_messages` - `ENCRYPT_MESSAGES`, default `false`.
+/// * `analytics_enabled` - `ANALYTICS_ENABLED`, default `false`.
+/// * `geocoding_enabled` - `GEOCODING_ENABLED`, default `false`.
+/// * `sanitize_ai_html` - `SANITIZE_AI_HTML`, default `false`.
+/// * `multi_tenant` - `MULTI_TENANT`, default `false`.
+/// * `db_write_retries` - `DB_WRITE_RETRIES`, default `db::DEFAULT_DB_WRITE_RETRIES`.
+/// * `db_busy_retries` - `DB_BUSY_RETRIES`, default `db::DEFAULT_DB_BUSY_RETRIES`.
+/// * `plan_max_chars` - `PLAN_MAX_CHARS`, default `db::DEFAULT_PLAN_MAX_CHARS`.
+/// * `prompt_max_chars` - `PROMPT_MAX_CHARS`, default `ai::DEFAULT_PROMPT_MAX_CHARS`.
+/// * `session_backend` - `SESSION_BACKEND` (see `session`), default the durable object.
+/// * `quiet_hours` - `SCHEDULED_QUIET_HOURS` (see `quiet_hours`), default `None` (scheduled jobs always run).
+/// * `disabled_features` - The features switched off in `FEATURE_FLAGS` (see `features`), default none.
+/// * `ai_missing` - `None` when the AI service can be called (see `ai::check_configured`),
+///   otherwise the missing setting, e.g. `"CF_API_TOKEN is not set"`.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub require_https: bool,
+    pub strict_form: bool,
+    pub chat_enabled: bool,
+    pub chat_summary: bool,
+    pub chat_created_json: bool,
+    pub dedup_trips: bool,
+    pub clamp_days: bool,
+    pub warmup_enabled: bool,
+    pub plan_weekdays: bool,
+    pub auto_detect_lang: bool,
+    pub plan_context_once: bool,
+    pub plan_versions_kept: u32,
+    pub trip_max_concurrency: u32,
+    pub trip_max_queue_depth: u32,
+    pub max_interests: u32,
+    pub admission_max_concurrency: u32,
+    pub admission_retry_after_secs: u32,
+    pub admission_fail_open: bool,
+    pub import_max_bytes: usize,
+    pub import_max_line_bytes: usize,
+    pub import_max_messages: usize,
+    pub plan_preview_chars: usize,
+    pub mock_ai: bool,
+    pub dedup_chat_history: bool,
+    pub debug_bodies: bool,
+    pub debug_bodies_until: Option<u64>,
+    pub encrypt_messages: bool,
+    pub analytics_enabled: bool,
+    pub geocoding_enabled: bool,
+    pub sanitize_ai_html: bool,
+    pub multi_tenant: bool,
+    pub db_write_retries: u32,
+    pub db_busy_retries: u32,
+    pub plan_max_chars: usize,
+    pub prompt_max_chars: usize,
+    pub session_backend: SessionBackend,
+    pub quiet_hours: Option<QuietHours>,
+    pub disabled_features: Vec<Feature>,
+    pub ai_missing: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            require_https: false,
+            strict_form: false,
+            chat_enabled: true,
+            chat_summary: false,
+            chat_created_json: true,
+            dedup_trips: false,
+            clamp_days: false,
+            warmup_enabled: false,
+            plan_weekdays: true,
+            auto_detect_lang: false,
+            plan_context_once: false,
+            plan_versions_kept: DEFAULT_PLAN_VERSIONS_KEPT,
+            trip_max_concurrency: DEFAULT_TRIP_MAX_CONCURRENCY,
+            trip_max_queue_depth: DEFAULT_TRIP_MAX_QUEUE_DEPTH,
+            max_interests: DEFAULT_MAX_INTERESTS,
+            admission_max_concurrency: 0,
+            admission_retry_after_secs: DEFAULT_ADMISSION_RETRY_AFTER_SECS,
+            admission_fail_open: true,
+            import_max_bytes: DEFAULT_IMPORT_MAX_BYTES,
+            import_max_line_bytes: DEFAULT_IMPORT_MAX_LINE_BYTES,
+            import_max_messages: DEFAULT_IMPORT_MAX_MESSAGES,
+            plan_preview_chars: DEFAULT_PLAN_PREVIEW_CHARS,
+            mock_ai: false,
+            dedup_chat_history: false,
+            debug_bodies: false,
+            debug_bodies_until: None,
+            encrypt_messages: false,
+            analytics_enabled: false,
+            geocoding_enabled: false,
+            sanitize_ai_html: false,
+            multi_tenant: false,
+            db_write_retries: crate::db::DEFAULT_DB_WRITE_RETRIES,
+            db_busy_retries: crate::db::DEFAULT_DB_BUSY_RETRIES,
+            plan_max_chars: crate::db::DEFAULT_PLAN_MAX_CHARS,
+            prompt_max_chars: crate::ai::DEFAULT_PROMPT_MAX_CHARS,
+            session_backend: SessionBackend::DurableObject,
+            quiet_hours: None,
+            disabled_features: Vec::new(),
+            ai_missing: None,
+        }
+    }
+}
+
+impl Config {
+    /// Reads and validates the deployment's settings.
+    ///
+    /// Unset variables take their defaults. Booleans accept `true`/`1`/`yes` and
+    /// `false`/`0`/`no` (case-insensitive).
+    ///
+    /// # Errors
+    /// A message naming every malformed variable and its value, e.g.
+    /// `PLAN_VERSIONS_KEPT must be a whole number, got "ten"`, separated by `; `.
+    pub fn from_env(env: &Env) -> std::result::Result<Config, String> {
+        let mut reader = Reader { env, problems: Vec::new() };
+        let defaults = Config::default();
+        let config = Config {
+            require_https: reader.flag("REQUIRE_HTTPS", defaults.require_https),
+            strict_form: reader.flag("STRICT_FORM", defaults.strict_form),
+            chat_enabled: reader.flag("CHAT_ENABLED", defaults.chat_enabled),
+            chat_summary: reader.flag("CHAT_SUMMARY", defaults.chat_summary),
+            chat_created_json: reader.flag("CHAT_CREATED_JSON", defaults.chat_created_json),
+            dedup_trips: reader.flag("DEDUP_TRIPS", defaults.dedup_trips),
+            clamp_days: reader.flag("CLAMP_DAYS", defaults.clamp_days),
+            warmup_enabled: reader.flag("WARMUP_ENABLED", defaults.warmup_enabled),
+            plan_weekdays: reader.flag("PLAN_WEEKDAYS", defaults.plan_weekdays),
+            auto_detect_lang: reader.flag("AUTO_DETECT_LANG", defaults.auto_detect_lang),
+            plan_context_once: reader.plan_context(),
+            plan_versions_kept: reader.number("PLAN_VERSIONS_KEPT", defaults.plan_versions_kept),
+            trip_max_concurrency: reader.number("TRIP_MAX_CONCURRENCY", defaults.trip_max_concurrency),
+            trip_max_queue_depth: reader.number("TRIP_MAX_QUEUE_DEPTH", defaults.trip_max_queue_depth),
+            max_interests: reader.number("MAX_INTERESTS", defaults.max_interests),
+            admission_max_concurrency: reader.number("ADMISSION_MAX_CONCURRENCY", defaults.admission_max_concurrency),
+            admission_retry_after_secs: reader.number("ADMISSION_RETRY_AFTER_SECS", defaults.admission_retry_after_secs),
+            admission_fail_open: reader.flag("ADMISSION_FAIL_OPEN", defaults.admission_fail_open),
+            import_max_bytes: reader.number("IMPORT_MAX_BYTES", defaults.import_max_bytes),
+            import_max_line_bytes: reader.number("IMPORT_MAX_LINE_BYTES", defaults.import_max_line_bytes),
+            import_max_messages: reader.number("IMPORT_MAX_MESSAGES", defaults.import_max_messages),
+            plan_preview_chars: reader.number("PLAN_PREVIEW_CHARS", defaults.plan_preview_chars),
+            mock_ai: reader.flag("MOCK_AI", defaults.mock_ai),
+            dedup_chat_history: reader.flag("DEDUP_CHAT_HISTORY", defaults.dedup_chat_history),
+            debug_bodies: reader.flag("DEBUG_BODIES", defaults.debug_bodies),
+            debug_bodies_until: reader.optional_number("DEBUG_BODIES_UNTIL"),
+            encrypt_messages: reader.flag("ENCRYPT_MESSAGES", defaults.encrypt_messages),
+            analytics_enabled: reader.flag("ANALYTICS_ENABLED", defaults.analytics_enabled),
+            geocoding_enabled: reader.flag("GEOCODING_ENABLED", defaults.geocoding_enabled),
+            sanitize_ai_html: reader.flag("SANITIZE_AI_HTML", defaults.sanitize_ai_html),
+            multi_tenant: reader.flag("MULTI_TENANT", defaults.multi_tenant),
+            db_write_retries: reader.number("DB_WRITE_RETRIES", defaults.db_write_retries),
+            db_busy_retries: reader.number("DB_BUSY_RETRIES", defaults.db_busy_retries),
+            plan_max_chars: reader.number("PLAN_MAX_CHARS", defaults.plan_max_chars),
+            prompt_max_chars: reader.number("PROMPT_MAX_CHARS", defaults.prompt_max_chars),
+            session_backend: reader.session_backend(),
+            quiet_hours: reader.quiet_hours(),
+            disabled_features: reader.features(),
+            ai_missing: None,
+        };
+        let config = Config { ai_missing: crate::ai::check_configured(env, config.mock_ai).err().map(|e| e.to_string()), ..config };
+        if let Err(problem) = crate::ai::model_tiers(env) {
+            reader.problems.push(format!("AI_MODEL_TIERS {problem}"));
+        }
+        if reader.problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(reader.problems.join("; "))
+        }
+    }
+}
+
+/// Reads variables for [`Config::from_env`], collecting a message for every bad value.
+struct Reader<'a> {
+    env: &'a Env,
+    problems: Vec<String>,
+}
+
+impl Reader<'_> {
+    /// The trimmed value of `name`, or `None` when it is unset or blank.
+    fn raw(&self, name: &str) -> Option<String> {
+        let value = self.env.var(name).ok()?.to_string().trim().to_string();
+        (!value.is_empty()).then_some(value)
+    }
+
+    fn flag(&mut self, name: &str, default: bool) -> bool {
+        let Some(value) = self.raw(name) else {
+            return default;
+        };
+        match value.to_lowercase().as_str() {
+            "true" | "1" | "yes" => true,
+            "false" | "0" | "no" => false,
+            _ => {
+                self.problems.push(format!("{name} must be true or false, got {value:?}"));
+                default
+            }
+        }
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, name: &str, default: T) -> T {
+        let Some(value) = self.raw(name) else {
+            return default;
+        };
+        value.parse().unwrap_or_else(|_| {
+            self.problems.push(format!("{name} must be a whole number, got {value:?}"));
+            default
+        })
+    }
+
+    fn optional_number<T: std::str::FromStr>(&mut self, name: &str) -> Option<T> {
+        let value = self.raw(name)?;
+        value
+            .parse()
+            .map_err(|_| self.problems.push(format!("{name} must be a whole number, got {value:?}")))
+            .ok()
+    }
+
+    fn features(&mut self) -> Vec<Feature> {
+        let Some(value) = self.raw("FEATURE_FLAGS") else {
+            return Vec::new();
+        };
+        features::parse(&value).unwrap_or_else(|problem| {
+            self.problems.push(format!("FEATURE_FLAGS {problem}"));
+            Vec::new()
+        })
+    }
+
+    fn quiet_hours(&mut self) -> Option<QuietHours> {
+        let value = self.raw("SCHEDULED_QUIET_HOURS")?;
+        quiet_hours::parse(&value)
+            .map_err(|problem| self.problems.push(format!("SCHEDULED_QUIET_HOURS {problem}")))
+            .ok()
+    }
+
+    fn session_backend(&mut self) -> SessionBackend {
+        let Some(value) = self.raw("SESSION_BACKEND") else {
+            return SessionBackend::DurableObject;
+        };
+        session::parse_backend(&value).unwrap_or_else(|problem| {
+            self.problems.push(format!("SESSION_BACKEND {problem}"));
+            SessionBackend::DurableObject
+        })
+    }
+
+    fn plan_context(&mut self) -> bool {
+        let Some(value) = self.raw("PLAN_CONTEXT") else {
+            return false;
+        };
+        match value.to_lowercase().as_str() {
+            "once" => true,
+            "always" => false,
+            _ => {
+                self.problems.push(format!("PLAN_CONTEXT must be always or once, got {value:?}"));
+                false
+            }
+        }
+    }
+}
@@ -1,7 +1,260 @@
 use worker::*;
 use worker::wasm_bindgen::__rt::IntoJsResult;
 use crate::TripData;
+use crate::status::TripStatus;
+use crate::encryption;
+use crate::tenant::Tenant;
+use crate::slug;
+use crate::role::MessageRole;
+use crate::units::UnitPreferences;
+use crate::config::Config;
 
+/// What D1 reported for one statement of a batch.
+///
+/// # Fields
+/// * `success` - Whether the statement ran.
+/// * `error` - D1's error message when it did not.
+/// * `changes` - The rows the statement inserted, updated or deleted.
+struct StatementOutcome {
+    success: bool,
+    error: Option<String>,
+    changes: usize,
+}
+
+impl From<&D1Result> for StatementOutcome {
+    fn from(result: &D1Result) -> Self {
+        StatementOutcome {
+            success: result.success(),
+            error: result.error(),
+            changes: result.meta().ok().flatten().and_then(|meta| meta.changes).unwrap_or(0),
+        }
+    }
+}
+
+/// Describes a batch from the outcome of each of its statements.
+///
+/// # Arguments
+/// * `outcomes` - One outcome per statement, in batch order.
+/// * `context` - A short description of the operation (e.g. `"create trip"`) used in error messages.
+///
+/// # Returns
+/// A [`BatchSummary`] with the rows changed by each statement.
+///
+/// # Errors
+/// - If the batch returned no results at all.
+/// - If any statement failed; the error names the first failing statement by its 1-based
+///   position in the batch along with D1's error message.
+fn summarize(outcomes: &[StatementOutcome], context: &str) -> Result<BatchSummary> {
+    if outcomes.is_empty() {
+        return Err(Error::RustError(format!("Failed to {context}: batch returned no results")));
+    }
+    if let Some((i, failed)) = outcomes.iter().enumerate().find(|(_, outcome)| !outcome.success) {
+        return Err(Error::RustError(format!(
+            "Failed to {context}: statement {} of {} failed with error {}",
+            i + 1,
+            outcomes.len(),
+            failed.error.clone().unwrap_or_default()
+        )));
+    }
+    Ok(BatchSummary { changes: outcomes.iter().map(|outcome| outcome.changes).collect() })
+}
+
+/// Checks every result of a D1 batch and fails if any statement was unsuccessful.
+///
+/// # Arguments
+/// * `results` - The results returned by `D1Database::batch`, one per statement.
+/// * `context` - A short description of the operation (e.g. `"create trip"`) used in error messages.
+///
+/// # Returns
+/// The results unchanged when every statement succeeded.
+///
+/// # Errors
+/// As for [`summarize`]: if the batch returned no results, or naming the first failing
+/// statement by its 1-based position along with D1's error message.
+fn check_batch(results: Vec<D1Result>, context: &str) -> Result<Vec<D1Result>> {
+    let outcomes: Vec<StatementOutcome> = results.iter().map(StatementOutcome::from).collect();
+    summarize(&outcomes, context)?;
+    Ok(results)
+}
+
+/// What a successful [`batch_all`] did, with one entry per statement in batch order.
+///
+/// # Fields
+/// * `changes` - The rows each statement inserted, updated or deleted.
+#[derive(Debug)]
+pub struct BatchSummary {
+    pub changes: Vec<usize>,
+}
+
+/// Runs several statements as one D1 batch and describes what each of them did.
+///
+/// D1 runs a batch's statements one after another, in the order given, inside a single
+/// transaction: a later statement sees the effects of the earlier ones, and if any
+/// statement fails the whole batch is rolled back, so none of them has any effect. This
+/// helper makes that contract explicit for multi-statement writes.
+///
+/// # Arguments
+/// * `db` - The database to run the batch against.
+/// * `statements` - The statements, in the order they must run.
+/// * `context` - A short description of the operation, as for [`check_batch`].
+///
+/// # Returns
+/// A [`BatchSummary`] of the committed batch (see [`summarize`]).
+///
+/// # Errors
+/// If D1 rejects the batch or any statement reports a failure. The error names the failing
+/// statement by its 1-based position (see [`summarize`]) and notes that the batch was
+/// rolled back.
+pub async fn batch_all(db: &D1Database, statements: Vec<D1PreparedStatement>, context: &str) -> Result<BatchSummary> {
+    let count = statements.len();
+    db.batch(statements)
+        .await
+        .and_then(|results| summarize(&results.iter().map(StatementOutcome::from).collect::<Vec<_>>(), context))
+        .map_err(|e| Error::RustError(format!("{e}; all {count} statements were rolled back")))
+}
+
+/// How many times a failed insert is retried when `DB_WRITE_RETRIES` is not set.
+pub const DEFAULT_DB_WRITE_RETRIES: u32 = 2;
+
+/// How many times an insert that found the database busy is retried when `DB_BUSY_RETRIES`
+/// is not set.
+pub const DEFAULT_DB_BUSY_RETRIES: u32 = 5;
+
+/// The longest wait before the first retry of a busy write, doubled for each later one.
+const DB_BUSY_BASE_MS: u64 = 50;
+
+/// Fragments of D1 error messages that retrying cannot fix: constraint violations and
+/// mistakes in the statement or schema.
+const PERMANENT_ERRORS: [&str; 6] = ["constraint", "syntax error", "no such table", "no such column", "datatype mismatch", "wrong number of"];
+
+/// Fragments of D1 error messages reporting that another write held the database, which
+/// clears as soon as that write finishes.
+const BUSY_ERRORS: [&str; 5] = ["database is locked", "database is busy", "database table is locked", "sqlite_busy", "sqlite_locked"];
+
+/// What a failed write means for [`batch_with_retry`].
+#[derive(PartialEq, Eq, Debug)]
+enum WriteFailure {
+    /// Another write held the database (see [`BUSY_ERRORS`]); retried quickly, with jitter.
+    Busy,
+    /// Retrying cannot help (see [`PERMANENT_ERRORS`]); returned at once.
+    Permanent,
+    /// Anything else, such as a reset connection; retried with plain backoff.
+    Transient,
+}
+
+/// Sorts a failed write into a [`WriteFailure`]. Permanent errors win over busy ones, so a
+/// constraint violation is never retried whatever else the message says.
+fn classify(error: &Error) -> WriteFailure {
+    let message = error.to_string().to_ascii_lowercase();
+    if PERMANENT_ERRORS.iter().any(|fragment| message.contains(fragment)) {
+        WriteFailure::Permanent
+    } else if BUSY_ERRORS.iter().any(|fragment| message.contains(fragment)) {
+        WriteFailure::Busy
+    } else {
+        WriteFailure::Transient
+    }
+}
+
+/// How [`with_write_retry`] retries a failed write.
+///
+/// # Fields
+/// * `write_retries` - Retries allowed for [`WriteFailure::Transient`] failures.
+/// * `busy_retries` - Retries allowed for [`WriteFailure::Busy`] failures, counted separately.
+/// * `random` - A source of numbers in `[0, 1)` for the busy-retry jitter.
+struct WriteRetryPolicy {
+    write_retries: u32,
+    busy_retries: u32,
+    random: fn() -> f64,
+}
+
+impl WriteRetryPolicy {
+    /// Takes the retry budgets from `DB_WRITE_RETRIES` and `DB_BUSY_RETRIES`.
+    fn from_config(config: &Config) -> Self {
+        WriteRetryPolicy {
+            write_retries: config.db_write_retries,
+            busy_retries: config.db_busy_retries,
+            random: js_sys::Math::random,
+        }
+    }
+}
+
+/// Runs `write` until it succeeds, fails permanently or runs out of retries.
+///
+/// Failures are handled by their [`WriteFailure`] class:
+/// - Busy or locked database errors are retried up to `policy.busy_retries` times. Each
+///   wait is a random time of up to [`DB_BUSY_BASE_MS`] doubled per retry, so writers that
+///   collided do not collide again in lockstep.
+/// - Other transient failures are retried up to `policy.write_retries` times with
+///   exponential backoff starting at 100ms.
+/// - Permanent errors are returned immediately.
+///
+/// # Arguments
+/// * `policy` - The retry budgets and jitter source.
+/// * `write` - Makes one attempt at the write.
+/// * `wait` - Called before each retry with the error that caused it and the delay to
+///   sleep for.
+///
+/// # Errors
+/// The error of the last attempt, unchanged.
+async fn with_write_retry<T, F, Fut, W, WaitFut>(policy: &WriteRetryPolicy, mut write: F, mut wait: W) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+    W: FnMut(&Error, std::time::Duration) -> WaitFut,
+    WaitFut: std::future::Future<Output = ()>,
+{
+    let (mut write_attempt, mut busy_attempt) = (0, 0);
+    loop {
+        let error = match write().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let delay = match classify(&error) {
+            WriteFailure::Busy if busy_attempt < policy.busy_retries => {
+                let ceiling = DB_BUSY_BASE_MS * 2u64.pow(busy_attempt);
+                busy_attempt += 1;
+                ((policy.random)() * ceiling as f64) as u64 + 1
+            }
+            WriteFailure::Transient if write_attempt < policy.write_retries => {
+                let delay = 100 * 2u64.pow(write_attempt);
+                write_attempt += 1;
+                delay
+            }
+            _ => return Err(error),
+        };
+        wait(&error, std::time::Duration::from_millis(delay)).await;
+    }
+}
+
+/// Runs a batch of writes with [`check_batch`], retrying failures with [`with_write_retry`].
+///
+/// D1 occasionally fails a write under load, which would otherwise surface as a spurious
+/// `500`. Busy or locked database errors, common under concurrent writes, are retried up to
+/// `DB_BUSY_RETRIES` times (default [`DEFAULT_DB_BUSY_RETRIES`]) with jittered backoff;
+/// other transient failures (a reset connection, an overloaded database) up to
+/// `DB_WRITE_RETRIES` times (default [`DEFAULT_DB_WRITE_RETRIES`]). Constraint violations
+/// and other permanent errors are returned immediately.
+///
+/// # Arguments
+/// * `db` - The database to run the batch against.
+/// * `statements` - The statements to run; they are resent unchanged on every attempt.
+/// * `context` - A short description of the operation, as for [`check_batch`].
+/// * `config` - The deployment's settings, providing `DB_WRITE_RETRIES` and `DB_BUSY_RETRIES`.
+///
+/// # Notes
+/// - A batch runs as one transaction, so a failed attempt leaves nothing behind. Only a
+///   failure reported after D1 committed (e.g. a dropped response) can lead to a duplicate.
+async fn batch_with_retry(db: &D1Database, statements: Vec<D1PreparedStatement>, context: &str, config: &Config) -> Result<Vec<D1Result>> {
+    with_write_retry(
+        &WriteRetryPolicy::from_config(config),
+        || async { db.batch(statements.clone()).await.and_then(|results| check_batch(results, context)) },
+        |error, delay| {
+            console_warn!("Failed to {context} ({error}), retrying in {}ms", delay.as_millis());
+            Delay::from(delay)
+        },
+    )
+    .await
+}
 
 /// Asynchronously creates a new trip entry in the "TripPlanner" database.
 ///
@@ -16,17 +269,25 @@ use crate::TripData;
 ///   - `id`: The unique identifier for the trip.
 ///   - `destination`: The destination of the trip.
 ///   - `days`: The number of days for the trip.
+///   - `slug`: Ignored; the slug is always derived from `destination` and `days` (see [`slug`](crate::slug)).
+/// * `config` - The deployment's settings, for the retries of its insert (see [`batch_with_retry`]).
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
 /// * `env` - An `Env` object used to access the "TripPlanner" D1 database.
 ///
 /// # Returns
-/// A `Result<D1Result>` which, on success, contains the result of the database operation. If an error
+/// A `Result<String>` which, on success, contains the slug assigned to the trip. If an error
 /// occurs, it returns an `Error` variant with a descriptive error message.
 ///
+/// # Slugs
+/// The first free slug is picked from those already stored. Should another trip claim the
+/// same slug between that lookup and the insert, the unique index rejects the insert and
+/// the next free slug is tried, up to [`SLUG_ATTEMPTS`] times.
+///
 /// # Errors
 /// This function can return an `Err` for the following reasons:
 /// - If there is an issue accessing the "TripPlanner" database.
 /// - If preparing or binding the SQL statement fails.
-/// - If the batch operation fails to execute.
+/// - If the batch operation fails to execute, after any retries (see [`batch_with_retry`]).
 /// - If the database operation does not succeed (e.g., due to constraint violations).
 ///
 /// # Example
@@ -39,12 +300,15 @@ use crate::TripData;
 ///         id: "trip_123".to_string(),
 ///         destination: "Paris".to_string(),
 ///         days: 5,
+///         status: TripStatus::Planning,
+///         title: Some("5 Days of Art and Food in Paris".to_string()),
+///         slug: None, // ignored; `create_trip` derives it
 ///     };
 ///
-///     let env = Env::new(); // Assume `Env` is properly initialized
+///     let env = Env::new(); // Assume `Env`, `config` and `tenant` come from the request
 ///
-///     match create_trip(trip, env).await {
-///         Ok(result) => println!("Trip created successfully: {:?}", result),
+///     match create_trip(trip, &config, &tenant, env).await {
+///         Ok(slug) => println!("Trip created with slug {slug}"),
 ///         Err(e) => eprintln!("Error creating trip: {}", e),
 ///     }
 /// }
@@ -52,26 +316,68 @@ use crate::TripData;
 ///
 /// # Notes
 /// - Ensure the `TripData` structure and `Env` environment are properly defined and initialized.
-/// - The database schema for the `trips` table should match the expected fields (`id`, `destination`, `days`).
+/// - The database schema for the `trips` table should match the expected fields (`id`, `destination`, `days`, `status`, `title`, `slug`).
 /// - Exception handling is implemented to ensure meaningful error messages in case of failures.
-pub async fn create_trip(trip: TripData, env: Env) -> Result<D1Result>{
+pub async fn create_trip(trip: TripData, config: &Config, tenant: &Tenant, env: Env) -> Result<String>{
     let db = env.d1("TripPlanner")?;
 
-    let statement = db.prepare("INSERT INTO trips (id, destination, days) VALUES (?, ?, ?)")
-        .bind(&[trip.id.into_js_result()?,trip.destination.into_js_result()?,trip.days.into_js_result()?])?;
-    let result = db.batch(vec![statement]).await?;
-    let mut iter_result = result.into_iter();
-    if let Some(r) = iter_result.next(){
-        if !r.success(){
-            return Err(Error::RustError(format!("Failed to create trip with error {}",r.error().unwrap())));
+    let base = slug::base(&trip.destination, trip.days);
+    let mut attempt = 1;
+    loop {
+        let slug = slug::next_free(&base, &taken_slugs(&db, &base).await?);
+        let title = trip.title.clone().map(wasm_bindgen::JsValue::from).unwrap_or(wasm_bindgen::JsValue::NULL);
+        let statement = db.prepare(format!("INSERT INTO trips (id, destination, days, status, title, slug{}) VALUES (?, ?, ?, ?, ?, ?{})", tenant.column(), tenant.placeholder()))
+            .bind(&tenant.bind(vec![trip.id.clone().into_js_result()?,trip.destination.clone().into_js_result()?,trip.days.into_js_result()?,trip.status.as_str().into_js_result()?,title,slug.clone().into_js_result()?]))?;
+        match batch_with_retry(&db, vec![statement], "create trip", config).await {
+            Ok(_) => return Ok(slug),
+            Err(e) if attempt < SLUG_ATTEMPTS && e.to_string().contains("trips.slug") => {
+                console_warn!("Slug {slug} was taken concurrently, picking another");
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
         }
-        Ok(r)
-    }
-    else{
-        Err(Error::RustError("Failed to create trip".into()))
     }
 }
 
+/// How many slugs [`create_trip`] tries before giving up on a trip.
+const SLUG_ATTEMPTS: u32 = 3;
+
+/// Lists the slugs in use that are `base` or `base` followed by `-` and a suffix.
+///
+/// Slugs are unique across tenants, so every trip is considered, deleted ones included.
+async fn taken_slugs(db: &D1Database, base: &str) -> Result<Vec<String>> {
+    let statement = db.prepare("SELECT slug FROM trips WHERE slug = ?1 OR slug LIKE ?1 || '-%'")
+        .bind(&[base.into_js_result()?])?;
+    Ok(statement
+        .all()
+        .await?
+        .results::<serde_json::Value>()?
+        .into_iter()
+        .filter_map(|row| Some(row.get("slug")?.as_str()?.to_string()))
+        .collect())
+}
+
+/// Asynchronously resolves a slug to the id of the live trip carrying it.
+///
+/// # Arguments
+///
+/// * `slug` - The slug, e.g. `paris-5-days` (see [`slug`](crate::slug)).
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The trip id, or `None` if no live trip has that slug.
+pub async fn find_trip_id_by_slug(slug: String, tenant: &Tenant, env: Env) -> Result<Option<String>> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("SELECT id FROM trips WHERE slug = ? AND deleted_at IS NULL{}", tenant.filter()))
+        .bind(&tenant.bind(vec![slug.into_js_result()?]))?;
+    Ok(statement
+        .first::<serde_json::Value>(None)
+        .await?
+        .and_then(|row| Some(row.get("id")?.as_str()?.to_string())))
+}
+
 /// Asynchronously creates a new plan for a specific trip in the database.
 ///
 /// # Arguments
@@ -79,6 +385,9 @@ pub async fn create_trip(trip: TripData, env: Env) -> Result<D1Result>{
 /// * `trip_id` - A `String` that represents the unique identifier for the trip.
 /// * `plan` - A reference to a `String` that represents the plan details to be saved.
 /// * `input_text` - A reference to a `String` containing additional input text related to the plan.
+/// * `refused` - Whether the AI refused to write the plan (see `ai::is_refusal`).
+/// * `config` - The deployment's settings, for `plan_max_chars` (see [`cap_plan`]) and the retries of its insert.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
 /// * `env` - The `Env` object containing the environment configuration and database access.
 ///
 /// # Returns
@@ -96,11 +405,12 @@ pub async fn create_trip(trip: TripData, env: Env) -> Result<D1Result>{
 /// # Behavior
 ///
 /// 1. Establishes a connection to the `TripPlanner` database from the provided `Env`.
-/// 2. Generates the current timestamp using the `Date::now()` function.
-/// 3. Prepares an SQL `INSERT` statement to store the new plan with the `trip_id`, `plan`, `input_text`,
+/// 2. Takes the current time in epoch milliseconds (see `time::now_millis`).
+/// 3. Caps the plan at `PLAN_MAX_CHARS` characters (see [`cap_plan`]).
+/// 4. Prepares an SQL `INSERT` statement to store the new plan with the `trip_id`, `plan`, `input_text`,
 ///    and the current timestamp.
-/// 4. Executes the SQL statements in batch mode.
-/// 5. Evaluates the database operation result to ensure the plan was created successfully:
+/// 5. Executes the SQL statements in batch mode, retrying transient failures (see [`batch_with_retry`]).
+/// 6. Evaluates every result of the batch with `check_batch` to ensure the plan was created successfully:
 ///     - If successful, returns the corresponding `D1Result`.
 ///     - If there is a failure, returns an appropriate error (e.g., a `RustError` with details).
 ///
@@ -116,37 +426,51 @@ pub async fn create_trip(trip: TripData, env: Env) -> Result<D1Result>{
 ///     let input_text = "Eiffel Tower, Louvre Museum".to_string();
 ///     let env = Env::new();
 ///
-///     match create_plan(trip_id, &plan, &input_text, env).await {
+///     match create_plan(trip_id, &plan, &input_text, false, &config, &tenant, env).await {
 ///         Ok(result) => println!("Plan created successfully: {:?}", result),
 ///         Err(e) => eprintln!("Failed to create plan: {:?}", e),
 ///     }
 /// }
 /// ```
-pub async fn create_plan(trip_id: String, plan: &String, input_text: &String, env: Env) -> Result<D1Result>{
-    let db = env.d1("TripPlanner")?;
-    let date = Date::now();
-    let timestamp = date.to_string();
-    let statement = db.prepare("INSERT INTO plans (trip_id, plan, input_text, updated_at) VALUES (?,?,?,?)")
-        .bind(&[trip_id.into_js_result()?,plan.into_js_result()?,input_text.into_js_result()?,timestamp.into_js_result()?])?;
-    let result = db.batch(vec![statement]).await?;
-    let mut iter_result = result.into_iter();
-    if let Some(r) = iter_result.next(){
-        if !r.success(){
-            return Err(Error::RustError(format!("Failed to create plan with error {}",r.error().unwrap())));
-        }
-        Ok(r)
-    }
-    else{
-        Err(Error::RustError("Failed to create plan".into()))
+pub async fn create_plan(trip_id: String, plan: &str, input_text: &String, refused: bool, config: &Config, tenant: &Tenant, env: Env) -> Result<D1Result>{
+    let db = env.d1("TripPlanner")?;
+    let timestamp = crate::time::now_millis() as f64;
+    let plan = cap_plan(config, &trip_id, plan);
+    let statement = db.prepare(format!("INSERT INTO plans (trip_id, plan, input_text, updated_at, refused{}) VALUES (?,?,?,?,?{})", tenant.column(), tenant.placeholder()))
+        .bind(&tenant.bind(vec![trip_id.into_js_result()?,plan.into_js_result()?,input_text.into_js_result()?,timestamp.into_js_result()?,u32::from(refused).into_js_result()?]))?;
+    let result = batch_with_retry(&db, vec![statement], "create plan", config).await?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// The longest plan, in characters, stored when `PLAN_MAX_CHARS` is not set.
+pub const DEFAULT_PLAN_MAX_CHARS: usize = 100_000;
+
+/// Appended to a plan cut short by [`cap_plan`].
+const PLAN_TRUNCATED_MARKER: &str = "\n\n[Plan truncated: it exceeded the maximum stored length.]";
+
+/// Cuts a plan down to `PLAN_MAX_CHARS` characters (default [`DEFAULT_PLAN_MAX_CHARS`]).
+///
+/// Plans within the limit are returned unchanged. Longer ones are cut at the last line
+/// break before the limit (or at the limit itself when there is none), followed by
+/// [`PLAN_TRUNCATED_MARKER`], and the truncation is logged.
+fn cap_plan(config: &Config, trip_id: &str, plan: &str) -> String {
+    let max_chars = config.plan_max_chars;
+    let (head, truncated) = crate::plan::preview(plan, max_chars);
+    if !truncated {
+        return head;
     }
+    console_warn!("Plan for trip {trip_id} truncated from {} to {max_chars} characters", plan.chars().count());
+    format!("{head}{PLAN_TRUNCATED_MARKER}")
 }
 
 /// Asynchronous function to create a new message entry in the database for a specific trip.
 ///
 /// # Parameters
 /// - `trip_id`: A `String` that represents the unique identifier of the trip to which the message belongs.
-/// - `message`: A reference to a `String` containing the content of the message.
+/// - `message`: A string slice containing the content of the message.
 /// - `messager_role`: A `&str` specifying the role of the message sender (e.g., "admin", "user").
+/// - `config`: The deployment's settings, for encryption (`encrypt_messages`) and the retries of its insert.
+/// - `tenant`: The tenant of the request; only its rows are read or written (see [`Tenant`]).
 /// - `env`: An `Env` object used to interact with the environment and database.
 ///
 /// # Returns
@@ -164,14 +488,16 @@ pub async fn create_plan(trip_id: String, plan: &String, input_text: &String, en
 ///   1. `trip_id` - Unique identifier for the trip (provided as input).
 ///   2. `message` - The content of the message (provided as input).
 ///   3. `messager_role` - Role of the sender (provided as input).
-///   4. `created_at` - The timestamp when the message is created (automatically generated using `Date::now()`).
+///   4. `created_at` - The timestamp when the message is created, in epoch milliseconds (see `time::now_millis`).
 ///
 /// # Example Usage
 /// ```rust
 /// let result = create_message(
 ///     "trip123".to_string(),
 ///     &"Hello, your trip is confirmed!".to_string(),
-///     "admin",
+///     MessageRole::System,
+///     &config,
+///     &tenant,
 ///     env,
 /// ).await;
 /// match result {
@@ -184,23 +510,45 @@ pub async fn create_plan(trip_id: String, plan: &String, input_text: &String, en
 /// - The function binds the input values (`trip_id`, `message`, `messager_role`, and `created_at`) to an SQL `INSERT` query.
 /// - Uses a batched database operation for efficient execution.
 /// - Ensures error handling for both database interaction and result validation.
-pub async fn create_message(trip_id: String, message: &String, messager_role: &str, env: Env) -> Result<D1Result>{
-    let db = env.d1("TripPlanner")?;
-    let date = Date::now();
-    let timestamp = date.to_string();
-    let statement = db.prepare("INSERT INTO messages (trip_id, message, messager_role, created_at) VALUES (?,?,?,?)")
-        .bind(&[trip_id.into_js_result()?,message.into_js_result()?,messager_role.into_js_result()?,timestamp.into_js_result()?])?;
-    let result = db.batch(vec![statement]).await?;
-    let mut iter_result = result.into_iter();
-    if let Some(r) = iter_result.next(){
-        if !r.success(){
-            return Err(Error::RustError(format!("Failed to create message with error {}",r.error().unwrap())));
-        }
-        Ok(r)
-    }
-    else{
-        Err(Error::RustError("Failed to create message".into()))
-    }
+/// - When `ENCRYPT_MESSAGES` is enabled the message is encrypted with `encryption::seal` before it is stored.
+pub async fn create_message(trip_id: String, message: &str, messager_role: MessageRole, config: &Config, tenant: &Tenant, env: Env) -> Result<D1Result>{
+    create_message_with_metadata(trip_id, message, messager_role, None, false, config, tenant, env).await
+}
+
+/// Asynchronously creates a message like [`create_message`], attaching optional metadata.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `message` - The content of the message.
+/// * `messager_role` - The role of the sender, stored by name (see [`MessageRole::as_str`]).
+/// * `metadata` - Optional JSON text stored in the `metadata` column, such as the plan
+///   sections a verbose chat reply referenced. `None` stores `NULL`.
+/// * `refused` - Whether the message is an AI refusal (see `ai::is_refusal`).
+/// * `config` - The deployment's settings, for encryption (`encrypt_messages`) and the retries of its insert.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the insert, or an error if the statement fails.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_message_with_metadata(trip_id: String, message: &str, messager_role: MessageRole, metadata: Option<&str>, refused: bool, config: &Config, tenant: &Tenant, env: Env) -> Result<D1Result>{
+    let db = env.d1("TripPlanner")?;
+    let statement = message_insert(&db, trip_id, message, messager_role, metadata, refused, config, tenant, &env)?;
+    let result = batch_with_retry(&db, vec![statement], "create message", config).await?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Prepares the insert of one message, stamped with the current time and encrypted like
+/// [`create_message`]; see [`create_message_with_metadata`] for the arguments.
+#[allow(clippy::too_many_arguments)]
+fn message_insert(db: &D1Database, trip_id: String, message: &str, messager_role: MessageRole, metadata: Option<&str>, refused: bool, config: &Config, tenant: &Tenant, env: &Env) -> Result<D1PreparedStatement> {
+    let timestamp = crate::time::now_millis() as f64;
+    let message = encryption::seal(env, config, message)?;
+    let metadata = metadata.map(wasm_bindgen::JsValue::from).unwrap_or(wasm_bindgen::JsValue::NULL);
+    db.prepare(format!("INSERT INTO messages (trip_id, message, messager_role, created_at, metadata, refused{}) VALUES (?,?,?,?,?,?{})", tenant.column(), tenant.placeholder()))
+        .bind(&tenant.bind(vec![trip_id.into_js_result()?,message.into_js_result()?,messager_role.as_str().into_js_result()?,timestamp.into_js_result()?,metadata,u32::from(refused).into_js_result()?]))
 }
 
 /// Asynchronously checks if there are any messages associated with a given trip ID in the database.
@@ -212,6 +560,7 @@ pub async fn create_message(trip_id: String, message: &String, messager_role: &s
 /// # Arguments
 ///
 /// * `trip_id` - A `String` representing the unique identifier of the trip to check for associated messages.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
 /// * `env` - An `Env` object that provides access to the database environment configuration.
 ///
 /// # Returns
@@ -238,7 +587,7 @@ pub async fn create_message(trip_id: String, message: &String, messager_role: &s
 ///     let trip_id = "12345".to_string();
 ///     let env = Env::new(); // Assume this initializes the environment properly.
 ///
-///     let has_messages = check_if_messages(trip_id, env).await?;
+///     let has_messages = check_if_messages(trip_id, &tenant, env).await?;
 ///     
 ///     if has_messages {
 ///         println!("There are messages for the provided trip ID.");
@@ -262,10 +611,10 @@ pub async fn create_message(trip_id: String, message: &String, messager_role: &s
 /// - `async`/`await` for asynchronous operation.
 /// - `serde_json::Value` for handling database query results.
 /// - Database access methods compatible with `Env` and `d1`.
-pub async fn check_if_messages(trip_id: String, env: Env) -> Result<bool> {
+pub async fn check_if_messages(trip_id: String, tenant: &Tenant, env: Env) -> Result<bool> {
     let db = env.d1("TripPlanner")?;
-    let statement = db.prepare("SELECT 1 as one FROM messages WHERE trip_id = ? LIMIT 1")
-        .bind(&[trip_id.into_js_result()?])?;
+    let statement = db.prepare(format!("SELECT 1 as one FROM messages WHERE trip_id = ?{} LIMIT 1", tenant.filter()))
+        .bind(&tenant.bind(vec![trip_id.into_js_result()?]))?;
     let result = statement.first::<serde_json::Value>(None).await?;
     Ok(result.is_some())
 }
@@ -275,6 +624,7 @@ pub async fn check_if_messages(trip_id: String, env: Env) -> Result<bool> {
 /// # Arguments
 ///
 /// * `trip_id` - A `String` representing the unique identifier for the trip.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
 /// * `env` - An `Env` object that provides access to database and environment configuration.
 ///
 /// # Returns
@@ -304,7 +654,7 @@ pub async fn check_if_messages(trip_id: String, env: Env) -> Result<bool> {
 ///     let env = Env::new();
 ///     let trip_id = "12345".to_string();
 ///
-///     match get_messages(trip_id, env).await {
+///     match get_messages(trip_id, &tenant, env).await {
 ///         Ok(messages) => {
 ///             for (message, role, created_at) in messages {
 ///                 println!("Message: {}, Role: {}, Created At: {}", message, role, created_at);
@@ -320,24 +670,1293 @@ pub async fn check_if_messages(trip_id: String, env: Env) -> Result<bool> {
 /// This function assumes that the `messages` table in the database includes the following columns:
 /// - `message` (text content of the message),
 /// - `messager_role` (role of the sender),
-/// - `created_at` (timestamp of message creation).
+/// - `created_at` (when the message was created, in epoch milliseconds; see `time::stored_millis`).
+///
+/// Messages are returned oldest first, ordered by `created_at` and then by `id` for messages
+/// stored at the same time (such as those of one import), so the chat history and the AI
+/// context follow the conversation.
+///
+/// Rows where any of these is missing or not text are left out rather than failing the
+/// whole history; how many were skipped, and why, is logged as a warning.
 ///
-pub async fn get_messages(trip_id: String, env: Env) -> Result<Vec<(String, String, String)>> {
+/// Messages stored encrypted (see `encryption`) are decrypted transparently; plaintext rows
+/// are returned as-is.
+///
+pub async fn get_messages(trip_id: String, tenant: &Tenant, env: Env) -> Result<Vec<(String, MessageRole, i64)>> {
     let db = env.d1("TripPlanner")?;
-    let statement = db.prepare("SELECT message, messager_role, created_at FROM messages WHERE trip_id = ? ")
-        .bind(&[trip_id.into_js_result()?])?;
+    let statement = db.prepare(format!("SELECT id, message, messager_role, created_at FROM messages WHERE trip_id = ?{} ORDER BY created_at ASC, id ASC", tenant.filter()))
+        .bind(&tenant.bind(vec![trip_id.clone().into_js_result()?]))?;
     let result = statement.all().await?;
-    let messages = result
+    let mut warnings = Vec::new();
+    let mut messages = result
         .results::<serde_json::Value>()? // get as JSON-like rows
         .into_iter()
+        .filter_map(|row| {
+            let parsed = (|| -> std::result::Result<_, String> { Ok((
+                text_field(&row, "message")?,
+                role_field(&row)?,
+                millis_field(&row, "created_at")?,
+            )) })();
+            parsed.map_err(|reason| warnings.push(skipped_row(&row, reason))).ok()
+        })
+        .map(|(message, role, created_at)| Ok((encryption::open(&env, &message)?, role, created_at)))
+        .collect::<Result<Vec<_>>>()?;
+    // SQLite sorts integer timestamps before the date strings of older rows (and those
+    // strings alphabetically), so the order is settled on the parsed times. The sort is
+    // stable, keeping messages with the same time in id order.
+    messages.sort_by_key(|(_, _, created_at)| *created_at);
+    log_skipped_rows(&trip_id, &warnings);
+
+    Ok(messages)
+}
+
+/// Reads a text column of a `messages` row.
+///
+/// # Errors
+/// A short reason, e.g. `"message is missing"` or `"created_at is not text"`, used to
+/// describe why the row was skipped.
+fn text_field(row: &serde_json::Value, column: &str) -> std::result::Result<String, String> {
+    match row.get(column) {
+        None | Some(serde_json::Value::Null) => Err(format!("{column} is missing")),
+        Some(value) => value.as_str().map(str::to_string).ok_or_else(|| format!("{column} is not text")),
+    }
+}
+
+/// Reads the `messager_role` column of a `messages` row. Unknown names are kept as
+/// [`MessageRole::Unknown`].
+///
+/// # Errors
+/// A short reason when the column is missing or not text, as for [`text_field`].
+fn role_field(row: &serde_json::Value) -> std::result::Result<MessageRole, String> {
+    let Ok(role) = text_field(row, "messager_role")?.parse();
+    Ok(role)
+}
+
+/// Reads a timestamp column of a `messages` row as epoch milliseconds (see
+/// `time::stored_millis`).
+///
+/// # Errors
+/// A short reason, e.g. `"created_at is not a timestamp"`, as for [`text_field`].
+fn millis_field(row: &serde_json::Value, column: &str) -> std::result::Result<i64, String> {
+    match row.get(column) {
+        None | Some(serde_json::Value::Null) => Err(format!("{column} is missing")),
+        Some(value) => crate::time::stored_millis(value).ok_or_else(|| format!("{column} is not a timestamp")),
+    }
+}
+
+/// Describes a `messages` row that could not be read, naming it by id when it has one.
+fn skipped_row(row: &serde_json::Value, reason: String) -> String {
+    match row.get("id").and_then(|v| v.as_i64()) {
+        Some(id) => format!("skipped message {id}: {reason}"),
+        None => format!("skipped message without id: {reason}"),
+    }
+}
+
+/// Logs how many of a trip's message rows were skipped and why; silent when none were.
+fn log_skipped_rows(trip_id: &str, warnings: &[String]) {
+    if !warnings.is_empty() {
+        console_warn!("Skipped {} malformed message row(s) for trip {trip_id}: {}", warnings.len(), warnings.join("; "));
+    }
+}
+
+/// Asynchronously checks whether a live (not soft-deleted) trip exists for the given ID.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// * `Ok(true)` - If a row exists in `trips` with the given `id` and no `deleted_at` timestamp.
+/// * `Ok(false)` - If the trip does not exist or has been soft-deleted.
+/// * `Err` - If the database could not be reached or the query failed.
+pub async fn trip_exists(trip_id: String, tenant: &Tenant, env: Env) -> Result<bool> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("SELECT 1 as one FROM trips WHERE id = ? AND deleted_at IS NULL{} LIMIT 1", tenant.filter()))
+        .bind(&tenant.bind(vec![trip_id.into_js_result()?]))?;
+    let result = statement.first::<serde_json::Value>(None).await?;
+    Ok(result.is_some())
+}
+
+/// Asynchronously merges one trip into another.
+///
+/// # Description
+/// All messages belonging to `source_id` are reassigned to `target_id`, and when
+/// `include_plans` is set the source's plans are reassigned as well so they show up as
+/// additional versions of the target's plan. The source trip is then soft-deleted by
+/// stamping its `deleted_at` column with the current time in epoch milliseconds. All
+/// statements run in a single D1 batch (see [`batch_all`]) so the reassignment either
+/// happens completely or not at all.
+///
+/// # Arguments
+/// * `source_id` - The trip whose history is moved and which is soft-deleted afterwards.
+/// * `target_id` - The trip that receives the messages (and optionally plans).
+/// * `include_plans` - Whether the source's `plans` rows should also be moved.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object used to access the "TripPlanner" D1 database.
+///
+/// # Returns
+/// The number of messages that were moved to the target trip.
+///
+/// # Errors
+/// - If the "TripPlanner" database cannot be accessed or a statement fails to bind.
+/// - If any statement in the batch reports a failure.
+///
+/// # Notes
+/// - Callers are expected to have validated that both trips exist (see [`trip_exists`]).
+pub async fn merge_trips(source_id: String, target_id: String, include_plans: bool, tenant: &Tenant, env: Env) -> Result<usize> {
+    let db = env.d1("TripPlanner")?;
+    let timestamp = crate::time::now_millis() as f64;
+    let mut statements = vec![
+        db.prepare(format!("UPDATE messages SET trip_id = ? WHERE trip_id = ?{}", tenant.filter()))
+            .bind(&tenant.bind(vec![target_id.clone().into_js_result()?, source_id.clone().into_js_result()?]))?,
+    ];
+    if include_plans {
+        statements.push(db.prepare(format!("UPDATE plans SET trip_id = ? WHERE trip_id = ?{}", tenant.filter()))
+            .bind(&tenant.bind(vec![target_id.into_js_result()?, source_id.clone().into_js_result()?]))?);
+    }
+    statements.push(db.prepare(format!("UPDATE trips SET deleted_at = ? WHERE id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![timestamp.into_js_result()?, source_id.into_js_result()?]))?);
+
+    let summary = batch_all(&db, statements, "merge trips").await?;
+    Ok(summary.changes[0])
+}
+
+/// Asynchronously deletes a trip together with its plans and messages.
+///
+/// # Description
+/// Unlike the soft delete of [`merge_trips`], the rows are removed for good. The trip's
+/// messages, plans and `trips` row are deleted in a single D1 batch (see [`batch_all`]), so
+/// either all of them are gone or, if any delete fails, none is and the error is returned.
+///
+/// # Arguments
+/// * `trip_id` - The trip to delete.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object used to access the "TripPlanner" D1 database.
+///
+/// # Returns
+/// `true` when the trip's row was deleted, `false` when there was no such trip.
+///
+/// # Errors
+/// - If the "TripPlanner" database cannot be accessed or a statement fails to bind.
+/// - If any delete in the batch reports a failure.
+///
+/// # Notes
+/// - The trip's durable object is not touched; callers clear it separately.
+pub async fn delete_trip(trip_id: String, tenant: &Tenant, env: Env) -> Result<bool> {
+    let db = env.d1("TripPlanner")?;
+    let statements = ["messages WHERE trip_id", "plans WHERE trip_id", "trips WHERE id"]
+        .into_iter()
+        .map(|target| {
+            db.prepare(format!("DELETE FROM {target} = ?{}", tenant.filter()))
+                .bind(&tenant.bind(vec![trip_id.as_str().into_js_result()?]))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let summary = batch_all(&db, statements, "delete trip").await?;
+    Ok(summary.changes[2] > 0)
+}
+
+/// Asynchronously loads a live trip's row from the `trips` table.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// * `Ok(Some(TripData))` - The trip's id, destination and day count.
+/// * `Ok(None)` - If no live trip exists with that id (soft-deleted trips are excluded).
+/// * `Err` - If the database could not be reached or the row could not be deserialized.
+pub async fn find_trip(trip_id: String, tenant: &Tenant, env: Env) -> Result<Option<TripData>> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("SELECT id, destination, days, status, title, slug FROM trips WHERE id = ? AND deleted_at IS NULL{}", tenant.filter()))
+        .bind(&tenant.bind(vec![trip_id.into_js_result()?]))?;
+    statement.first::<TripData>(None).await
+}
+
+/// Asynchronously retrieves the most recent plan stored for a trip.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// * `Ok(Some((plan, updated_at, refused, schedule)))` - The newest plan text, when it was
+///   written (epoch milliseconds), whether it was flagged as an AI refusal, and its schedule as JSON text when one
+///   was stored (see [`set_latest_plan_schedule`]).
+/// * `Ok(None)` - If no plan has been stored for the trip.
+/// * `Err` - If the database query fails.
+///
+/// # Notes
+///
+/// - Rows are ordered by their autoincrement `id`, which always follows insertion order,
+///   rather than by `updated_at`: two plans can be written in the same millisecond, and in
+///   older databases the column mixes epoch milliseconds with legacy date strings, which
+///   SQLite sorts after every number.
+pub async fn get_latest_plan(trip_id: String, tenant: &Tenant, env: Env) -> Result<Option<(String, i64, bool, Option<String>)>> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("SELECT plan, updated_at, refused, schedule FROM plans WHERE trip_id = ?{} ORDER BY id DESC LIMIT 1", tenant.filter()))
+        .bind(&tenant.bind(vec![trip_id.into_js_result()?]))?;
+    let row = statement.first::<serde_json::Value>(None).await?;
+    Ok(row.and_then(|row| {
+        Some((
+            row.get("plan")?.as_str()?.to_string(),
+            crate::time::stored_millis(row.get("updated_at")?)?,
+            row.get("refused").and_then(|v| v.as_i64()).unwrap_or(0) != 0,
+            row.get("schedule").and_then(|v| v.as_str()).map(str::to_string),
+        ))
+    }))
+}
+
+/// Asynchronously attaches a schedule to the most recent plan stored for a trip.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `schedule` - The schedule as JSON text (see `plan::ScheduleEntry`).
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the `UPDATE`, or an error if the statement fails.
+pub async fn set_latest_plan_schedule(trip_id: String, schedule: &str, tenant: &Tenant, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("UPDATE plans SET schedule = ? WHERE id = (SELECT MAX(id) FROM plans WHERE trip_id = ?{})", tenant.filter()))
+        .bind(&tenant.bind(vec![schedule.into_js_result()?, trip_id.into_js_result()?]))?;
+    let result = check_batch(db.batch(vec![statement]).await?, "set plan schedule")?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Asynchronously stores the packing list generated for a trip, replacing any earlier one.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `packing_list` - The list as JSON text (see `packing::PackingCategory`).
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the `UPDATE`, or an error if the statement fails.
+pub async fn set_packing_list(trip_id: String, packing_list: &str, tenant: &Tenant, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("UPDATE trips SET packing_list = ? WHERE id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![packing_list.into_js_result()?, trip_id.into_js_result()?]))?;
+    let result = check_batch(db.batch(vec![statement]).await?, "set packing list")?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Asynchronously stores the interests a trip was planned for.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `interests` - The normalized interests (see `form_interests`), stored as a JSON array.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the `UPDATE`, or an error if the statement fails.
+pub async fn set_trip_interests(trip_id: String, interests: &[String], tenant: &Tenant, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("UPDATE trips SET interests = ? WHERE id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![serde_json::to_string(interests)?.into_js_result()?, trip_id.into_js_result()?]))?;
+    let result = check_batch(db.batch(vec![statement]).await?, "set trip interests")?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Asynchronously stores the units and currency a trip is planned in.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `units` - The units and the currency, if one was chosen.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the `UPDATE`, or an error if the statement fails.
+pub async fn set_trip_units(trip_id: String, units: &UnitPreferences, tenant: &Tenant, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("UPDATE trips SET units = ?, currency = ? WHERE id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![units.units.as_str().into_js_result()?, units.currency.clone().into_js_result()?, trip_id.into_js_result()?]))?;
+    let result = check_batch(db.batch(vec![statement]).await?, "set trip units")?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Asynchronously reads the units and currency a trip is planned in.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The stored preferences. Trips created before units were stored, and trips that do not
+/// exist, get the defaults (metric, no currency); an unrecognized stored value is treated
+/// as metric as well.
+pub async fn get_trip_units(trip_id: String, tenant: &Tenant, env: Env) -> Result<UnitPreferences> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("SELECT units, currency FROM trips WHERE id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![trip_id.into_js_result()?]))?;
+    let Some(row) = statement.first::<serde_json::Value>(None).await? else {
+        return Ok(UnitPreferences::default());
+    };
+    Ok(UnitPreferences {
+        units: row.get("units").and_then(|v| v.as_str()).and_then(|v| v.parse().ok()).unwrap_or_default(),
+        currency: row.get("currency").and_then(|v| v.as_str()).map(str::to_string),
+    })
+}
+
+/// Asynchronously reads the tags of several trips.
+///
+/// # Arguments
+///
+/// * `trip_ids` - The trips to look up; must not be empty.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// A `Vec` of `(trip_id, tags)` for the live trips among `trip_ids`, with their stored tags
+/// (see [`crate::tags`]). Trips that do not exist, were soft-deleted or belong to another
+/// tenant are left out.
+pub async fn get_trip_tags(trip_ids: &[String], tenant: &Tenant, env: Env) -> Result<Vec<(String, Vec<String>)>> {
+    let db = env.d1("TripPlanner")?;
+    let placeholders = vec!["?"; trip_ids.len()].join(", ");
+    let values = trip_ids.iter().map(|id| id.as_str().into_js_result()).collect::<std::result::Result<Vec<_>, _>>()?;
+    let statement = db.prepare(format!("SELECT id, tags FROM trips WHERE id IN ({placeholders}) AND deleted_at IS NULL{}", tenant.filter()))
+        .bind(&tenant.bind(values))?;
+    let tags = statement
+        .all()
+        .await?
+        .results::<serde_json::Value>()?
+        .into_iter()
         .filter_map(|row| {
             Some((
-                row.get("message")?.as_str()?.to_string(),
-                row.get("messager_role")?.as_str()?.to_string(),
-                row.get("created_at")?.as_str()?.to_string(),
+                row.get("id")?.as_str()?.to_string(),
+                crate::tags::parse_stored(row.get("tags").and_then(|t| t.as_str())),
             ))
         })
-        .collect::<Vec<_>>();
+        .collect();
+    Ok(tags)
+}
 
-    Ok(messages)
-}
\ No newline at end of file
+/// Asynchronously replaces the tags of several trips in a single batch.
+///
+/// # Arguments
+///
+/// * `tags` - `(trip_id, tags)` pairs with each trip's complete new tags, already normalized.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The [`BatchSummary`] of the batch, with one entry per pair in `tags`. An empty `tags`
+/// writes nothing.
+///
+/// # Notes
+///
+/// - The statements run as one transaction (see [`batch_all`]), so either every trip's tags
+///   change or none do.
+pub async fn set_trip_tags(tags: &[(String, Vec<String>)], tenant: &Tenant, env: Env) -> Result<BatchSummary> {
+    if tags.is_empty() {
+        return Ok(BatchSummary { changes: vec![] });
+    }
+    let db = env.d1("TripPlanner")?;
+    let statements = tags
+        .iter()
+        .map(|(trip_id, tags)| {
+            db.prepare(format!("UPDATE trips SET tags = ? WHERE id = ?{}", tenant.filter()))
+                .bind(&tenant.bind(vec![serde_json::to_string(tags)?.into_js_result()?, trip_id.as_str().into_js_result()?]))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    batch_all(&db, statements, "set trip tags").await
+}
+
+/// Asynchronously counts live trips per destination.
+///
+/// # Arguments
+///
+/// * `limit` - When `Some(n)`, only the `n` most popular destinations are returned.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// A `Vec` of `(destination, trip_count)` tuples ordered from most to least trips.
+/// Soft-deleted trips are not counted.
+///
+/// # Errors
+///
+/// Returns an error if the database cannot be reached or the query fails.
+pub async fn destination_counts(limit: Option<u32>, tenant: &Tenant, env: Env) -> Result<Vec<(String, u32)>> {
+    let db = env.d1("TripPlanner")?;
+    let mut values = tenant.bind(vec![]);
+    values.push(limit.map(f64::from).unwrap_or(-1.0).into_js_result()?);
+    let statement = db.prepare(format!("SELECT destination, COUNT(*) as trips FROM trips WHERE deleted_at IS NULL{} GROUP BY destination ORDER BY COUNT(*) DESC LIMIT ?", tenant.filter()))
+        .bind(&values)?;
+    let result = statement.all().await?;
+    let counts = result
+        .results::<serde_json::Value>()?
+        .into_iter()
+        .filter_map(|row| {
+            Some((
+                row.get("destination")?.as_str()?.to_string(),
+                row.get("trips")?.as_u64()? as u32,
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    Ok(counts)
+}
+
+/// Asynchronously retrieves the most recent message of a trip.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// * `Ok(Some((id, message, messager_role)))` - The newest message and its row id.
+/// * `Ok(None)` - If the trip has no messages.
+/// * `Err` - If the database query fails.
+pub async fn get_last_message(trip_id: String, tenant: &Tenant, env: Env) -> Result<Option<(i64, String, MessageRole)>> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("SELECT id, message, messager_role FROM messages WHERE trip_id = ?{} ORDER BY id DESC LIMIT 1", tenant.filter()))
+        .bind(&tenant.bind(vec![trip_id.into_js_result()?]))?;
+    let row = statement.first::<serde_json::Value>(None).await?;
+    row.and_then(|row| {
+        Some((
+            row.get("id")?.as_i64()?,
+            row.get("message")?.as_str()?.to_string(),
+            role_field(&row).ok()?,
+        ))
+    })
+    .map(|(id, message, role)| Ok((id, encryption::open(&env, &message)?, role)))
+    .transpose()
+}
+
+/// Asynchronously replaces a message of a trip with a new one, as when an AI reply is
+/// regenerated.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `message_id` - The id of the message to remove.
+/// * `message` - The content of the new message.
+/// * `messager_role` - The role of the new message's sender.
+/// * `refused` - Whether the new message is an AI refusal (see `ai::is_refusal`).
+/// * `config` - The deployment's settings, for encryption (`encrypt_messages`) of the new message.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Notes
+///
+/// - The insert and the delete run as one batch (see [`batch_all`]), so if either fails the
+///   old message is kept and the new one is not stored.
+#[allow(clippy::too_many_arguments)]
+pub async fn replace_message(trip_id: String, message_id: i64, message: &str, messager_role: MessageRole, refused: bool, config: &Config, tenant: &Tenant, env: Env) -> Result<()> {
+    let db = env.d1("TripPlanner")?;
+    let insert = message_insert(&db, trip_id.clone(), message, messager_role, None, refused, config, tenant, &env)?;
+    let delete = db.prepare(format!("DELETE FROM messages WHERE id = ? AND trip_id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![(message_id as f64).into_js_result()?, trip_id.into_js_result()?]))?;
+    batch_all(&db, vec![insert, delete], "replace message").await?;
+    Ok(())
+}
+
+/// Asynchronously retrieves a specific version of a trip's plan.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `version` - The 1-based version number; version 1 is the first plan stored for the
+///   trip and each later insert (e.g. a regeneration) is the next version.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// * `Ok(Some((plan, updated_at)))` - The plan text of that version and when it was written,
+///   in epoch milliseconds.
+/// * `Ok(None)` - If the trip has fewer than `version` plans, or `version` is `0`.
+/// * `Err` - If the database query fails.
+pub async fn get_plan_version(trip_id: String, version: u32, tenant: &Tenant, env: Env) -> Result<Option<(String, i64)>> {
+    if version == 0 {
+        return Ok(None);
+    }
+    let db = env.d1("TripPlanner")?;
+    let mut values = tenant.bind(vec![trip_id.into_js_result()?]);
+    values.push((version - 1).into_js_result()?);
+    let statement = db.prepare(format!("SELECT plan, updated_at FROM plans WHERE trip_id = ?{} ORDER BY id ASC LIMIT 1 OFFSET ?", tenant.filter()))
+        .bind(&values)?;
+    let row = statement.first::<serde_json::Value>(None).await?;
+    Ok(row.and_then(|row| {
+        Some((
+            row.get("plan")?.as_str()?.to_string(),
+            crate::time::stored_millis(row.get("updated_at")?)?,
+        ))
+    }))
+}
+
+/// Asynchronously updates the number of days of a trip.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `days` - The new length of the trip.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the `UPDATE`, or an error if the statement fails.
+pub async fn update_trip_days(trip_id: String, days: u32, tenant: &Tenant, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("UPDATE trips SET days = ? WHERE id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![days.into_js_result()?, trip_id.into_js_result()?]))?;
+    let result = check_batch(db.batch(vec![statement]).await?, "update trip days")?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Asynchronously updates the destination and number of days of a trip.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `destination` - The new destination of the trip.
+/// * `days` - The new length of the trip.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the `UPDATE`, or an error if the statement fails.
+pub async fn update_trip(trip_id: String, destination: &str, days: u32, tenant: &Tenant, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("UPDATE trips SET destination = ?, days = ? WHERE id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![destination.into_js_result()?, days.into_js_result()?, trip_id.into_js_result()?]))?;
+    let result = check_batch(db.batch(vec![statement]).await?, "update trip")?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Asynchronously deletes old plan versions of a trip, keeping only the newest ones.
+///
+/// Every regeneration or extension appends a row to `plans`, so without pruning the table
+/// grows without bound per trip.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `keep` - How many of the most recent versions to keep. At least one is always kept, so
+///   the version currently in use (the newest) is never removed.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The number of plan versions deleted.
+///
+/// # Notes
+///
+/// - Plan versions are numbered by position (see [`get_plan_version`]), so pruning renumbers
+///   the versions that remain.
+pub async fn prune_plans(trip_id: String, keep: u32, tenant: &Tenant, env: Env) -> Result<usize> {
+    let db = env.d1("TripPlanner")?;
+    let mut values = tenant.bind(vec![trip_id.clone().into_js_result()?]);
+    values.extend(tenant.bind(vec![trip_id.into_js_result()?]));
+    values.push(keep.max(1).into_js_result()?);
+    let statement = db.prepare(format!("DELETE FROM plans WHERE trip_id = ?{0} AND id NOT IN (SELECT id FROM plans WHERE trip_id = ?{0} ORDER BY id DESC LIMIT ?)", tenant.filter()))
+        .bind(&values)?;
+    let result = check_batch(db.batch(vec![statement]).await?, "prune plans")?;
+    let deleted = result
+        .first()
+        .and_then(|r| r.meta().ok().flatten())
+        .and_then(|meta| meta.changes)
+        .unwrap_or(0);
+    Ok(deleted)
+}
+
+/// Asynchronously checks that the "TripPlanner" database is bound and answers queries.
+///
+/// Runs a trivial `SELECT 1`, so it is cheap enough for frequent readiness probes.
+///
+/// # Returns
+///
+/// `Ok(())` when the query succeeds, otherwise the binding or query error.
+pub async fn ping(env: Env) -> Result<()> {
+    let db = env.d1("TripPlanner")?;
+    db.prepare("SELECT 1 AS one").first::<serde_json::Value>(None).await?;
+    Ok(())
+}
+
+/// Asynchronously looks for a live trip matching a destination and length.
+///
+/// Used to avoid creating the same trip twice. Destinations are compared ignoring case
+/// and surrounding whitespace, so `" paris "` matches `"Paris"`.
+///
+/// # Arguments
+///
+/// * `destination` - The destination of the trip about to be created.
+/// * `days` - The length of the trip about to be created.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// * `Ok(Some(trip))` - The oldest matching trip that has not been deleted.
+/// * `Ok(None)` - If no such trip exists.
+/// * `Err` - If the database query fails.
+///
+/// # Notes
+///
+/// - Trips have no owner, so every trip on the deployment is considered.
+pub async fn find_duplicate_trip(destination: String, days: u32, tenant: &Tenant, env: Env) -> Result<Option<TripData>> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("SELECT id, destination, days, status, title, slug FROM trips WHERE lower(trim(destination)) = lower(?) AND days = ? AND deleted_at IS NULL{} ORDER BY rowid LIMIT 1", tenant.filter()))
+        .bind(&tenant.bind(vec![destination.trim().into_js_result()?, days.into_js_result()?]))?;
+    statement.first::<TripData>(None).await
+}
+
+/// Asynchronously sets the status of a live trip.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `status` - The new status. Callers check the transition with
+///   `TripStatus::can_transition_to` first.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the `UPDATE`, or an error if the statement fails.
+pub async fn update_trip_status(trip_id: String, status: TripStatus, tenant: &Tenant, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("UPDATE trips SET status = ? WHERE id = ? AND deleted_at IS NULL{}", tenant.filter()))
+        .bind(&tenant.bind(vec![status.as_str().into_js_result()?, trip_id.into_js_result()?]))?;
+    let result = check_batch(db.batch(vec![statement]).await?, "update trip status")?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Filters shared by the trip listing and counting queries.
+///
+/// # Fields
+/// * `status` - When `Some`, only trips in this status match.
+/// * `q` - When `Some`, only trips whose destination contains this text (ignoring ASCII
+///   case) match. `%` and `_` are matched literally.
+#[derive(Default)]
+pub struct TripFilter {
+    pub status: Option<TripStatus>,
+    pub q: Option<String>,
+}
+
+impl TripFilter {
+    /// Builds the `WHERE` clause for this filter and the values to bind to it.
+    ///
+    /// Deleted trips, and trips of other tenants, are always excluded.
+    fn where_clause(&self, tenant: &Tenant) -> Result<(String, Vec<wasm_bindgen::JsValue>)> {
+        let mut conditions = vec!["deleted_at IS NULL"];
+        let mut values = tenant.bind(Vec::new());
+        if tenant.is_scoped() {
+            conditions.push("tenant_id = ?");
+        }
+        if let Some(status) = self.status {
+            conditions.push("status = ?");
+            values.push(status.as_str().into_js_result()?);
+        }
+        if let Some(q) = &self.q {
+            conditions.push("destination LIKE ? ESCAPE '\\'");
+            let escaped = q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            values.push(format!("%{escaped}%").into_js_result()?);
+        }
+        Ok((format!("WHERE {}", conditions.join(" AND ")), values))
+    }
+}
+
+/// Asynchronously lists live trips matching a filter.
+///
+/// # Arguments
+///
+/// * `filter` - Which trips to include (see [`TripFilter`]).
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The trips in creation order, or an error if the query fails.
+pub async fn list_trips(filter: &TripFilter, tenant: &Tenant, env: Env) -> Result<Vec<TripData>> {
+    let db = env.d1("TripPlanner")?;
+    let (where_clause, values) = filter.where_clause(tenant)?;
+    let statement = db.prepare(format!("SELECT id, destination, days, status, title, slug FROM trips {where_clause} ORDER BY rowid"))
+        .bind(&values)?;
+    statement.all().await?.results::<TripData>()
+}
+
+/// Asynchronously counts live trips matching a filter, without fetching them.
+///
+/// # Arguments
+///
+/// * `filter` - Which trips to count; the same filter [`list_trips`] accepts.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The number of matching trips, or an error if the query fails.
+pub async fn count_trips(filter: &TripFilter, tenant: &Tenant, env: Env) -> Result<u32> {
+    let db = env.d1("TripPlanner")?;
+    let (where_clause, values) = filter.where_clause(tenant)?;
+    let statement = db.prepare(format!("SELECT COUNT(*) AS count FROM trips {where_clause}"))
+        .bind(&values)?;
+    Ok(statement.first::<u32>(Some("count")).await?.unwrap_or(0))
+}
+
+/// Asynchronously deletes a trip's whole chat history.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The number of messages deleted.
+pub async fn delete_messages(trip_id: String, tenant: &Tenant, env: Env) -> Result<usize> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("DELETE FROM messages WHERE trip_id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![trip_id.into_js_result()?]))?;
+    let result = check_batch(db.batch(vec![statement]).await?, "delete messages")?;
+    let deleted = result
+        .first()
+        .and_then(|r| r.meta().ok().flatten())
+        .and_then(|meta| meta.changes)
+        .unwrap_or(0);
+    Ok(deleted)
+}
+
+/// Asynchronously inserts several messages for a trip in a single batch.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `messages` - `(message, messager_role)` pairs, inserted in order so they keep their
+///   relative order in the history.
+/// * `config` - The deployment's settings, for encryption (`encrypt_messages`) and the retries of the inserts.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The number of messages inserted. An empty `messages` inserts nothing.
+///
+/// # Notes
+///
+/// - Messages are encrypted like [`create_message`] when `ENCRYPT_MESSAGES` is enabled.
+/// - D1 runs a batch as one transaction, so either every message is stored or none is.
+pub async fn create_messages(trip_id: String, messages: &[(String, MessageRole)], config: &Config, tenant: &Tenant, env: Env) -> Result<usize> {
+    if messages.is_empty() {
+        return Ok(0);
+    }
+    let db = env.d1("TripPlanner")?;
+    let timestamp = crate::time::now_millis() as f64;
+    let sql = format!("INSERT INTO messages (trip_id, message, messager_role, created_at{}) VALUES (?,?,?,?{})", tenant.column(), tenant.placeholder());
+    let mut statements = Vec::with_capacity(messages.len());
+    for (message, role) in messages {
+        let message = encryption::seal(&env, config, message)?;
+        statements.push(db.prepare(&sql)
+            .bind(&tenant.bind(vec![trip_id.clone().into_js_result()?, message.into_js_result()?, role.as_str().into_js_result()?, timestamp.into_js_result()?]))?);
+    }
+    let result = batch_with_retry(&db, statements, "create messages", config).await?;
+    Ok(result.len())
+}
+
+/// A stored chat message together with its id and pin state, as listed to clients.
+///
+/// # Fields
+/// * `id` - The message's row id, used to pin or unpin it.
+/// * `message` - The decrypted message text.
+/// * `role` - The sender's role (e.g. `"User"` or `"AI"`).
+/// * `created_at` - When the message was stored, in epoch milliseconds.
+/// * `pinned` - Whether the message is pinned.
+#[derive(serde::Serialize)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub message: String,
+    pub role: MessageRole,
+    pub created_at: i64,
+    pub pinned: bool,
+}
+
+/// Asynchronously lists a trip's messages with their ids and pin state.
+///
+/// Unlike [`get_messages`], which produces the history sent to the AI, this is what the
+/// message list endpoints return.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `pinned_only` - When `true`, only pinned messages are returned.
+/// * `since` - When set, only messages with a larger id, i.e. written after that message, are
+///   returned.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The messages in insertion order together with a description of every row that was
+/// skipped because a column was missing or of the wrong type (empty when all rows were
+/// readable), or an error if the query or decryption fails. Skipped rows are also logged.
+pub async fn list_messages(trip_id: String, pinned_only: bool, since: Option<i64>, tenant: &Tenant, env: Env) -> Result<(Vec<StoredMessage>, Vec<String>)> {
+    let db = env.d1("TripPlanner")?;
+    let pinned = if pinned_only { " AND pinned = 1" } else { "" };
+    let after = if since.is_some() { " AND id > ?" } else { "" };
+    let sql = format!("SELECT id, message, messager_role, created_at, pinned FROM messages WHERE trip_id = ?{}{pinned}{after} ORDER BY id", tenant.filter());
+    let mut values = tenant.bind(vec![trip_id.clone().into_js_result()?]);
+    if let Some(since) = since {
+        values.push((since as f64).into_js_result()?);
+    }
+    let statement = db.prepare(sql).bind(&values)?;
+    let mut warnings = Vec::new();
+    let messages = statement
+        .all()
+        .await?
+        .results::<serde_json::Value>()?
+        .into_iter()
+        .filter_map(|row| {
+            let parsed = (|| -> std::result::Result<_, String> { Ok((
+                row.get("id").and_then(|v| v.as_i64()).ok_or_else(|| "id is missing".to_string())?,
+                text_field(&row, "message")?,
+                role_field(&row)?,
+                millis_field(&row, "created_at")?,
+                row.get("pinned").and_then(|v| v.as_i64()).unwrap_or(0) != 0,
+            )) })();
+            parsed.map_err(|reason| warnings.push(skipped_row(&row, reason))).ok()
+        })
+        .map(|(id, message, role, created_at, pinned)| {
+            Ok(StoredMessage { id, message: encryption::open(&env, &message)?, role, created_at, pinned })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    log_skipped_rows(&trip_id, &warnings);
+    Ok((messages, warnings))
+}
+
+/// Asynchronously loads one of a trip's messages with its id and pin state.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `message_id` - The id of the message.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The message, or `None` if the trip has no message with that id.
+pub async fn get_message(trip_id: String, message_id: i64, tenant: &Tenant, env: Env) -> Result<Option<StoredMessage>> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("SELECT id, message, messager_role, created_at, pinned FROM messages WHERE id = ? AND trip_id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![(message_id as f64).into_js_result()?, trip_id.into_js_result()?]))?;
+    let row = statement.first::<serde_json::Value>(None).await?;
+    row.and_then(|row| {
+        Some((
+            row.get("id")?.as_i64()?,
+            row.get("message")?.as_str()?.to_string(),
+            role_field(&row).ok()?,
+            crate::time::stored_millis(row.get("created_at")?)?,
+            row.get("pinned").and_then(|v| v.as_i64()).unwrap_or(0) != 0,
+        ))
+    })
+    .map(|(id, message, role, created_at, pinned)| {
+        Ok(StoredMessage { id, message: encryption::open(&env, &message)?, role, created_at, pinned })
+    })
+    .transpose()
+}
+
+/// Asynchronously pins or unpins one of a trip's messages.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `message_id` - The id of the message.
+/// * `pinned` - `true` to pin the message, `false` to unpin it.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// `Ok(true)` if the message exists for the trip (whether or not its state changed),
+/// `Ok(false)` if it does not.
+pub async fn set_message_pinned(trip_id: String, message_id: i64, pinned: bool, tenant: &Tenant, env: Env) -> Result<bool> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("UPDATE messages SET pinned = ? WHERE id = ? AND trip_id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![u32::from(pinned).into_js_result()?, (message_id as f64).into_js_result()?, trip_id.into_js_result()?]))?;
+    let result = check_batch(db.batch(vec![statement]).await?, "pin message")?;
+    let matched = result
+        .first()
+        .and_then(|r| r.meta().ok().flatten())
+        .and_then(|meta| meta.changes)
+        .unwrap_or(0);
+    Ok(matched > 0)
+}
+
+/// Asynchronously sets the title of a trip.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `title` - The new title.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the `UPDATE`, or an error if the statement fails.
+pub async fn update_trip_title(trip_id: String, title: &str, tenant: &Tenant, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("UPDATE trips SET title = ? WHERE id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![title.into_js_result()?, trip_id.into_js_result()?]))?;
+    let result = check_batch(db.batch(vec![statement]).await?, "update trip title")?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Asynchronously loads the cached conversation summary of a trip.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// * `Ok(Some(summary))` - The summary stored by [`update_trip_summary`].
+/// * `Ok(None)` - If the trip has no summary yet, or the trip does not exist.
+/// * `Err` - If the database query fails.
+pub async fn get_trip_summary(trip_id: String, tenant: &Tenant, env: Env) -> Result<Option<String>> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("SELECT summary FROM trips WHERE id = ? AND deleted_at IS NULL{}", tenant.filter()))
+        .bind(&tenant.bind(vec![trip_id.into_js_result()?]))?;
+    Ok(statement.first::<Option<String>>(Some("summary")).await?.flatten())
+}
+
+/// Asynchronously stores or clears the cached conversation summary of a trip.
+///
+/// # Arguments
+///
+/// * `trip_id` - A `String` representing the unique identifier of the trip.
+/// * `summary` - The new summary, or `None` to clear it so it is recomputed on next use.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the `UPDATE`, or an error if the statement fails.
+pub async fn update_trip_summary(trip_id: String, summary: Option<&str>, tenant: &Tenant, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let summary = summary.map(wasm_bindgen::JsValue::from).unwrap_or(wasm_bindgen::JsValue::NULL);
+    let statement = db.prepare(format!("UPDATE trips SET summary = ? WHERE id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![summary, trip_id.into_js_result()?]))?;
+    let result = check_batch(db.batch(vec![statement]).await?, "update trip summary")?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// A batch job as stored in the `jobs` table.
+///
+/// # Fields
+/// * `id` - The job's unique identifier.
+/// * `job_type` - What the job does, e.g. `regenerate`; serialized as `type`.
+/// * `status` - `running` while items are processed, `done` once every item has a result.
+/// * `total` - How many items the job processes.
+/// * `completed` - How many items have succeeded so far.
+/// * `failed` - How many items have failed so far.
+/// * `results` - One result object per item once the job is `done`; empty while running.
+/// * `created_at` - When the job was started.
+#[derive(serde::Serialize)]
+pub struct Job {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub job_type: String,
+    pub status: String,
+    pub total: u32,
+    pub completed: u32,
+    pub failed: u32,
+    pub results: Vec<serde_json::Value>,
+    pub created_at: String,
+}
+
+/// Asynchronously records a new, `running` batch job.
+///
+/// # Arguments
+///
+/// * `job_id` - The job's unique identifier.
+/// * `job_type` - What the job does, e.g. `regenerate`.
+/// * `total` - How many items the job will process.
+/// * `config` - The deployment's settings, for the retries of its insert (see [`batch_with_retry`]).
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the insert, or an error if the statement fails.
+pub async fn create_job(job_id: String, job_type: &str, total: u32, config: &Config, tenant: &Tenant, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let timestamp = Date::now().to_string();
+    let statement = db.prepare(format!("INSERT INTO jobs (id, type, status, total, created_at{}) VALUES (?, ?, 'running', ?, ?{})", tenant.column(), tenant.placeholder()))
+        .bind(&tenant.bind(vec![job_id.into_js_result()?, job_type.into_js_result()?, total.into_js_result()?, timestamp.into_js_result()?]))?;
+    let result = batch_with_retry(&db, vec![statement], "create job", config).await?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Asynchronously counts one processed item of a running batch job.
+///
+/// The counter is incremented in SQL, so items finishing at the same time are all counted.
+///
+/// # Arguments
+///
+/// * `job_id` - The job's unique identifier.
+/// * `succeeded` - Whether the item succeeded (counted in `completed`) or failed (in `failed`).
+/// * `config` - The deployment's settings, for the retries of its update (see [`batch_with_retry`]).
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the `UPDATE`, or an error if the statement fails.
+pub async fn record_job_item(job_id: String, succeeded: bool, config: &Config, tenant: &Tenant, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let column = if succeeded { "completed" } else { "failed" };
+    let statement = db.prepare(format!("UPDATE jobs SET {column} = {column} + 1 WHERE id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![job_id.into_js_result()?]))?;
+    let result = batch_with_retry(&db, vec![statement], "record job item", config).await?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Asynchronously marks a batch job `done` and stores its per-item results.
+///
+/// # Arguments
+///
+/// * `job_id` - The job's unique identifier.
+/// * `results` - One result object per item, stored as JSON text.
+/// * `config` - The deployment's settings, for the retries of its update (see [`batch_with_retry`]).
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the `UPDATE`, or an error if the statement fails.
+pub async fn finish_job(job_id: String, results: &[serde_json::Value], config: &Config, tenant: &Tenant, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let results = serde_json::to_string(results)?;
+    let statement = db.prepare(format!("UPDATE jobs SET status = 'done', results = ? WHERE id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![results.into_js_result()?, job_id.into_js_result()?]))?;
+    let result = batch_with_retry(&db, vec![statement], "finish job", config).await?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Asynchronously loads a batch job.
+///
+/// # Arguments
+///
+/// * `job_id` - The job's unique identifier.
+/// * `tenant` - The tenant of the request; only its rows are read or written (see [`Tenant`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The job, or `None` if there is no job with that id.
+pub async fn get_job(job_id: String, tenant: &Tenant, env: Env) -> Result<Option<Job>> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare(format!("SELECT id, type, status, total, completed, failed, results, created_at FROM jobs WHERE id = ?{}", tenant.filter()))
+        .bind(&tenant.bind(vec![job_id.into_js_result()?]))?;
+    let Some(row) = statement.first::<serde_json::Value>(None).await? else {
+        return Ok(None);
+    };
+    let text = |column: &str| row.get(column).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let count = |column: &str| row.get(column).and_then(|v| v.as_u64()).unwrap_or(0) as u32;
+    Ok(Some(Job {
+        id: text("id"),
+        job_type: text("type"),
+        status: text("status"),
+        total: count("total"),
+        completed: count("completed"),
+        failed: count("failed"),
+        results: serde_json::from_str(&text("results")).unwrap_or_default(),
+        created_at: text("created_at"),
+    }))
+}
+
+/// Asynchronously records the latency of one AI call, for `GET /admin/stats`.
+///
+/// Calls are measured for the whole deployment, so the row has no tenant.
+///
+/// # Arguments
+///
+/// * `operation` - What the call did, e.g. `plan` or `chat`.
+/// * `model` - The model that answered.
+/// * `latency_ms` - The call's wall-clock time in milliseconds.
+/// * `config` - The deployment's settings, for the retries of its insert (see [`batch_with_retry`]).
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// The `D1Result` of the insert, or an error if the statement fails.
+pub async fn record_ai_call(operation: &str, model: &str, latency_ms: u64, config: &Config, env: Env) -> Result<D1Result> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare("INSERT INTO ai_calls (operation, model, latency_ms, created_at) VALUES (?, ?, ?, ?)")
+        .bind(&[operation.into_js_result()?, model.into_js_result()?, (latency_ms as f64).into_js_result()?, (crate::time::now_millis() as f64).into_js_result()?])?;
+    let result = batch_with_retry(&db, vec![statement], "record AI call", config).await?;
+    Ok(result.into_iter().next().expect("check_batch guarantees a result"))
+}
+
+/// Asynchronously retrieves the latencies of the AI calls recorded since a point in time.
+///
+/// # Arguments
+///
+/// * `since` - Epoch milliseconds; only calls recorded at or after it are returned.
+/// * `env` - An `Env` object that provides access to the "TripPlanner" database.
+///
+/// # Returns
+///
+/// A `Vec` of `(operation, model, latency_ms)` tuples, grouped by operation and model and
+/// ordered from fastest to slowest within each group.
+pub async fn ai_call_latencies(since: i64, env: Env) -> Result<Vec<(String, String, u64)>> {
+    let db = env.d1("TripPlanner")?;
+    let statement = db.prepare("SELECT operation, model, latency_ms FROM ai_calls WHERE created_at >= ? ORDER BY operation, model, latency_ms")
+        .bind(&[(since as f64).into_js_result()?])?;
+    let result = statement.all().await?;
+    let latencies = result
+        .results::<serde_json::Value>()?
+        .into_iter()
+        .filter_map(|row| {
+            Some((
+                row.get("operation")?.as_str()?.to_string(),
+                row.get("model")?.as_str()?.to_string(),
+                row.get("latency_ms")?.as_f64()? as u64,
+            ))
+        })
+        .collect();
+    Ok(latencies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    fn outcome(success: bool, error: Option<&str>, changes: usize) -> StatementOutcome {
+        StatementOutcome { success, error: error.map(str::to_string), changes }
+    }
+
+    fn error(message: &str) -> Error {
+        Error::RustError(format!("Failed to create trip: statement 1 of 1 failed with error {message}"))
+    }
+
+    /// Runs [`with_write_retry`] over `outcomes`, one per attempt, returning the result and
+    /// how many attempts were made.
+    fn run(outcomes: Vec<Result<&'static str>>, write_retries: u32, busy_retries: u32) -> (Result<&'static str>, usize) {
+        let policy = WriteRetryPolicy { write_retries, busy_retries, random: || 0.5 };
+        let mut outcomes = outcomes.into_iter();
+        let mut attempts = 0;
+        let result = with_write_retry(
+            &policy,
+            || {
+                attempts += 1;
+                std::future::ready(outcomes.next().expect("more attempts than outcomes"))
+            },
+            |_, _| std::future::ready(()),
+        )
+        .now_or_never()
+        .expect("no real waits");
+        (result, attempts)
+    }
+
+    #[test]
+    fn classifies_busy_errors() {
+        for message in ["D1_ERROR: database is locked", "SQLITE_BUSY: database is busy", "database table is locked: trips", "SQLITE_LOCKED"] {
+            assert_eq!(classify(&error(message)), WriteFailure::Busy, "{message}");
+        }
+    }
+
+    #[test]
+    fn classifies_permanent_errors() {
+        for message in ["UNIQUE constraint failed: trips.slug", "near \"SELEC\": syntax error", "no such table: trips", "no such column: units"] {
+            assert_eq!(classify(&error(message)), WriteFailure::Permanent, "{message}");
+        }
+        assert_eq!(classify(&error("database is locked; FOREIGN KEY constraint failed")), WriteFailure::Permanent);
+    }
+
+    #[test]
+    fn classifies_other_errors_as_transient() {
+        for message in ["Network connection lost.", "D1 is overloaded"] {
+            assert_eq!(classify(&error(message)), WriteFailure::Transient, "{message}");
+        }
+    }
+
+    #[test]
+    fn retries_busy_then_succeeds() {
+        let outcomes = vec![Err(error("database is locked")), Err(error("SQLITE_BUSY")), Err(error("database is locked")), Ok("saved")];
+        let (result, attempts) = run(outcomes, 0, 3);
+        assert_eq!(result.unwrap(), "saved");
+        assert_eq!(attempts, 4);
+    }
+
+    #[test]
+    fn gives_up_after_busy_retries() {
+        let outcomes = vec![Err(error("database is locked")), Err(error("database is locked")), Err(error("database is locked"))];
+        let (result, attempts) = run(outcomes, 5, 2);
+        assert!(result.unwrap_err().to_string().contains("database is locked"));
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn counts_busy_and_transient_retries_separately() {
+        let outcomes = vec![Err(error("Network connection lost.")), Err(error("database is locked")), Err(error("database is locked")), Ok("saved")];
+        let (result, attempts) = run(outcomes, 1, 2);
+        assert_eq!(result.unwrap(), "saved");
+        assert_eq!(attempts, 4);
+    }
+
+    #[test]
+    fn does_not_retry_permanent_errors() {
+        let (result, attempts) = run(vec![Err(error("UNIQUE constraint failed: trips.slug"))], 5, 5);
+        assert!(result.unwrap_err().to_string().contains("constraint"));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn summarizes_changes_per_statement() {
+        let outcomes = [outcome(true, None, 3), outcome(true, None, 0), outcome(true, None, 1)];
+        assert_eq!(summarize(&outcomes, "merge trips").unwrap().changes, vec![3, 0, 1]);
+    }
+
+    #[test]
+    fn names_the_failing_statement() {
+        let outcomes = [outcome(true, None, 2), outcome(false, Some("FOREIGN KEY constraint failed"), 0), outcome(true, None, 1)];
+        let message = summarize(&outcomes, "delete trip").unwrap_err().to_string();
+        assert!(message.contains("Failed to delete trip: statement 2 of 3 failed"), "{message}");
+        assert!(message.contains("FOREIGN KEY constraint failed"), "{message}");
+    }
+
+    #[test]
+    fn rejects_an_empty_batch() {
+        assert!(summarize(&[], "set trip tags").unwrap_err().to_string().contains("batch returned no results"));
+    }
+}
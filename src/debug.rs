@@ -0,0 +1,99 @@
+//! Opt-in logging of request and AI response bodies for debugging.
+//!
+//! Bodies can contain personal details, so logging them needs two settings:
+//!
+//! - `DEBUG_BODIES` must be enabled, and
+//! - `DEBUG_BODIES_UNTIL` must hold a time in the future (epoch milliseconds).
+//!
+//! The expiry means body logging switches itself off even if the flag is forgotten, so it
+//! cannot silently stay on in production. When the flag is on but the expiry is missing or
+//! past, a warning is logged instead of the body.
+//!
+//! Logged bodies are capped at [`MAX_LOGGED_CHARS`] characters and passed through
+//! [`redact`]. Headers are never logged, so `Authorization` and API tokens cannot leak.
+use worker::{console_log, console_warn, Date};
+
+use crate::config::Config;
+
+/// The longest body, in characters, that is logged; the rest is replaced by a marker.
+const MAX_LOGGED_CHARS: usize = 2048;
+
+/// Field names whose values are masked wherever they appear in a body.
+const SENSITIVE_KEYS: [&str; 7] = ["authorization", "password", "secret", "token", "api_key", "apikey", "cookie"];
+
+/// Returns `true` when body logging is switched on and has not expired.
+fn enabled(config: &Config) -> bool {
+    if !config.debug_bodies {
+        return false;
+    }
+    match config.debug_bodies_until {
+        Some(until) if Date::now().as_millis() < until => true,
+        _ => {
+            console_warn!("DEBUG_BODIES is set but DEBUG_BODIES_UNTIL is missing or in the past; bodies are not logged");
+            false
+        }
+    }
+}
+
+/// Masks credentials in a body before it is logged.
+///
+/// The value following any [`SENSITIVE_KEYS`] name (as `key=value`, `key: value` or
+/// `"key": "value"`, case-insensitively) is replaced by `[REDACTED]`, as is the token after
+/// `Bearer `.
+pub fn redact(body: &str) -> String {
+    let lower = body.to_ascii_lowercase();
+    let mut masked: Vec<(usize, usize)> = Vec::new();
+    for key in SENSITIVE_KEYS.iter().copied().chain(["bearer"]) {
+        let mut from = 0;
+        while let Some(pos) = lower[from..].find(key) {
+            let after_key = from + pos + key.len();
+            from = after_key;
+            // Skip the separator (closing quote, `=`, `:` or a space) and any opening quote.
+            let rest = &body[after_key..];
+            let separator = rest.len() - rest.trim_start_matches(['"', '\'', ' ', '=', ':']).len();
+            if separator == 0 {
+                continue;
+            }
+            let start = after_key + separator;
+            let len = body[start..]
+                .find(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '&' | ',' | '}' | ';'))
+                .unwrap_or(body.len() - start);
+            if len > 0 {
+                masked.push((start, start + len));
+            }
+        }
+    }
+    masked.sort_unstable();
+    let mut out = String::with_capacity(body.len());
+    let mut last = 0;
+    for (start, end) in masked {
+        if start < last {
+            continue;
+        }
+        out.push_str(&body[last..start]);
+        out.push_str("[REDACTED]");
+        last = end;
+    }
+    out.push_str(&body[last..]);
+    out
+}
+
+/// Logs a body under `label` when body logging is enabled; otherwise does nothing.
+///
+/// # Arguments
+/// * `config` - The deployment's settings, holding `DEBUG_BODIES` and `DEBUG_BODIES_UNTIL`.
+/// * `label` - What the body is, e.g. `"chat request"` or `"ai plan response"`.
+/// * `body` - The body text; it is redacted and truncated before logging.
+pub fn log_body(config: &Config, label: &str, body: &str) {
+    if !enabled(config) {
+        return;
+    }
+    let redacted = redact(body);
+    let total = redacted.chars().count();
+    if total > MAX_LOGGED_CHARS {
+        let head: String = redacted.chars().take(MAX_LOGGED_CHARS).collect();
+        console_log!("[debug body] {label} ({total} chars, truncated): {head}…");
+    } else {
+        console_log!("[debug body] {label}: {redacted}");
+    }
+}
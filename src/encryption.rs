@@ -0,0 +1,96 @@
+//! Optional encryption of chat messages at rest.
+//!
+//! When `ENCRYPT_MESSAGES` is enabled, message text is encrypted with AES-256-GCM before
+//! it is written to the `messages` table. The key is read from the `MESSAGE_KEY` secret,
+//! which must be 32 bytes encoded as base64 (e.g. `openssl rand -base64 32`).
+//!
+//! Encrypted values are stored as `enc:v1:` followed by the base64 encoding of the
+//! 12 byte nonce and the ciphertext. Reads recognise that prefix and decrypt; any other
+//! value is returned as-is, so rows written before encryption was enabled keep working.
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use uuid::Uuid;
+use worker::*;
+
+use crate::config::Config;
+
+/// Marks a stored message as encrypted and identifies the storage format version.
+const PREFIX: &str = "enc:v1:";
+
+/// Loads the AES-256-GCM cipher from the `MESSAGE_KEY` secret.
+fn cipher(env: &Env) -> Result<Aes256Gcm> {
+    let encoded = env.secret("MESSAGE_KEY")?.to_string();
+    let key = STANDARD
+        .decode(encoded.trim())
+        .map_err(|e| Error::RustError(format!("MESSAGE_KEY is not valid base64: {e}")))?;
+    Aes256Gcm::new_from_slice(&key)
+        .map_err(|_| Error::RustError("MESSAGE_KEY must decode to 32 bytes".into()))
+}
+
+/// Generates a random 96-bit nonce.
+///
+/// The first six bytes of a v4 UUID are fully random (the version and variant bits come
+/// later), so two UUIDs give twelve random bytes without pulling in another RNG.
+fn nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..6].copy_from_slice(&Uuid::new_v4().as_bytes()[..6]);
+    nonce[6..].copy_from_slice(&Uuid::new_v4().as_bytes()[..6]);
+    nonce
+}
+
+/// Prepares a message for storage, encrypting it when `ENCRYPT_MESSAGES` is enabled.
+///
+/// # Arguments
+/// * `env` - The environment providing the `MESSAGE_KEY` secret.
+/// * `config` - The deployment's settings; the message is encrypted when `encrypt_messages` is on.
+/// * `message` - The plaintext message.
+///
+/// # Returns
+/// The value to write to the `message` column.
+///
+/// # Errors
+/// - If encryption is enabled but `MESSAGE_KEY` is missing or malformed.
+/// - If encryption fails.
+pub fn seal(env: &Env, config: &Config, message: &str) -> Result<String> {
+    if !config.encrypt_messages {
+        return Ok(message.to_string());
+    }
+    let nonce = nonce();
+    let ciphertext = cipher(env)?
+        .encrypt(Nonce::from_slice(&nonce), message.as_bytes())
+        .map_err(|_| Error::RustError("Failed to encrypt message".into()))?;
+    let mut payload = nonce.to_vec();
+    payload.extend_from_slice(&ciphertext);
+    Ok(format!("{PREFIX}{}", STANDARD.encode(payload)))
+}
+
+/// Recovers a stored message, decrypting it if it carries the encrypted prefix.
+///
+/// # Arguments
+/// * `env` - The environment providing the `MESSAGE_KEY` secret.
+/// * `stored` - The value read from the `message` column.
+///
+/// # Returns
+/// The plaintext message. Values without the prefix are returned unchanged.
+///
+/// # Errors
+/// - If the value is encrypted but `MESSAGE_KEY` is missing or malformed.
+/// - If the payload is corrupt or was encrypted with a different key.
+pub fn open(env: &Env, stored: &str) -> Result<String> {
+    let Some(encoded) = stored.strip_prefix(PREFIX) else {
+        return Ok(stored.to_string());
+    };
+    let payload = STANDARD
+        .decode(encoded)
+        .map_err(|e| Error::RustError(format!("Encrypted message is not valid base64: {e}")))?;
+    if payload.len() < 12 {
+        return Err(Error::RustError("Encrypted message is truncated".into()));
+    }
+    let (nonce, ciphertext) = payload.split_at(12);
+    let plaintext = cipher(env)?
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::RustError("Failed to decrypt message".into()))?;
+    String::from_utf8(plaintext).map_err(|_| Error::RustError("Decrypted message is not UTF-8".into()))
+}
@@ -0,0 +1,78 @@
+//! AI feasibility checks of a trip's plan.
+//!
+//! `POST /trip/{trip_id}/plan/feasibility` sends the structured plan (see
+//! [`plan::parse_days`](crate::plan::parse_days)) to the AI and asks whether each day is
+//! realistic. The answer is read into a [`Report`] of per-day [`DayWarning`]s. As with
+//! packing lists, models do not always answer with the JSON they were asked for, so
+//! [`parse`] reads it defensively and drops warnings it cannot place.
+use serde::{Deserialize, Serialize};
+
+/// The kinds of warning the model is asked to give, as used in [`DayWarning::kind`].
+pub const WARNING_KINDS: [&str; 3] = ["too_packed", "too_sparse", "scattered"];
+
+/// One problem with one day of the plan.
+///
+/// # Fields
+/// * `day` - The day the warning is about (1-based).
+/// * `kind` - One of [`WARNING_KINDS`]: `too_packed` (more than fits in a day),
+///   `too_sparse` (little planned) or `scattered` (places far apart).
+/// * `message` - A short explanation, e.g. `"Eight sights and two museums is too much for one day"`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DayWarning {
+    pub day: u32,
+    pub kind: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+/// The outcome of a feasibility check.
+///
+/// # Fields
+/// * `feasible` - `true` when no day has a warning.
+/// * `warnings` - The warnings, ordered by day.
+/// * `summary` - The model's overall verdict in a sentence or two, if it gave one.
+#[derive(Serialize)]
+pub struct Report {
+    pub feasible: bool,
+    pub warnings: Vec<DayWarning>,
+    pub summary: Option<String>,
+}
+
+/// The JSON object the model is asked to answer with.
+#[derive(Deserialize)]
+struct Answer {
+    #[serde(default)]
+    warnings: Vec<DayWarning>,
+    #[serde(default)]
+    summary: Option<String>,
+}
+
+/// Parses the model's answer into a [`Report`] for a plan of `days` days.
+///
+/// Text around the outermost `{` ... `}` is ignored. Warnings for days outside `1..=days`,
+/// of a kind not in [`WARNING_KINDS`], or repeating an earlier day and kind are dropped.
+///
+/// # Returns
+/// `None` when the answer holds no JSON object of the expected shape.
+pub fn parse(text: &str, days: u32) -> Option<Report> {
+    let start = text.find('{')?;
+    let end = text.rfind('}')?;
+    if end < start {
+        return None;
+    }
+    let answer: Answer = serde_json::from_str(&text[start..=end]).ok()?;
+    let mut warnings: Vec<DayWarning> = Vec::new();
+    for warning in answer.warnings {
+        let kind = warning.kind.trim().to_ascii_lowercase();
+        if !(1..=days).contains(&warning.day) || !WARNING_KINDS.contains(&kind.as_str()) {
+            continue;
+        }
+        if warnings.iter().any(|w| w.day == warning.day && w.kind == kind) {
+            continue;
+        }
+        warnings.push(DayWarning { day: warning.day, kind, message: warning.message.trim().to_string() });
+    }
+    warnings.sort_by_key(|w| w.day);
+    let summary = answer.summary.map(|s| s.trim().to_string()).filter(|s| !s.is_empty());
+    Some(Report { feasible: warnings.is_empty(), warnings, summary })
+}
@@ -0,0 +1,99 @@
+//! Per-feature switches for turning whole groups of routes off without a redeploy.
+//!
+//! `FEATURE_FLAGS` holds a JSON object of `enable_<feature>` flags, e.g.
+//! `{"enable_compare": false, "enable_regenerate": false}`. Every feature is enabled unless
+//! its flag is `false`. The router checks [`Feature::of`] before dispatching, and requests
+//! to a disabled feature's routes get `503 Service Unavailable`, so a misbehaving endpoint
+//! can be switched off by changing a variable.
+use worker::Method;
+
+/// A group of routes that can be switched off with `FEATURE_FLAGS`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Feature {
+    /// Chat posts, `chat/retry` and the chat summary.
+    Chat,
+    /// Plan and title regeneration, including batch jobs.
+    Regenerate,
+    /// Downloads of a trip in another format: `map.geojson`, `plan.json` and `calendar.ics`.
+    Export,
+    /// `messages/import`.
+    Import,
+    /// `POST /compare`.
+    Compare,
+    /// `plan/extend`.
+    Extend,
+    /// `remix`.
+    Remix,
+    /// `POST /trips/merge`.
+    Merge,
+}
+
+impl Feature {
+    /// Every feature, in the order they are documented.
+    pub const ALL: [Feature; 8] = [
+        Feature::Chat, Feature::Regenerate, Feature::Export, Feature::Import,
+        Feature::Compare, Feature::Extend, Feature::Remix, Feature::Merge,
+    ];
+
+    /// The name of the feature's flag in `FEATURE_FLAGS`, e.g. `"enable_chat"`.
+    pub fn flag(self) -> &'static str {
+        match self {
+            Feature::Chat => "enable_chat",
+            Feature::Regenerate => "enable_regenerate",
+            Feature::Export => "enable_export",
+            Feature::Import => "enable_import",
+            Feature::Compare => "enable_compare",
+            Feature::Extend => "enable_extend",
+            Feature::Remix => "enable_remix",
+            Feature::Merge => "enable_merge",
+        }
+    }
+
+    /// The feature a request belongs to, or `None` for routes that are always on.
+    pub fn of(method: &Method, path: &str) -> Option<Feature> {
+        if let Some(rest) = path.strip_prefix("/trip/") {
+            let Some((_, action)) = rest.split_once('/') else {
+                return (*method == Method::Post).then_some(Feature::Chat);
+            };
+            return match (method, action) {
+                (Method::Post, "chat/retry") | (Method::Get, "summary") => Some(Feature::Chat),
+                (Method::Post, "regenerate" | "title/regenerate") => Some(Feature::Regenerate),
+                (Method::Get, "map.geojson" | "plan.json" | "calendar.ics") => Some(Feature::Export),
+                (Method::Post, "messages/import") => Some(Feature::Import),
+                (Method::Post, "plan/extend") => Some(Feature::Extend),
+                (Method::Post, "remix") => Some(Feature::Remix),
+                _ => None,
+            };
+        }
+        match (method, path) {
+            (_, "/trips/regenerate") => Some(Feature::Regenerate),
+            (_, path) if path.starts_with("/trips/regenerate/") || path.starts_with("/jobs/") => Some(Feature::Regenerate),
+            (Method::Post, "/compare") => Some(Feature::Compare),
+            (Method::Post, "/trips/merge") => Some(Feature::Merge),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a `FEATURE_FLAGS` value into the features it disables.
+///
+/// # Errors
+/// A description of the problem when the value is not a JSON object, names an unknown
+/// flag, or gives a flag a value other than `true` or `false`.
+pub fn parse(value: &str) -> Result<Vec<Feature>, String> {
+    let flags: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(value).map_err(|e| format!("must be a JSON object of flags: {e}"))?;
+    let mut disabled = Vec::new();
+    for (name, enabled) in flags {
+        let Some(feature) = Feature::ALL.into_iter().find(|f| f.flag() == name) else {
+            let known: Vec<&str> = Feature::ALL.iter().map(|f| f.flag()).collect();
+            return Err(format!("unknown flag {name:?}, expected one of {}", known.join(", ")));
+        };
+        match enabled.as_bool() {
+            Some(true) => {}
+            Some(false) => disabled.push(feature),
+            None => return Err(format!("{name} must be true or false, got {enabled}")),
+        }
+    }
+    Ok(disabled)
+}
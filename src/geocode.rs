@@ -0,0 +1,103 @@
+//! Looking up coordinates for the places mentioned in a plan.
+//!
+//! Geocoding is off unless `GEOCODING_ENABLED` is set. Lookups go to a Nominatim-compatible
+//! search endpoint (`GEOCODING_URL`, defaulting to the public OpenStreetMap instance) which
+//! answers `[{ "lat": "48.86", "lon": "2.33", ... }]` for a `q` query parameter.
+//!
+//! Results are cached in the optional `GEOCODE_CACHE` KV namespace, misses included, so
+//! a trip's map can be rendered again without repeating the lookups. Found places are
+//! kept for [`HIT_TTL_SECS`] and misses for [`MISS_TTL_SECS`].
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+/// The search endpoint used when `GEOCODING_URL` is not set.
+const DEFAULT_GEOCODING_URL: &str = "https://nominatim.openstreetmap.org/search?format=jsonv2&limit=1";
+
+/// How long a found place stays cached, in seconds (30 days).
+const HIT_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// How long a place that could not be found stays cached, in seconds (1 day).
+const MISS_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// A position in WGS 84 degrees.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Point {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// One result of the search endpoint; coordinates are sent as strings.
+#[derive(Deserialize)]
+struct SearchResult {
+    lat: String,
+    lon: String,
+}
+
+/// Normalizes a query into its `GEOCODE_CACHE` key, ignoring case and whitespace runs.
+fn cache_key(query: &str) -> String {
+    format!("geo:{}", query.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase())
+}
+
+/// Looks up the coordinates of a place.
+///
+/// # Arguments
+/// * `env` - The environment providing `GEOCODING_URL` and the `GEOCODE_CACHE` binding.
+/// * `query` - The place to look up, e.g. `"Louvre Museum, Paris"`.
+///
+/// # Returns
+/// The first match, or `None` when the place is unknown or the lookup failed. Failed
+/// lookups are logged and not cached, so they are retried next time.
+pub async fn geocode(env: &Env, query: &str) -> Option<Point> {
+    let key = cache_key(query);
+    let kv = env.kv("GEOCODE_CACHE").ok();
+    if let Some(kv) = &kv {
+        match kv.get(&key).json::<Option<Point>>().await {
+            Ok(Some(cached)) => return cached,
+            Ok(None) => {}
+            Err(e) => console_warn!("Failed to read geocode cache: {e:?}"),
+        }
+    }
+    let point = match search(env, query).await {
+        Ok(point) => point,
+        Err(e) => {
+            console_warn!("Geocoding failed: {e}");
+            return None;
+        }
+    };
+    if let Some(kv) = &kv {
+        let ttl = if point.is_some() { HIT_TTL_SECS } else { MISS_TTL_SECS };
+        let stored = match kv.put(&key, serde_json::to_string(&point).unwrap_or_default()) {
+            Ok(put) => put.expiration_ttl(ttl).execute().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = stored {
+            console_warn!("Failed to write geocode cache: {e:?}");
+        }
+    }
+    point
+}
+
+/// Queries the search endpoint for `query`.
+///
+/// # Errors
+/// If the request cannot be sent, the endpoint answers with a non-`200` status, or the
+/// body is not a list of results.
+async fn search(env: &Env, query: &str) -> Result<Option<Point>> {
+    let base = env.var("GEOCODING_URL").map(|v| v.to_string()).unwrap_or(DEFAULT_GEOCODING_URL.to_string());
+    let mut url = Url::parse(&base)?;
+    url.query_pairs_mut().append_pair("q", query);
+
+    let mut req = Request::new(url.as_str(), Method::Get)?;
+    // Nominatim's usage policy requires an identifying User-Agent.
+    req.headers_mut()?.set("User-Agent", "cf_ai_trip_planner")?;
+    req.headers_mut()?.set("Accept", "application/json")?;
+
+    let mut resp = Fetch::Request(req).send().await?;
+    if resp.status_code() != 200 {
+        return Err(format!("geocoding request failed with status {}", resp.status_code()).into());
+    }
+    let results: Vec<SearchResult> = resp.json().await?;
+    Ok(results.into_iter().next().and_then(|r| {
+        Some(Point { lat: r.lat.parse().ok()?, lon: r.lon.parse().ok()? })
+    }))
+}
@@ -0,0 +1,109 @@
+//! iCalendar (RFC 5545) export of a trip's plan.
+//!
+//! `GET /trip/{trip_id}/calendar.ics` turns the plan, split with
+//! [`plan::parse_days`](crate::plan::parse_days), into one all-day event per day, so the
+//! trip can be imported into Google Calendar and similar apps. Text values are escaped and
+//! long lines folded as the format requires, and lines end in CRLF.
+use crate::plan::PlanDay;
+use crate::time::CalendarDate;
+
+/// The `PRODID` of every exported calendar.
+const PRODUCT_ID: &str = "-//cf_ai_trip_planner//Trip plan//EN";
+
+/// The longest event summary, in characters, taken from a day's first line.
+const SUMMARY_MAX_CHARS: usize = 80;
+
+/// The most octets on one content line before it is folded.
+const LINE_MAX_OCTETS: usize = 75;
+
+/// Builds a calendar with an all-day event per plan day.
+///
+/// # Arguments
+/// * `trip_id` - The trip's id, used to make each event's `UID` stable across exports, so
+///   importing again updates the events instead of duplicating them.
+/// * `destination` - The trip's destination, named in every summary.
+/// * `days` - The plan's days; day `n` is placed on `start` plus `n - 1` days.
+/// * `start` - The date of Day 1.
+/// * `stamp` - The export time as an RFC 3339 UTC timestamp (see `time::to_rfc3339`).
+///
+/// # Returns
+/// The `text/calendar` body. Each event's summary is `"{destination} day {n}: {first line}"`
+/// and its description the day's full text.
+pub fn calendar(trip_id: &str, destination: &str, days: &[PlanDay], start: CalendarDate, stamp: &str) -> String {
+    let stamp: String = stamp.split('.').next().unwrap_or_default().chars().filter(|c| !matches!(c, '-' | ':')).collect();
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        format!("PRODID:{PRODUCT_ID}"),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+        format!("X-WR-CALNAME:{}", escape(destination)),
+    ];
+    for day in days {
+        let date = start.add_days(day.day.saturating_sub(1));
+        let first_line: String = day
+            .text
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .unwrap_or_default()
+            .chars()
+            .take(SUMMARY_MAX_CHARS)
+            .collect();
+        let summary = match first_line.is_empty() {
+            true => format!("{destination} day {}", day.day),
+            false => format!("{destination} day {}: {first_line}", day.day),
+        };
+        lines.extend([
+            "BEGIN:VEVENT".to_string(),
+            format!("UID:{trip_id}-day-{}@cf-ai-trip-planner", day.day),
+            format!("DTSTAMP:{stamp}Z"),
+            format!("DTSTART;VALUE=DATE:{}", compact(date)),
+            format!("DTEND;VALUE=DATE:{}", compact(date.add_days(1))),
+            format!("SUMMARY:{}", escape(&summary)),
+            format!("DESCRIPTION:{}", escape(day.text.trim())),
+            "END:VEVENT".to_string(),
+        ]);
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|line| fold(line)).collect::<Vec<_>>().join("")
+}
+
+/// Formats a date as `YYYYMMDD`.
+fn compact(date: CalendarDate) -> String {
+    date.to_string().replace('-', "")
+}
+
+/// Escapes a text value: backslashes, semicolons and commas are backslash-escaped and line
+/// breaks become `\n`.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Ends a content line with CRLF, folding it into lines of at most [`LINE_MAX_OCTETS`]
+/// octets. Continuation lines start with a space, and characters are never split.
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 8);
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > LINE_MAX_OCTETS {
+            folded.push_str("\r\n ");
+            octets = 1;
+        }
+        folded.push(c);
+        octets += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
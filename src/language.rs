@@ -0,0 +1,103 @@
+//! Lightweight detection of the language a chat message is written in.
+//!
+//! With `AUTO_DETECT_LANG` on, every chat message is run through [`detect`] and the model
+//! is told to reply in the detected language for that turn. Detection costs no AI call:
+//! messages in a non-Latin script are recognised by their script alone, and messages in
+//! Latin script by counting common words of each supported language. Short or mixed
+//! messages are reported as uncertain (`None`), and the chat then carries on without a
+//! language instruction, as it does with detection off.
+
+/// A detected language.
+///
+/// # Fields
+/// * `code` - The ISO 639-1 code, e.g. `"es"`, stored on the message for debugging.
+/// * `name` - The English name used in the prompt, e.g. `"Spanish"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Language {
+    pub code: &'static str,
+    pub name: &'static str,
+}
+
+/// The fewest common words a Latin-script message must contain for a language to be picked.
+const MIN_WORD_MATCHES: usize = 2;
+
+/// Common short words of each supported Latin-script language, which rarely appear in the
+/// others' text.
+const WORDS: [(Language, &[&str]); 7] = [
+    (Language { code: "en", name: "English" }, &[
+        "the", "and", "is", "are", "what", "where", "how", "can", "you", "of", "for", "with",
+        "it", "we", "my", "this", "that", "there", "should", "which",
+    ]),
+    (Language { code: "es", name: "Spanish" }, &[
+        "el", "los", "las", "que", "y", "es", "una", "por", "para", "con", "qué", "dónde",
+        "cómo", "hay", "puedo", "del", "mi", "también", "cuál", "está",
+    ]),
+    (Language { code: "fr", name: "French" }, &[
+        "le", "les", "et", "est", "une", "des", "qui", "pour", "dans", "avec", "où", "je",
+        "nous", "vous", "du", "au", "ce", "pas", "quel", "quelle",
+    ]),
+    (Language { code: "de", name: "German" }, &[
+        "der", "die", "das", "und", "ist", "ein", "eine", "nicht", "ich", "wir", "mit", "für",
+        "wo", "wie", "was", "den", "dem", "zu", "auf", "kann",
+    ]),
+    (Language { code: "it", name: "Italian" }, &[
+        "il", "lo", "gli", "è", "che", "di", "per", "dove", "come", "cosa", "sono", "non",
+        "della", "nel", "alla", "posso", "anche", "quale", "ci", "un",
+    ]),
+    (Language { code: "pt", name: "Portuguese" }, &[
+        "o", "os", "é", "um", "uma", "do", "da", "para", "com", "onde", "como", "não", "em",
+        "no", "na", "você", "posso", "também", "qual", "está",
+    ]),
+    (Language { code: "nl", name: "Dutch" }, &[
+        "het", "een", "en", "ik", "wij", "niet", "van", "voor", "met", "waar", "hoe", "wat",
+        "dat", "op", "te", "zijn", "kan", "er", "welke", "ook",
+    ]),
+];
+
+/// Detects the language of `text`.
+///
+/// # Returns
+/// The language, or `None` when the text is too short or too mixed to tell.
+pub fn detect(text: &str) -> Option<Language> {
+    if let Some(language) = detect_script(text) {
+        return Some(language);
+    }
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect();
+    let mut scores: Vec<(Language, usize)> = WORDS
+        .iter()
+        .map(|(language, common)| (*language, words.iter().filter(|w| common.contains(&w.as_str())).count()))
+        .collect();
+    scores.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+    let (best, best_score) = scores[0];
+    let runner_up = scores[1].1;
+    (best_score >= MIN_WORD_MATCHES && best_score > runner_up).then_some(best)
+}
+
+/// Detects languages written in their own script, when most letters of `text` are in it.
+fn detect_script(text: &str) -> Option<Language> {
+    let letters: Vec<char> = text.chars().filter(|c| c.is_alphabetic()).collect();
+    let count = |range: &[std::ops::RangeInclusive<char>]| letters.iter().filter(|&&c| range.iter().any(|r| r.contains(&c))).count();
+    let kana = count(&['\u{3040}'..='\u{30ff}']);
+    let han = count(&['\u{4e00}'..='\u{9fff}']);
+    // Japanese mixes kana with Han characters, so any kana tells it apart from Chinese.
+    let scripts: [(Language, usize); 9] = [
+        (Language { code: "ja", name: "Japanese" }, if kana > 0 { kana + han } else { 0 }),
+        (Language { code: "zh", name: "Chinese" }, if kana > 0 { 0 } else { han }),
+        (Language { code: "ko", name: "Korean" }, count(&['\u{ac00}'..='\u{d7af}', '\u{1100}'..='\u{11ff}'])),
+        (Language { code: "ru", name: "Russian" }, count(&['\u{0400}'..='\u{04ff}'])),
+        (Language { code: "el", name: "Greek" }, count(&['\u{0370}'..='\u{03ff}'])),
+        (Language { code: "ar", name: "Arabic" }, count(&['\u{0600}'..='\u{06ff}'])),
+        (Language { code: "he", name: "Hebrew" }, count(&['\u{0590}'..='\u{05ff}'])),
+        (Language { code: "th", name: "Thai" }, count(&['\u{0e00}'..='\u{0e7f}'])),
+        (Language { code: "hi", name: "Hindi" }, count(&['\u{0900}'..='\u{097f}'])),
+    ];
+    scripts
+        .into_iter()
+        .filter(|(_, n)| *n > 0 && *n * 2 >= letters.len())
+        .max_by_key(|(_, n)| *n)
+        .map(|(language, _)| language)
+}
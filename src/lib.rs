@@ -10,14 +10,55 @@
 //! - This struct is serializable and deserializable to formats such as JSON through the use
 //!   of the `serde` crate.
 //! - It is created as part of the process to set up and manage trip data.
+use std::cell::Cell;
+use std::future::Future;
+use std::task::Poll;
+use std::time::Duration;
+use futures_util::StreamExt;
 use uuid::Uuid;
 use worker::*;
 use serde::{Serialize, Deserialize};
 mod db;
 mod ai;
+mod admission;
+mod alternatives;
+mod analytics;
+mod compare;
+mod config;
+mod debug;
+mod encryption;
+mod features;
+mod feasibility;
+mod geocode;
+mod ics;
+mod language;
+mod ndjson;
+mod packing;
+mod pagination;
+mod plan;
+mod problem;
+mod quiet_hours;
+mod role;
+mod sanitize;
+mod sse;
+mod session;
+mod slug;
+mod status;
+mod tags;
+mod tenant;
+mod time;
+mod timing;
+mod units;
 
 use db::create_trip;
+use units::UnitPreferences;
 use crate::db::{check_if_messages, create_message, get_messages};
+use crate::config::Config;
+use crate::pagination::Paginated;
+use crate::role::MessageRole;
+use crate::status::TripStatus;
+use crate::tenant::Tenant;
+use crate::timing::ServerTiming;
 
 /// The `TripInit` struct represents the initialization details of a trip,
 /// including the destination, duration, and a response message.
@@ -27,6 +68,9 @@ use crate::db::{check_if_messages, create_message, get_messages};
 /// * `destination` (`String`): The destination of the trip.
 /// * `days` (`u32`): The number of days the trip will last.
 /// * `response` (`String`): A response or status message related to the trip initialization.
+/// * `plan` (`Vec<PlanDay>`): The plan split into days. Optional on input for backward
+///   compatibility; when absent it is derived from `response` with `plan::parse_days`.
+/// * `status` (`TripStatus`): The trip's lifecycle stage, `planning` when absent.
 ///
 /// This struct derives the `Serialize` and `Deserialize` traits to allow easy
 /// conversion to and from formats such as JSON or other serialized data representations.
@@ -35,6 +79,24 @@ struct TripInit {
     destination: String,
     days: u32,
     response: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    plan: Vec<plan::PlanDay>,
+    #[serde(default)]
+    status: TripStatus,
+}
+
+impl TripInit {
+    /// Serializes the trip for use as AI chat context.
+    ///
+    /// The structured `plan` is left out because it repeats `response` day by day and would
+    /// only double the size of the prompt.
+    fn plan_context(&self) -> Result<String> {
+        Ok(serde_json::to_string(&serde_json::json!({
+            "destination": self.destination,
+            "days": self.days,
+            "response": self.response
+        }))?)
+    }
 }
 
 
@@ -45,6 +107,11 @@ struct TripInit {
 /// * `id` - A unique identifier for the trip, represented as a `String`.
 /// * `destination` - The destination of the trip, represented as a `String`.
 /// * `days` - The number of days the trip will last, represented as a `u32`.
+/// * `status` - The trip's lifecycle stage (see [`TripStatus`]); `planning` when absent.
+/// * `title` - A short title generated from the plan (see `ai::generate_title`); `None` for
+///   trips created before titles existed.
+/// * `slug` - The trip's human-friendly slug (see [`slug`]); `None` for trips created before
+///   slugs existed. Assigned by `db::create_trip`.
 ///
 /// This struct derives the following traits:
 /// * `Serialize` - Enables the struct to be serialized into formats such as JSON.
@@ -61,12 +128,18 @@ struct TripInit {
 ///     pub id: String,
 ///     pub destination: String,
 ///     pub days: u32,
+///     pub status: TripStatus,
+///     pub title: Option<String>,
+///     pub slug: Option<String>,
 /// }
 ///
 /// let trip = TripData {
 ///     id: String::from("trip123"),
 ///     destination: String::from("Hawaii"),
 ///     days: 7,
+///     status: TripStatus::Planning,
+///     title: Some(String::from("A Week of Beaches in Hawaii")),
+///     slug: None,
 /// };
 /// println!("Trip to {} for {} days", trip.destination, trip.days);
 /// ```
@@ -75,6 +148,12 @@ pub struct TripData {
    pub id: String,
    pub destination: String,
    pub days: u32,
+   #[serde(default)]
+   pub status: TripStatus,
+   #[serde(default)]
+   pub title: Option<String>,
+   #[serde(default)]
+   pub slug: Option<String>,
 }
 
 /// The `main` function serves as the entry point for handling incoming HTTP requests.
@@ -87,7 +166,7 @@ pub struct TripData {
 ///
 /// # Returns
 /// - Returns a `Result<Response>` where `Response` is the HTTP response sent back to the client.
-/// - In case of an error during processing, a `Response::error` with status code `404` or another appropriate error response is returned.
+/// - In case of an error during processing, a [`json_error`] with status code `404` or another appropriate status is returned.
 ///
 /// # Routing Logic
 /// 1. **GET `/`:**
@@ -96,265 +175,3638 @@ pub struct TripData {
 /// 2. **POST `/input`:**
 ///    Calls the `input` handler with the request, environment, and context to process the input endpoint.
 ///
-/// 3. **GET `/trip/{trip_id}`:**
+/// 3. **`/trip/{trip_id}/...` sub-resources:**
+///    Paths with a further segment after the trip ID are dispatched on method and action:
+///    - **GET `/trip/{trip_id}/plan`:** Calls `get_plan` (`?format=json|text|html`).
+///    - **GET `/trip/{trip_id}/plan.json`:** Calls `download_plan` to download the structured plan as a file.
+///    - **GET `/trip/{trip_id}/calendar.ics`:** Calls `calendar_ics` to download the plan as an
+///      iCalendar file with one all-day event per day, starting on `?start=` (default tomorrow).
+///    - **GET `/trip/{trip_id}/plan/validate`:** Calls `validate_plan`.
+///    - **GET `/trip/{trip_id}/plan/diff?from=&to=`:** Calls `diff_plan`.
+///    - **GET `/trip/{trip_id}/map.geojson`:** Calls `map_geojson` (`403` when geocoding is disabled).
+///    - **POST `/trip/{trip_id}/plan/extend`:** Calls `extend_plan`.
+///    - **POST `/trip/{trip_id}/plan/feasibility`:** Calls `check_feasibility` for per-day warnings.
+///    - **POST `/trip/{trip_id}/plan/cancel`:** Calls `cancel_generation` to stop a running
+///      regeneration or extension.
+///    - **POST `/trip/{trip_id}/status`:** Calls `set_status`.
+///    - **POST `/trip/{trip_id}/plan/day/{n}/alternatives`:** Calls `day_alternatives`.
+///    - **POST `/trip/{trip_id}/regenerate`:** Calls `regenerate_plan` (`?reset_chat=true`).
+///    - **POST `/trip/{trip_id}/title/regenerate`:** Calls `regenerate_title`.
+///    - **POST `/trip/{trip_id}/remix`:** Calls `remix_trip`.
+///    - **POST `/trip/{trip_id}/packing-list`:** Calls `packing_list`; `?save=true` stores the list.
+///    - **POST `/trip/{trip_id}/messages/import`:** Calls `import_messages` with an NDJSON body;
+///      `?replace=true` replaces the existing messages instead of appending to them.
+///    - **GET `/trip/{trip_id}/messages`:** Calls `messages_since` (`?since=` for new messages only).
+///    - **GET `/trip/{trip_id}/messages/pinned`:** Calls `pinned_messages`.
+///    - **GET `/trip/{trip_id}/summary`:** Calls `chat_summary`.
+///    - **GET `/trip/{trip_id}/message/{message_id}`:** Calls `get_message`.
+///    - **POST `/trip/{trip_id}/message/{message_id}/pin` and `/unpin`:** Calls `pin_message`.
+///    - **POST `/trip/{trip_id}/chat/retry`:** Calls `retry_chat` (`403` when chat is disabled).
+///    - Anything else returns `404`.
+///
+/// 4. **GET `/trip/{trip_id}`:**
 ///    - Extracts the `trip_id` from the URL path.
 ///    - Checks the `Accept` header:
-///        - If it contains `text/html`, serves an HTML page (`chat.html`).
+///        - If it contains `text/html`, serves an HTML page (`chat.html`). When `CHAT_ENABLED` is
+///          `false` the page is marked with `data-chat-disabled` so the chat panel is hidden.
 ///        - Otherwise, processes the request by calling the `get_trip` handler to fetch trip details.
+///          With `?preview=true` the plan is shortened by `trip_preview`.
+///
+///    **DELETE `/trip/{trip_id}`** calls `delete_trip` to remove the trip, its plans and
+///    messages and its durable object's storage.
+///
+///    **PATCH `/trip/{trip_id}`** calls `update_trip` to change the trip's destination or days
+///    and generate a new plan for it.
+///
+/// 5. **POST `/trip/{trip_id}`:**
+///    Calls the `chat` handler with the request, environment, and context to process chat messages for the given trip ID.
+///    Returns `403` instead when the `CHAT_ENABLED` variable is `false`.
+///
+/// 6. **GET `/chat/{trip_id}`:**
+///    - Extracts the `trip_id` from the URL path.
+///    - Checks if any messages exist for the given trip ID via the `check_if_messages` function.
+///        - If messages exist, retrieves them via `db::list_messages` and returns them as JSON
+///          tuples `[message, role, created_at, pinned, id]`, with `created_at` in epoch milliseconds.
+///        - Otherwise, returns a response with "No messages yet".
+///    - Optional `?limit=` and `?offset=` select a page of messages.
+///    - With `?tz=` and/or `?locale=` each message is returned as an object
+///      `{ id, message, role, created_at, created_at_formatted, pinned }`, where the formatted value comes
+///      from `time::format_timestamp` and the raw `created_at` is kept alongside it.
+///    - With `?envelope=true` the page is wrapped in a [`Paginated`] envelope instead of being
+///      returned as a bare array (an empty envelope is returned when there are no messages).
+///    - Rows that cannot be read (a missing or non-text column) are skipped rather than failing
+///      the request. When any were, an `X-Skipped-Rows` header carries their count and the
+///      envelope gains a `warnings` array describing each one.
+///
+/// 7. **POST `/trips/merge`:**
+///    Calls the `merge` handler to move one trip's history into another. Trips have no
+///    owner yet, so like the batch routes it requires `ADMIN_API_KEY`.
+///
+///    **POST `/trips/tag`** calls `tag_trips` to add and remove tags on several trips at once;
+///    like the batch routes it requires `ADMIN_API_KEY`.
+///
+/// 8. **GET `/destinations`:**
+///    Calls the `destinations` handler to list destinations by number of trips.
+///
+///    **GET `/options`** calls `options` for the values and limits the trip forms are
+///    checked against, so a frontend can build its fields from them.
+///
+/// 9. **GET `/trips` and GET `/trips/count`:**
+///    Calls the `list_trips` handler to list live trips, or `count_trips` to count them,
+///    optionally filtered by `?status=` and `?q=` (destination search).
+///
+/// 10. **GET `/livez`, GET `/readyz` and GET `/warmup`:**
+///    `/livez` always answers `200 ok` while the worker runs. `/readyz` calls `readyz` to check
+///    D1 and the AI settings, answering `503` when either is unavailable. `/warmup` calls
+///    `warmup` to exercise both when `WARMUP_ENABLED` is on.
+///
+/// 11. **POST `/trips/regenerate` and GET `/jobs/{job_id}`:**
+///    Admin routes (see [`reject_unauthorized`]). `regenerate_batch` starts regenerating the
+///    plans of several trips in the background and answers `202 Accepted` with a job id;
+///    `job_status` reports the job's progress. GET `/trips/regenerate/{job_id}` is an alias.
+///
+/// 12. **GET `/admin/trip/{trip_id}/do-state`** and **POST `/admin/trip/{trip_id}/reconcile`:**
+///     Call `do_state` to return the raw storage of the trip's durable object, and
+///     `reconcile_trip` to rewrite it from D1. Both require `ADMIN_API_KEY` like the batch routes.
+///     **GET `/admin/stats`** calls `admin_stats` for AI latency percentiles per model.
+///
+/// 13. **POST `/compare`:**
+///    Calls `compare_destinations` to have the AI compare two destinations without creating a trip.
+///
+///    **POST `/plan/combine`** calls `combine_plans` to have the AI join the plans of several
+///    trips into one multi-leg itinerary, without changing the trips.
+///
+/// 14. **GET `/trip/by-slug/{slug}`:**
+///    Calls `trip_by_slug` to resolve a slug such as `paris-5-days` to its trip. Browsers
+///    (`Accept: text/html`) are redirected to `/trip/{trip_id}`; other clients get the same
+///    JSON as GET `/trip/{trip_id}`. Unknown slugs return `404`.
+///
+/// 15. **Fallback:**
+///    If no route matches, returns a `json_error("Not Found", 404)`.
+///
+/// # Request IDs
+/// Every request is tagged with an id before routing. If the upstream gateway already
+/// sent an `X-Request-Id` (or Cloudflare's `CF-Ray`) that value is reused, otherwise a new
+/// UUID is generated. The id is logged alongside the method and path and echoed back in
+/// the `X-Request-Id` response header so traces can be correlated across the stack.
+///
+/// # Server Timing
+/// A [`ServerTiming`] is created per request and handed to the handlers, which use it to
+/// time their AI (`ai`), D1 (`db`), durable object (`do`) and geocoding (`geo`) calls. The result is returned
+/// in the `Server-Timing` header, e.g. `ai;dur=1200, db;dur=40, do;dur=15, total;dur=1262`.
+///
+/// # Tenants
+/// When `MULTI_TENANT` is enabled, every route except `/`, `/livez`, `/readyz` and `/warmup` acts for the
+/// tenant resolved by [`Tenant::resolve`] (`400` when there is none), and D1 reads and writes
+/// are restricted to that tenant's rows. Trip pages and chat answer `404` for trips of other
+/// tenants before their durable object is consulted.
+///
+/// # Errors
+/// Error responses carry a JSON body built by [`json_error`],
+/// `{"error": {"message": "...", "status": 404}}`. A handler that fails outright is logged
+/// with the request id and answered with a `500` of the same shape, without the details.
+/// Clients sending `Accept: application/problem+json` get every error as RFC 7807 Problem
+/// Details instead (see [`problem_response`]).
+///
+/// # Configuration
+/// The deployment's settings are read once per request into a [`Config`] and handed to
+/// the handlers that need them. A malformed value (e.g. `PLAN_VERSIONS_KEPT=ten`) is logged
+/// with the variable's name and fails requests with a bare `500`, rather than being
+/// ignored. `/livez` is answered before the settings are read, so it keeps answering `200`,
+/// and `/readyz` reports the problems with `503` (see [`readyz`]).
+///
+/// # HTTPS
+/// When `REQUIRE_HTTPS` is enabled, requests that did not arrive over HTTPS are turned away
+/// by [`reject_insecure`] before routing.
+///
+/// # Notes
+/// - Handlers like `index`, `input`, `get_trip`, `chat`, `check_if_messages`, and `get_messages` must be properly implemented.
+/// - The included `chat.html` file is assumed to exist at `../public/chat.html`.
+/// - The function is designed for asynchronous execution and leverages the `async` Rust programming model.
+#[event(fetch)]
+pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response>{
+    let request_id = request_id(&req);
+    console_log!("[{request_id}] {} {}", req.method().to_string(), req.path());
+    let timing = ServerTiming::new();
+    let wants_problem = problem::is_accepted(&req.headers().get("Accept")?.unwrap_or_default());
+    let path = req.path();
+    if req.method() == Method::Get && path == "/livez" {
+        let headers = Headers::new();
+        headers.set("X-Request-Id", &request_id)?;
+        return Ok(Response::ok("ok")?.with_headers(headers));
+    }
+    let resp = match Config::from_env(&env) {
+        Err(problems) => {
+            console_error!("[{request_id}] Invalid configuration: {problems}");
+            match req.method() == Method::Get && path == "/readyz" {
+                true => readyz(env, Err(problems), &timing).await?,
+                false => json_error("Invalid configuration", 500)?,
+            }
+        }
+        Ok(config) => match reject_insecure(&req, &config)? {
+            Some(resp) => resp,
+            None => match route(req, env, _ctx, &config, &timing).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    console_error!("[{request_id}] Request failed: {e}");
+                    json_error("Internal Server Error", 500)?
+                }
+            },
+        },
+    };
+    let resp = match wants_problem && resp.status_code() >= 400 {
+        true => problem_response(resp, path).await?,
+        false => resp,
+    };
+    let headers = resp.headers().clone();
+    headers.set("X-Request-Id", &request_id)?;
+    headers.set("Server-Timing", &timing.header_value())?;
+    Ok(resp.with_headers(headers))
+}
+
+/// Runs the warmup on the cron schedule configured for the worker, when `WARMUP_ENABLED` is on.
+///
+/// See [`warm_up`]. Results are only logged; a failing check is retried on the next run.
+/// With `SCHEDULED_QUIET_HOURS` set, a trigger outside that window skips the work (and logs
+/// that it did), leaving it to the first trigger inside the window.
+#[event(scheduled)]
+pub async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    let Ok(config) = Config::from_env(&env) else {
+        console_error!("Invalid configuration, skipping warmup");
+        return;
+    };
+    if !config.warmup_enabled {
+        return;
+    }
+    if let Some(quiet_hours) = config.quiet_hours {
+        if !quiet_hours.contains(event.schedule() as u64) {
+            console_log!("Skipping scheduled warmup ({}): outside quiet hours {quiet_hours}, deferred to the next run inside them", event.cron());
+            return;
+        }
+    }
+    let (d1, ai) = warm_up(&env, &config, &ServerTiming::new()).await;
+    console_log!("Warmup done: d1={}, ai={}", check_status(&d1), check_status(&ai));
+}
+
+/// Resolves the id used to correlate logs for a single request.
+///
+/// Prefers an id supplied by an upstream proxy so traces line up across services:
+/// `X-Request-Id` is checked first, then `CF-Ray`. Blank header values are ignored.
+/// When neither header is present a fresh v4 UUID is generated.
+///
+/// # Arguments
+/// * `req` - The incoming request whose headers are inspected.
+///
+/// # Returns
+/// The request id as a `String`.
+fn request_id(req: &Request) -> String {
+    ["X-Request-Id", "CF-Ray"]
+        .iter()
+        .filter_map(|name| req.headers().get(name).ok().flatten())
+        .map(|value| value.trim().to_string())
+        .find(|value| !value.is_empty())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Enforces the `REQUIRE_HTTPS` flag (default `false`, so local development over plain
+/// HTTP keeps working).
+///
+/// The scheme is taken from `X-Forwarded-Proto` when a proxy sets it, since the worker may
+/// see the proxy's own connection, and from the request URL otherwise.
+///
+/// # Returns
+/// `None` when the request may proceed. For insecure requests, `Some` with a `301`
+/// redirect to the HTTPS URL for `GET` and `HEAD`, or `403 Forbidden` for other methods,
+/// whose bodies a redirect would drop.
+fn reject_insecure(req: &Request, config: &Config) -> Result<Option<Response>> {
+    if !config.require_https {
+        return Ok(None);
+    }
+    let mut url = req.url()?;
+    let forwarded = req.headers().get("X-Forwarded-Proto")?;
+    let scheme = forwarded
+        .as_deref()
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().to_lowercase())
+        .unwrap_or_else(|| url.scheme().to_string());
+    if scheme == "https" {
+        return Ok(None);
+    }
+    if matches!(req.method(), Method::Get | Method::Head) && url.set_scheme("https").is_ok() {
+        url.set_port(None).ok();
+        return Ok(Some(Response::redirect_with_status(url, 301)?));
+    }
+    Ok(Some(json_error("HTTPS is required", 403)?))
+}
+
+/// Turns away requests to a feature switched off in `FEATURE_FLAGS` (see [`features`]).
+///
+/// # Returns
+/// `None` when the request may proceed, otherwise `Some` with `503 Service Unavailable`
+/// naming the flag, e.g. `Feature disabled: enable_compare`.
+fn reject_disabled(req: &Request, config: &Config) -> Result<Option<Response>> {
+    match features::Feature::of(&req.method(), &req.path()) {
+        Some(feature) if config.disabled_features.contains(&feature) => {
+            Ok(Some(json_error(&format!("Feature disabled: {}", feature.flag()), 503)?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Actions under `/trip/{trip_id}/` that call the AI service.
+const AI_TRIP_ACTIONS: [(Method, &str); 8] = [
+    (Method::Post, "plan/extend"),
+    (Method::Post, "plan/feasibility"),
+    (Method::Post, "regenerate"),
+    (Method::Post, "title/regenerate"),
+    (Method::Post, "remix"),
+    (Method::Post, "chat/retry"),
+    (Method::Get, "summary"),
+    (Method::Post, "packing-list"),
+];
+
+/// Returns `true` when the route of `req` calls the AI service.
+fn uses_ai(req: &Request) -> bool {
+    let method = req.method();
+    let path = req.path();
+    match path.strip_prefix("/trip/") {
+        Some(rest) => match rest.split_once('/') {
+            Some((_, action)) => AI_TRIP_ACTIONS.iter().any(|(m, a)| *m == method && *a == action)
+                || (method == Method::Post && day_alternatives_day(action).is_some()),
+            None => matches!(method, Method::Post | Method::Patch),
+        },
+        None => method == Method::Post && matches!(path.as_str(), "/input" | "/compare" | "/plan/combine" | "/trips/regenerate"),
+    }
+}
+
+/// Fails AI routes fast when the AI service is not configured (see [`Config::ai_missing`]),
+/// instead of letting them do database work and then fail deep inside `ai`.
+///
+/// # Returns
+/// `None` when the request may proceed, otherwise `Some` with
+/// `503 Service Unavailable` and a message such as
+/// `AI binding not configured: CF_API_TOKEN is not set`.
+fn reject_without_ai(req: &Request, config: &Config) -> Result<Option<Response>> {
+    let Some(missing) = &config.ai_missing else {
+        return Ok(None);
+    };
+    if !uses_ai(req) {
+        return Ok(None);
+    }
+    Ok(Some(json_error(&format!("AI binding not configured: {missing}"), 503)?))
+}
+
+/// Guards the admin routes with the `ADMIN_API_KEY` secret.
+///
+/// The key is accepted as `Authorization: Bearer <key>` or in an `X-API-Key` header.
+///
+/// # Returns
+/// `None` when the request carries the key. Otherwise `Some` with `401 Unauthorized`, or
+/// `403 Forbidden` when no key is configured, which leaves the admin routes disabled.
+fn reject_unauthorized(req: &Request, env: &Env) -> Result<Option<Response>> {
+    let Ok(expected) = env.secret("ADMIN_API_KEY").map(|v| v.to_string()) else {
+        return Ok(Some(json_error("Admin routes are disabled on this deployment", 403)?));
+    };
+    let header = |name: &str| req.headers().get(name).ok().flatten();
+    let given = header("Authorization")
+        .and_then(|v| v.strip_prefix("Bearer ").map(|key| key.trim().to_string()))
+        .or_else(|| header("X-API-Key").map(|v| v.trim().to_string()))
+        .unwrap_or_default();
+    // Compare every byte so the time taken does not reveal how much of the key matched.
+    let matches = given.len() == expected.len()
+        && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+    if expected.is_empty() || !matches {
+        return Ok(Some(json_error("Unauthorized", 401)?));
+    }
+    Ok(None)
+}
+
+/// The longest single interest accepted by `POST /input`, in characters.
+const MAX_INTEREST_CHARS: usize = 40;
+
+/// Checks a destination submitted for planning or comparison.
+///
+/// # Returns
+/// The destination without surrounding whitespace.
+///
+/// # Errors
+/// A message for a `400` response when the destination is blank.
+fn checked_destination(destination: &str) -> std::result::Result<String, String> {
+    let destination = destination.trim();
+    if destination.is_empty() {
+        return Err("destination must not be blank".to_string());
+    }
+    Ok(destination.to_string())
+}
+
+/// Reads the `?model=` a client may use to pick one of the `AI_MODEL_TIERS` models
+/// instead of the one `ai::choose_model` would pick.
+///
+/// # Errors
+/// A message for a `400` response when the model is not one of the tiers.
+fn requested_model(req: &Request, env: &Env) -> std::result::Result<Option<String>, String> {
+    let Some(model) = query_param(req, "model").filter(|m| !m.trim().is_empty()) else {
+        return Ok(None);
+    };
+    if !ai::is_tier_model(env, &model) {
+        return Err(format!("model {model:?} is not one of the configured AI_MODEL_TIERS"));
+    }
+    Ok(Some(model))
+}
+
+/// Reads the optional `start_date` of a trip from a form.
+///
+/// The date is either `YYYY-MM-DD` or `today`/`tomorrow`, which are resolved in the time
+/// zone given by `?tz=` (an IANA name such as `America/New_York`, default `UTC`).
+///
+/// # Returns
+/// `None` when no date was given or `PLAN_WEEKDAYS` is off, so the plan is not tied to dates.
+///
+/// # Errors
+/// A message for a `400` response when the date or `tz` is not valid.
+fn form_start_date(req: &Request, form: &FormData, config: &Config) -> std::result::Result<Option<time::CalendarDate>, String> {
+    let tz = query_param(req, "tz").unwrap_or_else(|| "UTC".to_string());
+    let Some(today) = time::CalendarDate::today(&tz) else {
+        return Err(format!("tz must be an IANA time zone such as Europe/Paris, got {tz:?}"));
+    };
+    let Some(FormEntry::Field(value)) = form.get("start_date") else {
+        return Ok(None);
+    };
+    let date = match value.trim().to_ascii_lowercase().as_str() {
+        "" => return Ok(None),
+        "today" => today,
+        "tomorrow" => today.add_days(1),
+        value => time::CalendarDate::parse(value).ok_or("start_date must be a date such as 2025-07-14, today or tomorrow")?,
+    };
+    Ok(config.plan_weekdays.then_some(date))
+}
+
+/// Reads the optional `units` and `currency` of a trip from a form.
+///
+/// # Returns
+/// The preferences, metric and without a currency for fields that are absent or blank.
+///
+/// # Errors
+/// A message for a `400` response when `units` is not `metric` or `imperial`, or
+/// `currency` is not one of `units::CURRENCIES`.
+fn form_units(form: &FormData) -> std::result::Result<UnitPreferences, String> {
+    let field = |name: &str| match form.get(name) {
+        Some(FormEntry::Field(value)) if !value.trim().is_empty() => Some(value),
+        _ => None,
+    };
+    Ok(UnitPreferences {
+        units: field("units").map(|units| units.parse()).transpose()?.unwrap_or_default(),
+        currency: field("currency").map(|currency| units::checked_currency(&currency)).transpose()?,
+    })
+}
+
+/// Collects the optional `interests` of a trip from a form.
+///
+/// Both conventions are accepted, and can be mixed: the field repeated once per interest
+/// (`interests=food&interests=art`) and a comma separated value (`interests=food, art`).
+/// The values are joined and parsed with [`parse_interests`].
+///
+/// # Returns
+/// The normalized interests in the order given, empty when the field is absent.
+///
+/// # Errors
+/// A message for a `400` response when a value is a file, or as for [`parse_interests`]
+/// with `MAX_INTERESTS` (see [`Config::max_interests`]) as the limit.
+fn form_interests(form: &FormData, config: &Config) -> std::result::Result<Vec<String>, String> {
+    let mut values: Vec<String> = vec![];
+    for entry in form.get_all("interests").unwrap_or_default() {
+        let FormEntry::Field(value) = entry else {
+            return Err("interests must be text".to_string());
+        };
+        values.push(value);
+    }
+    parse_interests(&values.join(","), config.max_interests)
+}
+
+/// Parses a comma separated list of interests.
+///
+/// Each interest is normalized with [`normalize_interest`], blank entries are skipped, and
+/// duplicates (`food, Food, " FOOD "`) are kept once.
+///
+/// # Arguments
+/// * `value` - The comma separated interests.
+/// * `max_interests` - The most distinct interests allowed.
+///
+/// # Returns
+/// The normalized interests in the order given.
+///
+/// # Errors
+/// A message for a `400` response when there are more than `max_interests` distinct
+/// interests, or one is longer than [`MAX_INTEREST_CHARS`] characters.
+fn parse_interests(value: &str, max_interests: u32) -> std::result::Result<Vec<String>, String> {
+    let mut interests: Vec<String> = vec![];
+    for interest in value.split(',').map(normalize_interest).filter(|i| !i.is_empty()) {
+        if interest.chars().count() > MAX_INTEREST_CHARS {
+            return Err(format!("each interest must be at most {MAX_INTEREST_CHARS} characters"));
+        }
+        if !interests.contains(&interest) {
+            interests.push(interest);
+        }
+    }
+    if interests.len() > max_interests as usize {
+        return Err(format!("at most {max_interests} interests are allowed"));
+    }
+    Ok(interests)
+}
+
+/// Normalizes an interest: lowercased, trimmed, and with runs of whitespace inside it
+/// turned into a single space, so `" Street  Food "` becomes `"street food"`.
+fn normalize_interest(interest: &str) -> String {
+    interest.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Form encodings accepted by every endpoint that reads a form body.
+const FORM_CONTENT_TYPES: [&str; 2] = ["multipart/form-data", "application/x-www-form-urlencoded"];
+
+/// Encodings accepted by `POST /input`, which also takes a JSON object (see [`read_form`]).
+const INPUT_CONTENT_TYPES: [&str; 3] = ["multipart/form-data", "application/x-www-form-urlencoded", "application/json"];
+
+/// Returns the media type of a request's `Content-Type`, lowercased and without parameters
+/// such as `boundary` or `charset`.
+fn media_type(req: &Request) -> Option<String> {
+    let value = req.headers().get("Content-Type").ok().flatten()?;
+    let media_type = value.split(';').next().unwrap_or_default().trim().to_lowercase();
+    (!media_type.is_empty()).then_some(media_type)
+}
+
+/// Checks that a request body is in one of the encodings a handler can parse.
+///
+/// # Arguments
+/// * `req` - The incoming request.
+/// * `accepted` - The media types the handler accepts.
+///
+/// # Returns
+/// `Some(response)` with `415 Unsupported Media Type` listing the accepted types when the
+/// `Content-Type` header is missing or not one of them, otherwise `None`.
+fn check_content_type(req: &Request, accepted: &[&str]) -> Result<Option<Response>> {
+    match media_type(req) {
+        Some(media_type) if accepted.contains(&media_type.as_str()) => Ok(None),
+        _ => Ok(Some(json_error(&format!("Unsupported Content-Type; expected one of: {}", accepted.join(", ")), 415)?)),
+    }
+}
+
+/// Reads a request body as form fields, accepting a flat JSON object as well as form encodings.
+///
+/// JSON string values are used as-is and other values (such as numbers) by their JSON text,
+/// so `{"destination": "Paris", "days": 3}` reads the same as `destination=Paris&days=3`.
+/// An array becomes a repeated field, so `{"interests": ["food", "art"]}` reads the same as
+/// `interests=food&interests=art`.
+///
+/// # Errors
+/// Returns an error if the body cannot be parsed or a JSON body is not an object.
+async fn read_form(req: &mut Request) -> Result<FormData> {
+    if media_type(req).as_deref() != Some("application/json") {
+        return req.form_data().await;
+    }
+    let serde_json::Value::Object(fields) = req.json::<serde_json::Value>().await? else {
+        return Err(Error::RustError("JSON body must be an object".into()));
+    };
+    let form = FormData::new();
+    for (name, value) in fields {
+        let values = match value {
+            serde_json::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                serde_json::Value::String(value) => form.append(&name, &value)?,
+                value => form.append(&name, &value.to_string())?,
+            }
+        }
+    }
+    Ok(form)
+}
+
+/// Enforces the `STRICT_FORM` flag for a parsed form body.
+///
+/// Clients normally may send extra fields (tracking parameters and the like), which are
+/// ignored. When `STRICT_FORM` is enabled, any field not in `allowed` is rejected so that
+/// typos such as `day` instead of `days` surface immediately.
+///
+/// # Arguments
+/// * `config` - The deployment settings; `strict_form` (`STRICT_FORM`) turns the check on.
+/// * `form` - The parsed form body.
+/// * `allowed` - The field names the handler understands.
+///
+/// # Returns
+/// The form, handed back for further use, and `Some(response)` with a `400 Bad Request`
+/// listing the unrecognised fields when the request must be rejected.
+fn check_form_fields(config: &Config, form: FormData, allowed: &[&str]) -> Result<(FormData, Option<Response>)> {
+    if !config.strict_form {
+        return Ok((form, None));
+    }
+    use wasm_bindgen::JsCast;
+
+    let js: wasm_bindgen::JsValue = form.into();
+    let keys = js_sys::Reflect::get(&js, &"keys".into())?
+        .dyn_into::<js_sys::Function>()?
+        .call0(&js)?;
+    let mut unknown: Vec<String> = Vec::new();
+    if let Some(iter) = js_sys::try_iter(&keys)? {
+        for key in iter {
+            let key = key?.as_string().unwrap_or_default();
+            if !allowed.contains(&key.as_str()) && !unknown.contains(&key) {
+                unknown.push(key);
+            }
+        }
+    }
+    let form = FormData::from(js);
+    if unknown.is_empty() {
+        return Ok((form, None));
+    }
+    let resp = json_error(&format!("Unrecognized fields: {} (expected: {})", unknown.join(", "), allowed.join(", ")), 400)?;
+    Ok((form, Some(resp)))
+}
+
+/// Builds an error response with a JSON body, so clients can read the message without
+/// guessing the body's format.
+///
+/// # Arguments
+/// * `message` - What went wrong, e.g. `"Trip not found"`.
+/// * `code` - The HTTP status code, the same one `Response::error` would be given.
+///
+/// # Returns
+/// A response with status `code`, `Content-Type: application/json` and a body such as
+/// `{"error": {"message": "Trip not found", "status": 404}}`.
+fn json_error(message: &str, code: u16) -> Result<Response> {
+    Ok(Response::from_json(&serde_json::json!({ "error": { "message": message, "status": code } }))?.with_status(code))
+}
+
+/// Reads the message out of a [`json_error`] body, or returns the body as it is when it is
+/// not one (e.g. a plain-text error from the runtime).
+fn error_message(body: String) -> String {
+    serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|v| v["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or(body)
+}
+
+/// Rewrites an error response as RFC 7807 Problem Details (see [`problem`]), keeping its
+/// status and headers. `path` becomes the problem's `instance`.
+async fn problem_response(mut resp: Response, path: String) -> Result<Response> {
+    let status = resp.status_code();
+    let detail = error_message(resp.text().await?);
+    let body = serde_json::to_string(&problem::Problem::new(status, detail, path))?;
+    let headers = resp.headers().clone();
+    headers.set("Content-Type", problem::CONTENT_TYPE)?;
+    Ok(Response::ok(body)?.with_status(status).with_headers(headers))
+}
+
+/// Returns the first value of a query-string parameter, if present.
+///
+/// # Arguments
+/// * `req` - The request whose URL is inspected.
+/// * `name` - The name of the query parameter.
+///
+/// # Returns
+/// `Some(value)` for the first occurrence of `name`, or `None` if it is absent or the URL
+/// cannot be parsed.
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    req.url()
+        .ok()?
+        .query_pairs()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Dispatches a request to the matching handler.
+///
+/// This holds the routing table documented on [`main`]; `main` wraps it with the
+/// request-id logging and `Server-Timing` header so that every handler gets the same treatment.
+async fn route(req: Request, env: Env, _ctx: Context, config: &Config, timing: &ServerTiming) -> Result<Response>{
+    let path = req.path();
+
+    if req.method() == Method::Get && path == "/" {
+        return index().await;
+    }
+    if req.method() == Method::Get && path == "/readyz" {
+        return readyz(env, Ok(config), timing).await;
+    }
+    if req.method() == Method::Get && path == "/warmup" {
+        return warmup(env, config, timing).await;
+    }
+    if let Some(resp) = reject_disabled(&req, config)? {
+        return Ok(resp);
+    }
+    if let Some(resp) = reject_without_ai(&req, config)? {
+        return Ok(resp);
+    }
+    let tenant = match Tenant::resolve(&req, &env, config) {
+        Ok(tenant) => tenant,
+        Err(message) => return json_error(&message, 400),
+    };
+
+    if req.method() == Method::Post && path == "/input"{
+        return admission::guarded(&env.clone(), config, input(req, env, _ctx, config, &tenant, timing)).await;
+    }
+    if let (Method::Get, Some(slug)) = (req.method(), path.strip_prefix("/trip/by-slug/")) {
+        return trip_by_slug(&req, env, slug.to_string(), config, &tenant, timing).await;
+    }
+    if let Some((trip_id, action)) = path.strip_prefix("/trip/").and_then(|p| p.split_once('/')) {
+        let trip_id = trip_id.to_string();
+        match (req.method(), action) {
+            (Method::Get, "plan") => return get_plan(&req, env, trip_id, &tenant, timing).await,
+            (Method::Get, "plan.json") => return download_plan(env, trip_id, &tenant, timing).await,
+            (Method::Get, "calendar.ics") => return calendar_ics(&req, env, trip_id, &tenant, timing).await,
+            (Method::Get, "plan/validate") => return validate_plan(env, trip_id, &tenant, timing).await,
+            (Method::Get, "map.geojson") => return map_geojson(env, trip_id, config, &tenant, timing).await,
+            (Method::Get, "plan/diff") => return diff_plan(&req, env, trip_id, &tenant, timing).await,
+            (Method::Post, "plan/cancel") => return cancel_generation(env, trip_id, config, &tenant, timing).await,
+            (Method::Post, "plan/extend") => return extend_plan(req, env, trip_id, config, &tenant, timing).await,
+            (Method::Post, "plan/feasibility") => return check_feasibility(env, trip_id, config, &tenant, timing).await,
+            (Method::Post, "status") => return set_status(req, env, trip_id, config, &tenant, timing).await,
+            (Method::Post, "regenerate") => return regenerate_plan(&req, env, trip_id, config, &tenant, timing).await,
+            (Method::Post, "title/regenerate") => return regenerate_title(env, trip_id, config, &tenant, timing).await,
+            (Method::Post, "remix") => return remix_trip(req, env, trip_id, config, &tenant, timing).await,
+            (Method::Post, "packing-list") => return packing_list(&req, env, trip_id, config, &tenant, timing).await,
+            (Method::Post, "messages/import") => return import_messages(req, env, trip_id, config, &tenant, timing).await,
+            (Method::Get, "summary") => return chat_summary(env, trip_id, config, &tenant, timing).await,
+            (Method::Get, "messages") => return messages_since(&req, env, trip_id, &tenant, timing).await,
+            (Method::Get, "messages/pinned") => return pinned_messages(env, trip_id, &tenant, timing).await,
+            (Method::Get, action) if action.starts_with("message/") => return get_message(env, trip_id, action, &tenant, timing).await,
+            (Method::Post, action) if action.starts_with("plan/day/") => return day_alternatives(&req, env, trip_id, action, config, &tenant, timing).await,
+            (Method::Post, action) if action.starts_with("message/") => return pin_message(env, trip_id, action, &tenant, timing).await,
+            (Method::Post, "chat/retry") => {
+                if !config.chat_enabled {
+                    return json_error("Chat is disabled on this deployment", 403);
+                }
+                return retry_chat(env, trip_id, config, &tenant, timing).await;
+            }
+            _ => return json_error("Not Found", 404),
+        }
+    }
+    if req.method() == Method::Get && path.starts_with("/trip/") {
+        let trip_id = path.trim_start_matches("/trip/").to_string();
+        let accept_header = req.headers().get("Accept").unwrap_or_default().unwrap_or_default();
+        if accept_header.contains("text/html") {
+            let html = include_str!("../public/chat.html");
+            if !config.chat_enabled {
+                return Response::from_html(html.replacen("<body>", "<body data-chat-disabled>", 1));
+            }
+            return Response::from_html(html);
+        } else {
+            if tenant.is_scoped() && !timing.measure("db", db::trip_exists(trip_id.clone(), &tenant, env.clone())).await? {
+                return json_error("Not Found", 404);
+            }
+            let trip = timing.measure("do", get_trip(env.clone(), trip_id, config)).await?;
+            if query_param(&req, "preview").as_deref() != Some("true") {
+                return Ok(trip);
+            }
+            return trip_preview(trip, config).await;
+        }
+    }
+    if req.method() == Method::Delete && path.starts_with("/trip/") {
+        let trip_id = path.trim_start_matches("/trip/").to_string();
+        return delete_trip(env, trip_id, config, &tenant, timing).await;
+    }
+    if req.method() == Method::Patch && path.starts_with("/trip/") {
+        let trip_id = path.trim_start_matches("/trip/").to_string();
+        return update_trip(req, env, trip_id, config, &tenant, timing).await;
+    }
+    if req.method() == Method::Post && path.starts_with("/trip/") {
+        if !config.chat_enabled {
+            return json_error("Chat is disabled on this deployment", 403);
+        }
+        return chat(req, env, _ctx, config, &tenant, timing).await
+    }
+    if req.method() == Method::Get && path.starts_with("/chat/") {
+        let trip_id = path.trim_start_matches("/chat/").to_string();
+        let envelope = query_param(&req, "envelope").as_deref() == Some("true");
+        let limit = query_param(&req, "limit").and_then(|v| v.parse::<usize>().ok());
+        let offset = query_param(&req, "offset").and_then(|v| v.parse::<usize>().ok()).unwrap_or(0);
+        let tz = query_param(&req, "tz");
+        let locale = query_param(&req, "locale");
+        if timing.measure("db", check_if_messages(trip_id.clone(), &tenant, env.clone())).await? {
+            let (messages, warnings) = timing.measure("db", db::list_messages(trip_id, false, None, &tenant, env)).await?;
+            let messages = messages
+                .into_iter()
+                .map(|m| {
+                    if tz.is_none() && locale.is_none() {
+                        return serde_json::json!([m.message, m.role, m.created_at, m.pinned, m.id]);
+                    }
+                    let formatted = time::format_timestamp(&m.created_at.to_string(), locale.as_deref(), tz.as_deref());
+                    serde_json::json!({
+                        "id": m.id,
+                        "message": m.message,
+                        "role": m.role,
+                        "created_at": m.created_at,
+                        "created_at_formatted": formatted,
+                        "pinned": m.pinned
+                    })
+                })
+                .collect();
+            let page = Paginated::page(messages, limit, offset);
+            let response = if envelope {
+                let mut body = serde_json::to_value(&page)?;
+                if !warnings.is_empty() {
+                    body["warnings"] = serde_json::json!(warnings);
+                }
+                Response::from_json(&body)?
+            } else {
+                Response::ok(serde_json::to_string(&page.data)?)?
+            };
+            if warnings.is_empty() {
+                return Ok(response);
+            }
+            let headers = response.headers().clone();
+            headers.set("X-Skipped-Rows", &warnings.len().to_string())?;
+            return Ok(response.with_headers(headers));
+        }
+        if envelope {
+            return Response::from_json(&Paginated::<serde_json::Value>::page(vec![], limit, offset));
+        }
+        return Response::ok("No messages yet");
+    }
+    if req.method() == Method::Post && path == "/trips/merge" {
+        if let Some(resp) = reject_unauthorized(&req, &env)? {
+            return Ok(resp);
+        }
+        return merge(req, env, config, &tenant, timing).await;
+    }
+    if req.method() == Method::Post && path == "/trips/tag" {
+        if let Some(resp) = reject_unauthorized(&req, &env)? {
+            return Ok(resp);
+        }
+        return tag_trips(req, env, &tenant, timing).await;
+    }
+    if path == "/trips/regenerate" || path.starts_with("/trips/regenerate/") || path.starts_with("/jobs/") {
+        if let Some(resp) = reject_unauthorized(&req, &env)? {
+            return Ok(resp);
+        }
+        let job_id = path.strip_prefix("/jobs/").or_else(|| path.strip_prefix("/trips/regenerate/"));
+        return match (req.method(), job_id) {
+            (Method::Post, None) => regenerate_batch(req, env, &_ctx, config, &tenant, timing).await,
+            (Method::Get, Some(job_id)) => job_status(env, job_id.to_string(), &tenant, timing).await,
+            _ => json_error("Not Found", 404),
+        };
+    }
+    if path == "/admin/stats" {
+        if let Some(resp) = reject_unauthorized(&req, &env)? {
+            return Ok(resp);
+        }
+        if req.method() != Method::Get {
+            return json_error("Not Found", 404);
+        }
+        return admin_stats(&req, env, config, timing).await;
+    }
+    if let Some(rest) = path.strip_prefix("/admin/trip/") {
+        if let Some(resp) = reject_unauthorized(&req, &env)? {
+            return Ok(resp);
+        }
+        return match (req.method(), rest.split_once('/')) {
+            (Method::Get, Some((trip_id, "do-state"))) => do_state(env, trip_id.to_string(), config, &tenant, timing).await,
+            (Method::Post, Some((trip_id, "reconcile"))) => reconcile_trip(env, trip_id.to_string(), config, &tenant, timing).await,
+            _ => json_error("Not Found", 404),
+        };
+    }
+    if req.method() == Method::Post && path == "/compare" {
+        return compare_destinations(req, env, config, timing).await;
+    }
+    if req.method() == Method::Post && path == "/plan/combine" {
+        return combine_plans(req, env, config, &tenant, timing).await;
+    }
+    if req.method() == Method::Get && path == "/trips" {
+        return list_trips(&req, env, &tenant, timing).await;
+    }
+    if req.method() == Method::Get && path == "/trips/count" {
+        return count_trips(&req, env, &tenant, timing).await;
+    }
+    if req.method() == Method::Get && path == "/destinations" {
+        return destinations(&req, env, &tenant, timing).await;
+    }
+    if req.method() == Method::Get && path == "/options" {
+        return options(config);
+    }
+    json_error("Not Found", 404)
+}
+
+/// The JSON body accepted by `POST /trips/merge`.
+///
+/// # Fields
+/// * `source` - The id of the trip whose messages are moved and which is soft-deleted.
+/// * `target` - The id of the trip that receives the source's history.
+/// * `include_plans` - When `true`, the source's plans are moved too and become extra
+///   plan versions on the target. Defaults to `false`.
+#[derive(Deserialize)]
+struct MergeRequest {
+    source: String,
+    target: String,
+    #[serde(default)]
+    include_plans: bool,
+}
+
+/// Handles `POST /trips/merge`, combining two trips into one.
+///
+/// # Arguments
+/// * `req` - The HTTP request whose JSON body is a [`MergeRequest`].
+/// * `env` - The `Env` object providing access to the D1 database.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+///
+/// # Returns
+/// On success, a JSON response of the form:
+/// ```json
+/// { "merged": true, "source": "...", "target": "...", "messages_moved": 4 }
+/// ```
+///
+/// # Errors
+/// - `400 Bad Request` if the body is not valid JSON or `source` equals `target`.
+/// - `404 Not Found` if either trip does not exist or was already soft-deleted.
+/// - `502 Bad Gateway` if the source's durable object could not be cleared, as for
+///   [`delete_trip`]. The merge is already stored by then.
+/// - Propagates database errors from `db::merge_trips`.
+///
+/// # Notes
+/// - Trips are not yet associated with an owner, so both trips cannot be checked to belong
+///   to the same one. Until they can, the route is an admin route (see
+///   [`reject_unauthorized`]); once ownership exists it must be validated here before any
+///   data is moved.
+async fn merge(mut req: Request, env: Env, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Ok(body) = req.json::<MergeRequest>().await else {
+        return json_error("Body must be JSON with `source` and `target` trip ids", 400);
+    };
+    if body.source == body.target {
+        return json_error("source and target must be different trips", 400);
+    }
+    for id in [&body.source, &body.target] {
+        if !timing.measure("db", db::trip_exists(id.clone(), tenant, env.clone())).await? {
+            return json_error(&format!("Trip not found: {id}"), 404);
+        }
+    }
+    let moved = timing.measure("db", db::merge_trips(body.source.clone(), body.target.clone(), body.include_plans, tenant, env.clone()))
+        .await
+        .map_err(|e| Error::RustError(format!("db::merge_trips failed: {e}")))?;
+    invalidate_summary(&env, body.target.clone(), config, tenant, timing).await?;
+    // The source is soft-deleted, so its session must go too; otherwise it could still be
+    // fetched and chatted with.
+    let mut resp = timing.measure("do", reset_trip_session(env.clone(), body.source.clone(), config)).await?;
+    if resp.status_code() != 200 {
+        let body = resp.text().await.unwrap_or_else(|_| "<no body>".into());
+        return json_error(&format!("Trips merged, but clearing the source session failed: {body}"), 502);
+    }
+    Response::from_json(&serde_json::json!({
+        "merged": true,
+        "source": body.source,
+        "target": body.target,
+        "messages_moved": moved
+    }))
+}
+
+/// The most trips a single `POST /trips/tag` may name.
+const MAX_BATCH_TAG: usize = 50;
+
+/// The JSON body accepted by `POST /trips/tag`.
+///
+/// # Fields
+/// * `ids` - The trips to change; duplicates are ignored.
+/// * `add` - Tags to add to every trip (see [`tags::normalize`]).
+/// * `remove` - Tags to remove from every trip. A tag in both lists ends up on the trips.
+#[derive(Deserialize)]
+struct TagRequest {
+    ids: Vec<String>,
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+/// Handles `POST /trips/tag`, adding and removing tags on several trips at once.
+///
+/// The tags are normalized, each trip's new tags are worked out with [`tags::apply`], and
+/// all changed trips are written in a single batch (see [`db::set_trip_tags`]), so either
+/// every change is stored or none is.
+///
+/// # Arguments
+/// * `req` - The HTTP request whose JSON body is a [`TagRequest`].
+/// * `env` - The `Env` object providing access to the D1 database.
+/// * `tenant` - The tenant of the request; only its trips can be tagged.
+/// * `timing` - Collects the time spent per phase for the `Server-Timing` header.
+///
+/// # Returns
+/// `200 OK` with one result per trip, in the order the trips were named:
+/// ```json
+/// { "updated": 1, "results": [
+///     { "trip_id": "...", "ok": true, "tags": ["family", "summer-2025"] },
+///     { "trip_id": "...", "ok": false, "error": "Trip not found" }
+/// ] }
+/// ```
+/// A trip that does not exist, belongs to another tenant, or would carry more than
+/// [`tags::MAX_TAGS_PER_TRIP`] tags is reported as failed and left unchanged; the others
+/// are still updated.
+///
+/// # Errors
+/// - `400 Bad Request` if the body is not valid JSON, names no trips or more than
+///   [`MAX_BATCH_TAG`], has nothing to add or remove, or holds an invalid tag.
+/// - Propagates database errors from `db::get_trip_tags` and `db::set_trip_tags`.
+async fn tag_trips(mut req: Request, env: Env, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Ok(body) = req.json::<TagRequest>().await else {
+        return json_error("Body must be JSON with an `ids` array and `add` and/or `remove` tag arrays", 400);
+    };
+    let mut ids = body.ids;
+    let mut seen = std::collections::HashSet::new();
+    ids.retain(|id| seen.insert(id.clone()));
+    if ids.is_empty() {
+        return json_error("`ids` must name at least one trip", 400);
+    }
+    if ids.len() > MAX_BATCH_TAG {
+        return json_error(&format!("At most {MAX_BATCH_TAG} trips can be tagged at once"), 400);
+    }
+    let (add, remove) = match (tags::normalize_all(&body.add), tags::normalize_all(&body.remove)) {
+        (Ok(add), Ok(remove)) => (add, remove),
+        (Err(message), _) | (_, Err(message)) => return json_error(&message, 400),
+    };
+    if add.is_empty() && remove.is_empty() {
+        return json_error("`add` or `remove` must name at least one tag", 400);
+    }
+
+    let current: std::collections::HashMap<String, Vec<String>> = timing.measure("db", db::get_trip_tags(&ids, tenant, env.clone()))
+        .await
+        .map_err(|e| Error::RustError(format!("db::get_trip_tags failed: {e}")))?
+        .into_iter()
+        .collect();
+    let mut updates = Vec::new();
+    let results: Vec<serde_json::Value> = ids
+        .into_iter()
+        .map(|trip_id| {
+            let Some(current) = current.get(&trip_id) else {
+                return serde_json::json!({ "trip_id": trip_id, "ok": false, "error": "Trip not found" });
+            };
+            match tags::apply(current, &add, &remove) {
+                Ok(new_tags) => {
+                    let result = serde_json::json!({ "trip_id": trip_id, "ok": true, "tags": new_tags });
+                    if new_tags != *current {
+                        updates.push((trip_id, new_tags));
+                    }
+                    result
+                }
+                Err(message) => serde_json::json!({ "trip_id": trip_id, "ok": false, "error": message }),
+            }
+        })
+        .collect();
+    timing.measure("db", db::set_trip_tags(&updates, tenant, env.clone()))
+        .await
+        .map_err(|e| Error::RustError(format!("db::set_trip_tags failed: {e}")))?;
+    Response::from_json(&serde_json::json!({ "updated": updates.len(), "results": results }))
+}
+
+/// The JSON body accepted by `POST /trip/{trip_id}/status`.
+///
+/// # Fields
+/// * `status` - The new status, one of `planning`, `booked`, `completed` or `cancelled`.
+/// * `force` - Allows transitions that [`TripStatus::can_transition_to`] rejects, such as
+///   reopening a completed trip. Defaults to `false`.
+#[derive(Deserialize)]
+struct StatusRequest {
+    status: String,
+    #[serde(default)]
+    force: bool,
+}
+
+/// Handles `POST /trip/{trip_id}/status`, moving a trip to another lifecycle stage.
+///
+/// # Arguments
+/// * `req` - The request, with a JSON body such as `{ "status": "booked" }`.
+/// * `env` - The `Env` object providing access to D1 and the durable object.
+/// * `trip_id` - The unique identifier of the trip.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+/// * `timing` - Collects the time spent per phase for the `Server-Timing` header.
+///
+/// # Behavior
+/// Updates `trips.status` in D1 and the copy held by the trip's durable object, so both
+/// `GET /trips` and `GET /trip/{trip_id}` report the new status.
+///
+/// # Returns
+/// The updated trip as JSON: `{ "id", "destination", "days", "status", "title" }`.
+///
+/// # Errors
+/// - `400 Bad Request` if the body is not valid JSON or the status is unknown.
+/// - `404 Not Found` if the trip does not exist.
+/// - `409 Conflict` if the transition is not allowed and `force` is not set.
+async fn set_status(mut req: Request, env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Ok(body) = req.json::<StatusRequest>().await else {
+        return json_error("Expected a JSON body with status", 400);
+    };
+    let Some(status) = TripStatus::parse(&body.status) else {
+        return json_error(&format!("status must be one of: {}", TripStatus::names()), 400);
+    };
+    let Some(mut trip) = timing.measure("db", db::find_trip(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
+    };
+    if !body.force && !trip.status.can_transition_to(status) {
+        return json_error(&format!("Cannot change status from {} to {} without force", trip.status.as_str(), status.as_str()), 409);
+    }
+    if let Some(resp) = store_status(&env, &trip_id, status, config, tenant, timing).await? {
+        return Ok(resp);
+    }
+    trip.status = status;
+    Response::from_json(&trip)
+}
+
+/// Stores a trip's new status in D1 and in its session (see [`session`]).
+///
+/// # Returns
+/// `None` once both are updated, or the `500` response to send when the durable object
+/// rejected the update.
+async fn store_status(env: &Env, trip_id: &str, status: TripStatus, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Option<Response>> {
+    timing.measure("db", db::update_trip_status(trip_id.to_string(), status, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::update_trip_status failed: {e}")))?;
+
+    let mut resp = timing.measure("do", session::store(env, config)?.set_status(trip_id, status)).await?;
+    if resp.status_code() != 200 {
+        let body = resp.text().await.unwrap_or_else(|_| "<no body>".into());
+        return Ok(Some(json_error(&format!("failed to update trip session: {body}"), 500)?));
+    }
+    Ok(None)
+}
+
+/// Starts, cancels or finishes the plan generation recorded in a trip's session, like
+/// `POST /generation/{action}` on its durable object (see [`TripSession::fetch`]).
+async fn generation_request(env: &Env, trip_id: &str, action: &str, config: &Config, timing: &ServerTiming) -> Result<Response> {
+    timing.measure("do", session::store(env, config)?.generation(trip_id, action)).await
+}
+
+/// Marks a plan generation for the trip as in progress, so it can be cancelled with
+/// `POST /trip/{trip_id}/plan/cancel` until [`finish_generation`] is called.
+async fn begin_generation(env: &Env, trip_id: &str, config: &Config, timing: &ServerTiming) -> Result<()> {
+    let mut resp = generation_request(env, trip_id, "start", config, timing).await?;
+    if resp.status_code() != 200 {
+        let body = resp.text().await.unwrap_or_else(|_| "<no body>".into());
+        return Err(Error::RustError(format!("failed to start plan generation: {body}")));
+    }
+    Ok(())
+}
+
+/// Ends the trip's plan generation and reports whether it was cancelled meanwhile.
+///
+/// The durable object handles one request at a time, so a cancel either arrives before
+/// this call (and the caller discards the plan) or after it (and is refused as there is
+/// nothing left to cancel). A plan is therefore never both written and reported cancelled.
+async fn finish_generation(env: &Env, trip_id: &str, config: &Config, timing: &ServerTiming) -> Result<bool> {
+    let mut resp = generation_request(env, trip_id, "finish", config, timing).await?;
+    if resp.status_code() != 200 {
+        let body = resp.text().await.unwrap_or_else(|_| "<no body>".into());
+        return Err(Error::RustError(format!("failed to finish plan generation: {body}")));
+    }
+    Ok(resp.json::<GenerationState>().await?.cancelled)
+}
+
+/// Handles a generation that was cancelled before its plan was written: the trip is set
+/// to `cancelled` and `409 Conflict` is returned to the request that started it.
+async fn cancelled_generation(env: &Env, trip_id: &str, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response> {
+    if let Some(resp) = store_status(env, trip_id, TripStatus::Cancelled, config, tenant, timing).await? {
+        return Ok(resp);
+    }
+    json_error("Plan generation was cancelled", 409)
+}
+
+/// Handles `POST /trip/{trip_id}/plan/cancel`, cancelling a running regeneration or extension.
+///
+/// The generation keeps running, but once it finishes its plan is discarded instead of
+/// stored and the trip is set to `cancelled` (see [`finish_generation`]).
+///
+/// # Returns
+/// `202 Accepted` with `{ "cancelled": true }`.
+///
+/// # Errors
+/// - `404 Not Found` if the trip does not exist.
+/// - `409 Conflict` if no generation is in progress, including one that finished just
+///   before the cancel arrived; its plan has then been stored.
+/// - Propagates database and durable object errors.
+async fn cancel_generation(env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    if !timing.measure("db", db::trip_exists(trip_id.clone(), tenant, env.clone())).await? {
+        return json_error("Trip not found", 404);
+    }
+    let resp = generation_request(&env, &trip_id, "cancel", config, timing).await?;
+    match resp.status_code() {
+        200 => Ok(Response::from_json(&serde_json::json!({ "cancelled": true }))?.with_status(202)),
+        409 => json_error("No plan generation is in progress", 409),
+        status => json_error(&format!("failed to cancel plan generation: status {status}"), 500),
+    }
+}
+
+/// Reads the filters shared by `GET /trips` and `GET /trips/count` from the query string.
+///
+/// # Returns
+/// The filter, or the message for a `400 Bad Request` when `status` is not a known status.
+fn trip_filter(req: &Request) -> std::result::Result<db::TripFilter, String> {
+    let status = match query_param(req, "status") {
+        Some(value) => Some(TripStatus::parse(&value).ok_or_else(|| format!("status must be one of: {}", TripStatus::names()))?),
+        None => None,
+    };
+    let q = query_param(req, "q").map(|q| q.trim().to_string()).filter(|q| !q.is_empty());
+    Ok(db::TripFilter { status, q })
+}
+
+/// Handles `GET /trips`, listing live trips.
+///
+/// # Arguments
+/// * `req` - The request; optional `status` and `q` query parameters narrow the list (see
+///   [`trip_filter`]).
+/// * `env` - The `Env` object providing access to the D1 database.
+///
+/// # Returns
+/// A JSON array of trips in creation order, e.g.
+/// ```json
+/// [{ "id": "...", "destination": "Paris", "days": 3, "status": "booked", "title": "Three Days of Art in Paris" }]
+/// ```
+///
+/// # Errors
+/// - `400 Bad Request` if `status` is not a known status.
+/// - Propagates database errors from `db::list_trips`.
+async fn list_trips(req: &Request, env: Env, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let filter = match trip_filter(req) {
+        Ok(filter) => filter,
+        Err(message) => return json_error(&message, 400),
+    };
+    let trips = timing.measure("db", db::list_trips(&filter, tenant, env)).await?;
+    Response::from_json(&trips)
+}
+
+/// Handles `GET /trips/count`, counting the trips `GET /trips` would list.
+///
+/// Accepts the same `status` and `q` filters as `list_trips` and returns `{ "count": n }`,
+/// computed with `SELECT COUNT(*)` so no rows are fetched.
+///
+/// # Errors
+/// - `400 Bad Request` if `status` is not a known status.
+/// - Propagates database errors from `db::count_trips`.
+async fn count_trips(req: &Request, env: Env, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let filter = match trip_filter(req) {
+        Ok(filter) => filter,
+        Err(message) => return json_error(&message, 400),
+    };
+    let count = timing.measure("db", db::count_trips(&filter, tenant, env)).await?;
+    Response::from_json(&serde_json::json!({ "count": count }))
+}
+
+/// Handles `GET /readyz`, reporting whether the worker's dependencies are usable.
+///
+/// Unlike `/livez`, which only shows the worker is running, this checks:
+/// - `config`: the settings are valid (see [`Config::from_env`]); `config` is the outcome,
+///   with the problems found when it failed.
+/// - `d1`: the `TripPlanner` database answers a trivial query (`db::ping`).
+/// - `ai`: the AI service is configured (`ai::check_configured`); no model call is made.
+///
+/// # Returns
+/// `200` with `{ "ready": true, "checks": { "config": "ok", "d1": "ok", "ai": "ok" } }` when
+/// every check passes, otherwise `503` with the failing checks' error messages in place of
+/// `"ok"`.
+async fn readyz(env: Env, config: std::result::Result<&Config, String>, timing: &ServerTiming) -> Result<Response>{
+    let ai = ai::check_configured(&env, config.as_ref().is_ok_and(|config| config.mock_ai));
+    let config = config.map(|_| ()).map_err(|problems| Error::RustError(format!("Invalid configuration: {problems}")));
+    let d1 = timing.measure("db", db::ping(env.clone())).await;
+    let ready = config.is_ok() && d1.is_ok() && ai.is_ok();
+    let resp = Response::from_json(&serde_json::json!({
+        "ready": ready,
+        "checks": { "config": check_status(&config), "d1": check_status(&d1), "ai": check_status(&ai) }
+    }))?;
+    Ok(if ready { resp } else { resp.with_status(503) })
+}
+
+/// Reports a health or warmup check as `"ok"` or its error message.
+fn check_status(check: &Result<()>) -> String {
+    match check {
+        Ok(()) => "ok".to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Exercises the D1 and AI paths so the first real request after a cold start is not the
+/// one paying for their setup: a `SELECT 1` (`db::ping`) and a trivial prompt (`ai::ping`).
+///
+/// # Returns
+/// The result of each call, D1 first.
+async fn warm_up(env: &Env, config: &Config, timing: &ServerTiming) -> (Result<()>, Result<()>) {
+    let d1 = timing.measure("db", db::ping(env.clone())).await;
+    let ai = timing.measure("ai", ai::ping(env, config)).await;
+    (d1, ai)
+}
+
+/// Handles `GET /warmup`, running [`warm_up`] on demand.
+///
+/// The same warmup runs on the worker's cron trigger (see [`scheduled`]). Both are off
+/// unless `WARMUP_ENABLED` is set, since each run makes a real AI call.
+///
+/// # Returns
+/// `200` with `{ "warm": true, "checks": { "d1": "ok", "ai": "ok" } }` when both calls
+/// succeed, otherwise `503` with the failing calls' error messages in place of `"ok"`. The
+/// time each took is in the `Server-Timing` header.
+///
+/// # Errors
+/// - `403 Forbidden` when `WARMUP_ENABLED` is off.
+async fn warmup(env: Env, config: &Config, timing: &ServerTiming) -> Result<Response>{
+    if !config.warmup_enabled {
+        return json_error("Warmup is disabled on this deployment", 403);
+    }
+    let (d1, ai) = warm_up(&env, config, timing).await;
+    let warm = d1.is_ok() && ai.is_ok();
+    let resp = Response::from_json(&serde_json::json!({
+        "warm": warm,
+        "checks": { "d1": check_status(&d1), "ai": check_status(&ai) }
+    }))?;
+    Ok(if warm { resp } else { resp.with_status(503) })
+}
+
+/// Handles `GET /options`, returning the values and limits `POST /input` and
+/// `POST /trip/{trip_id}/remix` check their fields against.
+///
+/// # Arguments
+/// * `config` - The deployment settings, for `MAX_INTERESTS` and `CLAMP_DAYS`.
+///
+/// # Returns
+/// A JSON body such as:
+/// ```json
+/// {
+///     "max_days": 30,
+///     "clamp_days": false,
+///     "units": ["metric", "imperial"],
+///     "currencies": ["USD", "EUR", "GBP"],
+///     "max_interests": 10,
+///     "interest_max_chars": 40,
+///     "remix": { "style_max_chars": 40, "budget_max_chars": 40 }
+/// }
+/// ```
+/// With `clamp_days` on, longer trips are accepted and planned for `max_days` days.
+fn options(config: &Config) -> Result<Response>{
+    Response::from_json(&serde_json::json!({
+        "max_days": MAX_TRIP_DAYS,
+        "clamp_days": config.clamp_days,
+        "units": units::Units::ALL.map(units::Units::as_str),
+        "currencies": units::CURRENCIES,
+        "max_interests": config.max_interests,
+        "interest_max_chars": MAX_INTEREST_CHARS,
+        "remix": { "style_max_chars": REMIX_OPTION_MAX_CHARS, "budget_max_chars": REMIX_OPTION_MAX_CHARS },
+    }))
+}
+
+/// Handles `GET /destinations`, listing destinations with their trip counts.
+///
+/// # Arguments
+/// * `req` - The request; an optional `limit` query parameter keeps only the top N destinations.
+/// * `env` - The `Env` object providing access to the D1 database.
+///
+/// # Returns
+/// A JSON array ordered from most to least popular, e.g.
+/// ```json
+/// [{ "destination": "Paris", "trips": 5 }, { "destination": "Tokyo", "trips": 3 }]
+/// ```
+///
+/// # Errors
+/// - `400 Bad Request` if `limit` is not a positive number.
+/// - Propagates database errors from `db::destination_counts`.
+async fn destinations(req: &Request, env: Env, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let limit = match query_param(req, "limit") {
+        Some(raw) => match raw.parse::<u32>() {
+            Ok(limit) if limit > 0 => Some(limit),
+            _ => return json_error("limit must be a positive number", 400),
+        },
+        None => None,
+    };
+    let counts = timing.measure("db", db::destination_counts(limit, tenant, env)).await?;
+    let body: Vec<_> = counts
+        .into_iter()
+        .map(|(destination, trips)| serde_json::json!({ "destination": destination, "trips": trips }))
+        .collect();
+    Response::from_json(&body)
+}
+
+/// Handles an HTTP request to facilitate a chat interaction between a user and an AI.
+///
+/// # Arguments
+/// * `req` - The HTTP request that contains the form data and any necessary metadata.
+/// * `env` - The `Env` object, providing access to environment variables and external services.
+/// * `ctx` - Context used to schedule the background analytics write for the chat turn.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+///
+/// # Returns
+/// Returns an `Ok(Response)` containing the AI's chat response if successful. Returns an error if
+/// any required fields are missing or if an operation fails.
+///
+/// # Behavior
+/// 1. Extracts the form data from the request, specifically looking for a `message` field.
+///    - If the `message` field is missing, returns a `400 Missing field` error.
+///    - If `STRICT_FORM` is enabled and any other field is present, returns `400` listing it.
+///    - If the body is not `multipart/form-data` or `application/x-www-form-urlencoded`, returns `415`.
+/// 2. Extracts the `trip_id` from the request path by removing the "/trip/" prefix.
+/// 3. Creates a user message in the database by calling `create_message_with_metadata`, associating it with the trip and storing it as a "User" message.
+///    With `AUTO_DETECT_LANG` on, the message's language is detected (see [`detect_language`]),
+///    kept in its `metadata` as `{ "language": "es" }`, and the reply is asked for in it.
+///    - Returns an error if the database operation fails.
+/// 4. Retrieves the current state of the trip by calling `get_trip`.
+/// 5. Checks whether messages for the trip already exist by calling `check_if_messages`.
+///    - If no prior messages are found, initiates an AI response with an empty message history.
+///    - If messages are found, fetches the message history and includes it in the AI response generation.
+/// 6. Delegates to the AI system by calling `ai::chat` to generate a response based on the message history and the user's message.
+///    The plan is included in the prompt according to `PLAN_CONTEXT` (see `chat_plan_context`).
+///    The call's latency is recorded via `analytics::record` when analytics are enabled.
+///    With `?verbose=true`, `ai::chat_verbose` is used instead so the model cites the plan
+///    sections it relied on.
+/// 7. Stores the AI response as a message in the database as an "AI" message. Any references
+///    are kept in the message's `metadata` column as `{ "references": [...] }`.
+///    - Returns an error if the database operation fails during this step.
+/// 8. Returns an `Ok(Response)` containing the AI-generated response to the client: plain text
+///    by default, or `{ "reply": "...", "references": [...] }` with `?verbose=true`.
+///    Clients accepting `application/json` instead get `201 Created` with the stored message
+///    and a `Location` header (see [`wants_created_message`] and [`created_message_response`]).
+///
+/// With `?stream=true`, steps 6 to 8 are replaced by `ai::chat_stream`: the reply is sent as
+/// `text/event-stream` while it is generated and stored once it is complete (see
+/// [`stream_chat_response`]). Combining it with `?verbose=true` is a `400`. While
+/// `SANITIZE_AI_HTML` is on, `stream` is ignored, since only a whole reply can be sanitized.
+///
+/// # Errors
+/// This function can return errors in the following scenarios:
+/// - The "message" field is missing from the request's form data.
+/// - The plan and question are too large for the prompt budget (`413`, see `ai_error_response`).
+/// - Database operations (`create_message`, `get_trip`, `check_if_messages`) fail.
+/// - AI response generation (`ai::chat`) fails.
+///
+/// # Example
+/// ```
+/// // Example HTTP request with "message" in form data
+/// let req = Request::new().form_data("message", "Hello, AI!");
+/// let response = chat(req, env, _ctx).await;
+/// ```
+///
+/// This example demonstrates handling a user's "Hello, AI!" message in chat and returning the AI's response.
+async fn chat(mut req: Request, env: Env, ctx: Context, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    if let Some(resp) = check_content_type(&req, &FORM_CONTENT_TYPES)? {
+        return Ok(resp);
+    }
+    let (form, rejected) = check_form_fields(config, req.form_data().await?, &["message"])?;
+    if let Some(resp) = rejected {
+        return Ok(resp);
+    }
+    let Some(FormEntry::Field(message)) = form.get("message") else {
+        return json_error("Missing field: message", 400);
+    };
+    debug::log_body(config, "chat request", &format!("message={message}"));
+    let model = match requested_model(&req, &env) {
+        Ok(model) => model,
+        Err(message) => return json_error(&message, 400),
+    };
+    let path = req.path();
+    let trip_id = path.trim_start_matches("/trip/").to_string();
+    if tenant.is_scoped() && !timing.measure("db", db::trip_exists(trip_id.clone(), tenant, env.clone())).await? {
+        return json_error("Not Found", 404);
+    }
+    let language = detect_language(config, &message);
+    let metadata = language.map(|l| serde_json::json!({ "language": l.code }).to_string());
+    timing.measure("db", db::create_message_with_metadata(trip_id.clone(), &message, MessageRole::User, metadata.as_deref(), false, config, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::create_message failed: {e}")))?;
+    let mut trip = timing.measure("do", get_trip(env.clone(), trip_id.clone(), config)).await?;
+    let trip_text = trip.text().await?;
+    let trip_info = serde_json::from_str::<TripInit>(&trip_text).ok();
+    let trip_text = match &trip_info {
+        Some(info) => info.plan_context()?,
+        None => trip_text,
+    };
+    let record_chat = |started: u64| {
+        if let Some(info) = &trip_info {
+            analytics::record(&env, &ctx, config, "chat", &info.destination, info.days, Date::now().as_millis() - started);
+        }
+    };
+    let units = timing.measure("db", db::get_trip_units(trip_id.clone(), tenant, env.clone())).await?;
+    let options = ai::ChatOptions { model: model.as_deref(), language: language.map(|l| l.name), units };
+    let verbose = query_param(&req, "verbose").as_deref() == Some("true");
+    if !timing.measure("db", check_if_messages(trip_id.clone(), tenant, env.clone())).await? {
+        let started = Date::now().as_millis();
+        let resp = match timing.measure("ai", ask_ai(&env, config, &trip_text, vec![("".to_string(), MessageRole::Unknown(String::new()), 0)], &message, verbose, &options)).await {
+            Ok(resp) => resp,
+            Err(e) => return ai_error_response(e),
+        };
+        record_chat(started);
+        return chat_response(resp, verbose);
+    }
+    let history = timing.measure("db", get_messages(trip_id.clone(), tenant, env.clone())).await?;
+    let trip_text = chat_plan_context(config, &trip_text, &history);
+    let started = Date::now().as_millis();
+    if query_param(&req, "stream").as_deref() == Some("true") && !config.sanitize_ai_html {
+        if verbose {
+            return json_error("stream=true cannot be combined with verbose=true", 400);
+        }
+        let pieces = match timing.measure("ai", ai::chat_stream(&env, config, trip_text, history, &message, &options)).await {
+            Ok(pieces) => pieces,
+            Err(e) => return ai_error_response(e),
+        };
+        let analytics_trip = trip_info.as_ref().map(|info| (info.destination.clone(), info.days));
+        let (env, config, tenant) = (env.clone(), config.clone(), tenant.clone());
+        return stream_chat_response(pieces, move |reply| async move {
+            if let Some((destination, days)) = analytics_trip {
+                analytics::record(&env, &ctx, &config, "chat", &destination, days, Date::now().as_millis() - started);
+            }
+            let refused = ai::is_refusal(&env, &reply);
+            let stored = db::create_message_with_metadata(trip_id.clone(), &reply, MessageRole::Ai, None, refused, &config, &tenant, env.clone()).await?;
+            if config.chat_summary {
+                schedule_summary_update(&env, &ctx, trip_id, config, tenant, message, reply);
+            }
+            let message_id = stored.meta()?.and_then(|meta| meta.last_row_id);
+            Ok(serde_json::json!({ "id": message_id, "refused": refused }))
+        });
+    }
+    let resp = match timing.measure("ai", ask_ai(&env, config, trip_text, history, &message, verbose, &options)).await {
+        Ok(resp) => resp,
+        Err(e) => return ai_error_response(e),
+    };
+    debug::log_body(config, "chat ai response", &resp.reply);
+    record_chat(started);
+    let metadata = match resp.references.is_empty() {
+        true => None,
+        false => Some(serde_json::json!({ "references": resp.references }).to_string()),
+    };
+    let stored = timing.measure("db", db::create_message_with_metadata(trip_id.clone(), &resp.reply, MessageRole::Ai, metadata.as_deref(), resp.refused, config, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::create_message failed: {e}")))?;
+    if config.chat_summary {
+        schedule_summary_update(&env, &ctx, trip_id.clone(), config.clone(), tenant.clone(), message, resp.reply.clone());
+    }
+    let message_id = stored.meta()?.and_then(|meta| meta.last_row_id);
+    if let (true, Some(message_id)) = (wants_created_message(&req, config), message_id) {
+        if let Some(created) = timing.measure("db", db::get_message(trip_id.clone(), message_id, tenant, env)).await? {
+            return created_message_response(&trip_id, created, resp);
+        }
+    }
+    chat_response(resp, verbose)
+}
+
+/// Folds a chat exchange into the trip's cached summary without delaying the response.
+///
+/// The update runs after the response is sent (`Context::wait_until`). Nothing is done
+/// when the trip has no cached summary yet: `GET /trip/{trip_id}/summary` builds it from
+/// the full history on first use. Failures are logged; they leave the previous summary
+/// in place, only missing this exchange. Two turns finishing at the same time can likewise
+/// drop one exchange from the summary, as the later write wins.
+fn schedule_summary_update(env: &Env, ctx: &Context, trip_id: String, config: Config, tenant: Tenant, question: String, reply: String) {
+    let env = env.clone();
+    ctx.wait_until(async move {
+        let updated = async {
+            let Some(summary) = db::get_trip_summary(trip_id.clone(), &tenant, env.clone()).await? else {
+                return Ok(());
+            };
+            let summary = ai::update_summary(&env, &config, &summary, &question, &reply).await?;
+            db::update_trip_summary(trip_id, Some(&summary), &tenant, env.clone()).await?;
+            Ok::<(), Error>(())
+        };
+        if let Err(e) = updated.await {
+            console_warn!("Failed to update the conversation summary: {e}");
+        }
+    });
+}
+
+/// Clears the trip's cached summary after its history changed other than by a chat turn
+/// (an import, a merge, a retry or a reset), so the next request rebuilds it.
+///
+/// Does nothing when `CHAT_SUMMARY` is off, as no summary is cached then.
+async fn invalidate_summary(env: &Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<()> {
+    if config.chat_summary {
+        timing.measure("db", db::update_trip_summary(trip_id, None, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::update_trip_summary failed: {e}")))?;
+    }
+    Ok(())
+}
+
+/// Handles `GET /trip/{trip_id}/summary`, summarizing the trip's conversation.
+///
+/// # Returns
+/// `{ "summary": "...", "cached": true }`. The summary is `""` when there are no messages.
+///
+/// # Caching
+/// - With `CHAT_SUMMARY` on, the summary kept up to date after every chat turn is returned
+///   straight from the trip (`"cached": true`). When there is none yet, it is computed from
+///   the whole history with `ai::summarize_history`, stored, and returned with `"cached": false`.
+/// - With `CHAT_SUMMARY` off, nothing is cached and every request summarizes the whole
+///   history (`"cached": false`).
+///
+/// # Errors
+/// - `404 Not Found` if the trip does not exist.
+/// - Propagates database and AI errors.
+async fn chat_summary(env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    if !timing.measure("db", db::trip_exists(trip_id.clone(), tenant, env.clone())).await? {
+        return json_error("Trip not found", 404);
+    }
+    let cache = config.chat_summary;
+    if cache {
+        if let Some(summary) = timing.measure("db", db::get_trip_summary(trip_id.clone(), tenant, env.clone())).await? {
+            return Response::from_json(&serde_json::json!({ "summary": summary, "cached": true }));
+        }
+    }
+    let history = timing.measure("db", get_messages(trip_id.clone(), tenant, env.clone())).await?;
+    let summary = timing.measure("ai", ai::summarize_history(&env, config, history)).await.map_err(|e| Error::RustError(format!("ai::summarize_history failed: {e}")))?;
+    if cache && !summary.is_empty() {
+        timing.measure("db", db::update_trip_summary(trip_id, Some(&summary), tenant, env)).await.map_err(|e| Error::RustError(format!("db::update_trip_summary failed: {e}")))?;
+    }
+    Response::from_json(&serde_json::json!({ "summary": summary, "cached": false }))
+}
+
+/// Answers an AI chat error the client can act on, and propagates any other.
+///
+/// A prompt over the budget even after trimming history (see `ai::is_prompt_too_large`)
+/// becomes `413 Payload Too Large` with guidance, rather than a generic `500`. An AI
+/// service that kept failing after its retries (see `ai::is_transient`) becomes
+/// `503 Service Unavailable` with the last error, so the client knows to try again later.
+fn ai_error_response(e: Error) -> Result<Response> {
+    if ai::is_prompt_too_large(&e) {
+        return json_error(&e.to_string(), 413);
+    }
+    if ai::is_transient(&e) {
+        return json_error(&e.to_string(), 503);
+    }
+    Err(e)
+}
+
+/// Stands in for the plan in chat prompts when `PLAN_CONTEXT=once` leaves it out.
+const PLAN_CONTEXT_OMITTED: &str = "(The full plan was shared at the start of this conversation; rely on the conversation so far.)";
+
+/// Chooses the plan text sent with a chat turn, following the `PLAN_CONTEXT` strategy.
+///
+/// - `always` (default): every turn includes the full plan. Token use grows with the plan
+///   size times the number of turns, but the model always sees the current plan.
+/// - `once`: only the first turn includes the plan; later turns send
+///   [`PLAN_CONTEXT_OMITTED`] instead. This saves the plan's tokens on every later turn, but
+///   stored messages do not contain the plan, so the model then only knows what earlier
+///   replies said about it and may miss details or later changes.
+///
+/// # Arguments
+/// * `config` - The deployment settings, providing `PLAN_CONTEXT`.
+/// * `plan` - The trip context built from the durable object.
+/// * `history` - The chat history, whose last entry is the question being answered.
+fn chat_plan_context<'a>(config: &Config, plan: &'a str, history: &[(String, MessageRole, i64)]) -> &'a str {
+    if config.plan_context_once && history.len() > 1 {
+        PLAN_CONTEXT_OMITTED
+    } else {
+        plan
+    }
+}
+
+/// Runs `ai::chat`, or `ai::chat_verbose` when the client asked for `?verbose=true`, and
+/// flags the reply when `ai::is_refusal` recognises it as a refusal. `options` carries the
+/// client's `?model=` (see [`requested_model`]), the question's language when it was
+/// detected (see [`detect_language`]) and the trip's units.
+async fn ask_ai(env: &Env, config: &Config, plan: &str, history: Vec<(String, MessageRole, i64)>, question: &String, verbose: bool, options: &ai::ChatOptions<'_>) -> Result<ai::ChatReply> {
+    let mut reply = if verbose {
+        ai::chat_verbose(env, config, plan, history, question, options).await?
+    } else {
+        ai::ChatReply::plain(ai::chat(env, config, plan, history, question, options).await?)
+    };
+    reply.refused = ai::is_refusal(env, &reply.reply);
+    Ok(reply)
+}
+
+/// Detects the language of a chat question when `AUTO_DETECT_LANG` is on, so the reply can
+/// be written in it (see [`language::detect`]).
+///
+/// # Returns
+/// `None` when detection is off or uncertain; the model then picks the language itself.
+fn detect_language(config: &Config, question: &str) -> Option<language::Language> {
+    if !config.auto_detect_lang {
+        return None;
+    }
+    let detected = language::detect(question);
+    if detected.is_none() {
+        console_log!("Could not detect the language of a chat message; replying without a language instruction");
+    }
+    detected
+}
+
+/// Returns `true` when a chat client should get the stored reply as a created resource
+/// (see [`created_message_response`]) rather than its text.
+///
+/// That is the case when the client accepts `application/json` and `CHAT_CREATED_JSON`
+/// (default `true`) is on. Form and HTML clients, which accept `*/*` or `text/html`, keep
+/// the plain-text reply.
+fn wants_created_message(req: &Request, config: &Config) -> bool {
+    let accept = req.headers().get("Accept").ok().flatten().unwrap_or_default();
+    accept.contains("application/json") && config.chat_created_json
+}
+
+/// Builds the `201 Created` response for a stored AI reply.
+///
+/// The body is the message resource `{ "id", "message", "role", "created_at", "pinned",
+/// "references", "refused" }`, and `Location` points at it
+/// (`/trip/{trip_id}/message/{message_id}`, served by [`get_message`]). Refusals carry the
+/// same `X-AI-Refusal: true` header as [`chat_response`].
+fn created_message_response(trip_id: &str, created: db::StoredMessage, reply: ai::ChatReply) -> Result<Response> {
+    let location = format!("/trip/{trip_id}/message/{}", created.id);
+    let mut body = serde_json::to_value(&created)?;
+    body["references"] = serde_json::json!(reply.references);
+    body["refused"] = serde_json::json!(reply.refused);
+    let mut resp = Response::from_json(&body)?.with_status(201);
+    resp.headers_mut().set("Location", &location)?;
+    if reply.refused {
+        resp.headers_mut().set("X-AI-Refusal", "true")?;
+    }
+    Ok(resp)
+}
+
+/// Handles `GET /trip/{trip_id}/message/{message_id}`, returning a single stored message.
+///
+/// # Returns
+/// `{ "id", "message", "role", "created_at", "pinned" }` as JSON.
+///
+/// # Errors
+/// - `404 Not Found` if the id is not a number or the trip has no such message.
+/// - Propagates database errors.
+async fn get_message(env: Env, trip_id: String, action: &str, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Some(message_id) = action.strip_prefix("message/").and_then(|id| id.parse::<i64>().ok()) else {
+        return json_error("Not Found", 404);
+    };
+    match timing.measure("db", db::get_message(trip_id, message_id, tenant, env)).await? {
+        Some(message) => Response::from_json(&message),
+        None => json_error("Message not found", 404),
+    }
+}
+
+/// Builds the response to a chat message: the reply as plain text by default, or
+/// `{ "reply": "...", "references": [...], "refused": false }` as JSON when `verbose` is set.
+///
+/// Refusals also carry an `X-AI-Refusal: true` header, so clients reading the plain-text
+/// reply can show a retry prompt.
+fn chat_response(reply: ai::ChatReply, verbose: bool) -> Result<Response> {
+    let refused = reply.refused;
+    let mut resp = if verbose {
+        Response::from_json(&reply)?
+    } else {
+        Response::ok(reply.reply)?
+    };
+    if refused {
+        resp.headers_mut().set("X-AI-Refusal", "true")?;
+    }
+    Ok(resp)
+}
+
+/// Builds the `text/event-stream` response of a `?stream=true` chat turn.
+///
+/// Every piece of the reply is sent as it arrives, as `data: {"response": "..."}`. Once
+/// the model is done, `finish` is called with the whole reply to store it, and its result
+/// is sent as a final `event: done` (e.g. `{"id": 42, "refused": false}`). If the model
+/// stream or `finish` fails, an `event: error` with `{"message": "..."}` ends the response
+/// instead, and a reply cut short is not stored. A client that disconnects early stops the
+/// stream, so its reply is not stored either.
+fn stream_chat_response<F, Fut>(pieces: ai::TextStream, finish: F) -> Result<Response>
+where
+    F: FnOnce(String) -> Fut + 'static,
+    Fut: Future<Output = Result<serde_json::Value>> + 'static,
+{
+    let events = futures_util::stream::unfold(Some((pieces, String::new(), finish)), |state| async move {
+        let (mut pieces, mut reply, finish) = state?;
+        let event = match pieces.next().await {
+            Some(Ok(piece)) => {
+                let event = sse::event(None, &serde_json::json!({ "response": piece }).to_string());
+                reply.push_str(&piece);
+                return Some((Ok::<_, Error>(event.into_bytes()), Some((pieces, reply, finish))));
+            }
+            Some(Err(e)) => {
+                console_error!("Chat stream failed, the partial reply is not stored: {e}");
+                sse::event(Some("error"), &serde_json::json!({ "message": "The AI reply was interrupted" }).to_string())
+            }
+            None => match finish(reply).await {
+                Ok(done) => sse::event(Some("done"), &done.to_string()),
+                Err(e) => {
+                    console_error!("Failed to store the streamed chat reply: {e}");
+                    sse::event(Some("error"), &serde_json::json!({ "message": "The AI reply could not be saved" }).to_string())
+                }
+            },
+        };
+        Some((Ok(event.into_bytes()), None))
+    });
+    let mut resp = Response::from_stream(events)?;
+    resp.headers_mut().set("Content-Type", "text/event-stream")?;
+    resp.headers_mut().set("Cache-Control", "no-cache")?;
+    Ok(resp)
+}
+
+/// Handles `POST /trip/{trip_id}/chat/retry`, regenerating the most recent AI reply.
+///
+/// # Arguments
+/// * `env` - The `Env` object providing access to D1, the durable object and the AI service.
+/// * `trip_id` - The unique identifier of the trip.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+///
+/// # Behavior
+/// 1. Loads the newest message; it must be an `"AI"` message.
+/// 2. Loads the history and leaves that reply out of it, so the user's question is the last
+///    message the model sees.
+/// 3. Re-runs `ai::chat` with that history and question.
+/// 4. Stores the new reply as an `"AI"` message and deletes the old one in a single batch
+///    (see [`db::replace_message`]), then returns the reply.
+///
+/// The old reply is only removed once the new one is stored, so a failed retry leaves the
+/// history as it was.
+///
+/// # Errors
+/// - `400 Bad Request` if the trip has no messages, the last message is not from the AI, or
+///   no user message precedes it.
+/// - Propagates database, durable object and AI errors.
+async fn retry_chat(env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Some((last_id, _, last_role)) = timing.measure("db", db::get_last_message(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("No chat history to retry", 400);
+    };
+    if last_role != MessageRole::Ai {
+        return json_error("The last message is not an AI reply", 400);
+    }
+    let mut history = timing.measure("db", get_messages(trip_id.clone(), tenant, env.clone())).await?;
+    if history.last().is_some_and(|(_, role, _)| *role == MessageRole::Ai) {
+        history.pop();
+    }
+    let Some((question, _, _)) = history.iter().rev().find(|(_, role, _)| *role == MessageRole::User).cloned() else {
+        return json_error("No user message to answer", 400);
+    };
+    let mut trip = timing.measure("do", get_trip(env.clone(), trip_id.clone(), config)).await?;
+    let trip_text = trip.text().await?;
+    let trip_text = match serde_json::from_str::<TripInit>(&trip_text) {
+        Ok(info) => info.plan_context()?,
+        Err(_) => trip_text,
+    };
+    let trip_text = chat_plan_context(config, &trip_text, &history);
+    let units = timing.measure("db", db::get_trip_units(trip_id.clone(), tenant, env.clone())).await?;
+    let options = ai::ChatOptions { model: None, language: detect_language(config, &question).map(|l| l.name), units };
+    let resp = match timing.measure("ai", ai::chat(&env, config, trip_text, history, &question, &options)).await {
+        Ok(resp) => resp,
+        Err(e) => return ai_error_response(e),
+    };
+    let refused = ai::is_refusal(&env, &resp);
+    timing.measure("db", db::replace_message(trip_id.clone(), last_id, &resp, MessageRole::Ai, refused, config, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::replace_message failed: {e}")))?;
+    invalidate_summary(&env, trip_id, config, tenant, timing).await?;
+    chat_response(ai::ChatReply { reply: resp, references: vec![], refused }, false)
+}
+
+/// Handles the `input` endpoint for creating a trip plan. This function is responsible for:
+/// 1. Parsing and validating form data.
+/// 2. Generating a unique trip ID.
+/// 3. Interacting with the AI service to generate a travel plan.
+/// 4. Initializing a corresponding durable object to store session-specific trip data.
+/// 5. Creating a trip record in the database with its corresponding plan.
+/// 6. Redirecting the user to the newly created trip's page.
+///
+/// # Parameters
+/// - `req`: The incoming request containing form data (or a JSON object) with `destination` and `days` fields,
+///   and optional `interests` (see [`form_interests`]) added to the plan prompt. An optional
+///   `start_date` (see [`form_start_date`]) makes the prompt name each day's weekday, with
+///   `?tz=` choosing the time zone that `today` and `tomorrow` are resolved in. Optional
+///   `units` and `currency` (see [`form_units`]) set how distances, temperatures and costs
+///   are written, here and in later regenerations and chat replies.
+/// - `env`: The environment context providing required bindings (e.g., Durable Object, KV, AI services).
+/// - `ctx`: Execution context, used to schedule the background analytics write.
+///
+/// # Returns
+/// `Result<Response>`:
+/// - On success, a HTTP redirect response to the new trip's page. HTMX requests instead get
+///   `200` with an `HX-Redirect` header, and `XMLHttpRequest` posts `200` with `{ "redirect": url }`.
+/// - With `DEDUP_TRIPS` enabled and a matching trip already stored, `200 OK` with that trip
+///   as JSON (`{ "id", "destination", "days" }`) and an `X-Trip-Existing: true` header.
+/// - With `CLAMP_DAYS` enabled, a trip longer than [`MAX_TRIP_DAYS`] is planned for the
+///   maximum instead, and the response carries the warning described on
+///   [`days_clamped_warning`] (see [`with_warning`]).
+/// - On failure, an error response with an appropriate status code and message.
+///
+/// # Errors
+/// - Returns a `400 Bad Request` response:
+///   - If the `destination` or `days` fields are missing in the form data.
+///   - If `STRICT_FORM` is enabled and the form contains any other field.
+///   - If the `days` field is not a valid number, is `0` or negative, or is above [`MAX_TRIP_DAYS`]
+///     while `CLAMP_DAYS` is off.
+///   - If `start_date` is not a date or `tz` is not a time zone.
+///   - If `units` or `currency` is not one of the accepted values.
+/// - Returns a `415 Unsupported Media Type` response if the body is not `multipart/form-data`,
+///   `application/x-www-form-urlencoded` or `application/json`.
+/// - Returns a `500 Internal Server Error` response:
+///   - If the AI service fails to generate a trip plan.
+///   - If the durable object initialization fails.
+///   - If the database creation of the trip or its plan fails.
+///   - If any other unexpected error occurs during the request lifecycle.
+///
+/// # Process Flow
+/// 1. Parse form data and validate the presence of the `destination` and `days` fields.
+/// 2. Parse the `days` value to ensure it is a valid number.
+/// 3. When `DEDUP_TRIPS` is enabled, look for a live trip with the same destination (ignoring
+///    case and surrounding whitespace) and day count via `db::find_duplicate_trip`, and return
+///    it instead of creating a new one.
+/// 4. Generate a new unique trip ID using `Uuid`.
+/// 5. Call the `ai::create_plan` function with the destination and days to generate a travel plan,
+///    then `ai::generate_title` for its title. With `?schedule=true`, also ask for a
+///    time-slotted schedule of the plan (see [`plan_schedule`]); an invalid one is dropped.
+/// 6. Create a `TripInit` payload with the generated plan and initialize the trip session durable object:
+///    - Send it to the durable object's `https://trip-session/init` endpoint via `init_trip_session`.
+///    - If the request fails, return an error response.
+/// 7. Store the trip data by calling `create_trip` to persist the trip in the database.
+/// 8. Store the AI-generated plans with `db::create_plan` in the database, the schedule,
+///    if any, with `db::set_latest_plan_schedule`, the units and currency with
+///    `db::set_trip_units`, and the normalized interests, if any, with `db::set_trip_interests`.
+/// 9. Build a redirect URL pointing to the new trip's page and return a `302 Redirect` response,
+///    or a `200` carrying the URL for HTMX and AJAX posts (see `redirect_response`).
+///
+/// # Example
+/// When called with valid form data (`destination="Paris"`, `days="5"`), the function:
+/// - Creates a unique trip ID such as `12345678-abcd-1234-efgh-123456abcdef`.
+/// - Generates an AI travel plan for Paris for 5 days.
+/// - Initializes a trip session durable object and persists the trip to a database.
+/// - Redirects the user to `/trip/12345678-abcd-1234-efgh-123456abcdef`.
+async fn input(mut req: Request, env: Env, ctx: Context, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    if let Some(resp) = check_content_type(&req, &INPUT_CONTENT_TYPES)? {
+        return Ok(resp);
+    }
+    let (form, rejected) = check_form_fields(config, read_form(&mut req).await?, &["destination", "days", "interests", "start_date", "units", "currency"])?;
+    if let Some(resp) = rejected {
+        return Ok(resp);
+    }
+    let Some(FormEntry::Field(destination)) = form.get("destination") else {
+        return json_error("Missing field: destination", 400);
+    };
+    let destination = match checked_destination(&destination) {
+        Ok(destination) => destination,
+        Err(message) => return json_error(&message, 400),
+    };
+    let Some(FormEntry::Field(days_str)) = form.get("days") else {
+        return json_error("Missing field: days", 400);
+    };
+    debug::log_body(config, "input request", &format!("destination={destination}&days={days_str}"));
+    let requested_days = match parse_days(&days_str) {
+        Ok(days) => days,
+        Err(message) => return json_error(&message, 400),
+    };
+    let days = match checked_days(requested_days, config) {
+        Ok(days) => days,
+        Err(message) => return json_error(&message, 400),
+    };
+    let warning = (days != requested_days).then(|| days_clamped_warning(requested_days, days));
+    let interests = match form_interests(&form, config) {
+        Ok(interests) => interests,
+        Err(message) => return json_error(&message, 400),
+    };
+    if config.dedup_trips {
+        if let Some(existing) = timing.measure("db", db::find_duplicate_trip(destination.clone(), days, tenant, env.clone())).await? {
+            let mut resp = Response::from_json(&existing)?;
+            resp.headers_mut().set("X-Trip-Existing", "true")?;
+            return with_warning(resp, warning).await;
+        }
+    }
+    let trip_id = Uuid::new_v4().to_string();
+
+    let started = Date::now().as_millis();
+    let model = match requested_model(&req, &env) {
+        Ok(model) => model,
+        Err(message) => return json_error(&message, 400),
+    };
+    let start_date = match form_start_date(&req, &form, config) {
+        Ok(start_date) => start_date,
+        Err(message) => return json_error(&message, 400),
+    };
+    let units = match form_units(&form) {
+        Ok(units) => units,
+        Err(message) => return json_error(&message, 400),
+    };
+    let options = ai::PlanOptions { interests, model, start_date, units, ..Default::default() };
+    let response = timing.measure("ai", ai::create_plan_with_options(&env, config, &destination, days, &options)).await.map_err(|e| Error::RustError(format!("ai::create_plan failed: {e}")))?;
+    analytics::record(&env, &ctx, config, "trip_created", &destination, days, Date::now().as_millis() - started);
+    debug::log_body(config, "input ai response", &response.0);
+    let title = timing.measure("ai", ai::generate_title(&env, config, &destination, days, &response.0)).await;
+    let schedule = if query_param(&req, "schedule").as_deref() == Some("true") {
+        plan_schedule(&env, config, &response.0, days, timing).await
+    } else {
+        None
+    };
+    let r = response.0.clone();
+    let init_payload = TripInit { destination, days, plan: plan::parse_days(&r), response: r, status: TripStatus::Planning };
+
+    let mut resp = timing.measure("do", init_trip_session(env.clone(), trip_id.clone(), &init_payload, config)).await?;
+    if resp.status_code() != 200 {
+        let body = resp.text().await.unwrap_or_else(|_| "<no body>".into());
+        return json_error(&format!("failed to initialize trip: {body}"), 500);
+    }
+
+    let trip = &TripData {
+        id: trip_id.clone(),
+        destination: init_payload.destination,
+        days: init_payload.days,
+        status: init_payload.status,
+        title: Some(title),
+        slug: None,
+    };
+    timing.measure("db", create_trip(trip.clone(), config, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::create_trip failed: {e}")))?;
+    timing.measure("db", db::create_plan(trip.id.clone(),&response.0, &response.1, ai::is_refusal(&env, &response.0), config, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::create_plan failed: {e}")))?;
+    if let Some(schedule) = schedule {
+        timing.measure("db", db::set_latest_plan_schedule(trip.id.clone(), &schedule, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::set_latest_plan_schedule failed: {e}")))?;
+    }
+    timing.measure("db", db::set_trip_units(trip.id.clone(), &options.units, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::set_trip_units failed: {e}")))?;
+    if !options.interests.is_empty() {
+        timing.measure("db", db::set_trip_interests(trip.id.clone(), &options.interests, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::set_trip_interests failed: {e}")))?;
+    }
+    let mut url = req.url()?;
+    url.set_path(&format!("/trip/{trip_id}"));
+    url.set_query(None);
+    with_warning(redirect_response(&req, url)?, warning).await
+}
+
+/// Parses the `days` field of a form, to be checked with [`checked_days`].
+///
+/// Negative and oversized whole numbers are out of range rather than not numbers: they are
+/// read as `0` and `u32::MAX`, so they get the range message from [`checked_days`].
+///
+/// # Errors
+/// A message for a `400` response when `days` is not a whole number.
+fn parse_days(days: &str) -> std::result::Result<u32, String> {
+    match days.trim().parse::<i64>() {
+        Ok(days) => Ok(u32::try_from(days.max(0)).unwrap_or(u32::MAX)),
+        Err(_) => Err("days must be a number".to_string()),
+    }
+}
+
+/// Checks the `days` of a new trip against [`MAX_TRIP_DAYS`].
+///
+/// # Returns
+/// The number of days to plan: `days` itself, or [`MAX_TRIP_DAYS`] for a longer trip when
+/// `CLAMP_DAYS` is enabled.
+///
+/// # Errors
+/// A message for a `400` response when `days` is `0`, or above the maximum without `CLAMP_DAYS`.
+fn checked_days(days: u32, config: &Config) -> std::result::Result<u32, String> {
+    match days {
+        0 => Err(format!("days must be between 1 and {MAX_TRIP_DAYS}")),
+        days if days > MAX_TRIP_DAYS && config.clamp_days => Ok(MAX_TRIP_DAYS),
+        days if days > MAX_TRIP_DAYS => Err(format!("days must be between 1 and {MAX_TRIP_DAYS}")),
+        days => Ok(days),
+    }
+}
+
+/// The warning returned when `CLAMP_DAYS` shortened a trip, e.g.
+/// ```json
+/// {
+///     "code": "days_clamped",
+///     "requested_days": 45,
+///     "days": 30,
+///     "message": "days was lowered from 45 to 30, the longest trip that can be planned"
+/// }
+/// ```
+fn days_clamped_warning(requested_days: u32, days: u32) -> serde_json::Value {
+    serde_json::json!({
+        "code": "days_clamped",
+        "requested_days": requested_days,
+        "days": days,
+        "message": format!("days was lowered from {requested_days} to {days}, the longest trip that can be planned")
+    })
+}
+
+/// Attaches a warning (see [`days_clamped_warning`]) to a response of `input`.
+///
+/// Its `message` goes in an `X-Trip-Warning` header, and a JSON body also gets the whole
+/// warning as a `warning` field. Without a warning the response is returned unchanged.
+async fn with_warning(mut resp: Response, warning: Option<serde_json::Value>) -> Result<Response> {
+    let Some(warning) = warning else {
+        return Ok(resp);
+    };
+    let is_json = resp.headers().get("Content-Type")?.is_some_and(|t| t.starts_with("application/json"));
+    if is_json {
+        let (status, headers) = (resp.status_code(), resp.headers().clone());
+        let mut body: serde_json::Value = resp.json().await?;
+        body["warning"] = warning.clone();
+        resp = Response::from_json(&body)?.with_status(status).with_headers(headers);
+    }
+    resp.headers_mut().set("X-Trip-Warning", warning["message"].as_str().unwrap_or_default())?;
+    Ok(resp)
+}
+
+/// Asks the AI for a time-slotted schedule of `plan` and checks it with
+/// [`plan::parse_schedule`].
+///
+/// # Returns
+/// The schedule as JSON text ready to store, or `None` when the model failed or wrote an
+/// invalid schedule. Either is logged and the trip keeps just its regular day-by-day plan.
+async fn plan_schedule(env: &Env, config: &Config, plan_text: &str, days: u32, timing: &ServerTiming) -> Option<String> {
+    let text = match timing.measure("ai", ai::create_schedule(env, config, plan_text, days)).await {
+        Ok(text) => text,
+        Err(e) => {
+            console_warn!("ai::create_schedule failed, keeping the plan without a schedule: {e}");
+            return None;
+        }
+    };
+    match plan::parse_schedule(&text, days) {
+        Ok(entries) => serde_json::to_string(&entries).ok(),
+        Err(problem) => {
+            console_warn!("Ignoring the generated schedule ({problem}); keeping the plan without one");
+            None
+        }
+    }
+}
+
+/// Handles `GET /trip/by-slug/{slug}`, resolving a slug to its trip.
+///
+/// # Arguments
+/// * `req` - The request; its `Accept` header decides between a redirect and JSON.
+/// * `env` - The environment, used to reach D1 and the trip's durable object.
+/// * `slug` - The slug from the path, e.g. `paris-5-days`.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+/// * `tenant` - The tenant of the request; only its trips can be resolved.
+/// * `timing` - Collects the `Server-Timing` phases of the request.
+///
+/// # Returns
+/// - A `302` redirect to `/trip/{trip_id}` for browsers (`Accept: text/html`).
+/// - The trip's JSON, as returned by `get_trip`, for other clients.
+/// - `404 Not Found` when no live trip has the slug.
+async fn trip_by_slug(req: &Request, env: Env, slug: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    if !slug::is_valid(&slug) {
+        return json_error("Not Found", 404);
+    }
+    let trip_id = timing.measure("db", db::find_trip_id_by_slug(slug, tenant, env.clone())).await
+        .map_err(|e| Error::RustError(format!("db::find_trip_id_by_slug failed: {e}")))?;
+    let Some(trip_id) = trip_id else {
+        return json_error("Not Found", 404);
+    };
+    let accept_header = req.headers().get("Accept").unwrap_or_default().unwrap_or_default();
+    if accept_header.contains("text/html") {
+        let mut url = req.url()?;
+        url.set_path(&format!("/trip/{trip_id}"));
+        url.set_query(None);
+        return Response::redirect(url);
+    }
+    timing.measure("do", get_trip(env, trip_id, config)).await
+}
+
+/// Sends the client to `url` in the way its kind of request expects.
+///
+/// A plain form post gets a `302` redirect. Script-driven posts handle a `302` awkwardly,
+/// so they get a `200` describing the target instead:
+/// - HTMX (`HX-Request: true`): an empty body with an `HX-Redirect` header, which HTMX follows.
+/// - Other AJAX (`X-Requested-With: XMLHttpRequest`): `{ "redirect": "<url>" }` as JSON.
+fn redirect_response(req: &Request, url: Url) -> Result<Response> {
+    let header = |name: &str| req.headers().get(name).ok().flatten().unwrap_or_default();
+    if header("HX-Request").eq_ignore_ascii_case("true") {
+        let mut resp = Response::empty()?;
+        resp.headers_mut().set("HX-Redirect", url.as_str())?;
+        return Ok(resp);
+    }
+    if header("X-Requested-With").eq_ignore_ascii_case("XMLHttpRequest") {
+        return Response::from_json(&serde_json::json!({ "redirect": url.as_str() }));
+    }
+    Response::redirect(url)
+}
+
+/// Stores a trip's details in its session durable object, or in D1 with
+/// `SESSION_BACKEND=d1` (see [`session`]).
+///
+/// Used both when a trip is created and whenever its plan changes, since `/init` replaces
+/// everything the session holds.
+///
+/// # Arguments
+/// * `env` - The `Env` object providing the `TRIP_SESSION_DO` binding or the D1 database.
+/// * `trip_id` - The unique identifier of the trip, which names its durable object.
+/// * `payload` - The destination, length and plan to store.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+///
+/// # Returns
+/// The session store's response; a non-`200` status means the state was not stored.
+async fn init_trip_session(env: Env, trip_id: String, payload: &TripInit, config: &Config) -> Result<Response>{
+    session::store(&env, config)?.init(&trip_id, payload).await
+}
+
+/// Fetches a trip session from its durable object, or from D1 with `SESSION_BACKEND=d1`
+/// (see [`session`]), based on the provided trip ID.
+///
+/// # Arguments
+/// * `env` - The `Env` object, which is typically used to access environment bindings
+///   such as durable objects.
+/// * `trip_id` - A `String` representing the unique identifier for the trip session
+///   to be fetched.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+///
+/// # Returns
+/// * `Result<Response>` - Returns an `Ok(Response)` if the fetch operation is successful,
+///   or an `Err` if an error occurs during the process. Errors from the durable object,
+///   such as `404` for a trip that was never initialized, are turned into JSON by
+///   [`session_error`].
+///
+/// # Functionality
+/// 1. Picks the session store configured by `SESSION_BACKEND` with `session::store`.
+/// 2. With the durable object backend, retrieves a stub named after `trip_id` from the
+///    `TRIP_SESSION_DO` binding.
+/// 3. Constructs a `GET` request to the specific durable object endpoint (`https://trip-session/`).
+/// 4. Sends the request to the durable object through `do_fetch`, which retries transient failures.
+/// 5. Returns the HTTP response from the durable object. The D1 backend reads the `sessions`
+///    table and answers with the same response instead.
+///
+/// # Errors
+/// This function may return an error in the following cases:
+/// * If `SESSION_BACKEND` is invalid, or the D1 query fails with `SESSION_BACKEND=d1`.
+/// * If the durable object binding "TRIP_SESSION_DO" is not found.
+/// * If the `trip_id` cannot be converted to a valid durable object ID.
+/// * If there is an issue creating or sending the `Request`.
+/// * If there is an issue while fetching the response from the durable object.
+///
+/// # Example Usage
+/// ```rust
+/// use worker::{Env, Response};
+///
+/// async fn example(env: Env) -> Result<Response> {
+///     let trip_id = "some-trip-id".to_string();
+///     let response = get_trip(env, trip_id, config).await?;
+///     Ok(response)
+/// }
+/// ```
+///
+/// Ensure that your Worker has the `TRIP_SESSION_DO` binding configured in the environment,
+/// unless `SESSION_BACKEND=d1`, for the function to work properly.
+async fn get_trip(env: Env, trip_id: String, config: &Config) -> Result<Response>{
+    let trip = session::store(&env, config)?.get(&trip_id).await?;
+    session_error(trip).await
+}
+
+/// Rewrites an error from the session store, whose body is plain text, as a [`json_error`]
+/// with the same status, keeping any `Retry-After` header. Other responses are returned as
+/// they are.
+async fn session_error(mut resp: Response) -> Result<Response> {
+    if resp.status_code() == 200 {
+        return Ok(resp);
+    }
+    let message = resp.text().await?;
+    let mut error = json_error(&message, resp.status_code())?;
+    if let Some(retry_after) = resp.headers().get("Retry-After")? {
+        error.headers_mut().set("Retry-After", &retry_after)?;
+    }
+    Ok(error)
+}
+
+/// Shortens the plan in a trip session's response for `GET /trip/{trip_id}?preview=true`.
+///
+/// `response` is cut to `config.plan_preview_chars` characters (`PLAN_PREVIEW_CHARS`, default
+/// [`config::DEFAULT_PLAN_PREVIEW_CHARS`]) with [`plan::preview`], and `response_truncated` says
+/// whether anything was cut. When it was, the per-day `plan` is left out too, since it
+/// repeats the full text; `GET /trip/{trip_id}/plan` still returns everything.
+///
+/// # Returns
+/// The trip session's response unchanged when it is not a JSON trip (e.g. an error).
+async fn trip_preview(mut trip: Response, config: &Config) -> Result<Response> {
+    if trip.status_code() != 200 {
+        return Ok(trip);
+    }
+    let Ok(mut body) = trip.json::<serde_json::Value>().await else {
+        return json_error("Trip session returned an invalid trip", 502);
+    };
+    let Some(text) = body.get("response").and_then(|r| r.as_str()) else {
+        return Response::from_json(&body);
+    };
+    let (text, truncated) = plan::preview(text, config.plan_preview_chars);
+    body["response"] = serde_json::json!(text);
+    body["response_truncated"] = serde_json::json!(truncated);
+    if truncated {
+        if let Some(fields) = body.as_object_mut() {
+            fields.remove("plan");
+        }
+    }
+    Response::from_json(&body)
+}
+
+/// Handles `GET /trip/{trip_id}/plan`, returning the trip's latest plan in the requested format.
+///
+/// # Arguments
+/// * `req` - The request; its `format` query parameter selects the representation.
+/// * `env` - The `Env` object providing access to the D1 database.
+/// * `trip_id` - The unique identifier of the trip.
+///
+/// # Formats
+/// - `json` (default): `{ "plan": "...", "updated_at": "2025-11-06T14:03:27.512Z", "refused": false, "days": [{ "day": 1, "text": "..." }] }`
+///   where `days` is the plan split by [`plan::parse_days`] and `refused` is set when the AI
+///   refused to write the plan, so the page can offer a retry instead. `schedule` is the list of
+///   `{ "day", "time", "activity" }` slots when the trip was created with `?schedule=true`,
+///   and `null` otherwise.
+/// - `text`: the raw plan text as `text/plain`.
+/// - `html`: the plan rendered by [`plan::to_html`] as sanitized `text/html`.
+///
+/// `?format=` only chooses how the stored plan is returned. How it was written, as structured
+/// entries or prose, is fixed when it is generated by `DEFAULT_PLAN_FORMAT` (see
+/// [`ai::PlanFormat`]). Both kinds keep a `Day N` heading per day, so `days` is filled either way.
+///
+/// # Errors
+/// - `400 Bad Request` for an unknown `format`.
+/// - `404 Not Found` if no plan is stored for the trip.
+/// - Propagates database errors from `db::get_latest_plan`.
+async fn get_plan(req: &Request, env: Env, trip_id: String, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let format = query_param(req, "format").unwrap_or_else(|| "json".to_string());
+    if !matches!(format.as_str(), "json" | "text" | "html") {
+        return json_error("format must be one of: json, text, html", 400);
+    }
+    let Some((text, updated_at, refused, schedule)) = timing.measure("db", db::get_latest_plan(trip_id, tenant, env)).await? else {
+        return json_error("No plan stored for this trip", 404);
+    };
+    match format.as_str() {
+        "text" => Response::ok(text),
+        "html" => Response::from_html(plan::to_html(&text)),
+        _ => Response::from_json(&plan_json(text, updated_at, refused, schedule)),
+    }
+}
+
+/// Builds the `?format=json` representation of a stored plan described on [`get_plan`].
+fn plan_json(text: String, updated_at: i64, refused: bool, schedule: Option<String>) -> serde_json::Value {
+    serde_json::json!({
+        "days": plan::parse_days(&text),
+        "plan": text,
+        "updated_at": time::to_rfc3339(updated_at),
+        "refused": refused,
+        "schedule": schedule.and_then(|json| serde_json::from_str::<Vec<plan::ScheduleEntry>>(&json).ok()),
+    })
+}
+
+/// Handles `GET /trip/{trip_id}/plan.json`, returning the structured plan as a file download.
+///
+/// The body is the `?format=json` plan of [`get_plan`] plus the trip's `destination` and
+/// `title`, sent with `Content-Disposition: attachment; filename="trip-{destination}-plan.json"`
+/// (see [`download_filename`]).
+///
+/// # Errors
+/// - `404 Not Found` if the trip or its plan does not exist.
+/// - Propagates database errors.
+async fn download_plan(env: Env, trip_id: String, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Some(trip) = timing.measure("db", db::find_trip(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
+    };
+    let Some((text, updated_at, refused, schedule)) = timing.measure("db", db::get_latest_plan(trip_id, tenant, env)).await? else {
+        return json_error("No plan stored for this trip", 404);
+    };
+    let mut body = plan_json(text, updated_at, refused, schedule);
+    body["destination"] = serde_json::json!(trip.destination);
+    body["title"] = serde_json::json!(trip.title);
+    let mut resp = Response::from_json(&body)?;
+    let filename = download_filename(&trip.destination);
+    resp.headers_mut().set("Content-Disposition", &format!("attachment; filename=\"trip-{filename}-plan.json\""))?;
+    Ok(resp)
+}
+
+/// Handles `GET /trip/{trip_id}/calendar.ics`, exporting the plan as an iCalendar file.
+///
+/// Every day of the latest plan becomes an all-day event (see [`ics::calendar`]), Day 1
+/// falling on `?start=YYYY-MM-DD`. Without `start` the trip is placed from tomorrow, in
+/// the time zone given by `?tz=` (an IANA name, default `UTC`). A plan without day
+/// headings still gets an event per day of the trip (see [`calendar_days`]).
+///
+/// # Returns
+/// The calendar as `text/calendar`, sent with
+/// `Content-Disposition: attachment; filename="trip-{destination}.ics"` (see [`download_filename`]).
+///
+/// # Errors
+/// - `400 Bad Request` if `start` is not a date or `tz` is not a time zone.
+/// - `404 Not Found` if the trip or its plan does not exist.
+/// - `422 Unprocessable Entity` if the plan is empty.
+/// - Propagates database errors.
+async fn calendar_ics(req: &Request, env: Env, trip_id: String, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let start = match query_param(req, "start") {
+        Some(start) => match time::CalendarDate::parse(&start) {
+            Some(start) => start,
+            None => return json_error("start must be a date such as 2025-07-14", 400),
+        },
+        None => {
+            let tz = query_param(req, "tz").unwrap_or_else(|| "UTC".to_string());
+            match time::CalendarDate::today(&tz) {
+                Some(today) => today.add_days(1),
+                None => return json_error(&format!("tz must be an IANA time zone such as Europe/Paris, got {tz:?}"), 400),
+            }
+        }
+    };
+    let Some(trip) = timing.measure("db", db::find_trip(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
+    };
+    let Some((text, _, _, _)) = timing.measure("db", db::get_latest_plan(trip_id.clone(), tenant, env)).await? else {
+        return json_error("No plan stored for this trip", 404);
+    };
+    let days = calendar_days(&text, trip.days);
+    if days.is_empty() {
+        return json_error("The plan has no days to export", 422);
+    }
+    let body = ics::calendar(&trip_id, &trip.destination, &days, start, &time::to_rfc3339(time::now_millis()));
+    let mut resp = Response::ok(body)?;
+    let filename = download_filename(&trip.destination);
+    resp.headers_mut().set("Content-Type", "text/calendar; charset=utf-8")?;
+    resp.headers_mut().set("Content-Disposition", &format!("attachment; filename=\"trip-{filename}.ics\""))?;
+    Ok(resp)
+}
+
+/// The days `calendar_ics` exports: the plan split by [`plan::parse_days`], or, when the
+/// plan has no `Day N` headings, one day per day of the trip, each carrying the whole plan,
+/// so the export never comes out as an empty calendar.
+///
+/// # Returns
+/// No days only when the plan is blank.
+fn calendar_days(text: &str, trip_days: u32) -> Vec<plan::PlanDay> {
+    let days = plan::parse_days(text);
+    if !days.is_empty() || text.trim().is_empty() {
+        return days;
+    }
+    (1..=trip_days).map(|day| plan::PlanDay { day, text: text.trim().to_string() }).collect()
+}
+
+/// The longest destination part of a download's file name, in characters.
+const DOWNLOAD_FILENAME_MAX_CHARS: usize = 60;
+
+/// Makes a destination safe to use in a `Content-Disposition` file name.
+///
+/// Path separators, quotes and control characters are dropped, whitespace runs become `-`,
+/// and characters outside ASCII become `_`, since header values must be plain ASCII.
+/// A destination with nothing usable left becomes `trip`.
+fn download_filename(destination: &str) -> String {
+    let name: String = destination
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-")
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, '/' | '\\' | '"'))
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .take(DOWNLOAD_FILENAME_MAX_CHARS)
+        .collect();
+    let name = name.trim_matches(['.', '-']);
+    if name.is_empty() { "trip".to_string() } else { name.to_string() }
+}
+
+/// Handles `GET /trip/{trip_id}/messages`, listing the trip's messages for polling.
+///
+/// With `?since={message_id}` only the messages written after that one (a larger id) are
+/// listed, so a client can poll with the id of the last message it has and receive just the
+/// new ones. Without it every message is listed.
+///
+/// # Returns
+/// A JSON array of `{ "id", "message", "role", "created_at", "pinned" }` objects in the
+/// order the messages were written; empty when there is nothing new.
+///
+/// # Errors
+/// - `400 Bad Request` if `since` is not a whole number.
+async fn messages_since(req: &Request, env: Env, trip_id: String, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let since = match query_param(req, "since").map(|v| v.trim().parse::<i64>()) {
+        None => None,
+        Some(Ok(since)) => Some(since),
+        Some(Err(_)) => return json_error("since must be a message id", 400),
+    };
+    let (messages, _) = timing.measure("db", db::list_messages(trip_id, false, since, tenant, env)).await?;
+    Response::from_json(&messages)
+}
+
+/// Handles `GET /trip/{trip_id}/messages/pinned`, listing only the trip's pinned messages.
+///
+/// # Returns
+/// A JSON array of `{ "id", "message", "role", "created_at", "pinned" }` objects in the
+/// order the messages were written; empty when nothing is pinned.
+async fn pinned_messages(env: Env, trip_id: String, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let (messages, _) = timing.measure("db", db::list_messages(trip_id, true, None, tenant, env)).await?;
+    Response::from_json(&messages)
+}
+
+/// Handles `POST /trip/{trip_id}/message/{message_id}/pin` and `.../unpin`.
+///
+/// # Arguments
+/// * `env` - The `Env` object providing access to the D1 database.
+/// * `trip_id` - The unique identifier of the trip.
+/// * `action` - The path after the trip id, e.g. `message/42/pin`.
+/// * `timing` - Collects the time spent in D1 for the `Server-Timing` header.
+///
+/// # Returns
+/// `{ "id": 42, "pinned": true }` with the message's new state. Pinning an already pinned
+/// message (or unpinning an unpinned one) succeeds without change.
+///
+/// # Errors
+/// - `404 Not Found` if the path is not `message/{id}/pin` or `message/{id}/unpin`, or the
+///   message does not belong to the trip.
+async fn pin_message(env: Env, trip_id: String, action: &str, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let parsed = action
+        .strip_prefix("message/")
+        .and_then(|rest| rest.split_once('/'))
+        .and_then(|(id, verb)| Some((id.parse::<i64>().ok()?, verb)));
+    let (message_id, pinned) = match parsed {
+        Some((id, "pin")) => (id, true),
+        Some((id, "unpin")) => (id, false),
+        _ => return json_error("Not Found", 404),
+    };
+    if !timing.measure("db", db::set_message_pinned(trip_id, message_id, pinned, tenant, env)).await? {
+        return json_error("Message not found", 404);
+    }
+    Response::from_json(&serde_json::json!({ "id": message_id, "pinned": pinned }))
+}
+
+/// How many imported messages are inserted per D1 batch.
+const IMPORT_BATCH_SIZE: usize = 50;
+
+/// One line of a `POST /trip/{trip_id}/messages/import` body.
+#[derive(Deserialize)]
+struct ImportedMessage {
+    message: String,
+    role: String,
+}
+
+/// Handles `POST /trip/{trip_id}/messages/import`, adding messages from an NDJSON body.
+///
+/// Each line is one message, e.g. `{"message": "Is the Louvre open on Tuesdays?", "role": "User"}`,
+/// with a known role (see [`MessageRole::KNOWN`]). Messages are stored in the order of the body, after
+/// the trip's existing messages, or in their place with `?replace=true`. The body is streamed with [`ndjson::LineReader`] rather than read into memory, and
+/// messages are inserted with `db::create_messages` every [`IMPORT_BATCH_SIZE`] lines, so
+/// memory use does not depend on the size of the import.
+///
+/// # Arguments
+/// * `req` - The request whose body holds the messages.
+/// * `env` - The `Env` object providing access to the D1 database.
+/// * `trip_id` - The unique identifier of the trip receiving the messages.
+/// * `config` - Its `import_max_bytes` caps the body, `import_max_line_bytes` each line and
+///   `import_max_messages` the number of messages (see [`Config`]).
+/// * `timing` - Collects the time spent in D1 for the `Server-Timing` header.
+///
+/// # Returns
+/// `{ "imported": n, "replaced": m }` with the number of messages stored and the number of
+/// existing messages deleted (`0` without `?replace=true`).
+///
+/// # Errors
+/// - `400 Bad Request` if a line is not a valid message or has an unknown role; the error
+///   names the line.
+/// - `404 Not Found` if the trip does not exist.
+/// - `413 Payload Too Large` if the body, a line or the number of messages exceeds its cap.
+///
+/// Batches inserted before an error are kept; the error reports how many messages were
+/// imported so a client can resume after fixing the offending line. With `?replace=true`
+/// the existing messages are deleted just before the first batch is inserted, so an import
+/// rejected within its first [`IMPORT_BATCH_SIZE`] lines leaves the history untouched.
+async fn import_messages(mut req: Request, env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    if !timing.measure("db", db::trip_exists(trip_id.clone(), tenant, env.clone())).await? {
+        return json_error("Trip not found", 404);
+    }
+    let max_messages = config.import_max_messages;
+    let mut replace = query_param(&req, "replace").as_deref() == Some("true");
+    let mut replaced = 0;
+    let mut lines = ndjson::LineReader::new(
+        req.stream()?,
+        config.import_max_bytes,
+        config.import_max_line_bytes,
+    );
+
+    let mut imported = 0;
+    let mut batch: Vec<(String, MessageRole)> = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(ndjson::ReadError::TooLarge(reason)) => {
+                return json_error(&format!("Import too large: {reason} ({imported} messages imported)"), 413);
+            }
+            Err(ndjson::ReadError::Body(e)) => {
+                return json_error(&format!("Failed to read import: {e} ({imported} messages imported)"), 400);
+            }
+        };
+        let message = match serde_json::from_str::<ImportedMessage>(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                return json_error(&format!("Line {}: {e} ({imported} messages imported)", lines.line_number()), 400);
+            }
+        };
+        let Ok(role) = message.role.parse::<MessageRole>();
+        if let MessageRole::Unknown(name) = role {
+            return json_error(&format!("Line {}: unknown role {name:?}, expected one of {} ({imported} messages imported)", lines.line_number(), MessageRole::names()), 400);
+        }
+        if imported + batch.len() == max_messages {
+            return json_error(&format!("Import too large: more than {max_messages} messages ({imported} messages imported)"), 413);
+        }
+        batch.push((message.message, role));
+        if batch.len() == IMPORT_BATCH_SIZE {
+            if std::mem::take(&mut replace) {
+                replaced = timing.measure("db", db::delete_messages(trip_id.clone(), tenant, env.clone())).await?;
+            }
+            imported += timing.measure("db", db::create_messages(trip_id.clone(), &batch, config, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::create_messages failed: {e}")))?;
+            batch.clear();
+        }
+    }
+    if replace {
+        replaced = timing.measure("db", db::delete_messages(trip_id.clone(), tenant, env.clone())).await?;
+    }
+    imported += timing.measure("db", db::create_messages(trip_id.clone(), &batch, config, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::create_messages failed: {e}")))?;
+    invalidate_summary(&env, trip_id, config, tenant, timing).await?;
+    Response::from_json(&serde_json::json!({ "imported": imported, "replaced": replaced }))
+}
+
+/// Handles `POST /trip/{trip_id}/packing-list`, asking the AI for a packing list for the trip.
+///
+/// The destination, length and plan are read from the trip's durable object, the same
+/// context chat uses. An optional `season` query parameter (e.g. `?season=winter`) tells the
+/// model when the trip takes place. With `?save=true` the list is also stored on the trip.
+///
+/// # Returns
+/// `{ "categories": [{ "category": "Clothing", "items": ["..."] }], "text": null, "saved": false }`.
+/// When the answer cannot be read as categories (see [`packing::parse`]), `categories` is
+/// empty and `text` holds the model's answer as prose; prose is never saved.
+///
+/// # Errors
+/// - `404 Not Found` if the trip does not exist.
+/// - Propagates AI and database errors.
+async fn packing_list(req: &Request, env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    if tenant.is_scoped() && !timing.measure("db", db::trip_exists(trip_id.clone(), tenant, env.clone())).await? {
+        return json_error("Trip not found", 404);
+    }
+    let mut trip = timing.measure("do", get_trip(env.clone(), trip_id.clone(), config)).await?;
+    let Ok(trip) = serde_json::from_str::<TripInit>(&trip.text().await?) else {
+        return json_error("Trip not found", 404);
+    };
+    let season = query_param(req, "season").filter(|s| !s.trim().is_empty());
+    let text = timing.measure("ai", ai::create_packing_list(&env, config, &trip.destination, trip.days, &trip.response, season.as_deref())).await
+        .map_err(|e| Error::RustError(format!("ai::create_packing_list failed: {e}")))?;
+    let Some(categories) = packing::parse(&text) else {
+        return Response::from_json(&serde_json::json!({ "categories": [], "text": text, "saved": false }));
+    };
+    let saved = query_param(req, "save").as_deref() == Some("true");
+    if saved {
+        timing.measure("db", db::set_packing_list(trip_id, &serde_json::to_string(&categories)?, tenant, env)).await
+            .map_err(|e| Error::RustError(format!("db::set_packing_list failed: {e}")))?;
+    }
+    Response::from_json(&serde_json::json!({ "categories": categories, "text": null, "saved": saved }))
+}
+
+/// Handles `POST /trip/{trip_id}/title/regenerate`, generating a fresh title from the latest plan.
+///
+/// # Returns
+/// `{ "title": "..." }` with the stored title. When the AI call fails the
+/// `"{days} days in {destination}"` fallback is stored and returned.
+///
+/// # Errors
+/// - `404 Not Found` if the trip or its plan does not exist.
+/// - Propagates database errors.
+async fn regenerate_title(env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Some(trip) = timing.measure("db", db::find_trip(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
+    };
+    let Some((text, _, _, _)) = timing.measure("db", db::get_latest_plan(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Plan not found", 404);
+    };
+    let title = timing.measure("ai", ai::generate_title(&env, config, &trip.destination, trip.days, &text)).await;
+    timing.measure("db", db::update_trip_title(trip_id, &title, tenant, env)).await.map_err(|e| Error::RustError(format!("db::update_trip_title failed: {e}")))?;
+    Response::from_json(&serde_json::json!({ "title": title }))
+}
+
+/// The longest `style` or `budget` accepted by `POST /trip/{trip_id}/remix`, in characters.
+const REMIX_OPTION_MAX_CHARS: usize = 40;
+
+/// The JSON body accepted by `POST /trip/{trip_id}/remix`; every field is optional.
+///
+/// # Fields
+/// * `days` - A new trip length, replacing the source trip's.
+/// * `style` - A travel style for the new plan, e.g. `"relaxed"`.
+/// * `budget` - A spending level for the new plan, e.g. `"luxury"`.
+#[derive(Deserialize, Default)]
+struct RemixRequest {
+    days: Option<u32>,
+    style: Option<String>,
+    budget: Option<String>,
+}
+
+/// Handles `POST /trip/{trip_id}/remix`, creating a new trip from an existing one with some
+/// parameters changed.
+///
+/// The source trip's destination and length are merged with the overrides in the body and
+/// a fresh plan is generated for the result; the source trip is left untouched. An empty
+/// body remixes the trip with its own parameters.
+///
+/// # Arguments
+/// * `req` - The request, with an optional JSON body such as `{ "days": 3, "style": "relaxed" }`.
+/// * `env` - The `Env` object providing access to D1, the durable object and the AI service.
+/// * `trip_id` - The unique identifier of the source trip.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+/// * `timing` - Collects the time spent per phase for the `Server-Timing` header.
+///
+/// # Returns
+/// `201 Created` with `{ "id": "<new trip id>", "url": "/trip/<new trip id>" }`.
+///
+/// # Errors
+/// - `400 Bad Request` if the body is not valid JSON, `days` is outside 1 to
+///   [`MAX_TRIP_DAYS`], or `style`/`budget` is blank or longer than [`REMIX_OPTION_MAX_CHARS`].
+/// - `404 Not Found` if the source trip does not exist.
+/// - Propagates database, durable object and AI errors.
+async fn remix_trip(mut req: Request, env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let body = req.text().await?;
+    let overrides = if body.trim().is_empty() {
+        RemixRequest::default()
+    } else {
+        match serde_json::from_str::<RemixRequest>(&body) {
+            Ok(overrides) => overrides,
+            Err(e) => return json_error(&format!("Invalid remix body: {e}"), 400),
+        }
+    };
+    if overrides.days.is_some_and(|days| days == 0 || days > MAX_TRIP_DAYS) {
+        return json_error(&format!("days must be between 1 and {MAX_TRIP_DAYS}"), 400);
+    }
+    for (name, value) in [("style", &overrides.style), ("budget", &overrides.budget)] {
+        if value.as_ref().is_some_and(|v| v.trim().is_empty() || v.chars().count() > REMIX_OPTION_MAX_CHARS) {
+            return json_error(&format!("{name} must be between 1 and {REMIX_OPTION_MAX_CHARS} characters"), 400);
+        }
+    }
+    let Some(source) = timing.measure("db", db::find_trip(trip_id, tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
+    };
+
+    let units = timing.measure("db", db::get_trip_units(source.id.clone(), tenant, env.clone())).await?;
+    let days = overrides.days.unwrap_or(source.days);
+    let options = ai::PlanOptions {
+        style: overrides.style.map(|v| v.trim().to_string()),
+        budget: overrides.budget.map(|v| v.trim().to_string()),
+        format: None,
+        interests: vec![],
+        model: None,
+        start_date: None,
+        units,
+    };
+    let (text, input_text) = timing.measure("ai", ai::create_plan_with_options(&env, config, &source.destination, days, &options)).await.map_err(|e| Error::RustError(format!("ai::create_plan failed: {e}")))?;
+    let title = timing.measure("ai", ai::generate_title(&env, config, &source.destination, days, &text)).await;
+
+    let new_id = Uuid::new_v4().to_string();
+    let payload = TripInit { destination: source.destination, days, plan: plan::parse_days(&text), response: text, status: TripStatus::Planning };
+    let mut resp = timing.measure("do", init_trip_session(env.clone(), new_id.clone(), &payload, config)).await?;
+    if resp.status_code() != 200 {
+        let body = resp.text().await.unwrap_or_else(|_| "<no body>".into());
+        return json_error(&format!("failed to initialize trip: {body}"), 500);
+    }
+    let trip = TripData { id: new_id.clone(), destination: payload.destination, days, status: TripStatus::Planning, title: Some(title), slug: None };
+    let slug = timing.measure("db", create_trip(trip, config, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::create_trip failed: {e}")))?;
+    timing.measure("db", db::set_trip_units(new_id.clone(), &options.units, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::set_trip_units failed: {e}")))?;
+    timing.measure("db", db::create_plan(new_id.clone(), &payload.response, &input_text, ai::is_refusal(&env, &payload.response), config, tenant, env)).await.map_err(|e| Error::RustError(format!("db::create_plan failed: {e}")))?;
+    Ok(Response::from_json(&serde_json::json!({ "id": new_id, "slug": slug, "url": format!("/trip/{new_id}") }))?.with_status(201))
+}
+
+/// The message stored in the chat history, with role `"System"`, when a plan is regenerated.
+const PLAN_REGENERATED_NOTE: &str = "The trip plan was regenerated. Earlier messages may refer to the previous plan; answer using the current plan.";
+
+/// Handles `POST /trip/{trip_id}/regenerate`, replacing a trip's plan with a freshly generated one.
+///
+/// Chat history written about the old plan would otherwise contradict the new one, so the
+/// chat is always dealt with explicitly:
+/// - By default a `"System"` message ([`PLAN_REGENERATED_NOTE`]) is added to the history, so
+///   later replies know the plan changed while the conversation is kept.
+/// - With `?reset_chat=true` the history is deleted instead and the chat starts over.
+///
+/// # Arguments
+/// * `req` - The request; its `reset_chat` query parameter selects the chat behavior.
+/// * `env` - The `Env` object providing access to D1, the durable object and the AI service.
+/// * `trip_id` - The unique identifier of the trip.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+/// * `timing` - Collects the time spent per phase for the `Server-Timing` header.
+///
+/// # Behavior
+/// 1. Loads the trip and generates a new plan with `ai::create_plan` and a new title with
+///    `ai::generate_title`. The generation can be cancelled meanwhile (see [`cancel_generation`]),
+///    in which case nothing is stored, the trip is set to `cancelled` and `409` is returned.
+/// 2. Stores it as a new plan version and prunes old versions (see `Config::plan_versions_kept`).
+/// 3. Refreshes the trip's durable object with the new plan, keeping the trip's status.
+/// 4. Notes the regeneration in, or clears, the chat history.
+///
+/// # Returns
+/// `{ "plan": "...", "title": "...", "chat": "noted" }`, or `"chat": "reset"` when the history
+/// was cleared.
+///
+/// # Errors
+/// - `404 Not Found` if the trip does not exist.
+/// - Propagates database, durable object and AI errors.
+async fn regenerate_plan(req: &Request, env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let reset_chat = query_param(req, "reset_chat").as_deref() == Some("true");
+    regenerate_trip(env, trip_id, reset_chat, config, tenant, timing).await
+}
+
+/// Regenerates a trip's plan as described on [`regenerate_plan`], which parses the request
+/// for it; [`regenerate_batch`] calls it directly for every trip of a batch.
+async fn regenerate_trip(env: Env, trip_id: String, reset_chat: bool, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Some(trip) = timing.measure("db", db::find_trip(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
+    };
+    let units = timing.measure("db", db::get_trip_units(trip_id.clone(), tenant, env.clone())).await?;
+    begin_generation(&env, &trip_id, config, timing).await?;
+    let generated = timing.measure("ai", ai::create_plan(&env, config, &trip.destination, trip.days, &units)).await;
+    if finish_generation(&env, &trip_id, config, timing).await? {
+        return cancelled_generation(&env, &trip_id, config, tenant, timing).await;
+    }
+    let (text, input_text) = generated.map_err(|e| Error::RustError(format!("ai::create_plan failed: {e}")))?;
+    timing.measure("db", db::create_plan(trip_id.clone(), &text, &input_text, ai::is_refusal(&env, &text), config, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::create_plan failed: {e}")))?;
+    timing.measure("db", db::prune_plans(trip_id.clone(), config.plan_versions_kept, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::prune_plans failed: {e}")))?;
+
+    let title = timing.measure("ai", ai::generate_title(&env, config, &trip.destination, trip.days, &text)).await;
+    timing.measure("db", db::update_trip_title(trip_id.clone(), &title, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::update_trip_title failed: {e}")))?;
+
+    let payload = TripInit { destination: trip.destination, days: trip.days, plan: plan::parse_days(&text), response: text, status: trip.status };
+    let mut resp = timing.measure("do", init_trip_session(env.clone(), trip_id.clone(), &payload, config)).await?;
+    if resp.status_code() != 200 {
+        let body = resp.text().await.unwrap_or_else(|_| "<no body>".into());
+        return json_error(&format!("failed to update trip session: {body}"), 500);
+    }
+
+    let chat = if reset_chat {
+        timing.measure("db", db::delete_messages(trip_id.clone(), tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::delete_messages failed: {e}")))?;
+        invalidate_summary(&env, trip_id, config, tenant, timing).await?;
+        "reset"
+    } else {
+        timing.measure("db", create_message(trip_id, PLAN_REGENERATED_NOTE, MessageRole::System, config, tenant, env)).await.map_err(|e| Error::RustError(format!("db::create_message failed: {e}")))?;
+        "noted"
+    };
+    Response::from_json(&serde_json::json!({ "plan": payload.response, "title": title, "chat": chat }))
+}
+
+/// The most trips a single `POST /trips/regenerate` may name.
+const MAX_BATCH_REGENERATE: usize = 50;
+
+/// How many trips of a batch are regenerated at the same time.
+const BATCH_REGENERATE_CONCURRENCY: usize = 4;
+
+/// The JSON body accepted by `POST /trips/regenerate`.
+///
+/// # Fields
+/// * `trip_ids` - The trips whose plans are regenerated; duplicates are ignored.
+/// * `reset_chat` - Clears each trip's chat history instead of noting the regeneration in
+///   it, as `?reset_chat=true` does for a single trip. Defaults to `false`.
+#[derive(Deserialize)]
+struct BatchRegenerateRequest {
+    trip_ids: Vec<String>,
+    #[serde(default)]
+    reset_chat: bool,
+}
+
+/// Handles `POST /trips/regenerate`, regenerating the plans of several trips at once.
+///
+/// The trips are regenerated after the response is sent (`Context::wait_until`), at most
+/// [`BATCH_REGENERATE_CONCURRENCY`] at a time, each exactly as `POST /trip/{trip_id}/regenerate`
+/// would. A trip failing does not stop the others. Every finished trip is counted in the
+/// job's `completed` or `failed` counter as it finishes, and the per-trip results are
+/// stored once all are done.
+///
+/// # Arguments
+/// * `req` - The HTTP request whose JSON body is a [`BatchRegenerateRequest`].
+/// * `env` - The `Env` object providing access to D1, the durable objects and the AI service.
+/// * `ctx` - The request context used to keep the work running after the response.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+/// * `tenant` - The tenant of the request; only its trips can be regenerated.
+/// * `timing` - Collects the time spent per phase for the `Server-Timing` header.
+///
+/// # Returns
+/// `202 Accepted` with `{ "job_id": "...", "total": 3, "status_url": "/jobs/<job_id>" }`.
+/// Poll the status URL (see [`job_status`]) for progress and the per-trip results.
+///
+/// # Errors
+/// - `400 Bad Request` if the body is not valid JSON, names no trips, or names more than
+///   [`MAX_BATCH_REGENERATE`].
+/// - Propagates database errors from recording the job.
+///
+/// # Notes
+/// Work scheduled with `wait_until` is bounded by the Workers runtime; a job cut short
+/// stays `running`. Keep batches small enough to finish, or split them.
+async fn regenerate_batch(mut req: Request, env: Env, ctx: &Context, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Ok(body) = req.json::<BatchRegenerateRequest>().await else {
+        return json_error("Body must be JSON with a `trip_ids` array", 400);
+    };
+    let mut trip_ids = body.trip_ids;
+    let mut seen = std::collections::HashSet::new();
+    trip_ids.retain(|id| seen.insert(id.clone()));
+    if trip_ids.is_empty() {
+        return json_error("`trip_ids` must name at least one trip", 400);
+    }
+    if trip_ids.len() > MAX_BATCH_REGENERATE {
+        return json_error(&format!("At most {MAX_BATCH_REGENERATE} trips can be regenerated at once"), 400);
+    }
+
+    let job_id = Uuid::new_v4().to_string();
+    let total = trip_ids.len() as u32;
+    timing.measure("db", db::create_job(job_id.clone(), "regenerate", total, config, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::create_job failed: {e}")))?;
+
+    let (job, config, tenant, reset_chat) = (job_id.clone(), config.clone(), tenant.clone(), body.reset_chat);
+    ctx.wait_until(async move {
+        let results: Vec<serde_json::Value> = futures_util::stream::iter(trip_ids)
+            .map(|trip_id| {
+                let (env, config, tenant, job) = (env.clone(), config.clone(), tenant.clone(), job.clone());
+                async move {
+                    let timing = ServerTiming::new();
+                    let (status, error) = match regenerate_trip(env.clone(), trip_id.clone(), reset_chat, &config, &tenant, &timing).await {
+                        Ok(resp) if resp.status_code() == 200 => (200, None),
+                        Ok(mut resp) => (resp.status_code(), Some(error_message(resp.text().await.unwrap_or_default()))),
+                        Err(e) => (500, Some(e.to_string())),
+                    };
+                    if let Err(e) = db::record_job_item(job.clone(), error.is_none(), &config, &tenant, env).await {
+                        console_warn!("Failed to record progress of job {job}: {e}");
+                    }
+                    serde_json::json!({ "trip_id": trip_id, "ok": error.is_none(), "status": status, "error": error })
+                }
+            })
+            .buffer_unordered(BATCH_REGENERATE_CONCURRENCY)
+            .collect()
+            .await;
+        if let Err(e) = db::finish_job(job.clone(), &results, &config, &tenant, env).await {
+            console_error!("Failed to record the results of job {job}: {e}");
+        }
+    });
+
+    Ok(Response::from_json(&serde_json::json!({
+        "job_id": job_id,
+        "total": total,
+        "status_url": format!("/jobs/{job_id}")
+    }))?.with_status(202))
+}
+
+/// Handles `GET /jobs/{job_id}` (and its alias `GET /trips/regenerate/{job_id}`), reporting
+/// the progress of a batch job.
+///
+/// # Returns
+/// The job as JSON, e.g.:
+/// ```json
+/// {
+///     "id": "...", "type": "regenerate", "status": "done",
+///     "total": 2, "completed": 1, "failed": 1, "created_at": "...",
+///     "results": [
+///         { "trip_id": "a", "ok": true, "status": 200, "error": null },
+///         { "trip_id": "b", "ok": false, "status": 404, "error": "Trip not found" }
+///     ]
+/// }
+/// ```
+/// While the job is `running`, `completed` and `failed` count the items finished so far and
+/// `results` is empty. `404 Not Found` for unknown jobs.
+async fn job_status(env: Env, job_id: String, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let job = timing.measure("db", db::get_job(job_id, tenant, env)).await.map_err(|e| Error::RustError(format!("db::get_job failed: {e}")))?;
+    match job {
+        Some(job) => Response::from_json(&job),
+        None => json_error("Job not found", 404),
+    }
+}
+
+/// The longest trip, in days, a plan may cover.
+const MAX_TRIP_DAYS: u32 = 30;
+
+/// How many days of AI calls `GET /admin/stats` covers when `?days=` is not given.
+const DEFAULT_STATS_DAYS: u32 = 7;
+
+/// The longest period, in days, `GET /admin/stats` may cover.
+const MAX_STATS_DAYS: u32 = 90;
+
+/// Handles `GET /admin/stats`, reporting AI latency percentiles per operation and model.
+///
+/// The latencies are recorded by `analytics::record_ai_call` while `ANALYTICS_ENABLED` is
+/// on; with it off nothing new is recorded and the report only covers earlier calls.
+///
+/// # Arguments
+/// * `req` - The request; `?days=N` (1 to [`MAX_STATS_DAYS`], default
+///   [`DEFAULT_STATS_DAYS`]) sets how far back calls are included.
+/// * `env` - The `Env` object providing access to the D1 database.
+/// * `config` - The deployment settings; `analytics_enabled` is reported as `recording`.
+/// * `timing` - Collects the time spent in D1 for the `Server-Timing` header.
+///
+/// # Returns
+/// A JSON body such as:
+/// ```json
+/// {
+///     "days": 7,
+///     "recording": true,
+///     "ai_latency": [{ "operation": "chat", "model": "@cf/meta/llama-3.1-8b-instruct", "calls": 120, "p50_ms": 850, "p95_ms": 2400 }]
+/// }
+/// ```
+///
+/// # Errors
+/// - `400 Bad Request` if `days` is not a number in range.
+/// - Propagates database errors.
+async fn admin_stats(req: &Request, env: Env, config: &Config, timing: &ServerTiming) -> Result<Response>{
+    let days = match query_param(req, "days") {
+        None => DEFAULT_STATS_DAYS,
+        Some(days) => match days.parse::<u32>() {
+            Ok(days) if (1..=MAX_STATS_DAYS).contains(&days) => days,
+            _ => return json_error(&format!("days must be between 1 and {MAX_STATS_DAYS}"), 400),
+        },
+    };
+    let since = time::now_millis() - i64::from(days) * 24 * 60 * 60 * 1000;
+    let recording = config.analytics_enabled;
+    let latencies = timing.measure("db", db::ai_call_latencies(since, env)).await.map_err(|e| Error::RustError(format!("db::ai_call_latencies failed: {e}")))?;
+    Response::from_json(&serde_json::json!({
+        "days": days,
+        "recording": recording,
+        "ai_latency": analytics::latency_stats(latencies),
+    }))
+}
+
+/// Handles `GET /admin/trip/{trip_id}/do-state`, returning the raw storage of the trip's
+/// durable object.
+///
+/// Meant for debugging cases where D1 and the durable object disagree, e.g. after a failed
+/// `/init`. Every stored key is included, not only the ones `GET /trip/{trip_id}` returns.
+///
+/// # Returns
+/// A JSON object of the stored keys, e.g.
+/// `{ "destination": "Paris", "days": 3, "response": "...", "plan": [...], "status": "planning" }`.
+/// A session that was never initialized answers `{}`.
+///
+/// # Errors
+/// - `404 Not Found` for a trip of another tenant.
+/// - Propagates durable object errors.
+async fn do_state(env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    if tenant.is_scoped() && !timing.measure("db", db::trip_exists(trip_id.clone(), tenant, env.clone())).await? {
+        return json_error("Not Found", 404);
+    }
+    timing.measure("do", session_state(env, trip_id, config)).await
+}
+
+/// Handles `DELETE /trip/{trip_id}`, removing a trip for good.
+///
+/// The trip's row, plans and messages are deleted from D1 in one batch (see
+/// [`db::delete_trip`]), then its durable object's storage is cleared so the trip can no
+/// longer be fetched.
+///
+/// # Returns
+/// `200 OK` with `{ "deleted": true }`.
+///
+/// # Errors
+/// - `404 Not Found` if the trip does not exist, was already soft-deleted, or belongs to
+///   another tenant.
+/// - `502 Bad Gateway` if the durable object could not be cleared. The D1 rows are already
+///   gone by then, so a retry answers `404`; the leftover session only shows on
+///   `GET /trip/{trip_id}`.
+/// - Propagates database errors from `db::delete_trip`, in which case nothing was deleted.
+async fn delete_trip(env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    if !timing.measure("db", db::trip_exists(trip_id.clone(), tenant, env.clone())).await? {
+        return json_error("Trip not found", 404);
+    }
+    timing.measure("db", db::delete_trip(trip_id.clone(), tenant, env.clone()))
+        .await
+        .map_err(|e| Error::RustError(format!("db::delete_trip failed: {e}")))?;
+    let mut resp = timing.measure("do", reset_trip_session(env, trip_id, config)).await?;
+    if resp.status_code() != 200 {
+        let body = resp.text().await.unwrap_or_else(|_| "<no body>".into());
+        return json_error(&format!("Trip deleted, but clearing its session failed: {body}"), 502);
+    }
+    Response::from_json(&serde_json::json!({ "deleted": true }))
+}
+
+/// Handles `PATCH /trip/{trip_id}`, changing a trip's destination or length and planning it anew.
+///
+/// # Arguments
+/// * `req` - The request, with a form body holding `destination`, `days` or both. A field
+///   left out keeps the trip's current value, so `days=5` alone re-plans the same destination.
+/// * `env` - The `Env` object providing access to D1, the durable object and the AI service.
+/// * `trip_id` - The unique identifier of the trip.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+/// * `timing` - Collects the time spent per phase for the `Server-Timing` header.
+///
+/// # Behavior
+/// 1. Loads the trip and generates a plan for the new destination and days with
+///    `ai::create_plan`. As with `regenerate_plan`, a cancel (see [`cancel_generation`])
+///    discards the result and sets the trip to `cancelled`, leaving the trip unchanged.
+/// 2. Updates the trip's row (`db::update_trip`) and title, stores the plan as a new plan
+///    version and prunes old versions (see `Config::plan_versions_kept`).
+/// 3. Refreshes the trip's durable object with the new trip, keeping the trip's status.
+/// 4. Notes the change in the chat history ([`PLAN_REGENERATED_NOTE`]).
+///
+/// # Returns
+/// `{ "destination": "Rome", "days": 5, "title": "...", "plan": "..." }`.
+///
+/// # Errors
+/// - `400 Bad Request` if neither field is given, the destination is blank or `days` is
+///   not a valid trip length (see [`checked_days`]).
+/// - `404 Not Found` if the trip does not exist.
+/// - `415 Unsupported Media Type` if the body is not a form.
+/// - Propagates database, durable object and AI errors.
+async fn update_trip(mut req: Request, env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    if let Some(resp) = check_content_type(&req, &FORM_CONTENT_TYPES)? {
+        return Ok(resp);
+    }
+    let (form, rejected) = check_form_fields(config, req.form_data().await?, &["destination", "days"])?;
+    if let Some(resp) = rejected {
+        return Ok(resp);
+    }
+    let destination = match form.get("destination") {
+        Some(FormEntry::Field(destination)) => match checked_destination(&destination) {
+            Ok(destination) => Some(destination),
+            Err(message) => return json_error(&message, 400),
+        },
+        _ => None,
+    };
+    let days = match form.get("days") {
+        Some(FormEntry::Field(days)) => {
+            let days = match parse_days(&days) {
+                Ok(days) => days,
+                Err(message) => return json_error(&message, 400),
+            };
+            match checked_days(days, config) {
+                Ok(days) => Some(days),
+                Err(message) => return json_error(&message, 400),
+            }
+        }
+        _ => None,
+    };
+    if destination.is_none() && days.is_none() {
+        return json_error("Provide destination, days or both", 400);
+    }
+    let Some(trip) = timing.measure("db", db::find_trip(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
+    };
+    let destination = destination.unwrap_or(trip.destination);
+    let days = days.unwrap_or(trip.days);
+
+    let units = timing.measure("db", db::get_trip_units(trip_id.clone(), tenant, env.clone())).await?;
+    begin_generation(&env, &trip_id, config, timing).await?;
+    let generated = timing.measure("ai", ai::create_plan(&env, config, &destination, days, &units)).await;
+    if finish_generation(&env, &trip_id, config, timing).await? {
+        return cancelled_generation(&env, &trip_id, config, tenant, timing).await;
+    }
+    let (text, input_text) = generated.map_err(|e| Error::RustError(format!("ai::create_plan failed: {e}")))?;
+    timing.measure("db", db::update_trip(trip_id.clone(), &destination, days, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::update_trip failed: {e}")))?;
+    timing.measure("db", db::create_plan(trip_id.clone(), &text, &input_text, ai::is_refusal(&env, &text), config, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::create_plan failed: {e}")))?;
+    timing.measure("db", db::prune_plans(trip_id.clone(), config.plan_versions_kept, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::prune_plans failed: {e}")))?;
+
+    let title = timing.measure("ai", ai::generate_title(&env, config, &destination, days, &text)).await;
+    timing.measure("db", db::update_trip_title(trip_id.clone(), &title, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::update_trip_title failed: {e}")))?;
+
+    let payload = TripInit { destination, days, plan: plan::parse_days(&text), response: text, status: trip.status };
+    let mut resp = timing.measure("do", init_trip_session(env.clone(), trip_id.clone(), &payload, config)).await?;
+    if resp.status_code() != 200 {
+        let body = resp.text().await.unwrap_or_else(|_| "<no body>".into());
+        return json_error(&format!("failed to update trip session: {body}"), 500);
+    }
+    timing.measure("db", create_message(trip_id, PLAN_REGENERATED_NOTE, MessageRole::System, config, tenant, env)).await.map_err(|e| Error::RustError(format!("db::create_message failed: {e}")))?;
+    Response::from_json(&serde_json::json!({ "destination": payload.destination, "days": days, "title": title, "plan": payload.response }))
+}
+
+/// Clears every stored key of a trip's session (its durable object's `DELETE /`).
+async fn reset_trip_session(env: Env, trip_id: String, config: &Config) -> Result<Response>{
+    session::store(&env, config)?.reset(&trip_id).await
+}
+
+/// Fetches every stored key of a trip's session (its durable object's `GET /state`).
+async fn session_state(env: Env, trip_id: String, config: &Config) -> Result<Response>{
+    session::store(&env, config)?.state(&trip_id).await
+}
+
+/// The durable object keys that `POST /admin/trip/{trip_id}/reconcile` rewrites from D1.
+const RECONCILED_KEYS: [&str; 5] = ["destination", "days", "response", "plan", "status"];
+
+/// Handles `POST /admin/trip/{trip_id}/reconcile`, rewriting the trip's durable object from D1.
+///
+/// D1 is authoritative: the trip's destination, length and status and its latest plan are
+/// read from it and compared with the durable object's storage. When any of
+/// [`RECONCILED_KEYS`] differ, or the session was never initialized, the session is
+/// re-initialized with the D1 values; otherwise it is left alone.
+///
+/// # Returns
+/// A report such as
+/// `{ "trip_id": "...", "was_initialized": true, "changed": ["response", "plan"], "updated": true }`,
+/// where `changed` lists the keys whose stored value differed from D1.
+///
+/// # Errors
+/// - `404 Not Found` if the trip or its plan does not exist in D1.
+/// - `502 Bad Gateway` if the durable object's state cannot be read or the re-initialization fails.
+/// - Propagates database errors.
+async fn reconcile_trip(env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Some(trip) = timing.measure("db", db::find_trip(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
+    };
+    let Some((text, _, _, _)) = timing.measure("db", db::get_latest_plan(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Plan not found", 404);
+    };
+    let mut state = timing.measure("do", session_state(env.clone(), trip_id.clone(), config)).await?;
+    if state.status_code() != 200 {
+        return json_error(&format!("failed to read trip session state: {}", state.text().await.unwrap_or_default()), 502);
+    }
+    let stored: serde_json::Value = state.json().await?;
+    let was_initialized = stored.get("response").is_some();
+
+    let payload = TripInit { destination: trip.destination, days: trip.days, plan: plan::parse_days(&text), response: text, status: trip.status };
+    let expected = serde_json::to_value(&payload)?;
+    let changed: Vec<&str> = RECONCILED_KEYS
+        .into_iter()
+        .filter(|key| stored.get(key) != expected.get(key))
+        .collect();
+    let updated = !changed.is_empty();
+    if updated {
+        let mut resp = timing.measure("do", init_trip_session(env, trip_id.clone(), &payload, config)).await?;
+        if resp.status_code() != 200 {
+            let body = resp.text().await.unwrap_or_else(|_| "<no body>".into());
+            return json_error(&format!("failed to initialize trip: {body}"), 502);
+        }
+    }
+    Response::from_json(&serde_json::json!({
+        "trip_id": trip_id,
+        "was_initialized": was_initialized,
+        "changed": changed,
+        "updated": updated
+    }))
+}
+
+/// The most trips a single `POST /plan/combine` may combine.
+const MAX_COMBINED_TRIPS: usize = 5;
+
+/// The JSON body accepted by `POST /plan/combine`.
+///
+/// # Fields
+/// * `trip_ids` - The trips to combine, in travel order; duplicates are ignored.
+#[derive(Deserialize)]
+struct CombineRequest {
+    trip_ids: Vec<String>,
+}
+
+/// Handles `POST /plan/combine`, asking the AI to join several trips into one multi-leg
+/// itinerary.
+///
+/// The latest plan of each trip is given to the model as context, in the order the trips
+/// are named. Nothing is stored: the source trips, their plans and chats are left as they
+/// are, and the combined itinerary is only returned.
+///
+/// # Arguments
+/// * `req` - The HTTP request whose JSON body is a [`CombineRequest`].
+/// * `env` - The `Env` object providing access to D1 and the AI service.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+/// * `tenant` - The tenant of the request; only its trips can be combined.
+/// * `timing` - Collects the time spent per phase for the `Server-Timing` header.
+///
+/// # Returns
+/// A JSON body such as:
+/// ```json
+/// {
+///     "legs": [{ "trip_id": "...", "destination": "Paris", "days": 3 }, { "trip_id": "...", "destination": "Rome", "days": 4 }],
+///     "total_days": 7,
+///     "plan": "Day 1: ...",
+///     "days": [{ "day": 1, "text": "..." }]
+/// }
+/// ```
+/// where `days` is the plan split by [`plan::parse_days`].
+///
+/// # Errors
+/// - `400 Bad Request` if the body is not valid JSON, names fewer than two or more than
+///   [`MAX_COMBINED_TRIPS`] distinct trips, or the trips add up to more than [`MAX_TRIP_DAYS`].
+/// - `404 Not Found` if a trip does not exist, belongs to another tenant, or has no plan.
+/// - Propagates database and AI errors.
+async fn combine_plans(mut req: Request, env: Env, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Ok(body) = req.json::<CombineRequest>().await else {
+        return json_error("Body must be JSON with `trip_ids`", 400);
+    };
+    let mut trip_ids: Vec<String> = Vec::new();
+    for id in body.trip_ids {
+        if !trip_ids.contains(&id) {
+            trip_ids.push(id);
+        }
+    }
+    if trip_ids.len() < 2 || trip_ids.len() > MAX_COMBINED_TRIPS {
+        return json_error(&format!("`trip_ids` must name between 2 and {MAX_COMBINED_TRIPS} different trips"), 400);
+    }
+    let mut legs = Vec::new();
+    for id in &trip_ids {
+        let Some(trip) = timing.measure("db", db::find_trip(id.clone(), tenant, env.clone())).await? else {
+            return json_error(&format!("Trip not found: {id}"), 404);
+        };
+        let Some((plan, _, _, _)) = timing.measure("db", db::get_latest_plan(id.clone(), tenant, env.clone())).await? else {
+            return json_error(&format!("No plan stored for trip {id}"), 404);
+        };
+        legs.push((trip, plan));
+    }
+    let total_days: u32 = legs.iter().map(|(trip, _)| trip.days).sum();
+    if total_days > MAX_TRIP_DAYS {
+        return json_error(&format!("The combined trips are {total_days} days long; at most {MAX_TRIP_DAYS} can be combined"), 400);
+    }
+    let context: Vec<(String, u32, String)> = legs.iter().map(|(trip, plan)| (trip.destination.clone(), trip.days, plan.clone())).collect();
+    let text = timing.measure("ai", ai::combine_plans(&env, config, &context)).await.map_err(|e| Error::RustError(format!("ai::combine_plans failed: {e}")))?;
+    let legs: Vec<serde_json::Value> = legs
+        .into_iter()
+        .map(|(trip, _)| serde_json::json!({ "trip_id": trip.id, "destination": trip.destination, "days": trip.days }))
+        .collect();
+    Response::from_json(&serde_json::json!({
+        "legs": legs,
+        "total_days": total_days,
+        "days": plan::parse_days(&text),
+        "plan": text,
+    }))
+}
+
+/// The JSON body accepted by `POST /compare`.
+///
+/// # Fields
+/// * `destinations` - Exactly two destinations to compare.
+/// * `days` - The length of the trip being considered, from 1 to [`MAX_TRIP_DAYS`].
+#[derive(Deserialize)]
+struct CompareRequest {
+    destinations: Vec<String>,
+    days: u32,
+}
+
+/// Handles `POST /compare`, asking the AI to compare two destinations for a trip.
+///
+/// No trip is created. Each destination is checked like the one posted to `/input`.
+///
+/// # Returns
+/// The [`compare::Comparison`] with a `cached` flag, e.g.:
+/// ```json
+/// {
+///     "options": [
+///         { "destination": "Lisbon", "pros": ["..."], "cons": ["..."], "best_for": "..." },
+///         { "destination": "Porto", "pros": ["..."], "cons": ["..."], "best_for": "..." }
+///     ],
+///     "summary": "...",
+///     "recommendation": "...",
+///     "cached": false
+/// }
+/// ```
+///
+/// # Errors
+/// - `400 Bad Request` if the body is not valid JSON, does not name exactly two different
+///   destinations, a destination is blank, or `days` is outside 1 to [`MAX_TRIP_DAYS`].
+/// - Propagates AI errors, including an answer that is not a usable comparison.
+async fn compare_destinations(mut req: Request, env: Env, config: &Config, timing: &ServerTiming) -> Result<Response>{
+    let Ok(body) = req.json::<CompareRequest>().await else {
+        return json_error("Body must be JSON with `destinations` and `days`", 400);
+    };
+    let [first, second] = body.destinations.as_slice() else {
+        return json_error("`destinations` must name exactly two destinations", 400);
+    };
+    let (first, second) = match (checked_destination(first), checked_destination(second)) {
+        (Ok(first), Ok(second)) => (first, second),
+        (Err(message), _) | (_, Err(message)) => return json_error(&message, 400),
+    };
+    if first.eq_ignore_ascii_case(&second) {
+        return json_error("`destinations` must name two different destinations", 400);
+    }
+    if body.days == 0 || body.days > MAX_TRIP_DAYS {
+        return json_error(&format!("days must be between 1 and {MAX_TRIP_DAYS}"), 400);
+    }
+    let (comparison, cached) = timing.measure("ai", compare::compare(&env, config, &first, &second, body.days)).await
+        .map_err(|e| Error::RustError(format!("compare::compare failed: {e}")))?;
+    let mut json = serde_json::to_value(&comparison)?;
+    json["cached"] = serde_json::json!(cached);
+    Response::from_json(&json)
+}
+
+/// Body of a `POST /trip/{trip_id}/plan/extend` request.
+#[derive(Deserialize)]
+struct ExtendRequest {
+    additional_days: u32,
+}
+
+/// Handles `POST /trip/{trip_id}/plan/extend`, adding more days to an existing plan.
 ///
-/// 4. **POST `/trip/{trip_id}`:**
-///    Calls the `chat` handler with the request, environment, and context to process chat messages for the given trip ID.
+/// # Arguments
+/// * `req` - The request, with a JSON body such as `{ "additional_days": 2 }`.
+/// * `env` - The `Env` object providing access to D1, the durable object and the AI service.
+/// * `trip_id` - The unique identifier of the trip.
+/// * `config` - The deployment settings, passed down to the calls that read them.
+/// * `timing` - Collects the time spent per phase for the `Server-Timing` header.
 ///
-/// 5. **GET `/chat/{trip_id}`:**
-///    - Extracts the `trip_id` from the URL path.
-///    - Checks if any messages exist for the given trip ID via the `check_if_messages` function.
-///        - If messages exist, retrieves them via the `get_messages` function and returns as a JSON response.
-///        - Otherwise, returns a response with "No messages yet".
+/// # Behavior
+/// 1. Loads the trip and its latest plan.
+/// 2. Asks `ai::extend_plan` for the extra days, giving it the current plan as context. As
+///    with `regenerate_plan`, a cancel (see [`cancel_generation`]) discards the result and
+///    sets the trip to `cancelled`.
+/// 3. Stores the combined plan as a new plan version, prunes versions beyond
+///    `Config::plan_versions_kept`, and updates the trip's `days`.
+/// 4. Refreshes the trip's durable object so chat and the trip page see the longer plan.
 ///
-/// 6. **Fallback:**
-///    If no route matches, returns a `Response::error("Not Found", 404)`.
+/// # Returns
+/// `{ "days": 7, "plan": "..." }` with the new trip length and the combined plan.
 ///
-/// # Notes
-/// - Handlers like `index`, `input`, `get_trip`, `chat`, `check_if_messages`, and `get_messages` must be properly implemented.
-/// - The included `chat.html` file is assumed to exist at `../public/chat.html`.
-/// - The function is designed for asynchronous execution and leverages the `async` Rust programming model.
-#[event(fetch)]
-pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response>{
-    let path = req.path();
-
-    if req.method() == Method::Get && path == "/" {
-        return index().await;
-    }
-    else if req.method() == Method::Post && path == "/input"{
-        return input(req, env, _ctx).await;
+/// # Errors
+/// - `400 Bad Request` if the body is not valid JSON, `additional_days` is `0`, or the trip
+///   would exceed [`MAX_TRIP_DAYS`].
+/// - `404 Not Found` if the trip or its plan does not exist.
+/// - Propagates database, durable object and AI errors.
+async fn extend_plan(mut req: Request, env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Ok(body) = req.json::<ExtendRequest>().await else {
+        return json_error("Expected a JSON body with additional_days", 400);
+    };
+    if body.additional_days == 0 {
+        return json_error("additional_days must be at least 1", 400);
     }
-    if req.method() == Method::Get && path.starts_with("/trip/") {
-        let trip_id = path.trim_start_matches("/trip/").to_string();
-        let accept_header = req.headers().get("Accept").unwrap_or_default().unwrap_or_default();
-        if accept_header.contains("text/html") {
-            let html = include_str!("../public/chat.html");
-            return Ok(Response::from_html(html)?);
-        } else {
-            return get_trip(env, trip_id).await;
-        }
+    let Some(trip) = timing.measure("db", db::find_trip(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
+    };
+    let days = trip.days.saturating_add(body.additional_days);
+    if days > MAX_TRIP_DAYS {
+        return json_error(&format!("A trip can be at most {MAX_TRIP_DAYS} days long"), 400);
     }
-    if req.method() == Method::Post && path.starts_with("/trip/") {
-        return chat(req, env, _ctx).await
+    let Some((current, _, _, _)) = timing.measure("db", db::get_latest_plan(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Plan not found", 404);
+    };
+
+    let units = timing.measure("db", db::get_trip_units(trip_id.clone(), tenant, env.clone())).await?;
+    begin_generation(&env, &trip_id, config, timing).await?;
+    let generated = timing.measure("ai", ai::extend_plan(&env, config, &trip.destination, &current, trip.days, body.additional_days, &units)).await;
+    if finish_generation(&env, &trip_id, config, timing).await? {
+        return cancelled_generation(&env, &trip_id, config, tenant, timing).await;
     }
-    if req.method() == Method::Get && path.starts_with("/chat/") {
-        let trip_id = path.trim_start_matches("/chat/").to_string();
-        if check_if_messages(trip_id.clone(), env.clone()).await? {
-            let messages = get_messages(trip_id, env).await?;
-            let body = serde_json::to_string(&messages)?;
-            return Response::ok(body);
-        }
-        return Response::ok("No messages yet");
+    let extension = generated.map_err(|e| Error::RustError(format!("ai::extend_plan failed: {e}")))?;
+    let combined = format!("{}\n{}", current.trim_end(), extension);
+    let input_text = format!("Extend the trip to {} from {} to {days} days.", trip.destination, trip.days);
+    timing.measure("db", db::create_plan(trip_id.clone(), &combined, &input_text, ai::is_refusal(&env, &extension), config, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::create_plan failed: {e}")))?;
+    timing.measure("db", db::prune_plans(trip_id.clone(), config.plan_versions_kept, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::prune_plans failed: {e}")))?;
+    timing.measure("db", db::update_trip_days(trip_id.clone(), days, tenant, env.clone())).await.map_err(|e| Error::RustError(format!("db::update_trip_days failed: {e}")))?;
+
+    let payload = TripInit { destination: trip.destination, days, plan: plan::parse_days(&combined), response: combined, status: trip.status };
+    let mut resp = timing.measure("do", init_trip_session(env, trip_id, &payload, config)).await?;
+    if resp.status_code() != 200 {
+        let body = resp.text().await.unwrap_or_else(|_| "<no body>".into());
+        return json_error(&format!("failed to update trip session: {body}"), 500);
     }
-    Response::error("Not Found", 404)
+    Response::from_json(&serde_json::json!({ "days": days, "plan": payload.response }))
 }
 
-/// Handles an HTTP request to facilitate a chat interaction between a user and an AI.
+/// Handles `GET /trip/{trip_id}/plan/diff?from=<ver>&to=<ver>`, comparing two plan versions.
+///
+/// Versions are numbered from 1 in the order plans were stored for the trip.
 ///
 /// # Arguments
-/// * `req` - The HTTP request that contains the form data and any necessary metadata.
-/// * `env` - The `Env` object, providing access to environment variables and external services.
-/// * `_ctx` - Context parameter, not utilized in this implementation, but included for compatibility.
+/// * `req` - The request carrying the `from` and `to` version numbers.
+/// * `env` - The `Env` object providing access to the D1 database.
+/// * `trip_id` - The unique identifier of the trip.
+/// * `timing` - Collects the time spent in D1 for the `Server-Timing` header.
 ///
 /// # Returns
-/// Returns an `Ok(Response)` containing the AI's chat response if successful. Returns an error if
-/// any required fields are missing or if an operation fails.
-///
-/// # Behavior
-/// 1. Extracts the form data from the request, specifically looking for a `message` field.
-///    - If the `message` field is missing, returns a `400 Missing field` error.
-/// 2. Extracts the `trip_id` from the request path by removing the "/trip/" prefix.
-/// 3. Creates a user message in the database by calling `create_message`, associating it with the trip and storing it as a "User" message.
-///    - Returns an error if the database operation fails.
-/// 4. Retrieves the current state of the trip by calling `get_trip`.
-/// 5. Checks whether messages for the trip already exist by calling `check_if_messages`.
-///    - If no prior messages are found, initiates an AI response with an empty message history.
-///    - If messages are found, fetches the message history and includes it in the AI response generation.
-/// 6. Delegates to the AI system by calling `ai::chat` to generate a response based on the message history and the user's message.
-/// 7. Stores the AI response as a message in the database by calling `create_message` as an "AI" message.
-///    - Returns an error if the database operation fails during this step.
-/// 8. Returns an `Ok(Response)` containing the AI-generated response to the client.
+/// A JSON body such as:
+/// ```json
+/// {
+///     "from": 1,
+///     "to": 2,
+///     "changes": [{ "op": "delete", "text": "Morning: Louvre" }, { "op": "insert", "text": "Morning: Orsay" }],
+///     "unified": "@@ -1,3 +1,3 @@\n..."
+/// }
+/// ```
 ///
 /// # Errors
-/// This function can return errors in the following scenarios:
-/// - The "message" field is missing from the request's form data.
-/// - Database operations (`create_message`, `get_trip`, `check_if_messages`) fail.
-/// - AI response generation (`ai::chat`) fails.
+/// - `400 Bad Request` if `from` or `to` is missing or not a positive number.
+/// - `404 Not Found` if either version does not exist.
+async fn diff_plan(req: &Request, env: Env, trip_id: String, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let version = |name: &str| query_param(req, name).and_then(|v| v.parse::<u32>().ok()).filter(|v| *v > 0);
+    let (Some(from), Some(to)) = (version("from"), version("to")) else {
+        return json_error("from and to must be positive plan version numbers", 400);
+    };
+    let Some((old, _)) = timing.measure("db", db::get_plan_version(trip_id.clone(), from, tenant, env.clone())).await? else {
+        return json_error(&format!("Plan version {from} not found"), 404);
+    };
+    let Some((new, _)) = timing.measure("db", db::get_plan_version(trip_id, to, tenant, env)).await? else {
+        return json_error(&format!("Plan version {to} not found"), 404);
+    };
+    let (changes, unified) = plan::diff(&old, &new);
+    Response::from_json(&serde_json::json!({
+        "from": from,
+        "to": to,
+        "changes": changes,
+        "unified": unified
+    }))
+}
+
+/// Handles `GET /trip/{trip_id}/plan/validate`, checking the stored plan against the trip's day count.
 ///
-/// # Example
-/// ```
-/// // Example HTTP request with "message" in form data
-/// let req = Request::new().form_data("message", "Hello, AI!");
-/// let response = chat(req, env, _ctx).await;
+/// # Arguments
+/// * `env` - The `Env` object providing access to the D1 database.
+/// * `trip_id` - The unique identifier of the trip to validate.
+///
+/// # Returns
+/// A JSON [`plan::ValidationReport`], for example:
+/// ```json
+/// { "valid": false, "expected_days": 3, "found_days": [1, 2, 2], "issues": ["Day 2 appears 2 times", "Day 3 is missing"] }
 /// ```
 ///
-/// This example demonstrates handling a user's "Hello, AI!" message in chat and returning the AI's response.
-async fn chat(mut req: Request, env: Env, _ctx: Context) -> Result<Response>{
-    let form = req.form_data().await?;
-    let Some(FormEntry::Field(message)) = form.get("message") else {
-        return Response::error("Missing field: message", 400);
+/// # Errors
+/// - `404 Not Found` if the trip does not exist or has no stored plan.
+/// - Propagates database errors from `db::find_trip` and `db::get_latest_plan`.
+async fn validate_plan(env: Env, trip_id: String, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Some(trip) = timing.measure("db", db::find_trip(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
     };
-    let path = req.path();
-    let trip_id = path.trim_start_matches("/trip/").to_string();
-    create_message(trip_id.clone(), &message, "User", env.clone()).await.map_err(|e| Error::RustError(format!("db::create_message failed: {e}")))?;
-    let mut trip = get_trip(env.clone(), trip_id.clone()).await?;
-    if !check_if_messages(trip_id.clone(), env.clone()).await? {
-        let resp = ai::chat(&env, &trip.text().await?, vec![("".to_string(),"".to_string(),"".to_string())], &message).await?;
-        return Response::ok(resp);
-    }
-    let resp = ai::chat(&env, &trip.text().await?, get_messages(trip_id.clone(), env.clone()).await?, &message).await?;
-    create_message(trip_id, &resp, "AI", env.clone()).await.map_err(|e| Error::RustError(format!("db::create_message failed: {e}")))?;
-    Response::ok(resp)
+    let Some((text, _, _, _)) = timing.measure("db", db::get_latest_plan(trip_id, tenant, env)).await? else {
+        return json_error("No plan stored for this trip", 404);
+    };
+    let report = plan::validate(&plan::parse_days(&text), trip.days);
+    Response::from_json(&report)
 }
 
-/// Handles the `input` endpoint for creating a trip plan. This function is responsible for:
-/// 1. Parsing and validating form data.
-/// 2. Generating a unique trip ID.
-/// 3. Interacting with the AI service to generate a travel plan.
-/// 4. Initializing a corresponding durable object to store session-specific trip data.
-/// 5. Creating a trip record in the database with its corresponding plan.
-/// 6. Redirecting the user to the newly created trip's page.
+/// Handles `POST /trip/{trip_id}/plan/feasibility`, asking the AI whether each day of the
+/// latest plan is realistic.
 ///
-/// # Parameters
-/// - `req`: The incoming request containing form data with `destination` and `days` fields.
-/// - `env`: The environment context providing required bindings (e.g., Durable Object, KV, AI services).
-/// - `_ctx`: Execution context, not used in this implementation.
+/// # Arguments
+/// * `env` - The `Env` object providing access to the D1 database and the AI service.
+/// * `trip_id` - The unique identifier of the trip to check.
+/// * `config` - The deployment settings, passed down to the calls that read them.
 ///
 /// # Returns
-/// `Result<Response>`:
-/// - On success, a HTTP redirect response to the new trip's page.
-/// - On failure, an error response with an appropriate status code and message.
+/// A JSON [`feasibility::Report`] with a `text` field added, for example:
+/// ```json
+/// { "feasible": false, "warnings": [{ "day": 2, "kind": "too_packed", "message": "..." }], "summary": "...", "text": null }
+/// ```
+/// When the answer cannot be read as a report (see [`feasibility::parse`]), `feasible` is
+/// `null`, `warnings` is empty and `text` holds the model's answer as prose.
 ///
 /// # Errors
-/// - Returns a `400 Bad Request` response:
-///   - If the `destination` or `days` fields are missing in the form data.
-///   - If the `days` field is not a valid number.
-/// - Returns a `500 Internal Server Error` response:
-///   - If the AI service fails to generate a trip plan.
-///   - If the durable object initialization fails.
-///   - If the database creation of the trip or its plan fails.
-///   - If any other unexpected error occurs during the request lifecycle.
-///
-/// # Process Flow
-/// 1. Parse form data and validate the presence of the `destination` and `days` fields.
-/// 2. Parse the `days` value to ensure it is a valid number.
-/// 3. Generate a new unique trip ID using `Uuid`.
-/// 4. Establish a reference to the durable object using this trip ID.
-/// 5. Call the `ai::create_plan` function with the destination and days to generate a travel plan.
-/// 6. Create a `TripInit` payload with the generated plan and initialize the trip session durable object:
-///    - Send a POST request to the durable object at the `https://trip-session/init` endpoint.
-///    - If the request fails, return an error response.
-/// 7. Store the trip data by calling `create_trip` to persist the trip in the database.
-/// 8. Store the AI-generated plans with `db::create_plan` in the database.
-/// 9. Build a redirect URL pointing to the new trip's page and return a `302 Redirect` response.
-///
-/// # Example
-/// When called with valid form data (`destination="Paris"`, `days="5"`), the function:
-/// - Creates a unique trip ID such as `12345678-abcd-1234-efgh-123456abcdef`.
-/// - Generates an AI travel plan for Paris for 5 days.
-/// - Initializes a trip session durable object and persists the trip to a database.
-/// - Redirects the user to `/trip/12345678-abcd-1234-efgh-123456abcdef`.
-async fn input(mut req: Request, env: Env, _ctx: Context) -> Result<Response>{
-    let form = req.form_data().await?;
-    let Some(FormEntry::Field(destination)) = form.get("destination") else {
-        return Response::error("Missing field: destination", 400);
+/// - `404 Not Found` if the trip does not exist or has no stored plan.
+/// - `400 Bad Request` if the plan has no `Day N` sections to check.
+/// - Propagates database and AI errors.
+async fn check_feasibility(env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Some(trip) = timing.measure("db", db::find_trip(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
     };
-    let Some(FormEntry::Field(days_str)) = form.get("days") else {
-        return Response::error("Missing field: days", 400);
+    let Some((text, _, _, _)) = timing.measure("db", db::get_latest_plan(trip_id, tenant, env.clone())).await? else {
+        return json_error("No plan stored for this trip", 404);
     };
-    let days: u32 = days_str.parse().map_err(|_| Error::RustError("days must be a number".into()))?;
-    let trip_id = Uuid::new_v4().to_string();
-    let ns = env.durable_object("TRIP_SESSION_DO")?;
-    let stub = ns.get_by_name(trip_id.as_str())?;
-
-    let response = ai::create_plan(&env, &destination, days).await.map_err(|e| Error::RustError(format!("ai::create_plan failed: {e}")))?;
-    let r = response.0.clone();
-    let init_payload = TripInit { destination, days, response: r };
-
-    let mut headers = Headers::new();
-    headers.set("Content-Type", "application/json")?;
-
-    let mut init = RequestInit::new();
-    init.method = Method::Post;
-    init.with_headers(headers);
-    init.with_body(Some(serde_json::to_string(&init_payload)?.into()));
-
-    let do_req = Request::new_with_init("https://trip-session/init", &init)?;
-    let mut resp = stub.fetch_with_request(do_req).await?;
-    if resp.status_code() != 200 {
-        let body = resp.text().await.unwrap_or_else(|_| "<no body>".into());
-        return Response::error(format!("failed to initialize trip: {body}"), 500);
+    let days = plan::parse_days(&text);
+    if days.is_empty() {
+        return json_error("The plan has no structured days to check", 400);
     }
-
-    let trip = &TripData {
-        id: trip_id.clone(),
-        destination: init_payload.destination,
-        days: init_payload.days,
+    let answer = timing.measure("ai", ai::check_feasibility(&env, config, &trip.destination, &days)).await
+        .map_err(|e| Error::RustError(format!("ai::check_feasibility failed: {e}")))?;
+    let Some(report) = feasibility::parse(&answer, days.iter().map(|d| d.day).max().unwrap_or(trip.days)) else {
+        return Response::from_json(&serde_json::json!({ "feasible": null, "warnings": [], "summary": null, "text": answer }));
     };
-    create_trip(trip.clone(), env.clone()).await.map_err(|e| Error::RustError(format!("db::create_trip failed: {e}")))?;
-    db::create_plan(trip.id.clone(),&response.0, &response.1, env.clone()).await.map_err(|e| Error::RustError(format!("db::create_plan failed: {e}")))?;
-    let mut url = req.url()?;
-    url.set_path(&format!("/trip/{trip_id}"));
-    url.set_query(None);
-    let resp = Response::redirect(url)?;
-    Ok(resp)
+    let mut body = serde_json::to_value(&report)?;
+    body["text"] = serde_json::Value::Null;
+    Response::from_json(&body)
 }
 
-/// Fetches a trip session from a durable object based on the provided trip ID.
+/// Handles `POST /trip/{trip_id}/plan/day/{n}/alternatives`, asking the AI for other ways
+/// to spend day `n` of the latest plan. The stored plan is not changed.
 ///
 /// # Arguments
-/// * `env` - The `Env` object, which is typically used to access environment bindings
-///   such as durable objects.
-/// * `trip_id` - A `String` representing the unique identifier for the trip session
-///   to be fetched.
+/// * `req` - The request; `?count=` sets how many alternatives to ask for (default
+///   [`alternatives::DEFAULT_ALTERNATIVES`], at most [`alternatives::MAX_ALTERNATIVES`]).
+/// * `env` - The `Env` object providing access to the D1 database and the AI service.
+/// * `trip_id` - The unique identifier of the trip.
+/// * `action` - The path after the trip id, e.g. `plan/day/2/alternatives`.
+/// * `config` - The deployment settings, passed down to the calls that read them.
 ///
 /// # Returns
-/// * `Result<Response>` - Returns an `Ok(Response)` if the fetch operation is successful,
-///   or an `Err` if an error occurs during the process.
-///
-/// # Functionality
-/// 1. Retrieves a reference to the `TRIP_SESSION_DO` durable object binding using `env.durable_object("TRIP_SESSION_DO")`.
-/// 2. Generates an ID from the trip name (`trip_id`) using the `id_from_name` method.
-/// 3. Retrieves a durable object stub using the trip ID.
-/// 4. Constructs a `GET` request to the specific durable object endpoint (`https://trip-session/`).
-/// 5. Sends the request to the durable object using the `stub.fetch_with_request` method.
-/// 6. Returns the HTTP response from the durable object.
+/// For example:
+/// ```json
+/// { "day": 2, "alternatives": [{ "title": "...", "activities": ["Morning: ...", "..."] }], "text": null }
+/// ```
+/// When the answer cannot be read (see [`alternatives::parse`]), `alternatives` is empty and
+/// `text` holds the model's answer as prose.
 ///
 /// # Errors
-/// This function may return an error in the following cases:
-/// * If the durable object binding "TRIP_SESSION_DO" is not found.
-/// * If the `trip_id` cannot be converted to a valid durable object ID.
-/// * If there is an issue creating or sending the `Request`.
-/// * If there is an issue while fetching the response from the durable object.
+/// - `404 Not Found` if the path is not `plan/day/{n}/alternatives`, the trip or its plan
+///   does not exist, or the plan has no day `n`.
+/// - `400 Bad Request` if `count` is not a number from 1 to [`alternatives::MAX_ALTERNATIVES`].
+/// - Propagates database and AI errors.
+async fn day_alternatives(req: &Request, env: Env, trip_id: String, action: &str, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    let Some(day) = day_alternatives_day(action) else {
+        return json_error("Not Found", 404);
+    };
+    let count = match query_param(req, "count").map(|v| v.trim().parse::<usize>()) {
+        None => alternatives::DEFAULT_ALTERNATIVES,
+        Some(Ok(count)) if (1..=alternatives::MAX_ALTERNATIVES).contains(&count) => count,
+        Some(_) => return json_error(&format!("count must be between 1 and {}", alternatives::MAX_ALTERNATIVES), 400),
+    };
+    let Some(trip) = timing.measure("db", db::find_trip(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
+    };
+    let Some((text, _, _, _)) = timing.measure("db", db::get_latest_plan(trip_id, tenant, env.clone())).await? else {
+        return json_error("No plan stored for this trip", 404);
+    };
+    let days = plan::parse_days(&text);
+    if !days.iter().any(|d| d.day == day) {
+        return json_error(&format!("The plan has no day {day}"), 404);
+    }
+    let answer = timing.measure("ai", ai::day_alternatives(&env, config, &trip.destination, &days, day, count)).await
+        .map_err(|e| Error::RustError(format!("ai::day_alternatives failed: {e}")))?;
+    let body = match alternatives::parse(&answer, count) {
+        Some(alternatives) => serde_json::json!({ "day": day, "alternatives": alternatives, "text": null }),
+        None => serde_json::json!({ "day": day, "alternatives": [], "text": answer }),
+    };
+    Response::from_json(&body)
+}
+
+/// Reads the day out of a `plan/day/{n}/alternatives` action, or `None` for any other path.
+fn day_alternatives_day(action: &str) -> Option<u32> {
+    action.strip_prefix("plan/day/")?.strip_suffix("/alternatives")?.parse().ok()
+}
+
+/// The most places looked up for a single map, to bound the number of geocoding requests.
+const MAX_MAP_PLACES: usize = 50;
+
+/// Handles `GET /trip/{trip_id}/map.geojson`, returning the places in the trip's plan as a map.
 ///
-/// # Example Usage
-/// ```rust
-/// use worker::{Env, Response};
+/// # Arguments
+/// * `env` - The `Env` object providing access to the D1 database and geocoding settings.
+/// * `trip_id` - The unique identifier of the trip.
+/// * `config` - The deployment settings, passed down to the calls that read them.
 ///
-/// async fn example(env: Env) -> Result<Response> {
-///     let trip_id = "some-trip-id".to_string();
-///     let response = get_trip(env, trip_id).await?;
-///     Ok(response)
-/// }
+/// # Returns
+/// A GeoJSON `FeatureCollection` (`application/geo+json`) with one `Point` feature per
+/// place found by [`plan::places`] in each day of the latest plan, for example:
+/// ```json
+/// { "type": "FeatureCollection", "features": [
+///   { "type": "Feature", "geometry": { "type": "Point", "coordinates": [2.3376, 48.8606] },
+///     "properties": { "name": "Louvre Museum", "day": 1 } }
+/// ] }
 /// ```
+/// Places are looked up with the trip's destination appended (see [`geocode::geocode`]);
+/// places that cannot be geocoded are left out, and at most [`MAX_MAP_PLACES`] are looked up.
 ///
-/// Ensure that your Worker has the `TRIP_SESSION_DO` binding configured in the environment for the function to work properly.
-async fn get_trip(env: Env, trip_id: String) -> Result<Response>{
-    let ns = env.durable_object("TRIP_SESSION_DO")?;
+/// # Errors
+/// - `403 Forbidden` when `GEOCODING_ENABLED` is off.
+/// - `404 Not Found` if the trip does not exist or has no stored plan.
+/// - Propagates database errors.
+async fn map_geojson(env: Env, trip_id: String, config: &Config, tenant: &Tenant, timing: &ServerTiming) -> Result<Response>{
+    if !config.geocoding_enabled {
+        return json_error("Geocoding is disabled on this deployment", 403);
+    }
+    let Some(trip) = timing.measure("db", db::find_trip(trip_id.clone(), tenant, env.clone())).await? else {
+        return json_error("Trip not found", 404);
+    };
+    let Some((text, _, _, _)) = timing.measure("db", db::get_latest_plan(trip_id, tenant, env.clone())).await? else {
+        return json_error("No plan stored for this trip", 404);
+    };
+    let places = plan::parse_days(&text)
+        .into_iter()
+        .flat_map(|day| plan::places(&day.text).into_iter().map(move |place| (day.day, place)))
+        .take(MAX_MAP_PLACES)
+        .collect::<Vec<_>>();
+    let mut features = vec![];
+    for (day, place) in places {
+        let query = format!("{place}, {}", trip.destination);
+        let Some(point) = timing.measure("geo", geocode::geocode(&env, &query)).await else {
+            continue;
+        };
+        features.push(serde_json::json!({
+            "type": "Feature",
+            "geometry": { "type": "Point", "coordinates": [point.lon, point.lat] },
+            "properties": { "name": place, "day": day }
+        }));
+    }
+    let mut resp = Response::from_json(&serde_json::json!({ "type": "FeatureCollection", "features": features }))?;
+    resp.headers_mut().set("Content-Type", "application/geo+json")?;
+    Ok(resp)
+}
 
-    let stub = ns.get_by_name(trip_id.as_str());
+/// How many times a durable object request is retried after the first attempt fails.
+const DO_FETCH_RETRIES: u32 = 2;
 
-    let mut init = RequestInit::new();
-    init.method = Method::Get;
+/// How long a single durable object request may take before it is abandoned.
+const DO_FETCH_TIMEOUT_MS: u64 = 5_000;
 
-    let do_req = Request::new_with_init("https://trip-session/", &init)?;
-    let resp = stub?.fetch_with_request(do_req).await?;
+/// Sends a request to a durable object, retrying transient failures with a timeout per attempt.
+///
+/// # Arguments
+/// * `stub` - The durable object stub to send the request to.
+/// * `req` - The request to send. It is cloned for every attempt so its body can be resent.
+/// * `retries` - How many additional attempts are made after the first one fails.
+/// * `timeout_ms` - The time limit for each individual attempt, in milliseconds.
+///
+/// # Returns
+/// The first response with a status below `500`, or the last `5xx` response if every
+/// attempt returned one.
+///
+/// # Behavior
+/// - An attempt is considered transient if the fetch errors, times out, or returns a `5xx`.
+///   A `5xx` carrying `Retry-After` (a shed request, see [`TripSession::fetch`]) is returned
+///   as is, so an overloaded session is not sent the same request again.
+/// - Attempts are spaced with exponential backoff starting at 100ms (100ms, 200ms, 400ms, ...).
+///
+/// # Errors
+/// - If the request cannot be cloned.
+/// - If the final attempt errors or times out.
+async fn do_fetch(stub: &Stub, req: Request, retries: u32, timeout_ms: u64) -> Result<Response>{
+    let mut attempt = 0;
+    loop {
+        let mut fetch = std::pin::pin!(stub.fetch_with_request(req.clone()?));
+        let mut timer = std::pin::pin!(Delay::from(Duration::from_millis(timeout_ms)));
+        let outcome = std::future::poll_fn(|cx| {
+            if let Poll::Ready(result) = fetch.as_mut().poll(cx) {
+                return Poll::Ready(Some(result));
+            }
+            if timer.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(None);
+            }
+            Poll::Pending
+        }).await;
 
-    Ok(resp)
+        let error = match outcome {
+            Some(Ok(resp)) if resp.status_code() < 500 || attempt >= retries => return Ok(resp),
+            Some(Ok(resp)) if resp.headers().has("Retry-After").unwrap_or(false) => return Ok(resp),
+            Some(Ok(resp)) => format!("status {}", resp.status_code()),
+            Some(Err(e)) => e.to_string(),
+            None => format!("timed out after {timeout_ms}ms"),
+        };
+        if attempt >= retries {
+            return Err(Error::RustError(format!("durable object request failed: {error}")));
+        }
+        console_warn!("durable object request failed ({error}), retrying");
+        Delay::from(Duration::from_millis(100 * 2u64.pow(attempt))).await;
+        attempt += 1;
+    }
 }
 
 /// Serves the HTML content for the application's index page.
@@ -387,6 +3839,11 @@ async fn index() -> Result<Response>{
 ///
 /// # Attributes:
 /// - `state`: A `State` object that represents the persistent storage and state management for the `TripSession`.
+/// - `in_flight`: The number of requests this instance is currently handling.
+/// - `max_in_flight`: The concurrency cap, read from `TRIP_MAX_CONCURRENCY` (default 8).
+/// - `queued`: The number of requests currently waiting for a slot under the cap.
+/// - `max_queued`: How many requests may wait for a slot, read from `TRIP_MAX_QUEUE_DEPTH`
+///   (default 0, i.e. no queue).
 ///
 /// # Durable Object:
 /// This struct is marked with the `#[durable_object]` attribute, which allows the object to:
@@ -404,6 +3861,40 @@ async fn index() -> Result<Response>{
 #[durable_object]
 pub struct TripSession{
     state: State,
+    in_flight: Cell<u32>,
+    max_in_flight: u32,
+    queued: Cell<u32>,
+    max_queued: u32,
+}
+
+/// A plan generation running for a trip, kept in its durable object under `generation`.
+///
+/// # Fields
+/// * `cancelled` - Set by `POST /trip/{trip_id}/plan/cancel`; the generation's plan is then
+///   discarded when it finishes.
+#[derive(Serialize, Deserialize, Default)]
+struct GenerationState {
+    cancelled: bool,
+}
+
+/// How long a queued request waits for a slot before it is shed. Kept well under
+/// [`DO_FETCH_TIMEOUT_MS`] so the worker sees the rejection rather than a timeout.
+const QUEUE_MAX_WAIT_MS: u64 = 2_000;
+
+/// How often a queued request checks for a free slot.
+const QUEUE_POLL_MS: u64 = 25;
+
+/// The `Retry-After` value, in seconds, sent with requests shed from a full queue.
+const QUEUE_RETRY_AFTER_SECS: u32 = 1;
+
+/// Decrements a [`TripSession`]'s in-flight (or queued) counter when dropped, so the slot
+/// is released on every exit path, including errors.
+struct InFlightGuard<'a>(&'a Cell<u32>);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get().saturating_sub(1));
+    }
 }
 
 impl DurableObject for TripSession{
@@ -411,7 +3902,8 @@ impl DurableObject for TripSession{
     ///
     /// # Parameters
     /// - `state`: The `State` object used to initialize the instance.
-    /// - `_`: An unused `Env` parameter, typically passed in but ignored in this function.
+    /// - `env`: The environment, whose [`Config`] provides the `TRIP_MAX_CONCURRENCY` cap and
+    ///   the `TRIP_MAX_QUEUE_DEPTH` queue limit. Defaults are used if it is malformed.
     ///
     /// # Returns
     /// A new instance of the type initialized with the given `state`.
@@ -422,7 +3914,19 @@ impl DurableObject for TripSession{
     /// let env = Env::new();
     /// let instance = YourType::new(state, env);
     /// ```
-    fn new(state: State, _: Env) -> Self{ Self { state }}
+    fn new(state: State, env: Env) -> Self{
+        let config = Config::from_env(&env).unwrap_or_else(|problems| {
+            console_error!("Invalid configuration, trip session uses defaults: {problems}");
+            Config::default()
+        });
+        Self {
+            state,
+            in_flight: Cell::new(0),
+            max_in_flight: config.trip_max_concurrency,
+            queued: Cell::new(0),
+            max_queued: config.trip_max_queue_depth,
+        }
+    }
 
     /// Handles incoming HTTP requests and performs various operations based on the request.
     ///
@@ -434,6 +3938,8 @@ impl DurableObject for TripSession{
     ///     - `destination`: A string that represents the destination.
     ///     - `days`: A u32 representing the number of days.
     ///     - `response`: A string that holds additional response data.
+    ///     - `plan` (optional): The plan split into days. Derived from `response` when omitted.
+    ///
     ///   The data is stored persistently in the DO's storage. On success, responds with:
     ///     - HTTP 200 OK, with the message `"initialized"`.
     ///
@@ -443,18 +3949,41 @@ impl DurableObject for TripSession{
     ///     - `destination`: The stored destination (`String`).
     ///     - `days`: The stored number of days (`u32`).
     ///     - `response`: The stored response (`String`).
+    ///     - `plan`: The structured plan (`Vec<PlanDay>`). Sessions created before it was stored
+    ///       fall back to parsing `response`.
+    ///
     ///   If all keys (`destination`, `days`, and `response`) are found, it constructs a JSON response like:
     ///   ```json
     ///   {
     ///       "destination": "string",
     ///       "days": 7,
-    ///       "response": "string"
+    ///       "response": "string",
+    ///       "plan": [{ "day": 1, "text": "string" }]
     ///   }
     ///   ```
     ///   Responds with HTTP 200 OK and returns the JSON payload.
     ///   If any key is missing, responds with:
     ///     - HTTP 404 Not Found, with the message `"trip not initialized"`.
     ///
+    /// - **GET /state**:
+    ///   Returns every stored key as a JSON object, for the admin `do-state` route.
+    ///
+    /// - **DELETE /**:
+    ///   Deletes every stored key, leaving the session uninitialized, and responds with
+    ///   `"reset"`. Used when the trip is deleted.
+    ///
+    /// - **POST /generation/start**, **/generation/cancel**, **/generation/finish**:
+    ///   Track a running plan generation so it can be cancelled (see [`GenerationState`]).
+    ///   `start` records a new generation, `cancel` flags it (HTTP 409 when none is running),
+    ///   and `finish` removes it and answers `{ "cancelled": bool }`.
+    ///
+    /// - **Concurrency cap**:
+    ///   At most `TRIP_MAX_CONCURRENCY` requests are handled at once per trip. Requests beyond
+    ///   the cap are rejected with HTTP 429 Too Many Requests before any storage is touched.
+    ///   With `TRIP_MAX_QUEUE_DEPTH` set, up to that many requests instead wait (for at most
+    ///   [`QUEUE_MAX_WAIT_MS`]) for a slot; requests arriving while the queue is full, or
+    ///   still waiting when the time is up, are shed with HTTP 503 and a `Retry-After` header.
+    ///
     /// - All Other Requests:
     ///   For any other HTTP methods or paths, responds with:
     ///     - HTTP 404 Not Found, with the message `"not found"`.
@@ -502,7 +4031,60 @@ impl DurableObject for TripSession{
     /// HTTP 404 Not Found
     /// trip not initialized
     /// ```
-    async fn fetch(&self, mut req: Request) -> Result<Response> {
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        if self.in_flight.get() >= self.max_in_flight {
+            if self.max_queued == 0 {
+                return Response::error("too many concurrent requests for this trip", 429);
+            }
+            if !self.wait_for_slot().await {
+                let mut resp = Response::error("trip session overloaded, try again shortly", 503)?;
+                resp.headers_mut().set("Retry-After", &QUEUE_RETRY_AFTER_SECS.to_string())?;
+                return Ok(resp);
+            }
+        }
+        self.in_flight.set(self.in_flight.get() + 1);
+        let _guard = InFlightGuard(&self.in_flight);
+        self.handle(req).await
+    }
+}
+
+impl TripSession{
+    /// Queues the current request until an in-flight slot frees up.
+    ///
+    /// # Returns
+    /// `true` once a slot is free, or `false` when the queue already holds
+    /// `TRIP_MAX_QUEUE_DEPTH` requests or no slot freed up within [`QUEUE_MAX_WAIT_MS`].
+    async fn wait_for_slot(&self) -> bool {
+        if self.queued.get() >= self.max_queued {
+            return false;
+        }
+        self.queued.set(self.queued.get() + 1);
+        let _guard = InFlightGuard(&self.queued);
+        let mut waited = 0;
+        while self.in_flight.get() >= self.max_in_flight {
+            if waited >= QUEUE_MAX_WAIT_MS {
+                return false;
+            }
+            Delay::from(Duration::from_millis(QUEUE_POLL_MS)).await;
+            waited += QUEUE_POLL_MS;
+        }
+        true
+    }
+
+    /// Returns every key in this session's storage as one JSON object, for `GET /state`.
+    ///
+    /// Unlike `GET /`, nothing is filtered or reshaped, so keys such as `status` and
+    /// `generation`, or keys left by older versions, show up as they are stored.
+    async fn stored_state(&self) -> Result<Response> {
+        let entries = self.state.storage().list().await?;
+        let object = js_sys::Object::from_entries(&entries).map_err(Error::from)?;
+        let text = js_sys::JSON::stringify(&object).map_err(Error::from)?;
+        let state: serde_json::Value = serde_json::from_str(&String::from(text))?;
+        Response::from_json(&state)
+    }
+
+    /// Routes a request to the `/init` or `/` handlers described on [`TripSession::fetch`].
+    async fn handle(&self, mut req: Request) -> Result<Response> {
         let url = req.url()?;
         let pathname = url.path();
 
@@ -512,19 +4094,63 @@ impl DurableObject for TripSession{
             self.state.storage().put("destination", &init.destination).await?;
             self.state.storage().put("days", &init.days).await?;
             self.state.storage().put("response", &init.response).await?;
+            let plan = if init.plan.is_empty() { plan::parse_days(&init.response) } else { init.plan };
+            self.state.storage().put("plan", &plan).await?;
+            self.state.storage().put("status", &init.status).await?;
             return Response::ok("initialized");
         }
 
+        if req.method() == Method::Get && pathname == "/state" {
+            return self.stored_state().await;
+        }
+
+        if req.method() == Method::Delete && pathname == "/" {
+            self.state.storage().delete_all().await?;
+            return Response::ok("reset");
+        }
+
+        if req.method() == Method::Post && pathname == "/generation/start" {
+            self.state.storage().put("generation", &GenerationState::default()).await?;
+            return Response::ok("started");
+        }
+
+        if req.method() == Method::Post && pathname == "/generation/cancel" {
+            let Some(mut generation) = self.state.storage().get::<Option<GenerationState>>("generation").await? else {
+                return Response::error("no plan generation in progress", 409);
+            };
+            generation.cancelled = true;
+            self.state.storage().put("generation", &generation).await?;
+            return Response::ok("cancelled");
+        }
+
+        if req.method() == Method::Post && pathname == "/generation/finish" {
+            let generation = self.state.storage().get::<Option<GenerationState>>("generation").await?.unwrap_or_default();
+            self.state.storage().delete("generation").await?;
+            return Response::from_json(&generation);
+        }
+
+        if req.method() == Method::Post && pathname == "/status" {
+            let status: TripStatus = req.json().await?;
+            self.state.storage().put("status", &status).await?;
+            return Response::ok("updated");
+        }
+
         if req.method() == Method::Get && pathname == "/" {
             let destination: Option<String> = self.state.storage().get("destination").await?;
             let days: Option<u32> = self.state.storage().get("days").await?;
             let response: Option<String> = self.state.storage().get("response").await?;
+            let plan: Option<Vec<plan::PlanDay>> = self.state.storage().get("plan").await?;
+            let status: Option<TripStatus> = self.state.storage().get("status").await?;
             if let (Some(destination), Some(days), Some(response)) = (destination, days, response) {
+                // Sessions initialized before the structured plan existed only have the text.
+                let plan = plan.unwrap_or_else(|| plan::parse_days(&response));
                 // Use the DO's own id as the trip id for round-tripping if you like
                 let data = serde_json::json!({
                     "destination": destination,
                     "days": days,
-                    "response": response
+                    "response": response,
+                    "plan": plan,
+                    "status": status.unwrap_or_default()
                 });
                 return Response::from_json(&data);
             } else {
@@ -534,4 +4160,54 @@ impl DurableObject for TripSession{
 
         Response::error("not found", 404)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deduplicates_interests_across_casing() {
+        assert_eq!(parse_interests("food, Food, FOOD", 5).unwrap(), vec!["food"]);
+    }
+
+    #[test]
+    fn normalizes_whitespace_in_interests() {
+        assert_eq!(parse_interests("  Street   Food , street food,\tart\n", 5).unwrap(), vec!["street food", "art"]);
+    }
+
+    #[test]
+    fn skips_empty_interests() {
+        assert_eq!(parse_interests(",food,, ,art,", 5).unwrap(), vec!["food", "art"]);
+        assert!(parse_interests("", 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn caps_distinct_interests() {
+        assert_eq!(parse_interests("food, art, FOOD, Art", 2).unwrap(), vec!["food", "art"]);
+        assert_eq!(parse_interests("food, art, hiking", 2).unwrap_err(), "at most 2 interests are allowed");
+    }
+
+    #[test]
+    fn rejects_long_interests() {
+        let long = "a".repeat(MAX_INTEREST_CHARS + 1);
+        assert!(parse_interests(&long, 5).unwrap_err().contains("at most"));
+    }
+
+    #[test]
+    fn parses_out_of_range_days_as_numbers() {
+        assert_eq!(parse_days(" 7 "), Ok(7));
+        assert_eq!(parse_days("-3"), Ok(0));
+        assert_eq!(parse_days("99999999999"), Ok(u32::MAX));
+        assert_eq!(parse_days("seven"), Err("days must be a number".to_string()));
+        assert_eq!(parse_days("2.5"), Err("days must be a number".to_string()));
+    }
+
+    #[test]
+    fn exports_unstructured_plans_once_per_trip_day() {
+        let days = calendar_days(" See the museums and eat well. ", 3);
+        assert_eq!(days.iter().map(|day| day.day).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(days.iter().all(|day| day.text == "See the museums and eat well."));
+        assert!(calendar_days("  \n", 3).is_empty());
+    }
+}
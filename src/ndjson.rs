@@ -0,0 +1,99 @@
+//! Streaming reader for newline-delimited JSON (NDJSON) request bodies.
+//!
+//! Imports can be far larger than a worker should hold in memory, so the body is read chunk
+//! by chunk and split into lines as it arrives. At most one line (plus the chunk being
+//! split) is buffered at a time, and two caps bound the work done for a single request:
+//!
+//! - the total body size, so an endless upload is cut off, and
+//! - the size of a single line, so a body without newlines cannot grow the buffer.
+//!
+//! Exceeding either cap yields [`ReadError::TooLarge`], which handlers turn into
+//! `413 Payload Too Large`.
+use futures_util::StreamExt;
+use worker::{ByteStream, Error};
+
+/// Why a line could not be read.
+pub enum ReadError {
+    /// The body or a single line exceeded its cap; the message says which.
+    TooLarge(String),
+    /// Reading the body failed, or a line was not valid UTF-8.
+    Body(Error),
+}
+
+/// Yields the lines of an NDJSON body one at a time.
+///
+/// Blank lines are skipped and a trailing `\r` is removed, so files written on Windows read
+/// the same. The final line does not need a terminating newline.
+pub struct LineReader {
+    stream: ByteStream,
+    buffer: Vec<u8>,
+    max_bytes: usize,
+    max_line_bytes: usize,
+    read_bytes: usize,
+    line_number: usize,
+    done: bool,
+}
+
+impl LineReader {
+    /// Creates a reader over `stream` that accepts at most `max_bytes` in total and
+    /// `max_line_bytes` per line.
+    pub fn new(stream: ByteStream, max_bytes: usize, max_line_bytes: usize) -> Self {
+        Self { stream, buffer: Vec::new(), max_bytes, max_line_bytes, read_bytes: 0, line_number: 0, done: false }
+    }
+
+    /// Returns the 1-based number of the line most recently returned by [`Self::next_line`].
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    /// Reads the next non-blank line.
+    ///
+    /// # Returns
+    /// `Ok(Some(line))` for the next line, `Ok(None)` once the body is exhausted, or a
+    /// [`ReadError`] when a cap is exceeded or the body cannot be read.
+    pub async fn next_line(&mut self) -> Result<Option<String>, ReadError> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+                if end > self.max_line_bytes {
+                    return Err(ReadError::TooLarge(format!("line {} exceeds {} bytes", self.line_number + 1, self.max_line_bytes)));
+                }
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                self.line_number += 1;
+                match self.decode(&line[..end])? {
+                    Some(line) => return Ok(Some(line)),
+                    None => continue,
+                }
+            }
+            if self.buffer.len() > self.max_line_bytes {
+                return Err(ReadError::TooLarge(format!("line {} exceeds {} bytes", self.line_number + 1, self.max_line_bytes)));
+            }
+            if self.done {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                let line = std::mem::take(&mut self.buffer);
+                self.line_number += 1;
+                return self.decode(&line);
+            }
+            match self.stream.next().await {
+                Some(chunk) => {
+                    let chunk = chunk.map_err(ReadError::Body)?;
+                    self.read_bytes += chunk.len();
+                    if self.read_bytes > self.max_bytes {
+                        return Err(ReadError::TooLarge(format!("body exceeds {} bytes", self.max_bytes)));
+                    }
+                    self.buffer.extend_from_slice(&chunk);
+                }
+                None => self.done = true,
+            }
+        }
+    }
+
+    /// Decodes one raw line, returning `None` for blank lines.
+    fn decode(&self, line: &[u8]) -> Result<Option<String>, ReadError> {
+        let line = std::str::from_utf8(line)
+            .map_err(|_| ReadError::Body(Error::RustError(format!("line {} is not valid UTF-8", self.line_number))))?;
+        let line = line.trim_end_matches('\r');
+        Ok((!line.trim().is_empty()).then(|| line.to_string()))
+    }
+}
@@ -0,0 +1,44 @@
+//! AI-suggested packing lists for a trip.
+//!
+//! `POST /trip/{trip_id}/packing-list` asks the AI for a packing list grouped by category
+//! and returns it as a list of [`PackingCategory`]. Models do not always answer with the
+//! JSON they were asked for, so [`parse`] reads it defensively and the handler falls back
+//! to returning the answer as prose when no categories can be recovered.
+use serde::{Deserialize, Serialize};
+
+/// One group of a packing list.
+///
+/// # Fields
+/// * `category` - The group's name, e.g. `"Clothing"`.
+/// * `items` - The things to pack, e.g. `["Rain jacket", "Walking shoes"]`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PackingCategory {
+    pub category: String,
+    #[serde(default)]
+    pub items: Vec<String>,
+}
+
+/// Parses the model's answer into packing categories.
+///
+/// Text around the outermost `[` ... `]` is ignored. Blank items are dropped, and so are
+/// categories left with no items.
+///
+/// # Returns
+/// `None` when the answer holds no JSON array of categories, or none of them has an item.
+pub fn parse(text: &str) -> Option<Vec<PackingCategory>> {
+    let start = text.find('[')?;
+    let end = text.rfind(']')?;
+    if end < start {
+        return None;
+    }
+    let categories: Vec<PackingCategory> = serde_json::from_str(&text[start..=end]).ok()?;
+    let categories: Vec<PackingCategory> = categories
+        .into_iter()
+        .map(|c| PackingCategory {
+            category: c.category.trim().to_string(),
+            items: c.items.into_iter().map(|i| i.trim().to_string()).filter(|i| !i.is_empty()).collect(),
+        })
+        .filter(|c| !c.category.is_empty() && !c.items.is_empty())
+        .collect();
+    (!categories.is_empty()).then_some(categories)
+}
@@ -0,0 +1,62 @@
+//! Shared envelope type for list endpoints.
+//!
+//! List endpoints return bare JSON arrays by default. Clients that prefer an enveloped
+//! response can opt in with `?envelope=true`, in which case the results are wrapped in a
+//! [`Paginated`] value:
+//!
+//! ```json
+//! {
+//!     "data": [ ... ],
+//!     "pagination": { "total": 42, "limit": 20, "offset": 0, "next_cursor": "20" }
+//! }
+//! ```
+use serde::Serialize;
+
+/// Pagination metadata attached to an enveloped list response.
+///
+/// # Fields
+/// * `total` - The total number of items available, ignoring `limit` and `offset`.
+/// * `limit` - The maximum number of items returned in this page.
+/// * `offset` - The index of the first item in this page.
+/// * `next_cursor` - An opaque cursor for the next page, or `None` on the last page.
+#[derive(Serialize)]
+pub struct PageInfo {
+    pub total: usize,
+    pub limit: usize,
+    pub offset: usize,
+    pub next_cursor: Option<String>,
+}
+
+/// A page of results together with its [`PageInfo`].
+///
+/// # Fields
+/// * `data` - The items in this page.
+/// * `pagination` - Metadata describing where this page sits in the full result set.
+#[derive(Serialize)]
+pub struct Paginated<T: Serialize> {
+    pub data: Vec<T>,
+    pub pagination: PageInfo,
+}
+
+impl<T: Serialize> Paginated<T> {
+    /// Cuts a page out of a complete result set.
+    ///
+    /// # Arguments
+    /// * `items` - The full, already ordered result set.
+    /// * `limit` - The maximum page size, or `None` to return everything after `offset`.
+    /// * `offset` - The number of items to skip.
+    ///
+    /// The `next_cursor` is the offset of the following page, or `None` when this page
+    /// reaches the end of the result set.
+    pub fn page(items: Vec<T>, limit: Option<usize>, offset: usize) -> Self {
+        let total = items.len();
+        let limit = limit.unwrap_or(total);
+        let data: Vec<T> = items.into_iter().skip(offset).take(limit).collect();
+        let next = offset + data.len();
+        let next_cursor = (next < total).then(|| next.to_string());
+        Self {
+            data,
+            pagination: PageInfo { total, limit, offset, next_cursor },
+        }
+    }
+}
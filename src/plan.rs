@@ -0,0 +1,332 @@
+//! Helpers for working with generated plans as a sequence of days.
+//!
+//! Plans are stored as the text returned by the AI, one day after another. Each day's
+//! itinerary normally opens with a heading such as `Day 3`, `**Day 3:**` or `## Day 3`,
+//! which [`parse_days`] uses to split the text back into individual days.
+//!
+//! [`to_html`] renders the text as sanitized HTML for embedding in a page, and [`diff`]
+//! compares two versions of a plan line by line.
+//!
+//! # Structs
+//! - [`PlanDay`]: A single day of a plan.
+//! - [`ValidationReport`]: The result of checking a plan against the trip's day count.
+//! - [`ScheduleEntry`]: One time slot of a plan's optional schedule (see [`parse_schedule`]).
+use serde::{Deserialize, Serialize};
+
+/// A single day of a parsed plan.
+///
+/// # Fields
+/// * `day` - The day number taken from the heading (1-based).
+/// * `text` - The itinerary text for that day, without the heading line.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PlanDay {
+    pub day: u32,
+    pub text: String,
+}
+
+/// The outcome of validating a plan against the number of days in its trip.
+///
+/// # Fields
+/// * `valid` - `true` when the plan covers every day exactly once and nothing else.
+/// * `expected_days` - The trip's `days` value.
+/// * `found_days` - The day numbers found in the plan, in order of appearance.
+/// * `issues` - Human readable descriptions of every problem found.
+#[derive(Serialize)]
+pub struct ValidationReport {
+    pub valid: bool,
+    pub expected_days: u32,
+    pub found_days: Vec<u32>,
+    pub issues: Vec<String>,
+}
+
+/// Returns the day number if `line` is a day heading such as `Day 2`, `**Day 2:**` or
+/// `### Day 2 - Museums`.
+fn day_heading(line: &str) -> Option<u32> {
+    let line = line.trim().trim_start_matches(['#', '*', ' ']);
+    let rest = line.strip_prefix("Day ").or_else(|| line.strip_prefix("DAY "))?;
+    let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+/// Splits plan text into days using the `Day N` headings.
+///
+/// Text that appears before the first heading is ignored. A plan without any headings
+/// yields an empty vector.
+///
+/// # Arguments
+/// * `plan` - The plan text as stored in the `plans` table.
+///
+/// # Returns
+/// The days in the order they appear in the text. Duplicate or out-of-order headings are
+/// kept as-is so that [`validate`] can report them.
+pub fn parse_days(plan: &str) -> Vec<PlanDay> {
+    let mut days: Vec<PlanDay> = vec![];
+    for line in plan.lines() {
+        if let Some(day) = day_heading(line) {
+            days.push(PlanDay { day, text: String::new() });
+        } else if let Some(current) = days.last_mut() {
+            if !current.text.is_empty() {
+                current.text.push('\n');
+            }
+            current.text.push_str(line);
+        }
+    }
+    for day in days.iter_mut() {
+        day.text = day.text.trim().to_string();
+    }
+    days
+}
+
+/// Checks that a parsed plan covers exactly `expected_days` days with no gaps or duplicates.
+///
+/// # Arguments
+/// * `days` - The parsed plan, usually from [`parse_days`].
+/// * `expected_days` - The number of days the trip is supposed to last.
+///
+/// # Returns
+/// A [`ValidationReport`] listing missing days, repeated days, days outside the trip's
+/// range and days with an empty itinerary.
+pub fn validate(days: &[PlanDay], expected_days: u32) -> ValidationReport {
+    let found_days: Vec<u32> = days.iter().map(|d| d.day).collect();
+    let mut issues = vec![];
+    if days.is_empty() {
+        issues.push("No day headings found in plan".to_string());
+    }
+    for day in 1..=expected_days {
+        match found_days.iter().filter(|d| **d == day).count() {
+            0 => issues.push(format!("Day {day} is missing")),
+            1 => {}
+            n => issues.push(format!("Day {day} appears {n} times")),
+        }
+    }
+    for day in days {
+        if day.day == 0 || day.day > expected_days {
+            issues.push(format!("Day {} is outside the trip's {expected_days} days", day.day));
+        } else if day.text.is_empty() {
+            issues.push(format!("Day {} has no activities", day.day));
+        }
+    }
+    ValidationReport {
+        valid: issues.is_empty(),
+        expected_days,
+        found_days,
+        issues,
+    }
+}
+
+/// Returns `true` if a link target is safe to emit in an `href`/`src` attribute.
+///
+/// Only `http`, `https` and `mailto` URLs and scheme-less (relative or fragment) targets
+/// are allowed, which rules out `javascript:`, `data:` and similar schemes.
+fn is_safe_url(url: &str) -> bool {
+    let lower = url.trim().to_ascii_lowercase();
+    match lower.split_once(':') {
+        Some((scheme, _)) if !scheme.contains(['/', '?', '#']) => {
+            matches!(scheme, "http" | "https" | "mailto")
+        }
+        _ => true,
+    }
+}
+
+/// Renders plan text (which is loosely Markdown) to sanitized HTML.
+///
+/// # Arguments
+/// * `plan` - The plan text as stored in the `plans` table.
+///
+/// # Returns
+/// An HTML fragment safe to embed in a page.
+///
+/// # Sanitization
+/// The plan is AI output, so it is treated as untrusted:
+/// - Raw HTML blocks and inline tags are escaped and rendered as text, so `<script>` and
+///   event-handler attributes can never reach the page.
+/// - Links and images with a scheme other than `http`, `https` or `mailto` have their
+///   target replaced with `#`.
+pub fn to_html(plan: &str) -> String {
+    use pulldown_cmark::{html, CowStr, Event, Parser, Tag};
+
+    let events = Parser::new(plan).map(|event| match event {
+        Event::Html(raw) | Event::InlineHtml(raw) => Event::Text(raw),
+        Event::Start(Tag::Link { link_type, dest_url, title, id }) if !is_safe_url(&dest_url) => {
+            Event::Start(Tag::Link { link_type, dest_url: CowStr::from("#"), title, id })
+        }
+        Event::Start(Tag::Image { link_type, dest_url, title, id }) if !is_safe_url(&dest_url) => {
+            Event::Start(Tag::Image { link_type, dest_url: CowStr::from("#"), title, id })
+        }
+        other => other,
+    });
+    let mut out = String::new();
+    html::push_html(&mut out, events);
+    out
+}
+
+/// A single changed line between two plan versions.
+///
+/// # Fields
+/// * `op` - `"insert"` for a line only in the newer version, `"delete"` for a line only in the older one.
+/// * `text` - The line's text, without its trailing newline.
+#[derive(Serialize)]
+pub struct LineChange {
+    pub op: &'static str,
+    pub text: String,
+}
+
+/// Computes a line-based diff between two plan texts.
+///
+/// # Arguments
+/// * `from` - The older plan text.
+/// * `to` - The newer plan text.
+///
+/// # Returns
+/// A tuple of the changed lines in order and a unified diff (3 lines of context) suitable
+/// for display.
+pub fn diff(from: &str, to: &str) -> (Vec<LineChange>, String) {
+    use similar::{ChangeTag, TextDiff};
+
+    let diff = TextDiff::from_lines(from, to);
+    let changes = diff
+        .iter_all_changes()
+        .filter_map(|change| {
+            let op = match change.tag() {
+                ChangeTag::Insert => "insert",
+                ChangeTag::Delete => "delete",
+                ChangeTag::Equal => return None,
+            };
+            Some(LineChange { op, text: change.value().trim_end_matches('\n').to_string() })
+        })
+        .collect();
+    let unified = diff.unified_diff().context_radius(3).to_string();
+    (changes, unified)
+}
+
+/// Shortens a plan to a preview of at most `max_chars` characters.
+///
+/// The plan is cut at the last line break before the limit (or at the limit itself when
+/// there is none), so a preview does not end mid-line.
+///
+/// # Returns
+/// The preview and whether anything was cut. Plans within the limit are returned whole.
+pub fn preview(plan: &str, max_chars: usize) -> (String, bool) {
+    let Some((cut, _)) = plan.char_indices().nth(max_chars) else {
+        return (plan.to_string(), false);
+    };
+    let head = &plan[..cut];
+    let head = head.rfind('\n').map_or(head, |newline| &head[..newline]);
+    (head.trim_end().to_string(), true)
+}
+
+/// The longest text, in characters, accepted as a place name by [`places`].
+const MAX_PLACE_CHARS: usize = 80;
+
+/// Extracts the place names mentioned in one day of a plan.
+///
+/// Bold spans such as `**Louvre Museum**` are taken as place names, except labels ending
+/// in a colon (`**Morning:**`). A line without any bold place that follows the
+/// `Morning: Lunch at Café de Flore - description` layout contributes the text after the
+/// last ` at ` or ` in ` of its activity (here `Café de Flore`).
+///
+/// # Arguments
+/// * `text` - The text of a single day, as in [`PlanDay::text`].
+///
+/// # Returns
+/// The place names in order of first mention, without duplicates.
+pub fn places(text: &str) -> Vec<String> {
+    let mut found: Vec<String> = vec![];
+    for line in text.lines() {
+        let bold: Vec<&str> = line
+            .split("**")
+            .skip(1)
+            .step_by(2)
+            .map(str::trim)
+            .filter(|span| !span.ends_with(':'))
+            .collect();
+        let candidates = if bold.is_empty() {
+            let activity = line.split_once(": ").map_or(line, |(_, rest)| rest);
+            let activity = activity.split(" - ").next().unwrap_or_default();
+            [" at ", " in "]
+                .iter()
+                .filter_map(|sep| activity.rfind(sep).map(|i| (i, sep.len())))
+                .max()
+                .map(|(i, len)| vec![&activity[i + len..]])
+                .unwrap_or_default()
+        } else {
+            bold
+        };
+        for place in candidates {
+            let place = place.trim().trim_end_matches(['.', ',', ';', '!']).trim();
+            if !place.is_empty() && place.chars().count() <= MAX_PLACE_CHARS && !found.iter().any(|p| p == place) {
+                found.push(place.to_string());
+            }
+        }
+    }
+    found
+}
+
+/// One time slot of a plan's schedule.
+///
+/// # Fields
+/// * `day` - The day number (1-based).
+/// * `time` - The start time as `HH:MM` (24-hour clock).
+/// * `activity` - What happens at that time, e.g. `"Breakfast at Café de Flore"`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScheduleEntry {
+    pub day: u32,
+    pub time: String,
+    pub activity: String,
+}
+
+/// Parses the minutes since midnight from an `HH:MM` time.
+fn minutes(time: &str) -> Option<u32> {
+    let (hours, mins) = time.split_once(':')?;
+    if hours.len() != 2 || mins.len() != 2 {
+        return None;
+    }
+    let (hours, mins): (u32, u32) = (hours.parse().ok()?, mins.parse().ok()?);
+    (hours < 24 && mins < 60).then_some(hours * 60 + mins)
+}
+
+/// Parses and checks the schedule the model wrote for a plan.
+///
+/// The model is asked for a JSON array of `{ "day", "time", "activity" }` objects. Text
+/// around the outermost `[` ... `]` (a sentence of preamble, a code fence) is ignored.
+///
+/// # Arguments
+/// * `text` - The model's response.
+/// * `days` - The trip's length; every entry must fall on a day from `1` to `days`.
+///
+/// # Returns
+/// The entries in the order they were written.
+///
+/// # Errors
+/// A description of the first problem found: no JSON array, an entry of the wrong shape,
+/// an empty schedule, a day outside the trip, a time that is not `HH:MM`, an empty
+/// activity, or a time earlier than the one before it on the same day.
+pub fn parse_schedule(text: &str, days: u32) -> Result<Vec<ScheduleEntry>, String> {
+    let (Some(start), Some(end)) = (text.find('['), text.rfind(']')) else {
+        return Err("no JSON array found".to_string());
+    };
+    if end < start {
+        return Err("no JSON array found".to_string());
+    }
+    let entries: Vec<ScheduleEntry> = serde_json::from_str(&text[start..=end]).map_err(|e| format!("invalid schedule JSON: {e}"))?;
+    if entries.is_empty() {
+        return Err("the schedule is empty".to_string());
+    }
+    let mut latest: std::collections::HashMap<u32, u32> = std::collections::HashMap::new();
+    for entry in &entries {
+        if entry.day == 0 || entry.day > days {
+            return Err(format!("day {} is outside the {days}-day trip", entry.day));
+        }
+        let Some(at) = minutes(&entry.time) else {
+            return Err(format!("day {}: {:?} is not an HH:MM time", entry.day, entry.time));
+        };
+        if entry.activity.trim().is_empty() {
+            return Err(format!("day {} at {}: the activity is empty", entry.day, entry.time));
+        }
+        if latest.get(&entry.day).is_some_and(|&before| at < before) {
+            return Err(format!("day {}: {} comes after a later time", entry.day, entry.time));
+        }
+        latest.insert(entry.day, at);
+    }
+    Ok(entries)
+}
@@ -0,0 +1,69 @@
+//! Problem Details (RFC 7807) error bodies, for clients that ask for them.
+//!
+//! Errors are answered with the simple `{"error": {"message", "status"}}` body of
+//! `json_error` by default. A client sending `Accept: application/problem+json` gets the
+//! same error as a [`Problem`] instead: `main` rewrites every error response on the way
+//! out, so handlers do not need to know which format was asked for.
+
+use serde::Serialize;
+
+/// The media type of a Problem Details body.
+pub const CONTENT_TYPE: &str = "application/problem+json";
+
+/// The `type` (relative to the deployment) and `title` used for each status code. Other
+/// codes get [`GENERIC_PROBLEM`].
+const PROBLEM_TYPES: [(u16, &str, &str); 12] = [
+    (400, "/problems/invalid-request", "Invalid request"),
+    (401, "/problems/unauthorized", "Unauthorized"),
+    (403, "/problems/forbidden", "Forbidden"),
+    (404, "/problems/not-found", "Not found"),
+    (405, "/problems/method-not-allowed", "Method not allowed"),
+    (409, "/problems/conflict", "Conflict"),
+    (413, "/problems/too-large", "Request too large"),
+    (415, "/problems/unsupported-media-type", "Unsupported media type"),
+    (429, "/problems/too-many-requests", "Too many requests"),
+    (500, "/problems/internal-error", "Internal server error"),
+    (502, "/problems/upstream-error", "Upstream service error"),
+    (503, "/problems/unavailable", "Service unavailable"),
+];
+
+/// The `type` and `title` for status codes missing from [`PROBLEM_TYPES`]. `about:blank`
+/// is RFC 7807's type for problems with no more meaning than their status code.
+const GENERIC_PROBLEM: (&str, &str) = ("about:blank", "Request failed");
+
+/// An RFC 7807 Problem Details object.
+///
+/// # Fields
+/// * `problem_type` - A URI reference naming the kind of problem, serialized as `type`.
+/// * `title` - A short summary of the kind of problem, the same for every occurrence.
+/// * `status` - The HTTP status code.
+/// * `detail` - What went wrong this time, the message `json_error` would have sent.
+/// * `instance` - The path of the request that failed.
+#[derive(Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub detail: String,
+    pub instance: String,
+}
+
+impl Problem {
+    /// Describes an error with status `status` for the request to `instance`.
+    pub fn new(status: u16, detail: String, instance: String) -> Problem {
+        let (problem_type, title) = PROBLEM_TYPES
+            .iter()
+            .find(|(code, _, _)| *code == status)
+            .map(|(_, problem_type, title)| (*problem_type, *title))
+            .unwrap_or(GENERIC_PROBLEM);
+        Problem { problem_type, title, status, detail, instance }
+    }
+}
+
+/// Returns `true` when an `Accept` header value asks for Problem Details.
+pub fn is_accepted(accept: &str) -> bool {
+    accept
+        .split(',')
+        .any(|media| media.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(CONTENT_TYPE))
+}
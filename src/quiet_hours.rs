@@ -0,0 +1,62 @@
+//! The window of the day in which scheduled jobs may run.
+//!
+//! `SCHEDULED_QUIET_HOURS` holds a UTC time range such as `22:00-06:00`: the hours of low
+//! traffic in which the `scheduled` handler does its work. A cron trigger firing outside
+//! the window skips its work with a log line, and the job runs on the first trigger inside
+//! it instead. The range may wrap past midnight; unset, jobs run on every trigger.
+
+/// Minutes in a day.
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A daily UTC window, from `start` up to but not including `end`, in minutes after midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuietHours {
+    start: u32,
+    end: u32,
+}
+
+impl QuietHours {
+    /// Returns `true` when `epoch_millis` falls inside the window.
+    pub fn contains(&self, epoch_millis: u64) -> bool {
+        let minute = ((epoch_millis / 60_000) % u64::from(MINUTES_PER_DAY)) as u32;
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl std::fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}-{:02}:{:02} UTC", self.start / 60, self.start % 60, self.end / 60, self.end % 60)
+    }
+}
+
+/// Parses a window such as `22:00-06:00`.
+///
+/// # Errors
+/// A message for the configuration error when the value is not two `HH:MM` times joined
+/// by `-`, or both times are the same.
+pub fn parse(value: &str) -> Result<QuietHours, String> {
+    let format_error = || format!("must look like 22:00-06:00 (UTC), got {value:?}");
+    let (start, end) = value.split_once('-').ok_or_else(format_error)?;
+    let (Some(start), Some(end)) = (minute_of_day(start), minute_of_day(end)) else {
+        return Err(format_error());
+    };
+    if start == end {
+        return Err(format!("must start and end at different times, got {value:?}"));
+    }
+    Ok(QuietHours { start, end })
+}
+
+/// Reads `HH:MM` as minutes after midnight. `24:00` is accepted as the end of the day.
+fn minute_of_day(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    match (hours, minutes) {
+        (24, 0) => Some(0),
+        (0..=23, 0..=59) => Some(hours * 60 + minutes),
+        _ => None,
+    }
+}
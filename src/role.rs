@@ -0,0 +1,69 @@
+//! Who wrote a chat message.
+//!
+//! Roles are stored in the `messages.messager_role` column as `User`, `AI` or `System`, and
+//! sent to the model in that form as part of the chat history. [`MessageRole`] keeps code
+//! from writing any other spelling (`user` instead of `User` would no longer be recognised as
+//! the traveller's question), while rows holding a name it does not know are still read, as
+//! [`MessageRole::Unknown`], instead of being dropped.
+use std::convert::Infallible;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer};
+
+/// The author of a chat message.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum MessageRole {
+    /// The traveller asking questions.
+    User,
+    /// A reply written by the model.
+    Ai,
+    /// A note added by the application itself, e.g. when the plan was regenerated.
+    System,
+    /// A role name this version does not know, as stored.
+    Unknown(String),
+}
+
+impl MessageRole {
+    /// Every role messages are written with.
+    pub const KNOWN: [MessageRole; 3] = [MessageRole::User, MessageRole::Ai, MessageRole::System];
+
+    /// Returns the role as stored in the database and sent to the model.
+    pub fn as_str(&self) -> &str {
+        match self {
+            MessageRole::User => "User",
+            MessageRole::Ai => "AI",
+            MessageRole::System => "System",
+            MessageRole::Unknown(name) => name,
+        }
+    }
+
+    /// Returns the known role names as a comma-separated list, for error messages.
+    pub fn names() -> String {
+        MessageRole::KNOWN.map(|role| role.as_str().to_string()).join(", ")
+    }
+}
+
+impl FromStr for MessageRole {
+    type Err = Infallible;
+
+    /// Reads a stored role name. Names must match exactly; anything else becomes
+    /// [`MessageRole::Unknown`].
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(MessageRole::KNOWN
+            .into_iter()
+            .find(|role| role.as_str() == value)
+            .unwrap_or_else(|| MessageRole::Unknown(value.to_string())))
+    }
+}
+
+impl std::fmt::Display for MessageRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for MessageRole {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
@@ -0,0 +1,90 @@
+//! Optional clean-up of HTML in AI output.
+//!
+//! Plans and chat replies are Markdown, but nothing stops the model from emitting raw HTML,
+//! and a frontend that renders the stored text as HTML would then run whatever `<script>`
+//! it contains. With `SANITIZE_AI_HTML` enabled, every AI response is passed through
+//! [`html`] before it is stored in `plans`, `messages` or a trip's durable object:
+//!
+//! - Tags on [`ALLOWED_TAGS`] are kept, with all their attributes removed.
+//! - `<script>`, `<style>`, `<iframe>`, `<object>` and `<template>` elements are removed
+//!   together with their content.
+//! - Any other tag, comment or doctype is escaped (`<` becomes `&lt;`) so it shows as text.
+//!
+//! Markdown is left as it is: a `<` that does not start a tag (`temp < 20`) and autolinks
+//! such as `<https://example.com>` pass through untouched.
+
+/// Tags that are harmless without attributes and are kept.
+const ALLOWED_TAGS: [&str; 20] = [
+    "b", "strong", "i", "em", "u", "s", "del", "code", "pre", "br", "hr", "p", "ul", "ol", "li",
+    "blockquote", "h1", "h2", "h3", "h4",
+];
+
+/// Elements dropped together with everything up to their closing tag.
+const DROPPED_ELEMENTS: [&str; 5] = ["script", "style", "iframe", "object", "template"];
+
+/// Sanitizes `text` when `SANITIZE_AI_HTML` is on (see `Config::sanitize_ai_html`), and
+/// returns it unchanged otherwise.
+pub fn ai_output(config: &crate::config::Config, text: String) -> String {
+    if config.sanitize_ai_html { html(&text) } else { text }
+}
+
+/// Removes or escapes the HTML in `text` as described in the module docs.
+pub fn html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('>') else {
+            out.push_str(&escape(rest));
+            return out;
+        };
+        let tag = &rest[..=end];
+        let inner = &tag[1..tag.len() - 1];
+        let closing = inner.starts_with('/');
+        let name: String = inner
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect::<String>()
+            .to_ascii_lowercase();
+
+        let prose = name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit());
+        if is_autolink(inner) || (prose && !inner.starts_with('!') && !closing) {
+            // Not a tag: an autolink or a stray `<` in prose.
+            out.push('<');
+            rest = &rest[1..];
+            continue;
+        }
+        if DROPPED_ELEMENTS.contains(&name.as_str()) {
+            rest = &rest[end + 1..];
+            if !closing {
+                let close = format!("</{name}");
+                rest = match rest.to_ascii_lowercase().find(&close) {
+                    Some(at) => rest[at..].find('>').map_or("", |gt| &rest[at + gt + 1..]),
+                    None => "",
+                };
+            }
+            continue;
+        }
+        if ALLOWED_TAGS.contains(&name.as_str()) {
+            let slash = if closing { "/" } else { "" };
+            out.push_str(&format!("<{slash}{name}>"));
+        } else {
+            out.push_str(&escape(tag));
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Returns `true` for the inside of a Markdown autolink, e.g. `https://example.com`.
+fn is_autolink(inner: &str) -> bool {
+    !inner.contains(char::is_whitespace) && (inner.contains("://") || inner.starts_with("mailto:"))
+}
+
+/// Escapes the characters that let text be read as markup.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
@@ -0,0 +1,259 @@
+//! Where a trip's session state (destination, length, plan, status and any running plan
+//! generation) is kept.
+//!
+//! By default every trip has a [`TripSession`](crate::TripSession) durable object. Setting
+//! `SESSION_BACKEND=d1` keeps the same keys in the D1 `sessions` table instead, one row per
+//! trip and key with the value as JSON, so deployments can run without durable objects.
+//! Handlers go through [`SessionStore`], which [`store`] picks from the setting, and get
+//! the same responses from either backend: the D1 store answers exactly like the durable
+//! object's routes described on `TripSession::fetch`.
+//!
+//! The durable object handles one request per trip at a time; D1 does not, so with the D1
+//! backend a plan cancel racing the end of its generation can be missed, and the per-trip
+//! concurrency cap (`TRIP_MAX_CONCURRENCY`) does not apply.
+use futures_util::future::LocalBoxFuture;
+use serde_json::{Map, Value};
+use worker::wasm_bindgen::__rt::IntoJsResult;
+use worker::*;
+
+use crate::config::Config;
+use crate::status::TripStatus;
+use crate::{do_fetch, plan, GenerationState, TripInit, DO_FETCH_RETRIES, DO_FETCH_TIMEOUT_MS};
+
+/// Where trip sessions are stored, chosen with `SESSION_BACKEND`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionBackend {
+    /// `do`, the default: a `TripSession` durable object per trip.
+    DurableObject,
+    /// `d1`: rows in the `sessions` table.
+    D1,
+}
+
+/// Parses a `SESSION_BACKEND` value; blank means the durable object.
+///
+/// # Errors
+/// A description of the problem when the value is neither `do` nor `d1`.
+pub fn parse_backend(value: &str) -> std::result::Result<SessionBackend, String> {
+    match value.trim().to_lowercase().as_str() {
+        "" | "do" => Ok(SessionBackend::DurableObject),
+        "d1" => Ok(SessionBackend::D1),
+        _ => Err(format!("must be do or d1, got {value:?}")),
+    }
+}
+
+/// Returns the session store selected by `SESSION_BACKEND` (see [`Config::session_backend`]).
+pub fn store(env: &Env, config: &Config) -> Result<Box<dyn SessionStore>> {
+    match config.session_backend {
+        SessionBackend::DurableObject => Ok(Box::new(DurableObjectStore { env: env.clone() })),
+        SessionBackend::D1 => Ok(Box::new(D1Store { env: env.clone() })),
+    }
+}
+
+/// Reads and writes trip sessions.
+///
+/// Every method answers with the response the durable object route of the same name gives
+/// (see `TripSession::fetch`); a status other than `200` means the operation did not happen.
+pub trait SessionStore {
+    /// Stores a trip's details, replacing everything but a running generation (`POST /init`).
+    fn init<'a>(&'a self, trip_id: &'a str, payload: &'a TripInit) -> LocalBoxFuture<'a, Result<Response>>;
+
+    /// The trip's details as JSON, or `404` when it was never initialized (`GET /`).
+    fn get<'a>(&'a self, trip_id: &'a str) -> LocalBoxFuture<'a, Result<Response>>;
+
+    /// Every stored key as one JSON object, `{}` for an unknown trip (`GET /state`).
+    fn state<'a>(&'a self, trip_id: &'a str) -> LocalBoxFuture<'a, Result<Response>>;
+
+    /// Deletes every stored key (`DELETE /`).
+    fn reset<'a>(&'a self, trip_id: &'a str) -> LocalBoxFuture<'a, Result<Response>>;
+
+    /// Updates the trip's lifecycle stage (`POST /status`).
+    fn set_status<'a>(&'a self, trip_id: &'a str, status: TripStatus) -> LocalBoxFuture<'a, Result<Response>>;
+
+    /// Starts, cancels or finishes a plan generation (`POST /generation/{action}`).
+    fn generation<'a>(&'a self, trip_id: &'a str, action: &'a str) -> LocalBoxFuture<'a, Result<Response>>;
+}
+
+/// Sessions kept in `TripSession` durable objects, reached through the `TRIP_SESSION_DO`
+/// binding with [`do_fetch`].
+struct DurableObjectStore {
+    env: Env,
+}
+
+impl DurableObjectStore {
+    /// Sends a request to the trip's durable object, with `body` as JSON when given.
+    async fn send(&self, trip_id: &str, method: Method, path: &str, body: Option<String>) -> Result<Response> {
+        let stub = self.env.durable_object("TRIP_SESSION_DO")?.get_by_name(trip_id)?;
+        let mut init = RequestInit::new();
+        init.method = method;
+        if let Some(body) = body {
+            let headers = Headers::new();
+            headers.set("Content-Type", "application/json")?;
+            init.with_headers(headers);
+            init.with_body(Some(body.into()));
+        }
+        let do_req = Request::new_with_init(&format!("https://trip-session{path}"), &init)?;
+        do_fetch(&stub, do_req, DO_FETCH_RETRIES, DO_FETCH_TIMEOUT_MS).await
+    }
+}
+
+impl SessionStore for DurableObjectStore {
+    fn init<'a>(&'a self, trip_id: &'a str, payload: &'a TripInit) -> LocalBoxFuture<'a, Result<Response>> {
+        Box::pin(async move { self.send(trip_id, Method::Post, "/init", Some(serde_json::to_string(payload)?)).await })
+    }
+
+    fn get<'a>(&'a self, trip_id: &'a str) -> LocalBoxFuture<'a, Result<Response>> {
+        Box::pin(self.send(trip_id, Method::Get, "/", None))
+    }
+
+    fn state<'a>(&'a self, trip_id: &'a str) -> LocalBoxFuture<'a, Result<Response>> {
+        Box::pin(self.send(trip_id, Method::Get, "/state", None))
+    }
+
+    fn reset<'a>(&'a self, trip_id: &'a str) -> LocalBoxFuture<'a, Result<Response>> {
+        Box::pin(self.send(trip_id, Method::Delete, "/", None))
+    }
+
+    fn set_status<'a>(&'a self, trip_id: &'a str, status: TripStatus) -> LocalBoxFuture<'a, Result<Response>> {
+        Box::pin(async move { self.send(trip_id, Method::Post, "/status", Some(serde_json::to_string(&status)?)).await })
+    }
+
+    fn generation<'a>(&'a self, trip_id: &'a str, action: &'a str) -> LocalBoxFuture<'a, Result<Response>> {
+        Box::pin(async move { self.send(trip_id, Method::Post, &format!("/generation/{action}"), None).await })
+    }
+}
+
+/// Sessions kept in the D1 `sessions` table, one row per trip and key.
+struct D1Store {
+    env: Env,
+}
+
+impl D1Store {
+    /// Every stored key of the trip, with its value parsed from JSON.
+    async fn entries(&self, trip_id: &str) -> Result<Map<String, Value>> {
+        let db = self.env.d1("TripPlanner")?;
+        let statement = db.prepare("SELECT key, value FROM sessions WHERE trip_id = ?")
+            .bind(&[trip_id.into_js_result()?])?;
+        let entries = statement
+            .all()
+            .await?
+            .results::<Value>()?
+            .into_iter()
+            .filter_map(|row| {
+                let value = serde_json::from_str(row.get("value")?.as_str()?).ok()?;
+                Some((row.get("key")?.as_str()?.to_string(), value))
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    /// Writes several keys of the trip in one batch, replacing their previous values.
+    async fn put(&self, trip_id: &str, entries: Vec<(&str, Value)>) -> Result<()> {
+        let db = self.env.d1("TripPlanner")?;
+        let statements = entries
+            .into_iter()
+            .map(|(key, value)| {
+                db.prepare("INSERT OR REPLACE INTO sessions (trip_id, key, value) VALUES (?, ?, ?)")
+                    .bind(&[trip_id.into_js_result()?, key.into_js_result()?, value.to_string().into_js_result()?])
+            })
+            .collect::<Result<Vec<_>>>()?;
+        crate::db::batch_all(&db, statements, "store trip session").await?;
+        Ok(())
+    }
+
+    /// Deletes one key of the trip, or all of them when `key` is `None`.
+    async fn delete(&self, trip_id: &str, key: Option<&str>) -> Result<()> {
+        let db = self.env.d1("TripPlanner")?;
+        let statement = match key {
+            Some(key) => db.prepare("DELETE FROM sessions WHERE trip_id = ? AND key = ?")
+                .bind(&[trip_id.into_js_result()?, key.into_js_result()?])?,
+            None => db.prepare("DELETE FROM sessions WHERE trip_id = ?")
+                .bind(&[trip_id.into_js_result()?])?,
+        };
+        crate::db::batch_all(&db, vec![statement], "delete trip session").await?;
+        Ok(())
+    }
+
+    async fn get_trip(&self, trip_id: &str) -> Result<Response> {
+        let entries = self.entries(trip_id).await?;
+        let (Some(destination), Some(days), Some(Value::String(response))) =
+            (entries.get("destination"), entries.get("days"), entries.get("response"))
+        else {
+            return Response::error("trip not initialized", 404);
+        };
+        let plan = entries.get("plan").cloned().unwrap_or_else(|| serde_json::json!(plan::parse_days(response)));
+        let status = entries.get("status").cloned().unwrap_or_else(|| serde_json::json!(TripStatus::default()));
+        Response::from_json(&serde_json::json!({
+            "destination": destination,
+            "days": days,
+            "response": response,
+            "plan": plan,
+            "status": status
+        }))
+    }
+
+    async fn run_generation(&self, trip_id: &str, action: &str) -> Result<Response> {
+        let stored = self.entries(trip_id).await?.remove("generation");
+        let generation: Option<GenerationState> = stored.map(serde_json::from_value).transpose()?;
+        match action {
+            "start" => {
+                self.put(trip_id, vec![("generation", serde_json::to_value(GenerationState::default())?)]).await?;
+                Response::ok("started")
+            }
+            "cancel" => {
+                let Some(mut generation) = generation else {
+                    return Response::error("no plan generation in progress", 409);
+                };
+                generation.cancelled = true;
+                self.put(trip_id, vec![("generation", serde_json::to_value(&generation)?)]).await?;
+                Response::ok("cancelled")
+            }
+            "finish" => {
+                self.delete(trip_id, Some("generation")).await?;
+                Response::from_json(&generation.unwrap_or_default())
+            }
+            _ => Response::error("not found", 404),
+        }
+    }
+}
+
+impl SessionStore for D1Store {
+    fn init<'a>(&'a self, trip_id: &'a str, payload: &'a TripInit) -> LocalBoxFuture<'a, Result<Response>> {
+        Box::pin(async move {
+            let plan = if payload.plan.is_empty() { plan::parse_days(&payload.response) } else { payload.plan.clone() };
+            self.put(trip_id, vec![
+                ("destination", serde_json::to_value(&payload.destination)?),
+                ("days", serde_json::to_value(payload.days)?),
+                ("response", serde_json::to_value(&payload.response)?),
+                ("plan", serde_json::to_value(&plan)?),
+                ("status", serde_json::to_value(payload.status)?),
+            ]).await?;
+            Response::ok("initialized")
+        })
+    }
+
+    fn get<'a>(&'a self, trip_id: &'a str) -> LocalBoxFuture<'a, Result<Response>> {
+        Box::pin(self.get_trip(trip_id))
+    }
+
+    fn state<'a>(&'a self, trip_id: &'a str) -> LocalBoxFuture<'a, Result<Response>> {
+        Box::pin(async move { Response::from_json(&self.entries(trip_id).await?) })
+    }
+
+    fn reset<'a>(&'a self, trip_id: &'a str) -> LocalBoxFuture<'a, Result<Response>> {
+        Box::pin(async move {
+            self.delete(trip_id, None).await?;
+            Response::ok("reset")
+        })
+    }
+
+    fn set_status<'a>(&'a self, trip_id: &'a str, status: TripStatus) -> LocalBoxFuture<'a, Result<Response>> {
+        Box::pin(async move {
+            self.put(trip_id, vec![("status", serde_json::to_value(status)?)]).await?;
+            Response::ok("updated")
+        })
+    }
+
+    fn generation<'a>(&'a self, trip_id: &'a str, action: &'a str) -> LocalBoxFuture<'a, Result<Response>> {
+        Box::pin(self.run_generation(trip_id, action))
+    }
+}
@@ -0,0 +1,58 @@
+//! Human-friendly trip slugs.
+//!
+//! Every new trip gets a slug such as `paris-5-days` alongside its UUID, so it can be
+//! shared as `/trip/by-slug/paris-5-days`. Slugs are unique across the deployment; when
+//! the natural slug is taken an incrementing suffix is appended (`paris-5-days-2`,
+//! `paris-5-days-3`, ...). Trips created before slugs existed have none and are only
+//! reachable by id.
+
+/// The longest destination part of a slug, in characters.
+const MAX_DESTINATION_CHARS: usize = 40;
+
+/// Builds the natural slug of a trip from its destination and length.
+///
+/// ASCII letters and digits are kept (lowercased) and every other run of characters
+/// becomes a single `-`. A destination with nothing usable in it becomes `trip`.
+///
+/// # Example
+/// `base("Kyoto & Osaka", 7)` is `"kyoto-osaka-7-days"`.
+pub fn base(destination: &str, days: u32) -> String {
+    let mut name = String::new();
+    for c in destination.chars() {
+        if c.is_ascii_alphanumeric() {
+            name.push(c.to_ascii_lowercase());
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    name.truncate(MAX_DESTINATION_CHARS);
+    let name = name.trim_end_matches('-');
+    let name = if name.is_empty() { "trip" } else { name };
+    let unit = if days == 1 { "day" } else { "days" };
+    format!("{name}-{days}-{unit}")
+}
+
+/// Picks the first free slug for `base`, given the slugs already in use that start with it.
+///
+/// # Returns
+/// `base` itself when it is free, otherwise `base` followed by `-N`, where `N` is one more
+/// than the highest suffix in use (starting at `2`).
+pub fn next_free(base: &str, taken: &[String]) -> String {
+    if !taken.iter().any(|slug| slug == base) {
+        return base.to_string();
+    }
+    let highest = taken
+        .iter()
+        .filter_map(|slug| slug.strip_prefix(base)?.strip_prefix('-')?.parse::<u32>().ok())
+        .max()
+        .unwrap_or(1);
+    format!("{base}-{}", highest.max(1) + 1)
+}
+
+/// Returns `true` when `slug` could have been produced by [`base`] or [`next_free`], so
+/// lookups of anything else can be answered without touching the database.
+pub fn is_valid(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= MAX_DESTINATION_CHARS + 20
+        && slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
@@ -0,0 +1,49 @@
+//! Server-sent events, as read from Workers AI and written to chat clients.
+//!
+//! With `"stream": true` Workers AI answers with `text/event-stream`, one
+//! `data: {"response": "..."}` event per generated piece of text and a final
+//! `data: [DONE]`. [`Decoder`] splits such a body, which arrives in chunks that need not
+//! line up with events, back into the events' data. [`event`] formats the events
+//! `POST /trip/{trip_id}?stream=true` sends on to the client.
+
+/// Splits a `text/event-stream` body into the data of its events.
+#[derive(Default)]
+pub struct Decoder {
+    /// Bytes of the event not yet terminated by a blank line. Kept as bytes so a character
+    /// split across two chunks is decoded whole.
+    buffer: Vec<u8>,
+}
+
+impl Decoder {
+    /// Adds a chunk of the body.
+    ///
+    /// # Returns
+    /// The data of every event completed by the chunk, in order. The `data:` lines of an
+    /// event are joined with `\n`; events without data (comments, keep-alives) are skipped.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend(chunk.iter().filter(|b| **b != b'\r'));
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+            let raw: Vec<u8> = self.buffer.drain(..end + 2).collect();
+            let text = String::from_utf8_lossy(&raw);
+            let data: Vec<&str> = text
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|value| value.strip_prefix(' ').unwrap_or(value))
+                .collect();
+            if !data.is_empty() {
+                events.push(data.join("\n"));
+            }
+        }
+        events
+    }
+}
+
+/// Formats one event, e.g. `event: done\ndata: {"id":42}\n\n`. `name` is left out for
+/// unnamed (`message`) events. `data` must not contain line breaks; JSON text never does.
+pub fn event(name: Option<&str>, data: &str) -> String {
+    match name {
+        Some(name) => format!("event: {name}\ndata: {data}\n\n"),
+        None => format!("data: {data}\n\n"),
+    }
+}
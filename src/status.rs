@@ -0,0 +1,63 @@
+//! The lifecycle of a trip.
+//!
+//! Every trip starts out `planning`. Users then move it through the stages they use to
+//! track a trip, and [`TripStatus::can_transition_to`] decides which moves are allowed:
+//!
+//! ```text
+//! planning  -> booked, cancelled
+//! booked    -> planning, completed, cancelled
+//! cancelled -> planning
+//! completed -> (none)
+//! ```
+//!
+//! Any other change, such as reopening a completed trip, must be forced explicitly.
+use serde::{Deserialize, Serialize};
+
+/// The stage a trip is in, stored in the `trips.status` column as lowercase text.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TripStatus {
+    #[default]
+    Planning,
+    Booked,
+    Completed,
+    Cancelled,
+}
+
+impl TripStatus {
+    /// Every status, in lifecycle order.
+    pub const ALL: [TripStatus; 4] = [TripStatus::Planning, TripStatus::Booked, TripStatus::Completed, TripStatus::Cancelled];
+
+    /// Returns the status as stored in the database and used in JSON.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TripStatus::Planning => "planning",
+            TripStatus::Booked => "booked",
+            TripStatus::Completed => "completed",
+            TripStatus::Cancelled => "cancelled",
+        }
+    }
+
+    /// Parses a status name, ignoring case and surrounding whitespace.
+    pub fn parse(value: &str) -> Option<TripStatus> {
+        let value = value.trim().to_lowercase();
+        TripStatus::ALL.into_iter().find(|status| status.as_str() == value)
+    }
+
+    /// Returns the accepted status names as a comma-separated list, for error messages.
+    pub fn names() -> String {
+        TripStatus::ALL.map(TripStatus::as_str).join(", ")
+    }
+
+    /// Returns `true` when a trip may move from `self` to `next` without being forced.
+    ///
+    /// Staying in the same status is always allowed.
+    pub fn can_transition_to(self, next: TripStatus) -> bool {
+        use TripStatus::*;
+        self == next
+            || matches!(
+                (self, next),
+                (Planning, Booked | Cancelled) | (Booked, Planning | Completed | Cancelled) | (Cancelled, Planning)
+            )
+    }
+}
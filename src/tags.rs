@@ -0,0 +1,88 @@
+//! Free-form tags for organizing trips.
+//!
+//! A trip's tags are stored in the `trips.tags` column as a JSON array of strings, e.g.
+//! `["family","summer-2025"]`; a missing column value means no tags. Tags are normalized
+//! with [`normalize`] before they are stored or compared, so `"Summer 2025"` and
+//! `"summer-2025"` are the same tag. `POST /trips/tag` changes the tags of several trips
+//! at once with [`apply`].
+
+/// The longest tag accepted, in characters, after normalization.
+pub const MAX_TAG_CHARS: usize = 32;
+
+/// The most tags a single trip may carry.
+pub const MAX_TAGS_PER_TRIP: usize = 20;
+
+/// Normalizes a tag: trimmed, lowercased, with runs of whitespace, `-` and `_` turned into
+/// a single `-`.
+///
+/// # Errors
+/// A message suitable for a `400` response when the tag is blank, longer than
+/// [`MAX_TAG_CHARS`], or contains characters other than letters, digits, whitespace, `-`
+/// and `_`.
+pub fn normalize(tag: &str) -> Result<String, String> {
+    let mut normalized = String::new();
+    for c in tag.trim().chars() {
+        if c.is_alphanumeric() {
+            normalized.extend(c.to_lowercase());
+        } else if c.is_whitespace() || c == '-' || c == '_' {
+            if !normalized.ends_with('-') {
+                normalized.push('-');
+            }
+        } else {
+            return Err(format!("Tag {tag:?} may only contain letters, digits, spaces, `-` and `_`"));
+        }
+    }
+    let normalized = normalized.trim_matches('-').to_string();
+    if normalized.is_empty() {
+        return Err("Tags must not be blank".to_string());
+    }
+    if normalized.chars().count() > MAX_TAG_CHARS {
+        return Err(format!("Tag {tag:?} is longer than {MAX_TAG_CHARS} characters"));
+    }
+    Ok(normalized)
+}
+
+/// Normalizes a list of tags with [`normalize`], dropping duplicates.
+///
+/// # Errors
+/// The message of the first tag that does not normalize.
+pub fn normalize_all(tags: &[String]) -> Result<Vec<String>, String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = normalize(tag)?;
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    Ok(normalized)
+}
+
+/// Reads the tags stored in a `trips.tags` column. A missing or unreadable value is treated
+/// as no tags.
+pub fn parse_stored(value: Option<&str>) -> Vec<String> {
+    value.and_then(|v| serde_json::from_str(v).ok()).unwrap_or_default()
+}
+
+/// Applies a tag change to a trip's current tags.
+///
+/// Tags in `remove` are removed first, then those in `add` that the trip does not carry yet
+/// are appended, so a tag named in both ends up on the trip. Both lists must already be
+/// normalized (see [`normalize_all`]).
+///
+/// # Returns
+/// The trip's new tags, in the order they were first added.
+///
+/// # Errors
+/// A message when the trip would end up with more than [`MAX_TAGS_PER_TRIP`] tags.
+pub fn apply(current: &[String], add: &[String], remove: &[String]) -> Result<Vec<String>, String> {
+    let mut tags: Vec<String> = current.iter().filter(|t| !remove.contains(t)).cloned().collect();
+    for tag in add {
+        if !tags.contains(tag) {
+            tags.push(tag.clone());
+        }
+    }
+    if tags.len() > MAX_TAGS_PER_TRIP {
+        return Err(format!("A trip can carry at most {MAX_TAGS_PER_TRIP} tags"));
+    }
+    Ok(tags)
+}
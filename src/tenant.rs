@@ -0,0 +1,94 @@
+//! Optional separation of trips, plans and messages by tenant.
+//!
+//! With `MULTI_TENANT` enabled, every row in D1 carries a `tenant_id` and every query in
+//! `db` is restricted to the tenant of the current request, so one database can serve
+//! several tenants without them seeing each other's trips. The tenant is resolved by
+//! [`Tenant::resolve`] from:
+//!
+//! 1. the `X-Tenant-Id` header, or
+//! 2. the subdomain of the `Host` when it is a single label directly under `TENANT_DOMAIN`
+//!    (e.g. `acme.trips.example.com` with `TENANT_DOMAIN=trips.example.com`).
+//!
+//! With the flag off (the default) no tenant is resolved, the `tenant_id` column is never
+//! referenced, and queries are exactly what they were before.
+use worker::wasm_bindgen::JsValue;
+use worker::{Env, Request};
+
+use crate::config::Config;
+
+/// The longest tenant id accepted.
+const MAX_TENANT_ID_LEN: usize = 64;
+
+/// The tenant a request acts for; unscoped when multi-tenancy is off.
+#[derive(Clone, Debug, Default)]
+pub struct Tenant(Option<String>);
+
+impl Tenant {
+    /// Resolves the tenant of a request.
+    ///
+    /// # Returns
+    /// - An unscoped tenant when `MULTI_TENANT` is off.
+    /// - The tenant named by the `X-Tenant-Id` header or the `Host` subdomain otherwise.
+    ///
+    /// # Errors
+    /// A message suitable for a `400` response when multi-tenancy is on and no tenant was
+    /// given, or the id is longer than [`MAX_TENANT_ID_LEN`] or contains characters other
+    /// than ASCII letters, digits, `-` and `_`.
+    pub fn resolve(req: &Request, env: &Env, config: &Config) -> std::result::Result<Tenant, String> {
+        if !config.multi_tenant {
+            return Ok(Tenant(None));
+        }
+        let header = req.headers().get("X-Tenant-Id").ok().flatten().map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        let Some(id) = header.or_else(|| subdomain(req, env)) else {
+            return Err("Missing tenant: send an X-Tenant-Id header".to_string());
+        };
+        let valid = id.len() <= MAX_TENANT_ID_LEN
+            && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(format!("Invalid tenant id: use at most {MAX_TENANT_ID_LEN} letters, digits, '-' or '_'"));
+        }
+        Ok(Tenant(Some(id.to_ascii_lowercase())))
+    }
+
+    /// Returns `true` when queries are restricted to a tenant.
+    pub fn is_scoped(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// The condition to append to a `WHERE` clause: `" AND tenant_id = ?"` when scoped,
+    /// nothing otherwise. The value for the placeholder is added by [`Tenant::bind`].
+    pub fn filter(&self) -> &'static str {
+        if self.is_scoped() { " AND tenant_id = ?" } else { "" }
+    }
+
+    /// The column to append to an `INSERT` column list: `", tenant_id"` when scoped.
+    pub fn column(&self) -> &'static str {
+        if self.is_scoped() { ", tenant_id" } else { "" }
+    }
+
+    /// The placeholder to append to an `INSERT` value list: `", ?"` when scoped.
+    pub fn placeholder(&self) -> &'static str {
+        if self.is_scoped() { ", ?" } else { "" }
+    }
+
+    /// Appends the tenant id to `values` when scoped, to bind the placeholder added by
+    /// [`Tenant::filter`] or [`Tenant::placeholder`].
+    pub fn bind(&self, mut values: Vec<JsValue>) -> Vec<JsValue> {
+        if let Some(id) = &self.0 {
+            values.push(JsValue::from(id.as_str()));
+        }
+        values
+    }
+}
+
+/// Returns the `Host` label directly under `TENANT_DOMAIN`, if any.
+fn subdomain(req: &Request, env: &Env) -> Option<String> {
+    let domain = env.var("TENANT_DOMAIN").ok()?.to_string().trim().trim_start_matches('.').to_ascii_lowercase();
+    if domain.is_empty() {
+        return None;
+    }
+    let host = req.url().ok()?.host_str()?.to_ascii_lowercase();
+    let label = host.strip_suffix(&domain)?.strip_suffix('.')?;
+    (!label.is_empty() && !label.contains('.')).then(|| label.to_string())
+}
+
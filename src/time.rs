@@ -0,0 +1,158 @@
+//! Formatting of stored timestamps for display.
+//!
+//! Message and plan timestamps are stored as epoch milliseconds; rows written before that
+//! hold JavaScript date strings, which [`stored_millis`] still reads. Clients
+//! can ask for a human readable rendering with `?tz=` (an IANA time zone such as
+//! `Europe/Paris`) and `?locale=` (a BCP 47 tag such as `fr-FR`). Formatting is delegated
+//! to the runtime's `Intl` support via `Date.prototype.toLocaleString`.
+//!
+//! [`CalendarDate`] handles plain dates such as a trip's start date, which carry no time
+//! zone, so that plans can name the weekday of each day.
+use worker::js_sys::{Date, Function, Object, Reflect};
+use worker::wasm_bindgen::{JsCast, JsValue};
+
+/// Parses a stored timestamp, accepting epoch milliseconds or any string `Date` understands.
+fn parse(raw: &str) -> Option<Date> {
+    let value = match raw.trim().parse::<f64>() {
+        Ok(millis) => JsValue::from_f64(millis),
+        Err(_) => JsValue::from_str(raw),
+    };
+    let date = Date::new(&value);
+    (!date.get_time().is_nan()).then_some(date)
+}
+
+/// The current time in epoch milliseconds, as stored in `created_at` and `updated_at`.
+pub fn now_millis() -> i64 {
+    Date::now() as i64
+}
+
+/// Reads a stored timestamp column: an epoch-milliseconds integer, or the date string of a
+/// row written before timestamps were stored as integers.
+///
+/// # Returns
+/// The timestamp in epoch milliseconds, or `None` when the value is neither.
+pub fn stored_millis(value: &serde_json::Value) -> Option<i64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_i64().or_else(|| n.as_f64().map(|f| f as i64)),
+        serde_json::Value::String(raw) => parse(raw).map(|date| date.get_time() as i64),
+        _ => None,
+    }
+}
+
+/// Formats epoch milliseconds as an RFC 3339 UTC timestamp, e.g. `2025-11-06T14:03:27.512Z`.
+pub fn to_rfc3339(millis: i64) -> String {
+    Date::new(&JsValue::from_f64(millis as f64)).to_iso_string().into()
+}
+
+/// Formats a stored timestamp for the given locale and time zone.
+///
+/// # Arguments
+/// * `raw` - The timestamp as stored in the database.
+/// * `locale` - A BCP 47 locale tag, defaulting to `en-US`.
+/// * `tz` - An IANA time zone name, defaulting to `UTC`.
+///
+/// # Returns
+/// The localized date and time. If the time zone or locale is rejected by the runtime the
+/// timestamp is returned as a UTC ISO-8601 string instead, and if `raw` cannot be parsed
+/// at all it is returned unchanged.
+pub fn format_timestamp(raw: &str, locale: Option<&str>, tz: Option<&str>) -> String {
+    let Some(date) = parse(raw) else {
+        return raw.to_string();
+    };
+    let options = Object::new();
+    let _ = Reflect::set(&options, &"timeZone".into(), &tz.unwrap_or("UTC").into());
+    // Called through `Function::call2` so an invalid zone or locale surfaces as an `Err`
+    // (a JS RangeError) rather than an uncaught exception.
+    Reflect::get(&date, &"toLocaleString".into())
+        .ok()
+        .and_then(|f| f.dyn_into::<Function>().ok())
+        .and_then(|f| f.call2(&date, &locale.unwrap_or("en-US").into(), &options).ok())
+        .and_then(|formatted| formatted.as_string())
+        .unwrap_or_else(|| date.to_iso_string().into())
+}
+
+/// A calendar date without a time or time zone, such as a trip's start date.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CalendarDate {
+    /// Days since 1970-01-01.
+    days: i64,
+}
+
+/// Weekday names, starting from Monday.
+const WEEKDAYS: [&str; 7] = ["Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday", "Sunday"];
+
+impl CalendarDate {
+    /// Parses an ISO-8601 date such as `2025-07-14`.
+    pub fn parse(value: &str) -> Option<CalendarDate> {
+        let mut parts = value.trim().splitn(3, '-');
+        let year: i64 = parts.next()?.parse().ok()?;
+        let month: i64 = parts.next()?.parse().ok()?;
+        let day: i64 = parts.next()?.parse().ok()?;
+        if !(1..=9999).contains(&year) || !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+            return None;
+        }
+        // Days from civil, after Howard Hinnant's `days_from_civil`.
+        let y = if month <= 2 { year - 1 } else { year };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        Some(CalendarDate { days: era * 146_097 + doe - 719_468 })
+    }
+
+    /// Today's date in the time zone `tz` (an IANA name such as `Asia/Tokyo`).
+    ///
+    /// # Returns
+    /// `None` when the runtime rejects the time zone.
+    pub fn today(tz: &str) -> Option<CalendarDate> {
+        let options = Object::new();
+        let _ = Reflect::set(&options, &"timeZone".into(), &tz.into());
+        let date = Date::new_0();
+        // `en-CA` formats dates as `YYYY-MM-DD`.
+        let formatted = Reflect::get(&date, &"toLocaleDateString".into())
+            .ok()?
+            .dyn_into::<Function>()
+            .ok()?
+            .call2(&date, &"en-CA".into(), &options)
+            .ok()?
+            .as_string()?;
+        CalendarDate::parse(&formatted)
+    }
+
+    /// The date `days` days later.
+    pub fn add_days(self, days: u32) -> CalendarDate {
+        CalendarDate { days: self.days + i64::from(days) }
+    }
+
+    /// The English name of the date's weekday, e.g. `"Sunday"`.
+    pub fn weekday(self) -> &'static str {
+        // 1970-01-01 was a Thursday.
+        WEEKDAYS[(self.days + 3).rem_euclid(7) as usize]
+    }
+}
+
+impl std::fmt::Display for CalendarDate {
+    /// Formats the date as `YYYY-MM-DD`, after Howard Hinnant's `civil_from_days`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let z = self.days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        write!(f, "{year:04}-{month:02}-{day:02}")
+    }
+}
+
+/// The number of days in `month` (1-12) of `year`.
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
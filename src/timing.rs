@@ -0,0 +1,61 @@
+//! Coarse per-request phase timing reported through the `Server-Timing` header.
+//!
+//! Handlers wrap their AI, D1 and durable object calls with [`ServerTiming::measure`].
+//! Durations are summed per phase and rendered as, for example,
+//! `ai;dur=1200, db;dur=40, do;dur=15, total;dur=1262`, which browsers show in their
+//! devtools and monitoring can scrape. Timing uses `Date::now()` at millisecond
+//! resolution to keep the overhead negligible.
+use std::cell::RefCell;
+use std::future::Future;
+use worker::Date;
+
+/// Accumulates the time spent in each phase of a single request.
+///
+/// A new instance is created for every request in `main` and passed by reference to the
+/// handlers, so concurrent requests never share timings.
+pub struct ServerTiming {
+    started: u64,
+    phases: RefCell<Vec<(&'static str, u64)>>,
+}
+
+impl ServerTiming {
+    /// Starts timing a request.
+    pub fn new() -> Self {
+        Self {
+            started: Date::now().as_millis(),
+            phases: RefCell::new(vec![]),
+        }
+    }
+
+    /// Awaits `fut` and adds its duration to `phase`.
+    ///
+    /// # Arguments
+    /// * `phase` - The phase name, e.g. `"ai"`, `"db"` or `"do"`.
+    /// * `fut` - The operation to time.
+    ///
+    /// # Returns
+    /// Whatever `fut` resolves to.
+    pub async fn measure<F: Future>(&self, phase: &'static str, fut: F) -> F::Output {
+        let started = Date::now().as_millis();
+        let output = fut.await;
+        let elapsed = Date::now().as_millis().saturating_sub(started);
+        let mut phases = self.phases.borrow_mut();
+        match phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => phases.push((phase, elapsed)),
+        }
+        output
+    }
+
+    /// Renders the `Server-Timing` header value, with a final `total` entry for the whole request.
+    pub fn header_value(&self) -> String {
+        let total = Date::now().as_millis().saturating_sub(self.started);
+        self.phases
+            .borrow()
+            .iter()
+            .map(|(name, dur)| format!("{name};dur={dur}"))
+            .chain(std::iter::once(format!("total;dur={total}")))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
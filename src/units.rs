@@ -0,0 +1,92 @@
+//! The measurement units and currency a trip's plan and chat replies are written in.
+//!
+//! `POST /input` takes optional `units` (`metric` or `imperial`, default `metric`) and
+//! `currency` (one of [`CURRENCIES`]) fields. They are stored with the trip, and every
+//! later plan generation and chat reply for it gets the same instruction
+//! ([`UnitPreferences::instruction`]), so a trip planned in miles and dollars is not
+//! answered in kilometres and euros.
+use std::str::FromStr;
+
+/// The currency codes a trip may ask for costs in.
+pub const CURRENCIES: [&str; 16] = [
+    "USD", "EUR", "GBP", "JPY", "CHF", "CAD", "AUD", "NZD",
+    "CNY", "INR", "KRW", "SGD", "HKD", "SEK", "NOK", "DKK",
+];
+
+/// The system distances and temperatures are written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Units {
+    /// Kilometres, metres and degrees Celsius.
+    #[default]
+    Metric,
+    /// Miles, feet and degrees Fahrenheit.
+    Imperial,
+}
+
+impl Units {
+    /// Every system, in the order forms list them.
+    pub const ALL: [Units; 2] = [Units::Metric, Units::Imperial];
+
+    /// Returns the name used in forms and stored in `trips.units`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Units::Metric => "metric",
+            Units::Imperial => "imperial",
+        }
+    }
+}
+
+impl FromStr for Units {
+    type Err = String;
+
+    /// Parses `metric` or `imperial`, ignoring ASCII case and surrounding whitespace.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "metric" => Ok(Units::Metric),
+            "imperial" => Ok(Units::Imperial),
+            _ => Err("units must be one of: metric, imperial".to_string()),
+        }
+    }
+}
+
+/// Checks a requested currency against [`CURRENCIES`].
+///
+/// # Returns
+/// The code in upper case, e.g. `"EUR"` for `" eur "`.
+///
+/// # Errors
+/// A message for a `400` response listing the accepted codes.
+pub fn checked_currency(value: &str) -> Result<String, String> {
+    let code = value.trim().to_ascii_uppercase();
+    match CURRENCIES.contains(&code.as_str()) {
+        true => Ok(code),
+        false => Err(format!("currency must be one of: {}", CURRENCIES.join(", "))),
+    }
+}
+
+/// How a trip's measurements and costs are expressed.
+///
+/// # Fields
+/// * `units` - The system for distances and temperatures.
+/// * `currency` - The currency costs are given in, or `None` to leave it to the model
+///   (usually the local currency).
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct UnitPreferences {
+    pub units: Units,
+    pub currency: Option<String>,
+}
+
+impl UnitPreferences {
+    /// The sentences added to plan and chat prompts, e.g. `Give distances in miles and
+    /// temperatures in degrees Fahrenheit. Give all costs in USD.`
+    pub fn instruction(&self) -> String {
+        let mut text = match self.units {
+            Units::Metric => "Give distances in kilometres or metres and temperatures in degrees Celsius.".to_string(),
+            Units::Imperial => "Give distances in miles or feet and temperatures in degrees Fahrenheit.".to_string(),
+        };
+        if let Some(currency) = &self.currency {
+            text.push_str(&format!(" Give all costs in {currency}."));
+        }
+        text
+    }
+}